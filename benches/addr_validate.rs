@@ -1,8 +1,11 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
+#[cfg(unix)]
 use criterion::{criterion_group, criterion_main, Criterion};
+#[cfg(unix)]
 use pprof::validate;
 
+#[cfg(unix)]
 fn bench_validate_addr(c: &mut Criterion) {
     c.bench_function("validate stack addr", |b| {
         let stack_addrs = [0; 100];
@@ -25,5 +28,11 @@ fn bench_validate_addr(c: &mut Criterion) {
     });
 }
 
+#[cfg(unix)]
 criterion_group!(benches, bench_validate_addr);
+#[cfg(unix)]
 criterion_main!(benches);
+
+// there is no signal handler whose reads need to be validated on Windows
+#[cfg(not(unix))]
+fn main() {}
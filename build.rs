@@ -1,5 +1,16 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
+/// The OpenTelemetry protos of the `otlp` feature, which import each other
+/// from the `proto` directory.
+#[cfg(feature = "otlp")]
+const OTLP_PROTOS: &[&str] = &[
+    "proto/opentelemetry/proto/common/v1/common.proto",
+    "proto/opentelemetry/proto/resource/v1/resource.proto",
+    "proto/opentelemetry/proto/profiles/v1experimental/pprofextended.proto",
+    "proto/opentelemetry/proto/profiles/v1experimental/profiles.proto",
+    "proto/opentelemetry/proto/collector/profiles/v1experimental/profiles_service.proto",
+];
+
 #[cfg(feature = "protobuf-codec")]
 // Allow deprecated as TiKV pin versions to a outdated one.
 #[allow(deprecated)]
@@ -20,10 +31,39 @@ fn generate_protobuf() {
     .unwrap();
     let mut f = std::fs::File::create(format!("{}/mod.rs", out_dir)).unwrap();
     write!(f, "pub mod profile;").unwrap();
+    #[cfg(feature = "otlp")]
+    write!(f, "\n#[doc(hidden)]\npub mod otlp;").unwrap();
+
+    // The OpenTelemetry messages are generated into their own module, as
+    // `pprofextended.proto` defines a `Profile` too.
+    #[cfg(feature = "otlp")]
+    {
+        let otlp_dir = format!("{}/otlp", out_dir);
+        std::fs::create_dir_all(&otlp_dir).unwrap();
+        protobuf_codegen_pure::run(protobuf_codegen_pure::Args {
+            out_dir: &otlp_dir,
+            includes: &["proto"],
+            input: OTLP_PROTOS,
+            customize: protobuf_codegen_pure::Customize {
+                generate_accessors: Some(false),
+                lite_runtime: Some(true),
+                ..Default::default()
+            },
+        })
+        .unwrap();
+        let mut f = std::fs::File::create(format!("{}/mod.rs", otlp_dir)).unwrap();
+        for proto in OTLP_PROTOS {
+            let name = std::path::Path::new(proto).file_stem().unwrap();
+            writeln!(f, "pub mod {};", name.to_str().unwrap()).unwrap();
+        }
+    }
 }
 
+/// Generate the prost files of `protos` with `config` into the `proto`
+/// directory, unless the `generated` ones are already up-to-date, which is
+/// told by the hash of the protos on their first line.
 #[cfg(feature = "prost-codec")]
-fn generate_prost() {
+fn generate_prost(mut config: prost_build::Config, protos: &[&str], generated: &[&str]) {
     use sha2::{Digest, Sha256};
     use std::{
         fmt::Write,
@@ -31,42 +71,69 @@ fn generate_prost() {
         io::{self, BufRead, BufReader},
     };
 
-    const PRE_GENERATED_PATH: &str = "proto/perftools.profiles.rs";
-
-    // Calculate the SHA256 of the proto file
+    // Calculate the SHA256 of the proto files
     let mut hasher = Sha256::new();
-    let mut proto_file = BufReader::new(File::open("proto/profile.proto").unwrap());
-    io::copy(&mut proto_file, &mut hasher).unwrap();
+    for proto in protos {
+        let mut proto_file = BufReader::new(File::open(proto).unwrap());
+        io::copy(&mut proto_file, &mut hasher).unwrap();
+    }
     let mut hex = String::new();
     for b in hasher.finalize() {
         write!(&mut hex, "{:x}", b).unwrap();
     }
-    let hash_comment = format!("// {}  proto/profile.proto", hex);
+    let hash_comment = format!("// {}  {}", hex, protos.join(" "));
 
-    let first_line = File::open(PRE_GENERATED_PATH)
-        .and_then(|f| {
-            let mut reader = BufReader::new(f);
-            let mut first_line = String::new();
-            reader.read_line(&mut first_line)?;
-            Ok(first_line)
-        })
-        .unwrap_or_default();
-    // If the hash of the proto file changes, regenerate the prost file.
-    if first_line.trim() != hash_comment {
-        prost_build::Config::new()
+    let up_to_date = generated.iter().all(|path| {
+        let first_line = File::open(path)
+            .and_then(|f| {
+                let mut reader = BufReader::new(f);
+                let mut first_line = String::new();
+                reader.read_line(&mut first_line)?;
+                Ok(first_line)
+            })
+            .unwrap_or_default();
+        first_line.trim() == hash_comment
+    });
+    // If the hash of the proto files changes, regenerate the prost files.
+    if !up_to_date {
+        config
             .out_dir("proto/")
-            .compile_protos(&["proto/profile.proto"], &["proto/"])
+            .compile_protos(protos, &["proto/"])
             .unwrap();
-        // Prepend the hash comment to the generated file.
-        let generated = fs::read_to_string(PRE_GENERATED_PATH).unwrap();
-        let with_hex = format!("{}\n\n{}", hash_comment, generated);
-        fs::write(PRE_GENERATED_PATH, with_hex).unwrap();
+        // Prepend the hash comment to the generated files.
+        for path in generated {
+            let generated = fs::read_to_string(path).unwrap();
+            let with_hex = format!("{}\n\n{}", hash_comment, generated);
+            fs::write(path, with_hex).unwrap();
+        }
     }
 }
 
 fn main() {
     #[cfg(feature = "prost-codec")]
-    generate_prost();
+    generate_prost(
+        prost_build::Config::new(),
+        &["proto/profile.proto"],
+        &["proto/perftools.profiles.rs"],
+    );
+    #[cfg(all(feature = "prost-codec", feature = "otlp"))]
+    generate_prost(
+        {
+            let mut config = prost_build::Config::new();
+            // its indented examples would be run as doctests
+            config.disable_comments([
+                ".opentelemetry.proto.profiles.v1experimental.ProfileContainer.attributes",
+            ]);
+            config
+        },
+        OTLP_PROTOS,
+        &[
+            "proto/opentelemetry.proto.common.v1.rs",
+            "proto/opentelemetry.proto.resource.v1.rs",
+            "proto/opentelemetry.proto.profiles.v1experimental.rs",
+            "proto/opentelemetry.proto.collector.profiles.v1experimental.rs",
+        ],
+    );
     #[cfg(feature = "protobuf-codec")]
     generate_protobuf();
 }
@@ -0,0 +1,41 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+use pprof::protos::Message;
+use std::fs::File;
+use std::io::Write;
+
+#[global_allocator]
+static ALLOC: pprof::AllocProfiler = pprof::AllocProfiler::system();
+
+#[inline(never)]
+fn build_table(n: usize) -> Vec<String> {
+    (0..n).map(|i| format!("entry-{}", i)).collect()
+}
+
+#[inline(never)]
+fn build_buffers(n: usize) -> Vec<Vec<u8>> {
+    (0..n).map(|i| vec![0; 4096 + i]).collect()
+}
+
+fn main() {
+    let guard = pprof::HeapProfilerGuard::new(64 * 1024).unwrap();
+
+    let mut total = 0;
+    for _ in 0..100 {
+        total += build_table(10000).len();
+        total += build_buffers(1000).len();
+    }
+    println!("allocated: {}", total);
+
+    let report = guard.report();
+    for (frames, stat) in report.sorted_data().into_iter().take(5) {
+        println!("{:?}: {:?}", stat, frames);
+    }
+
+    let mut file = File::create("heap.pb").unwrap();
+    let profile = report.pprof().unwrap();
+
+    let mut content = Vec::new();
+    profile.encode(&mut content).unwrap();
+    file.write_all(&content).unwrap();
+}
@@ -0,0 +1,28 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::fs::File;
+
+#[inline(never)]
+fn is_prime_number(v: usize) -> bool {
+    (2..v).take_while(|n| n * n <= v).all(|n| v % n != 0)
+}
+
+fn main() {
+    let guard = pprof::ProfilerGuard::new(100).unwrap();
+
+    let mut v = 0;
+    for i in 2..5000000 {
+        if is_prime_number(i) {
+            v += 1;
+        }
+    }
+
+    println!("Prime numbers: {}", v);
+
+    if let Ok(report) = guard.report().build() {
+        let file = File::create("flamegraph.html").unwrap();
+        report.html(file).unwrap();
+
+        println!("report: {:?}", &report);
+    };
+}
@@ -0,0 +1,31 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+#[inline(never)]
+fn is_prime_number(v: usize) -> bool {
+    (2..v).take_while(|n| n * n <= v).all(|n| v % n != 0)
+}
+
+fn main() {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(1000)
+        .perf_event(pprof::PerfEventKind::CpuClock)
+        .build()
+        .unwrap();
+
+    let mut count = 0;
+    for i in 2..5_000_000 {
+        if is_prime_number(i) {
+            count += 1;
+        }
+    }
+    println!("{} prime numbers", count);
+
+    match guard.report().build() {
+        Ok(report) => {
+            println!("report: {:?}", &report);
+        }
+        Err(err) => {
+            println!("error: {}", err);
+        }
+    }
+}
@@ -0,0 +1,16 @@
+// 9f6a2fb95751e27b9cbf864df78054b686e981ffcf5e9681e9fc34157829a3d  proto/opentelemetry/proto/common/v1/common.proto proto/opentelemetry/proto/resource/v1/resource.proto proto/opentelemetry/proto/profiles/v1experimental/pprofextended.proto proto/opentelemetry/proto/profiles/v1experimental/profiles.proto proto/opentelemetry/proto/collector/profiles/v1experimental/profiles_service.proto
+
+/// Resource information.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Resource {
+    /// Set of attributes that describe the resource.
+    /// Attribute keys MUST be unique (it is not allowed to have more than one
+    /// attribute with the same key).
+    #[prost(message, repeated, tag = "1")]
+    pub attributes: ::prost::alloc::vec::Vec<super::super::common::v1::KeyValue>,
+    /// dropped_attributes_count is the number of dropped attributes. If the value is 0, then
+    /// no attributes were dropped.
+    #[prost(uint32, tag = "2")]
+    pub dropped_attributes_count: u32,
+}
@@ -12,8 +12,15 @@ impl super::Frame for backtrace::Frame {
     fn symbol_address(&self) -> *mut libc::c_void {
         self.symbol_address()
     }
+
+    fn from_backtrace(frame: &backtrace::Frame) -> Self {
+        frame.clone()
+    }
 }
 
+/// Unwinds with `libunwind`, which doesn't check that the stack it reads is
+/// still mapped: a runtime unmapping the stack of the sampled thread while
+/// it's unwound makes the signal handler fault.
 pub struct Trace {}
 
 impl super::Trace for Trace {
@@ -24,5 +31,7 @@ impl super::Trace for Trace {
     }
 }
 
+#[allow(unused_imports)]
 pub use backtrace::Frame;
+#[allow(unused_imports)]
 pub use backtrace::Symbol;
@@ -34,6 +34,12 @@ impl super::Frame for Frame {
             unsafe { _Unwind_FindEnclosingFunction(self.ip as *mut c_void) }
         }
     }
+
+    fn from_backtrace(frame: &backtrace::Frame) -> Self {
+        Frame {
+            ip: frame.ip() as usize,
+        }
+    }
 }
 
 /// helper to read a pointer from a potentially unaligned address
@@ -111,6 +117,18 @@ impl super::Trace for Trace {
                 break;
             }
 
+            // stop at a frame pointer outside of the mappings known to be
+            // readable, e.g. into a stack unmapped by the runtime, before
+            // paying for the syscalls of `validate`
+            #[cfg(target_os = "linux")]
+            if crate::memory_map::is_mapped(
+                frame_pointer as usize,
+                std::mem::size_of::<FramePointerLayout>(),
+            ) == Some(false)
+            {
+                break;
+            }
+
             if !validate(frame_pointer as *const libc::c_void) {
                 break;
             }
@@ -129,6 +147,65 @@ impl super::Trace for Trace {
     }
 }
 
+/// The number of nested calls of `probe_frames` which the self-test unwinds.
+const PROBE_DEPTH: usize = 4;
+
+/// Unwind the frames from the innermost of `depth` nested calls, and count
+/// the frames found in this function.
+#[inline(never)]
+fn probe_frames(depth: usize) -> usize {
+    if depth == 0 {
+        count_probe_frames()
+    } else {
+        // not a tail call, so that every call keeps its frame
+        std::hint::black_box(probe_frames(depth - 1))
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    target_env = "gnu",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[inline(never)]
+fn count_probe_frames() -> usize {
+    let mut ucontext: libc::ucontext_t = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getcontext(&mut ucontext) } != 0 {
+        return PROBE_DEPTH + 1;
+    }
+
+    let probe = probe_frames as *const () as usize;
+    let mut found = 0;
+    let mut depth = 0;
+    <Trace as super::Trace>::trace(&mut ucontext as *mut _ as *mut c_void, |frame| {
+        if super::Frame::symbol_address(frame) as usize == probe {
+            found += 1;
+        }
+        depth += 1;
+        depth < 2 * PROBE_DEPTH + 2
+    });
+
+    found
+}
+
+// the context of the current thread can't be captured portably, assume that
+// the frame pointers are fine
+#[cfg(not(all(
+    target_os = "linux",
+    target_env = "gnu",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+fn count_probe_frames() -> usize {
+    PROBE_DEPTH + 1
+}
+
+/// Returns `false` if unwinding through the frame pointers doesn't find the
+/// frames of a known call chain, which means that the binary was most likely
+/// compiled without `-C force-frame-pointers=yes`.
+pub(crate) fn frame_pointers_available() -> bool {
+    probe_frames(PROBE_DEPTH) > PROBE_DEPTH
+}
+
 #[repr(C)]
 struct FramePointerLayout {
     frame_pointer: *mut FramePointerLayout,
@@ -148,6 +225,17 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_probe_frames() {
+        // the result depends on the flags of the build, but it never finds
+        // more frames than the calls of the probe
+        assert!(probe_frames(PROBE_DEPTH) <= PROBE_DEPTH + 1);
+        assert_eq!(
+            frame_pointers_available(),
+            probe_frames(PROBE_DEPTH) > PROBE_DEPTH
+        );
+    }
+
     #[test]
     fn test_read_ptr_aligned() {
         let x = AlignToSixtyFour::default();
@@ -34,6 +34,8 @@ pub trait Frame: Sized + Clone {
     fn resolve_symbol<F: FnMut(&Self::S)>(&self, cb: F);
     fn symbol_address(&self) -> *mut c_void;
     fn ip(&self) -> usize;
+    /// Convert a frame unwound by `backtrace` outside of a signal handler.
+    fn from_backtrace(frame: &backtrace::Frame) -> Self;
 }
 
 pub trait Trace {
@@ -44,28 +46,35 @@ pub trait Trace {
         Self: Sized;
 }
 
-#[cfg(not(all(
-    any(
-        target_arch = "x86_64",
-        target_arch = "aarch64",
-        target_arch = "riscv64",
-        target_arch = "loongarch64"
-    ),
-    feature = "frame-pointer"
-)))]
+#[cfg(all(
+    unix,
+    not(all(
+        any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64",
+            target_arch = "loongarch64"
+        ),
+        feature = "frame-pointer"
+    ))
+))]
 mod backtrace_rs;
-#[cfg(not(all(
-    any(
-        target_arch = "x86_64",
-        target_arch = "aarch64",
-        target_arch = "riscv64",
-        target_arch = "loongarch64"
-    ),
-    feature = "frame-pointer"
-)))]
+#[cfg(all(
+    unix,
+    not(all(
+        any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64",
+            target_arch = "loongarch64"
+        ),
+        feature = "frame-pointer"
+    ))
+))]
 pub use backtrace_rs::Trace as TraceImpl;
 
 #[cfg(all(
+    unix,
     any(
         target_arch = "x86_64",
         target_arch = "aarch64",
@@ -76,6 +85,7 @@ pub use backtrace_rs::Trace as TraceImpl;
 ))]
 pub mod frame_pointer;
 #[cfg(all(
+    unix,
     any(
         target_arch = "x86_64",
         target_arch = "aarch64",
@@ -85,3 +95,8 @@ pub mod frame_pointer;
     feature = "frame-pointer"
 ))]
 pub use frame_pointer::Trace as TraceImpl;
+
+#[cfg(windows)]
+pub mod windows;
+#[cfg(windows)]
+pub use windows::Trace as TraceImpl;
@@ -0,0 +1,155 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use std::ptr::null_mut;
+
+use libc::c_void;
+use windows_sys::Win32::System::Diagnostics::Debug::CONTEXT;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use windows_sys::Win32::System::Diagnostics::Debug::{RtlLookupFunctionEntry, RtlVirtualUnwind};
+
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub ip: usize,
+}
+
+impl super::Frame for Frame {
+    type S = backtrace::Symbol;
+
+    fn ip(&self) -> usize {
+        self.ip
+    }
+
+    fn resolve_symbol<F: FnMut(&Self::S)>(&self, cb: F) {
+        backtrace::resolve(self.ip as *mut c_void, cb);
+    }
+
+    fn symbol_address(&self) -> *mut c_void {
+        function_start(self.ip).unwrap_or(self.ip) as *mut c_void
+    }
+
+    fn from_backtrace(frame: &backtrace::Frame) -> Self {
+        Frame {
+            ip: frame.ip() as usize,
+        }
+    }
+}
+
+/// The start of the function containing `ip`, from the unwind tables of its
+/// module. The leaf functions, which have no unwind info, aren't found.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[allow(clippy::unnecessary_cast)]
+fn function_start(ip: usize) -> Option<usize> {
+    let mut image_base = 0;
+    let entry = unsafe { RtlLookupFunctionEntry(ip as _, &mut image_base, null_mut()) };
+    if entry.is_null() {
+        return None;
+    }
+
+    Some(image_base as usize + unsafe { (*entry).BeginAddress } as usize)
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn function_start(_ip: usize) -> Option<usize> {
+    None
+}
+
+/// The `CONTEXT` of a sampled thread, which `GetThreadContext` requires to
+/// be aligned on 16 bytes.
+#[repr(C, align(16))]
+pub(crate) struct AlignedContext(pub(crate) CONTEXT);
+
+/// Unwinds the `CONTEXT` of a suspended thread with `RtlVirtualUnwind`, which
+/// follows the unwind tables of the modules, so the frame pointers aren't
+/// needed. The unwinding stops at the first address outside of the known
+/// functions, e.g. in JIT code which didn't register its unwind info.
+pub struct Trace {}
+
+impl super::Trace for Trace {
+    type Frame = Frame;
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn trace<F: FnMut(&Self::Frame) -> bool>(context: *mut c_void, mut cb: F) {
+        if context.is_null() {
+            return;
+        }
+        let context = unsafe { &mut *(context as *mut CONTEXT) };
+
+        let mut first = true;
+        loop {
+            let (ip, sp) = registers(context);
+            if ip == 0 || !cb(&Frame { ip }) {
+                break;
+            }
+
+            let mut image_base = 0;
+            let entry = unsafe { RtlLookupFunctionEntry(ip as _, &mut image_base, null_mut()) };
+            if entry.is_null() {
+                // only the interrupted function may be a leaf function, which
+                // has no unwind info and hasn't touched the stack pointer
+                if !first {
+                    break;
+                }
+                return_from_leaf(context);
+            } else {
+                let mut handler_data = null_mut();
+                let mut establisher_frame = 0;
+                unsafe {
+                    RtlVirtualUnwind(
+                        0,
+                        image_base,
+                        ip as _,
+                        entry,
+                        context,
+                        &mut handler_data,
+                        &mut establisher_frame,
+                        null_mut(),
+                    )
+                };
+            }
+            first = false;
+
+            // the context is left unchanged at the end of the stack on aarch64
+            if registers(context) == (ip, sp) {
+                break;
+            }
+        }
+    }
+
+    /// Only the interrupted instruction is recorded on the other
+    /// architectures.
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    fn trace<F: FnMut(&Self::Frame) -> bool>(context: *mut c_void, mut cb: F) {
+        if context.is_null() {
+            return;
+        }
+        let context = unsafe { &*(context as *mut CONTEXT) };
+        cb(&Frame {
+            ip: context.Eip as usize,
+        });
+    }
+}
+
+/// The program counter and the stack pointer of `context`.
+#[cfg(target_arch = "x86_64")]
+fn registers(context: &CONTEXT) -> (usize, usize) {
+    (context.Rip as usize, context.Rsp as usize)
+}
+
+#[cfg(target_arch = "aarch64")]
+fn registers(context: &CONTEXT) -> (usize, usize) {
+    (context.Pc as usize, context.Sp as usize)
+}
+
+/// Unwind a leaf function, whose return address is on the top of the stack.
+#[cfg(target_arch = "x86_64")]
+fn return_from_leaf(context: &mut CONTEXT) {
+    context.Rip = unsafe { std::ptr::read_unaligned(context.Rsp as *const u64) };
+    context.Rsp += 8;
+}
+
+/// Unwind a leaf function, whose return address is in the link register.
+#[cfg(target_arch = "aarch64")]
+fn return_from_leaf(context: &mut CONTEXT) {
+    context.Pc = unsafe { context.Anonymous.Anonymous.Lr };
+}
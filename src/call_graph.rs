@@ -0,0 +1,126 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! The call graph of a report, like `pprof -web`.
+//!
+//! A flamegraph is a tree, so a function called from several places is split
+//! into as many nodes, and mutually recursive functions nest into each
+//! other. The call graph has a single node per function, and an edge per
+//! caller and callee, which shows where the samples of a function come from
+//! and where they go.
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+use crate::report::{FunctionStat, Report};
+use crate::Result;
+
+/// The functions of a report and the calls between them, see
+/// `Report::call_graph`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CallGraph {
+    /// The self and total samples of every function, keyed by its demangled
+    /// name.
+    pub nodes: HashMap<String, FunctionStat>,
+    /// The number of samples in which a function calls another, keyed by
+    /// `(caller, callee)`. A call repeated in a stack, e.g. by a recursion,
+    /// is only counted once per sample.
+    pub edges: HashMap<(String, String), isize>,
+    /// The number of samples of the report.
+    pub total: isize,
+}
+
+impl CallGraph {
+    /// Write the graph in the DOT language of Graphviz, e.g. to render it with
+    /// `dot -Tsvg`. Every node is labeled with the self and total samples of
+    /// its function, and every edge with the samples of its call. The nodes
+    /// and the edges are written in a stable order, the heaviest first.
+    pub fn write_dot<W: Write>(&self, mut writer: W) -> Result<()> {
+        let mut nodes: Vec<_> = self.nodes.iter().collect();
+        nodes.sort_by(|(a_name, a), (b_name, b)| {
+            (Reverse(a.total_count), Reverse(a.self_count), a_name).cmp(&(
+                Reverse(b.total_count),
+                Reverse(b.self_count),
+                b_name,
+            ))
+        });
+        let ids: HashMap<&str, usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(index, (name, _))| (name.as_str(), index + 1))
+            .collect();
+
+        let mut edges: Vec<_> = self.edges.iter().collect();
+        edges.sort_by(|(a_call, a), (b_call, b)| (Reverse(*a), a_call).cmp(&(Reverse(*b), b_call)));
+
+        writeln!(writer, "digraph \"call graph\" {{")?;
+        writeln!(writer, "node [shape=box fontname=\"Helvetica\"];")?;
+        for (name, stat) in nodes {
+            writeln!(
+                writer,
+                "N{} [label=\"{}\\n{} ({})\\nof {} ({})\"];",
+                ids[name.as_str()],
+                escape(name),
+                stat.self_count,
+                self.percent(stat.self_count),
+                stat.total_count,
+                self.percent(stat.total_count),
+            )?;
+        }
+        for ((caller, callee), count) in edges {
+            writeln!(
+                writer,
+                "N{} -> N{} [label=\"{}\" weight={}];",
+                ids[caller.as_str()],
+                ids[callee.as_str()],
+                count,
+                count,
+            )?;
+        }
+        writeln!(writer, "}}")?;
+
+        Ok(())
+    }
+
+    fn percent(&self, count: isize) -> String {
+        format!("{:.2}%", count as f64 * 100.0 / self.total.max(1) as f64)
+    }
+}
+
+/// Escape `name` for a quoted DOT string.
+fn escape(name: &str) -> String {
+    name.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl Report {
+    /// The call graph of the report: a node per function with its self and
+    /// total samples, as in `function_stats`, and an edge per caller and
+    /// callee with the number of samples in which it's called. The functions
+    /// inlined into a frame are called by the function of the frame.
+    pub fn call_graph(&self) -> CallGraph {
+        let mut edges: HashMap<(String, String), isize> = HashMap::new();
+        let mut seen = HashSet::new();
+        for (key, count) in self.data.iter() {
+            // from the leaf, so every function is called by the next one
+            let names: Vec<String> = key
+                .frames
+                .iter()
+                .flatten()
+                .map(|symbol| symbol.name())
+                .collect();
+            seen.clear();
+            for call in names.windows(2) {
+                let call = (call[1].clone(), call[0].clone());
+                if seen.insert(call.clone()) {
+                    *edges.entry(call).or_default() += count;
+                }
+            }
+        }
+
+        CallGraph {
+            nodes: self.function_stats_map(),
+            edges,
+            total: self.data.values().sum(),
+        }
+    }
+}
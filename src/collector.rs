@@ -5,6 +5,7 @@ use std::convert::TryInto;
 use std::fmt::Debug;
 use std::hash::{Hash, Hasher};
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::SystemTime;
 
 use crate::frames::UnresolvedFrames;
 
@@ -15,10 +16,28 @@ pub const BUCKETS: usize = 1 << 12;
 pub const BUCKETS_ASSOCIATIVITY: usize = 4;
 pub const BUFFER_LENGTH: usize = (1 << 18) / std::mem::size_of::<Entry<UnresolvedFrames>>();
 
+/// How the profiler collects the samples, see
+/// [`ProfilerGuardBuilder::collect_mode`](crate::ProfilerGuardBuilder::collect_mode).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CollectMode {
+    /// Count the samples of every stack, with the time of its first and its
+    /// last sample.
+    #[default]
+    Aggregate,
+    /// Append every sample to a log, in the order they are taken, with its
+    /// own time. The log is spilled to a temporary file, and the samples
+    /// after the first `max_trace_samples` are dropped.
+    Trace,
+}
+
 #[derive(Debug)]
 pub struct Entry<T> {
     pub item: T,
     pub count: isize,
+    /// The time of the earliest and the latest sample added with a timestamp,
+    /// see `Collector::add_at`.
+    pub first_seen: Option<SystemTime>,
+    pub last_seen: Option<SystemTime>,
 }
 
 impl<T: Default> Default for Entry<T> {
@@ -26,6 +45,34 @@ impl<T: Default> Default for Entry<T> {
         Entry {
             item: Default::default(),
             count: 0,
+            first_seen: None,
+            last_seen: None,
+        }
+    }
+}
+
+impl<T> Entry<T> {
+    fn new(item: T, count: isize, timestamp: Option<SystemTime>) -> Self {
+        Entry {
+            item,
+            count,
+            first_seen: timestamp,
+            last_seen: timestamp,
+        }
+    }
+
+    // plain comparisons, so that it can be called in the signal handler
+    fn add(&mut self, count: isize, timestamp: Option<SystemTime>) {
+        self.count += count;
+        if let Some(timestamp) = timestamp {
+            self.first_seen = Some(match self.first_seen {
+                Some(first_seen) if first_seen < timestamp => first_seen,
+                _ => timestamp,
+            });
+            self.last_seen = Some(match self.last_seen {
+                Some(last_seen) if last_seen > timestamp => last_seen,
+                _ => timestamp,
+            });
         }
     }
 }
@@ -45,11 +92,11 @@ impl<T: Eq + Default> Default for Bucket<T> {
 }
 
 impl<T: Eq> Bucket<T> {
-    pub fn add(&mut self, key: T, count: isize) -> Option<Entry<T>> {
+    pub fn add(&mut self, key: T, count: isize, timestamp: Option<SystemTime>) -> Option<Entry<T>> {
         let mut done = false;
         self.entries[0..self.length].iter_mut().for_each(|ele| {
             if ele.item == key {
-                ele.count += count;
+                ele.add(count, timestamp);
                 done = true;
             }
         });
@@ -57,9 +104,7 @@ impl<T: Eq> Bucket<T> {
         if done {
             None
         } else if self.length < BUCKETS_ASSOCIATIVITY {
-            let ele = &mut self.entries[self.length];
-            ele.item = key;
-            ele.count = count;
+            self.entries[self.length] = Entry::new(key, count, timestamp);
 
             self.length += 1;
             None
@@ -74,13 +119,13 @@ impl<T: Eq> Bucket<T> {
                 }
             }
 
-            let mut new_entry = Entry { item: key, count };
+            let mut new_entry = Entry::new(key, count, timestamp);
             std::mem::swap(&mut self.entries[min_index], &mut new_entry);
             Some(new_entry)
         }
     }
 
-    pub fn iter(&self) -> BucketIterator<T> {
+    pub fn iter(&self) -> BucketIterator<'_, T> {
         BucketIterator::<T> {
             related_bucket: self,
             index: 0,
@@ -128,10 +173,20 @@ impl<T: Hash + Eq> HashCounter<T> {
     }
 
     pub fn add(&mut self, key: T, count: isize) -> Option<Entry<T>> {
+        self.add_at(key, count, None)
+    }
+
+    /// Same as `add`, but also records `timestamp` as the time of the sample.
+    pub fn add_at(
+        &mut self,
+        key: T,
+        count: isize,
+        timestamp: Option<SystemTime>,
+    ) -> Option<Entry<T>> {
         let hash_value = Self::hash(&key);
         let bucket = &mut self.buckets[(hash_value % BUCKETS as u64) as usize];
 
-        bucket.add(key, count)
+        bucket.add(key, count, timestamp)
     }
 
     pub fn iter(&self) -> impl Iterator<Item = &Entry<T>> {
@@ -246,6 +301,10 @@ impl<'a, T> Iterator for TempFdArrayIterator<'a, T> {
 pub struct Collector<T: Hash + Eq + 'static> {
     map: HashCounter<T>,
     temp_array: TempFdArray<Entry<T>>,
+    sample_count: isize,
+    /// The maximum number of samples of a trace, see `Collector::trace`.
+    trace_capacity: Option<usize>,
+    traced: usize,
 }
 
 impl<T: Hash + Eq + Default + Debug + 'static> Collector<T> {
@@ -253,22 +312,86 @@ impl<T: Hash + Eq + Default + Debug + 'static> Collector<T> {
         Ok(Self {
             map: HashCounter::<T>::default(),
             temp_array: TempFdArray::<Entry<T>>::new()?,
+            sample_count: 0,
+            trace_capacity: None,
+            traced: 0,
+        })
+    }
+
+    /// A collector which keeps every entry added, with its own time, instead of
+    /// counting the equal keys. They are spilled to the temporary file, and
+    /// the entries after the first `capacity` are rejected.
+    pub fn trace(capacity: usize) -> std::io::Result<Self> {
+        Ok(Self {
+            trace_capacity: Some(capacity),
+            ..Self::new()?
         })
     }
 }
 
 impl<T: Hash + Eq + 'static> Collector<T> {
     pub fn add(&mut self, key: T, count: isize) -> std::io::Result<()> {
-        if let Some(evict) = self.map.add(key, count) {
+        self.add_at(key, count, None)
+    }
+
+    /// Same as `add`, but also records `timestamp` as the time of the sample,
+    /// so that the entries know when their key was first and last seen.
+    pub fn add_at(
+        &mut self,
+        key: T,
+        count: isize,
+        timestamp: Option<SystemTime>,
+    ) -> std::io::Result<()> {
+        self.sample_count += count;
+        if let Some(capacity) = self.trace_capacity {
+            if self.traced >= capacity {
+                // an error without a message doesn't allocate
+                return Err(std::io::ErrorKind::OutOfMemory.into());
+            }
+            self.traced += 1;
+            return self.temp_array.push(Entry::new(key, count, timestamp));
+        }
+        if let Some(evict) = self.map.add_at(key, count, timestamp) {
             self.temp_array.push(evict)?;
         }
 
         Ok(())
     }
 
+    /// Whether the entries are a trace, see `Collector::trace`.
+    pub fn is_trace(&self) -> bool {
+        self.trace_capacity.is_some()
+    }
+
     pub fn try_iter(&self) -> std::io::Result<impl Iterator<Item = &Entry<T>>> {
         Ok(self.map.iter().chain(self.temp_array.try_iter()?))
     }
+
+    /// The sum of the counts added to the collector, including the ones which
+    /// couldn't be stored. It's updated with the entries, so it's consistent
+    /// with them when both are read under the same borrow.
+    pub fn sample_count(&self) -> isize {
+        self.sample_count
+    }
+}
+
+/// Add many `(key, count)` pairs at once, e.g. to replay a recorded profile.
+/// The pairs don't need to be aggregated: the counts of equal keys are summed
+/// when the collector is iterated. The pairs which can't be stored, because
+/// the temporary file can't be written or the trace is full, are dropped with
+/// a warning, like the samples of the signal handler, but still counted.
+impl<T: Hash + Eq + 'static> Extend<(T, isize)> for Collector<T> {
+    fn extend<I: IntoIterator<Item = (T, isize)>>(&mut self, iter: I) {
+        let mut dropped = 0;
+        for (key, count) in iter {
+            if self.add(key, count).is_err() {
+                dropped += 1;
+            }
+        }
+        if dropped > 0 {
+            log::warn!("{} entries couldn't be added to the collector", dropped);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -342,6 +465,85 @@ mod tests {
         }
     }
 
+    #[test]
+    fn collector_extend() {
+        let mut collector = Collector::new().unwrap();
+        let entries = (0..(1 << 12) * 4).map(|item| (item % (1 << 13), 2));
+        collector.extend(entries);
+
+        let mut real_map = BTreeMap::new();
+        collector.try_iter().unwrap().for_each(|entry| {
+            test_utils::add_map(&mut real_map, entry);
+        });
+        assert_eq!(real_map.len(), 1 << 13);
+        assert!(real_map.values().all(|count| *count == 4));
+
+        // the entries past the capacity of a trace are dropped, but counted
+        let mut collector = Collector::trace(2).unwrap();
+        collector.extend([(1, 1), (2, 1), (3, 1)]);
+        assert_eq!(collector.try_iter().unwrap().count(), 2);
+        assert_eq!(collector.sample_count(), 3);
+    }
+
+    #[test]
+    fn collector_first_last_seen() {
+        let mut collector = Collector::new().unwrap();
+        let at = |secs| Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs));
+        collector.add_at(1, 1, at(20)).unwrap();
+        collector.add_at(1, 1, at(10)).unwrap();
+        collector.add_at(1, 1, at(30)).unwrap();
+        collector.add(2, 1).unwrap();
+
+        let mut entries: Vec<_> = collector
+            .try_iter()
+            .unwrap()
+            .map(|entry| (entry.item, entry.count, entry.first_seen, entry.last_seen))
+            .collect();
+        entries.sort();
+        assert_eq!(entries, vec![(1, 3, at(10), at(30)), (2, 1, None, None)]);
+    }
+
+    #[test]
+    fn collector_trace() {
+        let mut collector = Collector::trace(BUFFER_LENGTH + 2).unwrap();
+        let at = |secs| Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs));
+        // more than a buffer, so that some are spilled to the file
+        for secs in 0..BUFFER_LENGTH as u64 + 3 {
+            let added = collector.add_at(secs % 2, 1, at(secs));
+            assert_eq!(added.is_ok(), secs < BUFFER_LENGTH as u64 + 2);
+        }
+        assert!(collector.is_trace());
+
+        let mut entries: Vec<_> = collector
+            .try_iter()
+            .unwrap()
+            .map(|entry| (entry.first_seen, entry.item, entry.count))
+            .collect();
+        entries.sort();
+        assert_eq!(entries.len(), BUFFER_LENGTH + 2);
+        assert_eq!(entries[..3], [(at(0), 0, 1), (at(1), 1, 1), (at(2), 0, 1)]);
+        // the rejected sample is counted
+        assert_eq!(collector.sample_count(), BUFFER_LENGTH as isize + 3);
+    }
+
+    #[test]
+    fn collector_sample_count() {
+        let mut collector = Collector::new().unwrap();
+        for item in 0..(1 << 12) * 4 {
+            for _ in 0..(item % 4) {
+                collector.add(item, 1).unwrap();
+            }
+        }
+
+        let mut real_map = BTreeMap::new();
+        collector.try_iter().unwrap().for_each(|entry| {
+            test_utils::add_map(&mut real_map, entry);
+        });
+        // including the entries evicted to the temporary file
+        assert_eq!(collector.sample_count(), 6 << 12);
+        assert_eq!(real_map.values().sum::<isize>(), collector.sample_count());
+    }
+
     #[test]
     fn collector_test() {
         let mut collector = Collector::new().unwrap();
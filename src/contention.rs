@@ -0,0 +1,395 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Profiling of the lock contention, like the mutex profiles of Go.
+//!
+//! `Mutex` and `RwLock` wrap the locks of `parking_lot`. When one of them
+//! can't be taken right away, the time spent waiting for it is measured, and
+//! while a `ContentionProfilerGuard` is alive, the stack of the waiting thread
+//! is recorded with the delay. The contention of the other synchronization
+//! primitives, e.g. the locks of `std::sync`, can be recorded with
+//! `record_contention`.
+//!
+//! ```rust
+//! let guard = pprof::contention::ContentionProfilerGuard::new(1).unwrap();
+//! let counter = pprof::contention::Mutex::new(0);
+//! *counter.lock() += 1;
+//! let report = guard.report();
+//! ```
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use parking_lot::{const_mutex, MutexGuard, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::frames::{Frames, UnresolvedFrames};
+use crate::{Error, Result};
+
+/// One in `SAMPLE_RATE` contentions is recorded, or none when the contention
+/// profiler isn't running.
+static SAMPLE_RATE: AtomicU64 = AtomicU64::new(0);
+
+/// The number of contentions seen while the profiler runs.
+static CONTENTIONS: AtomicU64 = AtomicU64::new(0);
+
+static CONTENTION_PROFILE: parking_lot::Mutex<Option<ContentionProfile>> = const_mutex(None);
+
+/// Record that the current thread waited `delay` for a lock, if it's
+/// sampled by the running `ContentionProfilerGuard`. It's called by `Mutex`
+/// and `RwLock`, and can be called after waiting for any other primitive.
+#[inline(never)]
+pub fn record_contention(delay: Duration) {
+    let rate = SAMPLE_RATE.load(Ordering::Relaxed);
+    if rate == 0 || CONTENTIONS.fetch_add(1, Ordering::Relaxed) % rate != 0 {
+        return;
+    }
+
+    let key = UnresolvedFrames::capture();
+    if let Some(profile) = CONTENTION_PROFILE.lock().as_mut() {
+        let sampled = profile.samples.entry(key).or_default();
+        sampled.0 += 1;
+        sampled.1 += delay;
+    }
+}
+
+/// A `parking_lot::Mutex` whose contention is profiled.
+#[derive(Debug, Default)]
+pub struct Mutex<T: ?Sized> {
+    inner: parking_lot::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: const_mutex(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Acquire the mutex, and record the time spent waiting for it if it's
+    /// held by another thread.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        match self.inner.try_lock() {
+            Some(guard) => guard,
+            None => self.lock_contended(),
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn lock_contended(&self) -> MutexGuard<'_, T> {
+        let start = Instant::now();
+        let guard = self.inner.lock();
+        record_contention(start.elapsed());
+        guard
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.inner.try_lock()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+/// A `parking_lot::RwLock` whose contention is profiled.
+#[derive(Debug, Default)]
+pub struct RwLock<T: ?Sized> {
+    inner: parking_lot::RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: parking_lot::const_rwlock(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Acquire the lock shared, and record the time spent waiting for it if
+    /// it's held exclusively by another thread.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        match self.inner.try_read() {
+            Some(guard) => guard,
+            None => self.read_contended(),
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn read_contended(&self) -> RwLockReadGuard<'_, T> {
+        let start = Instant::now();
+        let guard = self.inner.read();
+        record_contention(start.elapsed());
+        guard
+    }
+
+    /// Acquire the lock exclusively, and record the time spent waiting for
+    /// it if it's held by another thread.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        match self.inner.try_write() {
+            Some(guard) => guard,
+            None => self.write_contended(),
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn write_contended(&self) -> RwLockWriteGuard<'_, T> {
+        let start = Instant::now();
+        let guard = self.inner.write();
+        record_contention(start.elapsed());
+        guard
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.inner.try_read()
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.inner.try_write()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+/// The samples of the running contention profiler: the number of sampled
+/// contentions and their total delay, by stack.
+struct ContentionProfile {
+    sample_rate: u64,
+    start_time: SystemTime,
+    start_instant: Instant,
+    samples: HashMap<UnresolvedFrames, (u64, Duration)>,
+}
+
+/// RAII structure used to stop the contention profiler when dropped. Only
+/// one contention profiler can run at a time.
+pub struct ContentionProfilerGuard {
+    _private: (),
+}
+
+impl ContentionProfilerGuard {
+    /// Start recording one in `sample_rate` contentions, like
+    /// `runtime.SetMutexProfileFraction` of Go. A rate of 1 records all of
+    /// them, at the cost of unwinding the stack of every waiting thread.
+    pub fn new(sample_rate: u64) -> Result<ContentionProfilerGuard> {
+        if cfg!(feature = "disabled") {
+            return Ok(ContentionProfilerGuard { _private: () });
+        }
+
+        let mut profile = CONTENTION_PROFILE.lock();
+        if profile.is_some() {
+            return Err(Error::Running);
+        }
+        let sample_rate = sample_rate.max(1);
+        *profile = Some(ContentionProfile {
+            sample_rate,
+            start_time: SystemTime::now(),
+            start_instant: Instant::now(),
+            samples: HashMap::new(),
+        });
+        CONTENTIONS.store(0, Ordering::Relaxed);
+        SAMPLE_RATE.store(sample_rate, Ordering::Relaxed);
+
+        Ok(ContentionProfilerGuard { _private: () })
+    }
+
+    /// The contentions recorded so far, with their stacks resolved.
+    pub fn report(&self) -> ContentionReport {
+        let snapshot = CONTENTION_PROFILE.lock().as_ref().map(|profile| {
+            (
+                profile.sample_rate,
+                profile.start_time,
+                profile.start_instant.elapsed(),
+                profile.samples.clone(),
+            )
+        });
+        let Some((sample_rate, start_time, duration, samples)) = snapshot else {
+            return ContentionReport {
+                data: HashMap::new(),
+                sample_rate: 0,
+                start_time: SystemTime::now(),
+                duration: Duration::ZERO,
+            };
+        };
+
+        let mut data: HashMap<Frames, ContentionStat> = HashMap::new();
+        for (key, (count, delay)) in samples {
+            let mut frames = Frames::from(key);
+            // the leaf frames are the profiler, up to the contended lock
+            frames.drop_inner_frames(&[
+                "pprof::contention::Mutex<",
+                "pprof::contention::RwLock<",
+                "pprof::contention::record_contention",
+            ]);
+
+            // every recorded contention stands for `sample_rate` of them
+            let stat = data.entry(frames).or_default();
+            stat.contentions += (count * sample_rate) as isize;
+            stat.delay += delay * sample_rate as u32;
+        }
+
+        ContentionReport {
+            data,
+            sample_rate,
+            start_time,
+            duration,
+        }
+    }
+}
+
+impl Drop for ContentionProfilerGuard {
+    fn drop(&mut self) {
+        SAMPLE_RATE.store(0, Ordering::Relaxed);
+        // the samples are freed after the lock is released
+        let profile = CONTENTION_PROFILE.lock().take();
+        drop(profile);
+    }
+}
+
+/// The estimated contentions of a stack.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ContentionStat {
+    pub contentions: isize,
+    /// The total time spent waiting.
+    pub delay: Duration,
+}
+
+/// The contentions recorded by a `ContentionProfilerGuard`, scaled to an
+/// estimate of all the contentions while it ran.
+#[derive(Debug)]
+pub struct ContentionReport {
+    pub data: HashMap<Frames, ContentionStat>,
+    /// One in `sample_rate` contentions was recorded.
+    pub sample_rate: u64,
+    pub start_time: SystemTime,
+    pub duration: Duration,
+}
+
+impl ContentionReport {
+    /// The stacks which waited the longest first, in a stable order.
+    pub fn sorted_data(&self) -> Vec<(&Frames, ContentionStat)> {
+        let mut data: Vec<_> = self.data.iter().map(|(key, stat)| (key, *stat)).collect();
+        data.sort_by_cached_key(|(key, stat)| {
+            let names: Vec<String> = key
+                .frames
+                .iter()
+                .rev()
+                .flat_map(|frame| frame.iter().rev().map(|symbol| symbol.to_string()))
+                .collect();
+            (
+                Reverse(stat.delay),
+                Reverse(stat.contentions),
+                key.thread_id,
+                names,
+            )
+        });
+        data
+    }
+}
+
+#[cfg(feature = "_protobuf")]
+impl ContentionReport {
+    /// The `pprof` profile of the contentions, with the `contentions` and
+    /// `delay` values of the mutex profiles of Go.
+    pub fn pprof(&self) -> Result<crate::protos::Profile> {
+        use crate::report::{encode_profile, ProfileValues};
+
+        let stacks = self
+            .sorted_data()
+            .into_iter()
+            .map(|(key, stat)| {
+                (
+                    key,
+                    vec![stat.contentions as i64, stat.delay.as_nanos() as i64],
+                )
+            })
+            .collect();
+
+        Ok(encode_profile(
+            stacks,
+            ProfileValues {
+                sample_types: &[("contentions", "count"), ("delay", "nanoseconds")],
+                period_type: ("contentions", "count"),
+                period: self.sample_rate as i64,
+                start_time: self.start_time,
+                duration: self.duration,
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(feature = "disabled"))]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    #[inline(never)]
+    fn wait_for_lock(lock: &Mutex<u32>) {
+        *lock.lock() += 1;
+    }
+
+    #[test]
+    fn record_lock_contention() {
+        let guard = ContentionProfilerGuard::new(1).unwrap();
+        assert!(ContentionProfilerGuard::new(1).is_err());
+
+        let lock = Arc::new(Mutex::new(0));
+        let held = lock.lock();
+        let waiter = {
+            let lock = lock.clone();
+            std::thread::spawn(move || wait_for_lock(&lock))
+        };
+        std::thread::sleep(Duration::from_millis(100));
+        drop(held);
+        waiter.join().unwrap();
+        // not contended
+        wait_for_lock(&lock);
+        assert_eq!(*lock.lock(), 2);
+
+        let report = guard.report();
+        assert_eq!(report.data.len(), 1);
+        let (frames, stat) = report.data.iter().next().unwrap();
+        assert_eq!(stat.contentions, 1);
+        assert!(stat.delay >= Duration::from_millis(50));
+        // the leaf is the function which waited
+        assert!(frames.frames[0]
+            .iter()
+            .any(|symbol| symbol.name().contains("wait_for_lock")));
+
+        #[cfg(feature = "_protobuf")]
+        {
+            let profile = report.pprof().unwrap();
+            let sample_types: Vec<_> = profile
+                .sample_type
+                .iter()
+                .map(|ty| {
+                    (
+                        profile.string_table[ty.ty as usize].as_str(),
+                        profile.string_table[ty.unit as usize].as_str(),
+                    )
+                })
+                .collect();
+            assert_eq!(
+                sample_types,
+                vec![("contentions", "count"), ("delay", "nanoseconds")]
+            );
+        }
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::time::Duration;
+
+/// The max number of threads whose CPU time can be tracked at the same time.
+const MAX_TRACKED_THREADS: usize = 1 << 10;
+
+/// The CPU time of a thread observed by the signal handler. As the CPU time
+/// is read when a sample is taken, `cpu_time` only covers the time between the
+/// first and the last sample of the thread.
+///
+/// Comparing `cpu_time` with `samples` divided by the sampling frequency tells
+/// whether the samples attributed to the thread are plausible: a thread which
+/// got many samples but barely consumed any CPU time was most likely blamed for
+/// samples triggered by other threads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ThreadCpuTime {
+    /// Number of samples taken on this thread.
+    pub samples: u64,
+    /// CPU time of the thread when its first sample was taken.
+    pub first: Duration,
+    /// CPU time of the thread when its last sample was taken.
+    pub last: Duration,
+}
+
+impl ThreadCpuTime {
+    /// The CPU time consumed by the thread between its first and its last sample.
+    pub fn cpu_time(&self) -> Duration {
+        self.last.saturating_sub(self.first)
+    }
+}
+
+/// Reads the CPU time consumed by the calling thread. It's async-signal-safe.
+#[cfg(unix)]
+pub(crate) fn current_thread_cpu_time() -> Option<Duration> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) } != 0 {
+        return None;
+    }
+
+    Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+/// A fixed size open addressing table from thread id to `ThreadCpuTime`. It's
+/// allocated up front so that it can be updated in the signal handler.
+pub(crate) struct ThreadCpuTimeTable {
+    entries: Box<[(u64, ThreadCpuTime)]>,
+}
+
+impl Default for ThreadCpuTimeTable {
+    fn default() -> Self {
+        Self {
+            entries: vec![(0, ThreadCpuTime::default()); MAX_TRACKED_THREADS].into_boxed_slice(),
+        }
+    }
+}
+
+impl ThreadCpuTimeTable {
+    /// Records that a sample was taken on `thread_id` when its CPU time was
+    /// `cpu_time`, and returns the CPU time consumed since its previous
+    /// sample. Threads which don't fit in the table are ignored.
+    pub(crate) fn record(&mut self, thread_id: u64, cpu_time: Duration) -> Option<Duration> {
+        // 0 marks an empty slot
+        let key = thread_id.max(1);
+        let start = (key.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 54) as usize % MAX_TRACKED_THREADS;
+        for probe in 0..MAX_TRACKED_THREADS {
+            let (id, entry) = &mut self.entries[(start + probe) % MAX_TRACKED_THREADS];
+            if *id == 0 {
+                *id = key;
+                *entry = ThreadCpuTime {
+                    samples: 1,
+                    first: cpu_time,
+                    last: cpu_time,
+                };
+                return None;
+            }
+            if *id == key {
+                let consumed = cpu_time.saturating_sub(entry.last);
+                entry.samples += 1;
+                entry.last = entry.last.max(cpu_time);
+                return Some(consumed);
+            }
+        }
+
+        None
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u64, ThreadCpuTime)> + '_ {
+        self.entries
+            .iter()
+            .filter(|(id, _)| *id != 0)
+            .map(|(id, entry)| (*id, *entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_thread_cpu_time() {
+        let mut table = ThreadCpuTimeTable::default();
+        assert_eq!(table.record(42, Duration::from_millis(10)), None);
+        table.record(7, Duration::from_millis(1));
+        assert_eq!(
+            table.record(42, Duration::from_millis(30)),
+            Some(Duration::from_millis(20))
+        );
+
+        let mut entries: Vec<_> = table.iter().collect();
+        entries.sort_by_key(|(id, _)| *id);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].1.samples, 1);
+        assert_eq!(entries[1].1.samples, 2);
+        assert_eq!(entries[1].1.cpu_time(), Duration::from_millis(20));
+    }
+}
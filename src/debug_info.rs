@@ -0,0 +1,442 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Symbolication from debug info files which `backtrace` can't find.
+//!
+//! Production binaries are often stripped, with their debug info shipped
+//! separately (e.g. through `objcopy --only-keep-debug`) or kept in a cache.
+//! For the frames which `backtrace` leaves unnamed, or only finds in the
+//! symbol table of the module, the modules containing them are looked up in
+//! the search paths given to `ReportBuilder::debug_info_path`, then where gdb
+//! looks for them, i.e. next to the module and in `/usr/lib/debug`, where the
+//! debug info packages of the distributions install them. They are resolved
+//! from the DWARF and the symbol table of the file found there. The DWARF
+//! expands a frame into the functions inlined at its address, so that they
+//! aren't attributed to the function they are inlined into.
+//!
+//! With the `debuginfod` feature, the debug info of the modules which aren't
+//! in the search paths is downloaded by their build id from the debuginfod
+//! servers listed in `DEBUGINFOD_URLS`.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use findshlibs::{Segment, SharedLibrary, SharedLibraryId, TargetSharedLibrary};
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+
+use crate::frames::Symbol;
+
+type Reader = gimli::EndianArcSlice<gimli::RunTimeEndian>;
+
+/// The global directory of the debug info files, as configured in gdb.
+const GLOBAL_DEBUG_DIR: &str = "/usr/lib/debug";
+
+/// The debug info loaded for a module.
+struct ModuleDebugInfo {
+    context: Option<addr2line::Context<Reader>>,
+    /// `(address, size, name)` of the functions, sorted by address.
+    symbols: Vec<(u64, u64, Vec<u8>)>,
+}
+
+impl ModuleDebugInfo {
+    /// Load the debug info in the file at `path`, unless it isn't the one of
+    /// the module with `build_id`, or doesn't have the `crc` of the
+    /// `.gnu_debuglink` it was found through.
+    fn load(path: &Path, build_id: Option<&[u8]>, crc: Option<u32>) -> Option<Self> {
+        let data = std::fs::read(path).ok()?;
+        if let Some(crc) = crc {
+            if crc32fast::hash(&data) != crc {
+                log::debug!("ignore {}, its crc doesn't match", path.display());
+                return None;
+            }
+        }
+        let file = object::File::parse(&*data).ok()?;
+        if let (Some(expected), Ok(Some(actual))) = (build_id, file.build_id()) {
+            if expected != actual {
+                log::debug!("ignore {}, its build id doesn't match", path.display());
+                return None;
+            }
+        }
+
+        let endian = if file.is_little_endian() {
+            gimli::RunTimeEndian::Little
+        } else {
+            gimli::RunTimeEndian::Big
+        };
+        let dwarf = gimli::Dwarf::load(|id| -> Result<Reader, gimli::Error> {
+            // the debug info packages usually compress the sections
+            let data = file
+                .section_by_name(id.name())
+                .and_then(|section| section.uncompressed_data().ok())
+                .unwrap_or_default();
+            Ok(Reader::new(Arc::from(&*data), endian))
+        });
+        let context = dwarf
+            .ok()
+            .and_then(|dwarf| addr2line::Context::from_dwarf(dwarf).ok());
+
+        let mut symbols: Vec<_> = file
+            .symbols()
+            .chain(file.dynamic_symbols())
+            .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.address() != 0)
+            .filter_map(|symbol| {
+                let name = symbol.name_bytes().ok()?;
+                Some((symbol.address(), symbol.size(), name.to_vec()))
+            })
+            .collect();
+        symbols.sort_by_key(|(address, _, _)| *address);
+
+        Some(Self { context, symbols })
+    }
+
+    /// Resolve `probe`, an address stated in the module.
+    fn resolve(&self, probe: u64) -> Vec<Symbol> {
+        let mut resolved = Vec::new();
+        if let Some(context) = &self.context {
+            if let Ok(mut frames) = context.find_frames(probe).skip_all_loads() {
+                while let Ok(Some(frame)) = frames.next() {
+                    let name = frame
+                        .function
+                        .as_ref()
+                        .and_then(|function| function.raw_name().ok())
+                        .map(|name| name.into_owned().into_bytes());
+                    let location = frame.location.as_ref();
+                    resolved.push(Symbol {
+                        name,
+                        addr: None,
+                        lineno: location.and_then(|location| location.line),
+                        filename: location
+                            .and_then(|location| location.file)
+                            .map(PathBuf::from),
+                        offset: None,
+                    });
+                }
+            }
+        }
+
+        if resolved.iter().all(|symbol| symbol.name.is_none()) {
+            let index = self
+                .symbols
+                .partition_point(|(address, _, _)| *address <= probe);
+            if let Some((address, size, name)) = index.checked_sub(1).map(|i| &self.symbols[i]) {
+                if *size == 0 || probe < address + size {
+                    let location = resolved.pop();
+                    resolved = vec![Symbol {
+                        name: Some(name.clone()),
+                        addr: None,
+                        lineno: location.as_ref().and_then(|symbol| symbol.lineno),
+                        filename: location.and_then(|symbol| symbol.filename),
+                        offset: None,
+                    }];
+                }
+            }
+        }
+
+        resolved
+    }
+}
+
+/// A module loaded in the process, whose debug info is loaded on first use.
+struct Module {
+    path: PathBuf,
+    build_id: Option<Vec<u8>>,
+    bias: usize,
+    segments: Vec<(usize, usize)>,
+    debug_info: Option<Option<ModuleDebugInfo>>,
+}
+
+/// The debug info file named after `build_id` in `dir`.
+fn build_id_debug_file(dir: &Path, build_id: &[u8]) -> Option<PathBuf> {
+    if build_id.len() < 2 {
+        return None;
+    }
+    let hex: String = build_id
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Some(
+        dir.join(".build-id")
+            .join(&hex[..2])
+            .join(format!("{}.debug", &hex[2..])),
+    )
+}
+
+/// The files in `dir` which may hold the debug info of the module at `path`,
+/// following the conventions of gdb.
+fn debug_file_candidates(
+    dir: &Path,
+    path: &Path,
+    build_id: Option<&[u8]>,
+    debuglink: Option<&Path>,
+) -> Vec<PathBuf> {
+    let mut candidates: Vec<_> = build_id
+        .and_then(|build_id| build_id_debug_file(dir, build_id))
+        .into_iter()
+        .collect();
+    if let Some(debuglink) = debuglink {
+        if let Some(parent) = path.parent() {
+            candidates.push(
+                dir.join(parent.strip_prefix("/").unwrap_or(parent))
+                    .join(debuglink),
+            );
+        }
+        candidates.push(dir.join(debuglink));
+    }
+    if let Some(name) = path.file_name() {
+        let mut debug_name = name.to_owned();
+        debug_name.push(".debug");
+        candidates.push(dir.join(debug_name));
+        candidates.push(dir.join(name));
+    }
+
+    candidates
+}
+
+/// The files which may hold the debug info of the module at `path` when it
+/// isn't in the search paths: in the global debug directory by build id,
+/// then next to the module, in its `.debug` directory and in the global
+/// debug directory by `.gnu_debuglink`, as gdb does.
+fn default_debug_file_candidates(
+    path: &Path,
+    build_id: Option<&[u8]>,
+    debuglink: Option<&Path>,
+) -> Vec<PathBuf> {
+    let global = Path::new(GLOBAL_DEBUG_DIR);
+    let mut candidates: Vec<_> = build_id
+        .and_then(|build_id| build_id_debug_file(global, build_id))
+        .into_iter()
+        .collect();
+    if let (Some(debuglink), Some(parent)) = (debuglink, path.parent()) {
+        candidates.push(parent.join(debuglink));
+        candidates.push(parent.join(".debug").join(debuglink));
+        candidates.push(
+            global
+                .join(parent.strip_prefix("/").unwrap_or(parent))
+                .join(debuglink),
+        );
+    }
+
+    // a module which links to itself doesn't have its debug info elsewhere
+    candidates.retain(|candidate| candidate != path);
+    candidates
+}
+
+/// Where to look for the debug info of the modules.
+struct Sources {
+    search_paths: Vec<PathBuf>,
+    #[cfg(feature = "debuginfod")]
+    debuginfod: Option<crate::debuginfod::Client>,
+}
+
+impl Sources {
+    fn load_debug_info(&self, module: &Module) -> Option<ModuleDebugInfo> {
+        let debuglink = std::fs::read(&module.path).ok().and_then(|data| {
+            let file = object::File::parse(&*data).ok()?;
+            let (debuglink, crc) = file.gnu_debuglink().ok()??;
+            Some((
+                PathBuf::from(String::from_utf8_lossy(debuglink).into_owned()),
+                crc,
+            ))
+        });
+        let build_id = module.build_id.as_deref();
+        let debuglink_name = debuglink.as_ref().map(|(name, _)| name.as_path());
+
+        let candidates = self
+            .search_paths
+            .iter()
+            .flat_map(|dir| debug_file_candidates(dir, &module.path, build_id, debuglink_name))
+            .chain(default_debug_file_candidates(
+                &module.path,
+                build_id,
+                debuglink_name,
+            ));
+        for candidate in candidates {
+            if !candidate.is_file() {
+                continue;
+            }
+            // the files found through the debuglink must have its crc
+            let crc = debuglink
+                .as_ref()
+                .filter(|(name, _)| candidate.file_name() == Some(name.as_os_str()))
+                .map(|(_, crc)| *crc);
+            if let Some(debug_info) = ModuleDebugInfo::load(&candidate, build_id, crc) {
+                log::debug!(
+                    "load the debug info of {} from {}",
+                    module.path.display(),
+                    candidate.display()
+                );
+                return Some(debug_info);
+            }
+        }
+
+        #[cfg(feature = "debuginfod")]
+        if let (Some(client), Some(build_id)) = (&self.debuginfod, &module.build_id) {
+            let path = client.debuginfo(build_id)?;
+            return ModuleDebugInfo::load(&path, Some(build_id), None);
+        }
+
+        None
+    }
+}
+
+/// Resolves addresses from the debug info files found in the search paths or
+/// the default directories, or downloaded from the debuginfod servers.
+pub(crate) struct DebugInfo {
+    sources: Sources,
+    modules: Option<Vec<Module>>,
+}
+
+impl DebugInfo {
+    pub(crate) fn new(search_paths: &[PathBuf]) -> Self {
+        Self {
+            sources: Sources {
+                search_paths: search_paths.to_vec(),
+                #[cfg(feature = "debuginfod")]
+                debuginfod: crate::debuginfod::Client::from_env(),
+            },
+            modules: None,
+        }
+    }
+
+    fn loaded_modules() -> Vec<Module> {
+        let mut modules = Vec::new();
+        TargetSharedLibrary::each(|shlib| {
+            let path = PathBuf::from(shlib.name());
+            // the executable itself is listed without a name on Linux
+            let path = if path.as_os_str().is_empty() {
+                std::env::current_exe().unwrap_or_default()
+            } else {
+                path
+            };
+            let build_id = match shlib.id() {
+                Some(SharedLibraryId::GnuBuildId(build_id)) => Some(build_id),
+                _ => None,
+            };
+            modules.push(Module {
+                path,
+                build_id,
+                bias: shlib.virtual_memory_bias().0,
+                segments: shlib
+                    .segments()
+                    .map(|seg| {
+                        let start = seg.actual_virtual_memory_address(shlib).0;
+                        (start, start + seg.len())
+                    })
+                    .collect(),
+                debug_info: None,
+            });
+        });
+        modules
+    }
+
+    /// Resolve `ip`, a return address like the sampled ones, into symbols
+    /// from the innermost inlined function.
+    pub(crate) fn resolve(&mut self, ip: usize) -> Option<Vec<Symbol>> {
+        let sources = &self.sources;
+        let module = self
+            .modules
+            .get_or_insert_with(Self::loaded_modules)
+            .iter_mut()
+            .find(|module| {
+                module
+                    .segments
+                    .iter()
+                    .any(|(start, end)| ip >= *start && ip < *end)
+            })?;
+        if module.debug_info.is_none() {
+            module.debug_info = Some(sources.load_debug_info(module));
+        }
+        let debug_info = module.debug_info.as_ref()?.as_ref()?;
+
+        // look up the call instruction rather than the return address
+        let probe = ip.wrapping_sub(1).wrapping_sub(module.bias) as u64;
+        let symbols = debug_info.resolve(probe);
+        if symbols.is_empty() {
+            None
+        } else {
+            Some(symbols)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candidates() {
+        let candidates = debug_file_candidates(
+            Path::new("/debug"),
+            Path::new("/usr/bin/server"),
+            Some(&[0xab, 0xcd, 0xef]),
+            Some(Path::new("server.dbg")),
+        );
+        assert_eq!(
+            candidates,
+            vec![
+                PathBuf::from("/debug/.build-id/ab/cdef.debug"),
+                PathBuf::from("/debug/usr/bin/server.dbg"),
+                PathBuf::from("/debug/server.dbg"),
+                PathBuf::from("/debug/server.debug"),
+                PathBuf::from("/debug/server"),
+            ]
+        );
+    }
+
+    #[test]
+    fn default_candidates() {
+        let candidates = default_debug_file_candidates(
+            Path::new("/usr/lib/libfoo.so.1"),
+            Some(&[0xab, 0xcd, 0xef]),
+            Some(Path::new("libfoo.so.1.debug")),
+        );
+        assert_eq!(
+            candidates,
+            vec![
+                PathBuf::from("/usr/lib/debug/.build-id/ab/cdef.debug"),
+                PathBuf::from("/usr/lib/libfoo.so.1.debug"),
+                PathBuf::from("/usr/lib/.debug/libfoo.so.1.debug"),
+                PathBuf::from("/usr/lib/debug/usr/lib/libfoo.so.1.debug"),
+            ]
+        );
+
+        let candidates = default_debug_file_candidates(
+            Path::new("/usr/bin/server"),
+            None,
+            Some(Path::new("server")),
+        );
+        assert_eq!(
+            candidates,
+            vec![
+                PathBuf::from("/usr/bin/.debug/server"),
+                PathBuf::from("/usr/lib/debug/usr/bin/server"),
+            ]
+        );
+    }
+
+    #[test]
+    fn debuglink_crc() {
+        let exe = std::env::current_exe().unwrap();
+        let crc = crc32fast::hash(&std::fs::read(&exe).unwrap());
+        assert!(ModuleDebugInfo::load(&exe, None, Some(crc)).is_some());
+        assert!(ModuleDebugInfo::load(&exe, None, Some(!crc)).is_none());
+    }
+
+    #[inline(never)]
+    fn resolve_me() -> usize {
+        std::hint::black_box(42)
+    }
+
+    #[test]
+    fn resolve_from_search_path() {
+        std::hint::black_box(resolve_me());
+        let exe = std::env::current_exe().unwrap();
+        let mut debug_info = DebugInfo::new(&[exe.parent().unwrap().to_owned()]);
+
+        let symbols = debug_info
+            .resolve(resolve_me as *const () as usize + 1)
+            .unwrap();
+        assert!(symbols
+            .iter()
+            .any(|symbol| symbol.name().contains("resolve_me")));
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! A client of debuginfod servers, which serve the debug info of the
+//! binaries of a distribution by their build id.
+//!
+//! Like the client of elfutils, it's only enabled when `DEBUGINFOD_URLS`
+//! lists some servers, and keeps the files it downloads in the same cache,
+//! `$DEBUGINFOD_CACHE_PATH` or `~/.cache/debuginfod_client`, so that they
+//! are shared with gdb and the other tools.
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+/// The timeout of a request, unless `DEBUGINFOD_TIMEOUT` sets it.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// The build ids which none of the servers have, not to ask for them again
+/// for every report.
+static MISSING: Lazy<Mutex<HashSet<Vec<u8>>>> = Lazy::new(Default::default);
+
+pub(crate) struct Client {
+    servers: Vec<String>,
+    cache: PathBuf,
+    agent: ureq::Agent,
+}
+
+impl Client {
+    /// The client configured by the environment, if `DEBUGINFOD_URLS` lists
+    /// some servers.
+    pub(crate) fn from_env() -> Option<Self> {
+        let servers = std::env::var("DEBUGINFOD_URLS").ok()?;
+        let timeout = std::env::var("DEBUGINFOD_TIMEOUT")
+            .ok()
+            .and_then(|timeout| timeout.trim().parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_TIMEOUT);
+        let cache = match std::env::var_os("DEBUGINFOD_CACHE_PATH") {
+            Some(cache) => PathBuf::from(cache),
+            None => match std::env::var_os("XDG_CACHE_HOME") {
+                Some(cache) => PathBuf::from(cache).join("debuginfod_client"),
+                None => PathBuf::from(std::env::var_os("HOME")?)
+                    .join(".cache")
+                    .join("debuginfod_client"),
+            },
+        };
+
+        Self::new(&servers, cache, timeout)
+    }
+
+    fn new(servers: &str, cache: PathBuf, timeout: Duration) -> Option<Self> {
+        let servers: Vec<_> = servers
+            .split_whitespace()
+            .map(|server| server.trim_end_matches('/').to_owned())
+            .collect();
+        if servers.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            servers,
+            cache,
+            agent: ureq::AgentBuilder::new().timeout(timeout).build(),
+        })
+    }
+
+    /// The path of the debug info of the module with `build_id`, downloaded
+    /// from the servers unless it's in the cache already.
+    pub(crate) fn debuginfo(&self, build_id: &[u8]) -> Option<PathBuf> {
+        let hex: String = build_id
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let path = self.cache.join(&hex).join("debuginfo");
+        if path.is_file() {
+            return Some(path);
+        }
+        if MISSING.lock().contains(build_id) {
+            return None;
+        }
+
+        for server in &self.servers {
+            let url = format!("{}/buildid/{}/debuginfo", server, hex);
+            match self.download(&url, &path) {
+                Ok(()) => {
+                    log::debug!("download the debug info of {} from {}", hex, url);
+                    return Some(path);
+                }
+                Err(err) => log::debug!("failed to download {}: {}", url, err),
+            }
+        }
+
+        MISSING.lock().insert(build_id.to_vec());
+        None
+    }
+
+    /// Download `url` into a temporary file next to `path`, and rename it to
+    /// `path` once it's complete, as other processes may share the cache.
+    fn download(&self, url: &str, path: &Path) -> std::io::Result<()> {
+        let response = self
+            .agent
+            .get(url)
+            .call()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+
+        let dir = path.parent().unwrap_or(&self.cache);
+        std::fs::create_dir_all(dir)?;
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        std::io::copy(&mut response.into_reader(), &mut file)?;
+        file.flush()?;
+        file.persist(path).map_err(|err| err.error)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    #[test]
+    fn no_servers() {
+        assert!(Client::new(" ", PathBuf::from("/cache"), DEFAULT_TIMEOUT).is_none());
+    }
+
+    #[test]
+    fn download_and_cache() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = format!("http://{}/", listener.local_addr().unwrap());
+        // only serves a single request, the second lookup must hit the cache
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let body = "debug info";
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            request
+        });
+
+        let cache = tempfile::tempdir().unwrap();
+        let client = Client::new(&server, cache.path().to_owned(), DEFAULT_TIMEOUT).unwrap();
+        let path = client.debuginfo(&[0xab, 0xcd, 0xef]).unwrap();
+        assert_eq!(path, cache.path().join("abcdef").join("debuginfo"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "debug info");
+        assert!(handle
+            .join()
+            .unwrap()
+            .starts_with("GET /buildid/abcdef/debuginfo "));
+
+        assert_eq!(client.debuginfo(&[0xab, 0xcd, 0xef]), Some(path));
+    }
+}
@@ -0,0 +1,210 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! The difference between two reports, like `pprof -diff_base`.
+//!
+//! The stacks of two reports are matched by their thread name and the names
+//! of their frames, as the thread ids and the addresses of two processes, or
+//! two runs of a process, have nothing in common.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+
+use crate::frames::Frames;
+use crate::report::folded_stack;
+use crate::timer::ReportTiming;
+use crate::Report;
+
+/// How the samples of a stack changed, see `Report::diff`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// The stack was only sampled in the report.
+    Added,
+    /// The stack was only sampled in the baseline.
+    Removed,
+    /// The stack was sampled in both, more or less often.
+    Changed,
+    /// The stack was sampled as often in both.
+    Unchanged,
+}
+
+/// The samples of a stack in the baseline and in the report.
+#[derive(Clone, Debug)]
+pub struct StackDiff {
+    /// The stack as sampled in the report, or in the baseline if it was
+    /// removed.
+    pub frames: Frames,
+    pub before: isize,
+    pub after: isize,
+}
+
+impl StackDiff {
+    /// The samples gained by the stack, negative when it lost some.
+    pub fn delta(&self) -> isize {
+        self.after - self.before
+    }
+
+    pub fn change(&self) -> Change {
+        match (self.before, self.after) {
+            (0, _) => Change::Added,
+            (_, 0) => Change::Removed,
+            (before, after) if before == after => Change::Unchanged,
+            _ => Change::Changed,
+        }
+    }
+}
+
+/// The difference between a report and a baseline, see `Report::diff`.
+pub struct ReportDiff {
+    /// The stacks of both reports, the largest changes first.
+    pub stacks: Vec<StackDiff>,
+
+    /// The timing of the report, not of the baseline.
+    pub timing: ReportTiming,
+}
+
+impl Report {
+    /// The samples of every stack in `baseline` and in this report, to tell
+    /// which stacks were added, removed, or changed between them. The
+    /// stacks of threads with the same name are summed, e.g. the ones of
+    /// the workers of a pool.
+    pub fn diff(&self, baseline: &Report) -> ReportDiff {
+        let mut stacks: HashMap<String, StackDiff> = HashMap::new();
+        for (report, is_baseline) in [(baseline, true), (self, false)] {
+            for (key, count) in report.data.iter() {
+                let stack = stacks
+                    .entry(folded_stack(key))
+                    .or_insert_with(|| StackDiff {
+                        frames: key.clone(),
+                        before: 0,
+                        after: 0,
+                    });
+                if is_baseline {
+                    stack.before += count;
+                } else {
+                    // the frames of the report win over the baseline's
+                    if stack.after == 0 {
+                        stack.frames = key.clone();
+                    }
+                    stack.after += count;
+                }
+            }
+        }
+
+        let mut stacks: Vec<(String, StackDiff)> = stacks.into_iter().collect();
+        stacks.sort_by(|(a_name, a), (b_name, b)| {
+            (Reverse(a.delta().abs()), Reverse(a.delta()), a_name).cmp(&(
+                Reverse(b.delta().abs()),
+                Reverse(b.delta()),
+                b_name,
+            ))
+        });
+
+        ReportDiff {
+            stacks: stacks.into_iter().map(|(_, stack)| stack).collect(),
+            timing: self.timing.clone(),
+        }
+    }
+}
+
+impl ReportDiff {
+    /// The stacks which changed, the largest changes first.
+    pub fn changes(&self) -> impl Iterator<Item = &StackDiff> {
+        self.stacks
+            .iter()
+            .filter(|stack| stack.change() != Change::Unchanged)
+    }
+}
+
+/// A line per changed stack, with its delta, its samples before and after, and
+/// the stack in the collapsed stack format, e.g. `+3 1 -> 4 main;work`.
+impl Display for ReportDiff {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for stack in self.changes() {
+            writeln!(
+                f,
+                "{:+} {} -> {} {}",
+                stack.delta(),
+                stack.before,
+                stack.after,
+                folded_stack(&stack.frames)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "flamegraph")]
+impl ReportDiff {
+    /// `flamegraph` will write a differential svg flamegraph into `writer`,
+    /// like `Report::flamegraph_diff`. **only available with `flamegraph`
+    /// feature**
+    pub fn flamegraph<W>(&self, writer: W) -> crate::Result<()>
+    where
+        W: std::io::Write,
+    {
+        self.flamegraph_with_options(writer, &mut crate::flamegraph::Options::default())
+    }
+
+    /// same as `flamegraph`, but accepts custom `options` for the flamegraph
+    pub fn flamegraph_with_options<W>(
+        &self,
+        writer: W,
+        options: &mut crate::flamegraph::Options,
+    ) -> crate::Result<()>
+    where
+        W: std::io::Write,
+    {
+        // the differential format of inferno has the samples before and after
+        let lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|stack| {
+                format!(
+                    "{} {} {}",
+                    folded_stack(&stack.frames),
+                    stack.before,
+                    stack.after
+                )
+            })
+            .collect();
+        if !lines.is_empty() {
+            crate::flamegraph::from_lines(options, lines.iter().map(|s| &**s), writer)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "_protobuf")]
+impl ReportDiff {
+    /// The `pprof` profile of the deltas, with the same values as
+    /// `Report::pprof`, which are negative for the stacks which lost
+    /// samples. Like the profiles of `pprof -diff_base`, it shows where the
+    /// samples went in the usual views.
+    pub fn pprof(&self) -> crate::Result<crate::protos::Profile> {
+        use crate::report::{encode_profile, ProfileValues};
+
+        let period = 1_000_000_000 / self.timing.frequency.max(1) as i64;
+        let stacks = self
+            .changes()
+            .map(|stack| {
+                let delta = stack.delta() as i64;
+                (&stack.frames, vec![delta, delta * period])
+            })
+            .collect();
+
+        Ok(encode_profile(
+            stacks,
+            ProfileValues {
+                sample_types: &[("samples", "count"), ("cpu", "nanoseconds")],
+                period_type: ("cpu", "nanoseconds"),
+                period,
+                start_time: self.timing.start_time,
+                duration: self.timing.duration,
+            },
+        ))
+    }
+}
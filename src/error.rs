@@ -2,6 +2,7 @@
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[cfg(unix)]
     #[error("{0}")]
     NixError(#[from] nix::Error),
     #[error("{0}")]
@@ -12,6 +13,16 @@ pub enum Error {
     Running,
     #[error("stop running cpu profiler error")]
     NotRunning,
+    #[error("invalid raw report: {0}")]
+    InvalidRawReport(&'static str),
+    #[error("invalid shared region: {0}")]
+    InvalidSharedRegion(&'static str),
+    #[error("invalid pprof profile: {0}")]
+    InvalidPprof(&'static str),
+    #[error("symbolizing thread panicked")]
+    SymbolizingPanicked,
+    #[error("invalid argument: {0}")]
+    InvalidArgument(&'static str),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
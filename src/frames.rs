@@ -7,10 +7,13 @@ use std::os::raw::c_void;
 use std::path::PathBuf;
 use std::time::SystemTime;
 
+use findshlibs::{Segment, SharedLibrary, TargetSharedLibrary};
 use smallvec::SmallVec;
 use symbolic_demangle::demangle;
 
 use crate::backtrace::{Frame, Trace, TraceImpl};
+#[cfg(unix)]
+use crate::profiler::write_thread_name;
 use crate::{MAX_DEPTH, MAX_THREAD_NAME};
 
 #[derive(Clone)]
@@ -19,18 +22,52 @@ pub struct UnresolvedFrames {
     pub thread_name: [u8; MAX_THREAD_NAME],
     pub thread_name_length: usize,
     pub thread_id: u64,
+    /// Time of the first sample of this stack.
     pub sample_timestamp: SystemTime,
+    /// Time of the last sample of this stack.
+    pub last_sample_timestamp: SystemTime,
+    /// Whether frames are keyed on their instruction pointer rather than the
+    /// address of the enclosing symbol. See
+    /// [`ProfilerGuardBuilder::symbol_offsets`](crate::ProfilerGuardBuilder::symbol_offsets).
+    pub symbol_offsets: bool,
+    /// Whether the frames which can't be symbolicated are named after their
+    /// module and offset. See
+    /// [`ProfilerGuardBuilder::report_unresolved_addresses`](crate::ProfilerGuardBuilder::report_unresolved_addresses).
+    pub unresolved_addresses: bool,
+    /// Whether the unwinding stopped because the stack was deeper than
+    /// `MAX_DEPTH`, rather than because the unwinder reached its end.
+    pub truncated: bool,
+    /// Whether the innermost frame, after the frames of the signal handler,
+    /// is the address of the interrupted instruction rather than a return
+    /// address. It's then resolved at its own address instead of at the call
+    /// instruction before it.
+    pub exact_leaf: bool,
+    /// The id of the labels of the thread when it was sampled, see
+    /// `tag::with_labels`.
+    pub(crate) label_set: u32,
+    /// Whether the frames are a single sample of a trace, which is told apart
+    /// from the other samples of its stack by its time. See
+    /// [`CollectMode::Trace`](crate::CollectMode::Trace).
+    pub traced: bool,
 }
 
 impl Default for UnresolvedFrames {
     fn default() -> Self {
         let frames = SmallVec::with_capacity(MAX_DEPTH);
+        let now = SystemTime::now();
         Self {
             frames,
             thread_name: [0; MAX_THREAD_NAME],
             thread_name_length: 0,
             thread_id: 0,
-            sample_timestamp: SystemTime::now(),
+            sample_timestamp: now,
+            last_sample_timestamp: now,
+            symbol_offsets: false,
+            unresolved_addresses: false,
+            truncated: false,
+            exact_leaf: false,
+            label_set: crate::tag::NO_LABELS,
+            traced: false,
         }
     }
 }
@@ -47,6 +84,7 @@ impl UnresolvedFrames {
         tn: &[u8],
         thread_id: u64,
         sample_timestamp: SystemTime,
+        truncated: bool,
     ) -> Self {
         let thread_name_length = tn.len();
         let mut thread_name = [0; MAX_THREAD_NAME];
@@ -58,6 +96,73 @@ impl UnresolvedFrames {
             thread_name_length,
             thread_id,
             sample_timestamp,
+            last_sample_timestamp: sample_timestamp,
+            symbol_offsets: false,
+            unresolved_addresses: false,
+            truncated,
+            exact_leaf: false,
+            label_set: crate::tag::NO_LABELS,
+            traced: false,
+        }
+    }
+
+    /// Capture the stack of the current thread, outside of a signal handler,
+    /// e.g. where a profiled allocation is made.
+    pub(crate) fn capture() -> Self {
+        let mut bt: SmallVec<[<TraceImpl as Trace>::Frame; MAX_DEPTH]> = SmallVec::new();
+        let mut truncated = false;
+        unsafe {
+            backtrace::trace_unsynchronized(|frame| {
+                if bt.len() < bt.inline_size() {
+                    bt.push(Frame::from_backtrace(frame));
+                    true
+                } else {
+                    truncated = true;
+                    false
+                }
+            })
+        };
+
+        #[cfg(unix)]
+        {
+            let current_thread = unsafe { libc::pthread_self() };
+            let mut name = [0; MAX_THREAD_NAME];
+            write_thread_name(current_thread, &mut name);
+            // the name isn't terminated if it fills the buffer
+            let name_length = name.iter().position(|c| *c == 0).unwrap_or(MAX_THREAD_NAME);
+            let name =
+                unsafe { std::slice::from_raw_parts(name.as_ptr() as *const u8, name_length) };
+
+            Self::new(
+                bt,
+                name,
+                current_thread as u64,
+                SystemTime::now(),
+                truncated,
+            )
+        }
+        #[cfg(windows)]
+        {
+            let mut name = [0; MAX_THREAD_NAME];
+            let name_length = crate::platform::write_current_thread_name(&mut name);
+
+            Self::new(
+                bt,
+                &name[..name_length],
+                crate::platform::current_thread_id(),
+                SystemTime::now(),
+                truncated,
+            )
+        }
+    }
+
+    /// The address which identifies a frame: its instruction pointer when
+    /// `symbol_offsets` is enabled, or the address of its enclosing symbol.
+    fn frame_key(&self, frame: &<TraceImpl as Trace>::Frame) -> usize {
+        if self.symbol_offsets {
+            Frame::ip(frame)
+        } else {
+            Frame::symbol_address(frame) as usize
         }
     }
 }
@@ -65,12 +170,18 @@ impl UnresolvedFrames {
 impl PartialEq for UnresolvedFrames {
     fn eq(&self, other: &Self) -> bool {
         let (frames1, frames2) = (&self.frames, &other.frames);
-        if self.thread_id != other.thread_id || frames1.len() != frames2.len() {
+        if self.thread_id != other.thread_id
+            || self.symbol_offsets != other.symbol_offsets
+            || self.truncated != other.truncated
+            || self.label_set != other.label_set
+            || self.traced != other.traced
+            || (self.traced && self.sample_timestamp != other.sample_timestamp)
+            || frames1.len() != frames2.len()
+        {
             false
         } else {
             Iterator::zip(frames1.iter(), frames2.iter())
-                .map(|(s1, s2)| s1.symbol_address() == s2.symbol_address())
-                .all(|equal| equal)
+                .all(|(s1, s2)| self.frame_key(s1) == other.frame_key(s2))
         }
     }
 }
@@ -81,8 +192,13 @@ impl Hash for UnresolvedFrames {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.frames
             .iter()
-            .for_each(|frame| frame.symbol_address().hash(state));
+            .for_each(|frame| self.frame_key(frame).hash(state));
         self.thread_id.hash(state);
+        self.truncated.hash(state);
+        self.label_set.hash(state);
+        if self.traced {
+            self.sample_timestamp.hash(state);
+        }
     }
 }
 
@@ -101,6 +217,10 @@ pub struct Symbol {
 
     /// Filename of this symbol. If compiled with debug message, you can get it.
     pub filename: Option<PathBuf>,
+
+    /// Offset of the sampled instruction from the start of this symbol. It is
+    /// only recorded when the profiler is built with `symbol_offsets(true)`.
+    pub offset: Option<usize>,
 }
 
 impl Symbol {
@@ -112,11 +232,11 @@ impl Symbol {
         demangle(&String::from_utf8_lossy(self.raw_name())).into_owned()
     }
 
-    pub fn sys_name(&self) -> Cow<str> {
+    pub fn sys_name(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(self.raw_name())
     }
 
-    pub fn filename(&self) -> Cow<str> {
+    pub fn filename(&self) -> Cow<'_, str> {
         self.filename
             .as_ref()
             .map(|name| name.as_os_str().to_string_lossy())
@@ -130,6 +250,76 @@ impl Symbol {
 
 unsafe impl Send for Symbol {}
 
+/// Name an address after the module which contains it and its offset in the
+/// module, e.g. `libfoo.so+0x1234`. The offset is relative to the addresses
+/// stated in the module, so that `addr2line` can resolve it against the
+/// original binary.
+fn module_offset(ip: usize) -> Option<String> {
+    let mut name = None;
+    TargetSharedLibrary::each(|shlib| {
+        if name.is_some() {
+            return;
+        }
+        let contains = shlib.segments().any(|seg| {
+            let start = seg.actual_virtual_memory_address(shlib).0;
+            ip >= start && ip < start + seg.len()
+        });
+        if !contains {
+            return;
+        }
+
+        let path = PathBuf::from(shlib.name());
+        // the executable itself is listed without a name on Linux
+        let path = if path.as_os_str().is_empty() {
+            std::env::current_exe().unwrap_or_default()
+        } else {
+            path
+        };
+        let module = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "Unknown".to_owned());
+        let offset = ip.wrapping_sub(shlib.virtual_memory_bias().0);
+        name = Some(format!("{}+{:#x}", module, offset));
+    });
+
+    name
+}
+
+/// Resolve an instruction pointer into symbols, with the same logic as the
+/// reports. An address inside inlined code resolves into several symbols,
+/// from the innermost inlined function to the function which contains it.
+///
+/// Like the sampled frames, `ip` is expected to be a return address: the
+/// instruction before it is looked up.
+pub fn symbolicate(ip: usize) -> Vec<Symbol> {
+    let mut symbols = Vec::new();
+    backtrace::resolve(ip as *mut c_void, |symbol| {
+        symbols.push(Symbol::from(symbol));
+    });
+
+    symbols
+}
+
+/// Same as `symbolicate`, but `pc` is the address of the instruction to
+/// resolve, e.g. the interrupted instruction of a sample.
+pub(crate) fn symbolicate_instruction(pc: usize) -> Vec<Symbol> {
+    // `backtrace` always looks up the byte before the address
+    symbolicate(pc.wrapping_add(1))
+}
+
+/// Whether the symbols `resolved` by a fallback resolve a frame better than
+/// the `symbols` of `backtrace`: they name it, or they come from the debug
+/// info, with the lines and the inlined functions, instead of the symbol
+/// table.
+fn resolves_better(resolved: &[Symbol], symbols: &[Symbol]) -> bool {
+    if resolved.iter().all(|symbol| symbol.name.is_none()) {
+        return false;
+    }
+    symbols.iter().all(|symbol| symbol.name.is_none())
+        || resolved.iter().any(|symbol| symbol.filename.is_some())
+}
+
 impl<T> From<&T> for Symbol
 where
     T: crate::backtrace::Symbol,
@@ -140,36 +330,58 @@ where
             addr: symbol.addr(),
             lineno: symbol.lineno(),
             filename: symbol.filename(),
+            offset: None,
         }
     }
 }
 
 impl Display for Symbol {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        f.write_str(&self.name())
+        f.write_str(&self.name())?;
+        if let Some(offset) = self.offset {
+            write!(f, "+{:#x}", offset)?;
+        }
+
+        Ok(())
     }
 }
 
+// the lines of a function are told apart, for the line-level views
 impl PartialEq for Symbol {
     fn eq(&self, other: &Self) -> bool {
         self.raw_name() == other.raw_name()
+            && self.offset == other.offset
+            && self.lineno == other.lineno
+            && self.filename == other.filename
     }
 }
 
 impl Hash for Symbol {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.raw_name().hash(state)
+        self.raw_name().hash(state);
+        self.offset.hash(state);
+        self.lineno.hash(state);
     }
 }
 
 /// A representation of a backtrace. `thread_name` and `thread_id` was got from `pthread_getname_np`
-/// and `pthread_self`. frames is a vector of symbols.
+/// and `pthread_self`. frames is a vector of symbols. `truncated` is set when the outermost frames
+/// were dropped because the stack was deeper than `MAX_DEPTH`.
 #[derive(Clone, PartialEq, Hash)]
 pub struct Frames {
     pub frames: Vec<Vec<Symbol>>,
     pub thread_name: String,
     pub thread_id: u64,
+    /// Time of the first sample of this stack.
     pub sample_timestamp: SystemTime,
+    /// Time of the last sample of this stack. Together with
+    /// `sample_timestamp`, it tells whether the stack was hot during the
+    /// whole profile or only during a part of it.
+    pub last_sample_timestamp: SystemTime,
+    pub truncated: bool,
+    /// The labels of the thread when it was sampled, sorted by key, see
+    /// `tag::with_labels`.
+    pub labels: Vec<(String, String)>,
 }
 
 impl Frames {
@@ -185,17 +397,100 @@ impl Frames {
 
 impl From<UnresolvedFrames> for Frames {
     fn from(frames: UnresolvedFrames) -> Self {
+        Frames::resolve(frames, |_| None)
+    }
+}
+
+impl Frames {
+    /// Drop the innermost frames, up to the outermost one whose function
+    /// starts with any of `prefixes`, e.g. the frames of the profiler which
+    /// captured the stack. The functions inlined into a frame are ignored, so
+    /// that a frame isn't dropped for an inlined call.
+    pub(crate) fn drop_inner_frames(&mut self, prefixes: &[&str]) {
+        let outermost = self.frames.iter().rposition(|frame| {
+            frame.last().map_or(false, |symbol| {
+                let name = symbol.name();
+                prefixes.iter().any(|prefix| name.starts_with(prefix))
+            })
+        });
+        if let Some(outermost) = outermost {
+            self.frames.drain(..=outermost);
+        }
+    }
+
+    /// Resolve the symbols of `frames`. `fallback` is called with the
+    /// address of the frames which `backtrace` can't name or can't find in
+    /// the debug info, and its symbols, from the innermost inlined function,
+    /// replace the ones of `backtrace` if they name the frame or have its
+    /// lines.
+    pub(crate) fn resolve<F>(frames: UnresolvedFrames, mut fallback: F) -> Self
+    where
+        F: FnMut(usize) -> Option<Vec<Symbol>>,
+    {
         let mut fs = Vec::new();
 
         let mut frame_iter = frames.frames.iter();
+        // the return addresses are resolved at their call instruction, but
+        // not the interrupted instruction, which would be attributed to the
+        // previous line or even to the previous function
+        let mut at_leaf = frames.exact_leaf;
 
         while let Some(frame) = frame_iter.next() {
             let mut symbols: Vec<Symbol> = Vec::new();
 
-            frame.resolve_symbol(|symbol| {
-                let symbol = Symbol::from(symbol);
-                symbols.push(symbol);
-            });
+            let probe = if at_leaf {
+                symbols = symbolicate_instruction(Frame::ip(frame));
+                Frame::ip(frame).wrapping_add(1)
+            } else {
+                frame.resolve_symbol(|symbol| {
+                    let symbol = Symbol::from(symbol);
+                    symbols.push(symbol);
+                });
+                Frame::ip(frame)
+            };
+
+            #[cfg(all(feature = "perf-event", target_os = "linux"))]
+            if symbols.iter().all(|symbol| symbol.name.is_none()) {
+                if let Some(symbol) = crate::perf_event::kernel_symbol(probe) {
+                    symbols = vec![symbol];
+                }
+            }
+
+            // `backtrace` may only find the frame in the symbol table, e.g.
+            // of a stripped binary, without its lines nor the functions
+            // inlined at its address
+            if symbols.iter().all(|symbol| symbol.filename.is_none()) {
+                if let Some(resolved) = fallback(probe) {
+                    if resolves_better(&resolved, &symbols) {
+                        symbols = resolved;
+                    }
+                }
+            }
+
+            if frames.unresolved_addresses && symbols.iter().all(|symbol| symbol.name.is_none()) {
+                let ip = Frame::ip(frame);
+                if let Some(name) = module_offset(ip) {
+                    symbols = vec![Symbol {
+                        name: Some(name.into_bytes()),
+                        addr: Some(ip as *mut c_void),
+                        lineno: None,
+                        filename: None,
+                        offset: None,
+                    }];
+                }
+            }
+
+            if frames.symbol_offsets {
+                let ip = Frame::ip(frame);
+                let symbol_address = Frame::symbol_address(frame) as usize;
+                for symbol in symbols.iter_mut() {
+                    let start = symbol
+                        .addr
+                        .map(|addr| addr as usize)
+                        .unwrap_or(symbol_address);
+                    symbol.offset = Some(ip.wrapping_sub(start));
+                }
+            }
 
             if symbols.iter().any(|symbol| {
                 // macOS prepends an underscore even with `#[no_mangle]`
@@ -206,20 +501,62 @@ impl From<UnresolvedFrames> for Frames {
             }) {
                 // ignore frame itself and its next one
                 frame_iter.next();
+                at_leaf = frames.exact_leaf;
                 continue;
             }
+            at_leaf = false;
 
             if !symbols.is_empty() {
                 fs.push(symbols);
             }
         }
 
+        Self::with_symbols(&frames, fs)
+    }
+
+    /// The frames of `frames` without their symbols, to be symbolicated
+    /// offline: a nameless symbol per frame, at the address of the
+    /// interrupted instruction for the leaf, and of the call instruction,
+    /// before the return address, for the others.
+    pub(crate) fn unsymbolized(frames: UnresolvedFrames) -> Self {
+        let handler = crate::profiler::signal_handler_address();
+        let mut fs = Vec::new();
+
+        let mut frame_iter = frames.frames.iter();
+        let mut at_leaf = frames.exact_leaf;
+        while let Some(frame) = frame_iter.next() {
+            if Some(Frame::symbol_address(frame) as usize) == handler {
+                // ignore frame itself and its next one, as `resolve` does
+                frame_iter.next();
+                at_leaf = frames.exact_leaf;
+                continue;
+            }
+
+            let ip = Frame::ip(frame);
+            let address = if at_leaf { ip } else { ip.wrapping_sub(1) };
+            at_leaf = false;
+            fs.push(vec![Symbol {
+                name: None,
+                addr: Some(address as *mut c_void),
+                lineno: None,
+                filename: None,
+                offset: None,
+            }]);
+        }
+
+        Self::with_symbols(&frames, fs)
+    }
+
+    fn with_symbols(frames: &UnresolvedFrames, symbols: Vec<Vec<Symbol>>) -> Self {
         Self {
-            frames: fs,
+            frames: symbols,
             thread_name: String::from_utf8_lossy(&frames.thread_name[0..frames.thread_name_length])
                 .into_owned(),
             thread_id: frames.thread_id,
             sample_timestamp: frames.sample_timestamp,
+            last_sample_timestamp: frames.last_sample_timestamp,
+            truncated: frames.truncated,
+            labels: crate::tag::get_labels(frames.label_set).to_vec(),
         }
     }
 }
@@ -234,6 +571,9 @@ impl Debug for Frames {
                 write!(f, "{} -> ", symbol)?;
             }
         }
+        if self.truncated {
+            write!(f, "FRAME: [truncated] -> ")?;
+        }
         write!(f, "THREAD: ")?;
         if !self.thread_name.is_empty() {
             write!(f, "{}", self.thread_name)
@@ -254,6 +594,7 @@ mod tests {
             addr: None,
             lineno: None,
             filename: None,
+            offset: None,
         };
 
         assert_eq!(&symbol.name(), "foo::bar")
@@ -270,6 +611,7 @@ mod tests {
             addr: None,
             lineno: None,
             filename: None,
+            offset: None,
         };
 
         assert_eq!(
@@ -277,4 +619,119 @@ mod tests {
             "Map<StringName, Ref<GDScript>, Comparator<StringName>, DefaultAllocator>::has(StringName const&) const"
         )
     }
+
+    #[test]
+    fn display_offset() {
+        let symbol = Symbol {
+            name: Some(b"_ZN3foo3barE".to_vec()),
+            addr: None,
+            lineno: None,
+            filename: None,
+            offset: Some(0x1a),
+        };
+
+        assert_eq!(&symbol.to_string(), "foo::bar+0x1a");
+        assert!(
+            symbol
+                != Symbol {
+                    offset: Some(0x2b),
+                    ..symbol.clone()
+                }
+        );
+    }
+
+    #[test]
+    fn resolves_better_than_symbol_table() {
+        let symbol = |name: Option<&str>, filename: Option<&str>| Symbol {
+            name: name.map(|name| name.as_bytes().to_vec()),
+            addr: None,
+            lineno: filename.map(|_| 12),
+            filename: filename.map(PathBuf::from),
+            offset: None,
+        };
+        let unnamed = [symbol(None, None)];
+        let symbol_table = [symbol(Some("outer"), None)];
+        let inlined = [
+            symbol(Some("inner"), Some("src/inner.rs")),
+            symbol(Some("outer"), Some("src/outer.rs")),
+        ];
+
+        assert!(resolves_better(&symbol_table, &unnamed));
+        assert!(resolves_better(&inlined, &unnamed));
+        assert!(resolves_better(&inlined, &symbol_table));
+        assert!(!resolves_better(&symbol_table, &symbol_table));
+        assert!(!resolves_better(&unnamed, &unnamed));
+    }
+
+    #[test]
+    fn unsymbolized() {
+        let unresolved = UnresolvedFrames::capture();
+        let ips: Vec<usize> = unresolved.frames.iter().map(Frame::ip).collect();
+        let frames = Frames::unsymbolized(unresolved);
+
+        // the addresses of the calls, before the return addresses
+        let addresses: Vec<usize> = frames
+            .frames
+            .iter()
+            .map(|frame| {
+                assert_eq!(frame.len(), 1);
+                assert!(frame[0].name.is_none());
+                frame[0].addr.unwrap() as usize
+            })
+            .collect();
+        let calls: Vec<usize> = ips.iter().map(|ip| ip.wrapping_sub(1)).collect();
+        assert_eq!(addresses, calls);
+    }
+
+    #[test]
+    fn debug_truncated() {
+        let frames = Frames {
+            frames: vec![],
+            thread_name: "main".to_owned(),
+            thread_id: 1,
+            sample_timestamp: SystemTime::UNIX_EPOCH,
+            last_sample_timestamp: SystemTime::UNIX_EPOCH,
+            truncated: true,
+            labels: Vec::new(),
+        };
+
+        assert_eq!(
+            format!("{:?}", frames),
+            "FRAME: [truncated] -> THREAD: main"
+        );
+    }
+
+    #[inline(never)]
+    fn symbolicate_me() -> usize {
+        std::hint::black_box(42)
+    }
+
+    #[test]
+    fn symbolicate_function() {
+        std::hint::black_box(symbolicate_me());
+        let symbols = symbolicate(symbolicate_me as *const () as usize + 1);
+        assert!(symbols
+            .iter()
+            .any(|symbol| symbol.name().contains("symbolicate_me")));
+    }
+
+    #[test]
+    fn symbolicate_exact_instruction() {
+        std::hint::black_box(symbolicate_me());
+        // the first instruction of the function, which `symbolicate` would
+        // attribute to the code before it
+        let symbols = symbolicate_instruction(symbolicate_me as *const () as usize);
+        assert!(symbols
+            .iter()
+            .any(|symbol| symbol.name().contains("symbolicate_me")));
+    }
+
+    #[test]
+    fn name_unresolved_address() {
+        let ip = symbolicate_me as *const () as usize;
+        let name = module_offset(ip).unwrap();
+        let exe = std::env::current_exe().unwrap();
+        let exe = exe.file_name().unwrap().to_string_lossy();
+        assert!(name.starts_with(&format!("{}+0x", exe)), "{}", name);
+    }
 }
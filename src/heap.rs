@@ -0,0 +1,509 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Sampling of the heap allocations, like the heap profiles of Go.
+//!
+//! `AllocProfiler` wraps the global allocator and forwards every call to it.
+//! While a `HeapProfilerGuard` is alive, every thread counts down a random
+//! number of allocated bytes, drawn from an exponential distribution whose
+//! mean is the sample rate, and records the stack of the allocation which
+//! reaches zero into a `Collector`, like the CPU samples, once with the
+//! number of allocations and once with their bytes. Every allocated byte has
+//! the same chance to be sampled, so the samples are scaled back to an
+//! estimate of all the allocations.
+//!
+//! The stacks are unwound and recorded outside of any signal handler, so the
+//! recording may allocate: the allocations of a thread which is recording a
+//! sample are forwarded without being sampled.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use parking_lot::{const_mutex, Mutex};
+
+use crate::collector::Collector;
+use crate::frames::{Frames, UnresolvedFrames};
+use crate::{Error, Result};
+
+/// The mean number of bytes between two samples, or zero when the heap
+/// profiler isn't running.
+static SAMPLE_RATE: AtomicU64 = AtomicU64::new(0);
+
+static HEAP_PROFILE: Mutex<Option<HeapProfile>> = const_mutex(None);
+
+/// The sampling state of a thread.
+struct ThreadSampler {
+    /// The rate of the countdown, so that it's drawn again when a profiler
+    /// starts with another rate.
+    rate: Cell<u64>,
+    /// The bytes left to allocate before the next sample.
+    left: Cell<i64>,
+    /// The state of the xorshift generator of the countdowns.
+    rng: Cell<u64>,
+    /// Whether the thread is recording a sample, or holding the profile.
+    busy: Cell<bool>,
+}
+
+impl ThreadSampler {
+    const fn new() -> Self {
+        Self {
+            rate: Cell::new(0),
+            left: Cell::new(0),
+            rng: Cell::new(0),
+            busy: Cell::new(false),
+        }
+    }
+
+    /// The bytes to allocate before the next sample, exponentially
+    /// distributed around `rate`.
+    fn next_countdown(&self, rate: u64) -> i64 {
+        let mut x = self.rng.get();
+        if x == 0 {
+            let seed = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64;
+            x = (seed ^ self as *const Self as u64) | 1;
+        }
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng.set(x);
+
+        // uniform in (0, 1]
+        let unit = ((x >> 11) + 1) as f64 / (1u64 << 53) as f64;
+        (-unit.ln() * rate as f64) as i64 + 1
+    }
+}
+
+thread_local! {
+    static THREAD_SAMPLER: ThreadSampler = const { ThreadSampler::new() };
+}
+
+/// Run `f` with the heap profile, without sampling the allocations of the
+/// current thread meanwhile, which would lock the profile again.
+fn with_heap_profile<R>(f: impl FnOnce(&mut Option<HeapProfile>) -> R) -> R {
+    let busy = THREAD_SAMPLER
+        .try_with(|sampler| sampler.busy.replace(true))
+        .unwrap_or(true);
+    let result = f(&mut HEAP_PROFILE.lock());
+    let _ = THREAD_SAMPLER.try_with(|sampler| sampler.busy.set(busy));
+    result
+}
+
+/// Count `size` allocated bytes down, and record the stack of the current
+/// allocation if it's sampled.
+#[inline]
+fn count_allocation(size: usize) {
+    let rate = SAMPLE_RATE.load(Ordering::Relaxed);
+    if rate == 0 {
+        return;
+    }
+
+    // the thread local is gone while the thread exits
+    let _ = THREAD_SAMPLER.try_with(|sampler| {
+        if sampler.rate.get() != rate {
+            sampler.rate.set(rate);
+            sampler.left.set(sampler.next_countdown(rate));
+        }
+        let left = sampler.left.get() - size as i64;
+        if left > 0 {
+            sampler.left.set(left);
+            return;
+        }
+
+        sampler.left.set(sampler.next_countdown(rate));
+        if !sampler.busy.replace(true) {
+            record_allocation(size);
+            sampler.busy.set(false);
+        }
+    });
+}
+
+#[inline(never)]
+fn record_allocation(size: usize) {
+    let key = UnresolvedFrames::capture();
+    if let Some(profile) = HEAP_PROFILE.lock().as_mut() {
+        // the sample is dropped if the temporary file of the collector can't
+        // be written, like the samples of the signal handler
+        let _ = profile
+            .samples
+            .add((key.clone(), AllocValue::Objects), 1)
+            .and_then(|()| profile.samples.add((key, AllocValue::Bytes), size as isize));
+    }
+}
+
+/// A global allocator which samples the allocations for `HeapProfilerGuard`,
+/// and forwards them to the wrapped allocator `A`. It has to be installed
+/// for the heap profiler to see any allocation:
+///
+/// ```rust
+/// #[global_allocator]
+/// static ALLOC: pprof::AllocProfiler = pprof::AllocProfiler::system();
+/// ```
+///
+/// It only costs an atomic load per allocation while no heap profiler is
+/// running.
+#[derive(Debug, Default)]
+pub struct AllocProfiler<A = System> {
+    inner: A,
+}
+
+impl AllocProfiler<System> {
+    /// Wrap the system allocator.
+    pub const fn system() -> Self {
+        Self { inner: System }
+    }
+}
+
+impl<A> AllocProfiler<A> {
+    /// Wrap `inner`, e.g. a jemalloc or mimalloc allocator.
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for AllocProfiler<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            count_allocation(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            count_allocation(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // a reallocation is sampled as an allocation of its new size
+        let ptr = self.inner.realloc(ptr, layout, new_size);
+        if !ptr.is_null() {
+            count_allocation(new_size);
+        }
+        ptr
+    }
+}
+
+/// What the count of a stack in the heap collector stands for, as every
+/// sampled allocation is added to it once per value.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+enum AllocValue {
+    /// The number of sampled allocations.
+    #[default]
+    Objects,
+    /// The bytes of the sampled allocations.
+    Bytes,
+}
+
+/// The samples of the running heap profiler: the number of sampled
+/// allocations and their bytes, by stack.
+struct HeapProfile {
+    sample_rate: u64,
+    start_time: SystemTime,
+    start_instant: Instant,
+    samples: Collector<(UnresolvedFrames, AllocValue)>,
+}
+
+/// RAII structure used to stop the heap profiler when dropped. Only one heap
+/// profiler can run at a time, and it only samples the allocations made
+/// through `AllocProfiler`.
+pub struct HeapProfilerGuard {
+    _private: (),
+}
+
+impl HeapProfilerGuard {
+    /// Start sampling on average one allocation every `sample_rate` bytes.
+    /// Go samples every 512KiB by default; a rate of 1 records every
+    /// allocation, at the cost of unwinding its stack.
+    pub fn new(sample_rate: u64) -> Result<HeapProfilerGuard> {
+        if cfg!(feature = "disabled") {
+            return Ok(HeapProfilerGuard { _private: () });
+        }
+
+        let sample_rate = sample_rate.max(1);
+        with_heap_profile(|profile| {
+            if profile.is_some() {
+                return Err(Error::Running);
+            }
+            *profile = Some(HeapProfile {
+                sample_rate,
+                start_time: SystemTime::now(),
+                start_instant: Instant::now(),
+                samples: Collector::new()?,
+            });
+            SAMPLE_RATE.store(sample_rate, Ordering::Relaxed);
+            Ok(HeapProfilerGuard { _private: () })
+        })
+    }
+
+    /// The allocations sampled so far, with their stacks resolved.
+    pub fn report(&self) -> HeapReport {
+        let snapshot = with_heap_profile(|profile| {
+            profile.as_ref().map(|profile| {
+                (
+                    profile.sample_rate,
+                    profile.start_time,
+                    profile.start_instant.elapsed(),
+                    copy_samples(&profile.samples),
+                )
+            })
+        });
+        let Some((sample_rate, start_time, duration, samples)) = snapshot else {
+            return HeapReport {
+                data: HashMap::new(),
+                sample_rate: 0,
+                start_time: SystemTime::now(),
+                duration: Duration::ZERO,
+            };
+        };
+
+        let mut data: HashMap<Frames, AllocStat> = HashMap::new();
+        for (key, (count, size)) in samples {
+            let mut frames = Frames::from(key);
+            // the leaf frames are the heap profiler, up to `AllocProfiler`
+            frames.drop_inner_frames(&[
+                "<pprof::heap::AllocProfiler",
+                "pprof::heap::count_allocation",
+                "pprof::heap::record_allocation",
+            ]);
+
+            let stat = data.entry(frames).or_default();
+            let scaled = AllocStat::scaled(count, size, sample_rate);
+            stat.objects += scaled.objects;
+            stat.bytes += scaled.bytes;
+        }
+
+        HeapReport {
+            data,
+            sample_rate,
+            start_time,
+            duration,
+        }
+    }
+}
+
+/// Copy the number of sampled allocations and their bytes out of the
+/// collector, by stack. The same stack may be stored more than once.
+fn copy_samples(
+    collector: &Collector<(UnresolvedFrames, AllocValue)>,
+) -> HashMap<UnresolvedFrames, (u64, u64)> {
+    let mut samples: HashMap<UnresolvedFrames, (u64, u64)> = HashMap::new();
+    let entries = match collector.try_iter() {
+        Ok(entries) => entries,
+        Err(err) => {
+            log::error!("fail to read the heap samples: {}", err);
+            return samples;
+        }
+    };
+    for entry in entries {
+        let (key, value) = &entry.item;
+        let sampled = samples.entry(key.clone()).or_default();
+        match value {
+            AllocValue::Objects => sampled.0 += entry.count as u64,
+            AllocValue::Bytes => sampled.1 += entry.count as u64,
+        }
+    }
+
+    samples
+}
+
+impl Drop for HeapProfilerGuard {
+    fn drop(&mut self) {
+        SAMPLE_RATE.store(0, Ordering::Relaxed);
+        // the samples are freed after the lock is released
+        drop(with_heap_profile(|profile| profile.take()));
+    }
+}
+
+/// The estimated allocations of a stack.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocStat {
+    pub objects: isize,
+    pub bytes: isize,
+}
+
+impl AllocStat {
+    /// Estimate the allocations which `count` samples of `size` bytes in
+    /// total stand for, like `scaleHeapSample` of Go: an allocation of `s`
+    /// bytes is sampled with the probability `1 - exp(-s / rate)`.
+    fn scaled(count: u64, size: u64, rate: u64) -> Self {
+        if count == 0 || size == 0 || rate <= 1 {
+            return AllocStat {
+                objects: count as isize,
+                bytes: size as isize,
+            };
+        }
+
+        let average = size as f64 / count as f64;
+        let scale = 1.0 / (1.0 - (-average / rate as f64).exp());
+        AllocStat {
+            objects: (count as f64 * scale) as isize,
+            bytes: (size as f64 * scale) as isize,
+        }
+    }
+}
+
+/// The allocations sampled by a `HeapProfilerGuard`, scaled to an estimate of
+/// all the allocations made while it ran.
+#[derive(Debug)]
+pub struct HeapReport {
+    pub data: HashMap<Frames, AllocStat>,
+    /// The mean number of bytes between two samples.
+    pub sample_rate: u64,
+    pub start_time: SystemTime,
+    pub duration: Duration,
+}
+
+impl HeapReport {
+    /// The stacks which allocated the most bytes first, in a stable order.
+    pub fn sorted_data(&self) -> Vec<(&Frames, AllocStat)> {
+        let mut data: Vec<_> = self.data.iter().map(|(key, stat)| (key, *stat)).collect();
+        data.sort_by_cached_key(|(key, stat)| {
+            let names: Vec<String> = key
+                .frames
+                .iter()
+                .rev()
+                .flat_map(|frame| frame.iter().rev().map(|symbol| symbol.to_string()))
+                .collect();
+            (
+                Reverse(stat.bytes),
+                Reverse(stat.objects),
+                key.thread_id,
+                names,
+            )
+        });
+        data
+    }
+}
+
+#[cfg(feature = "_protobuf")]
+impl HeapReport {
+    /// The `pprof` profile of the allocations, with the `alloc_objects` and
+    /// `alloc_space` values of the heap profiles of Go, so that `go tool
+    /// pprof -sample_index` can switch between them.
+    pub fn pprof(&self) -> Result<crate::protos::Profile> {
+        use crate::report::{encode_profile, ProfileValues};
+
+        let stacks = self
+            .sorted_data()
+            .into_iter()
+            .map(|(key, stat)| (key, vec![stat.objects as i64, stat.bytes as i64]))
+            .collect();
+
+        Ok(encode_profile(
+            stacks,
+            ProfileValues {
+                sample_types: &[("alloc_objects", "count"), ("alloc_space", "bytes")],
+                period_type: ("space", "bytes"),
+                period: self.sample_rate as i64,
+                start_time: self.start_time,
+                duration: self.duration,
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(feature = "disabled"))]
+mod tests {
+    use super::*;
+
+    static ALLOC: AllocProfiler = AllocProfiler::system();
+
+    #[inline(never)]
+    fn allocate_blocks() {
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+        for _ in 0..10 {
+            unsafe {
+                let ptr = ALLOC.alloc(layout);
+                ALLOC.dealloc(ptr, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn sample_allocations() {
+        let guard = HeapProfilerGuard::new(1).unwrap();
+        assert!(HeapProfilerGuard::new(1).is_err());
+
+        allocate_blocks();
+        let report = guard.report();
+        assert!(report.data.keys().all(|frames| frames
+            .frames
+            .iter()
+            .flatten()
+            .all(|symbol| !symbol.name().contains("record_allocation"))));
+        let stat = report
+            .data
+            .iter()
+            .filter(|(frames, _)| {
+                frames
+                    .frames
+                    .iter()
+                    .flatten()
+                    .any(|symbol| symbol.name().contains("allocate_blocks"))
+            })
+            .fold(AllocStat::default(), |total, (_, stat)| AllocStat {
+                objects: total.objects + stat.objects,
+                bytes: total.bytes + stat.bytes,
+            });
+        assert_eq!(
+            stat,
+            AllocStat {
+                objects: 10,
+                bytes: 40960
+            }
+        );
+
+        #[cfg(feature = "_protobuf")]
+        {
+            let profile = report.pprof().unwrap();
+            let sample_types: Vec<_> = profile
+                .sample_type
+                .iter()
+                .map(|ty| {
+                    (
+                        profile.string_table[ty.ty as usize].as_str(),
+                        profile.string_table[ty.unit as usize].as_str(),
+                    )
+                })
+                .collect();
+            assert_eq!(
+                sample_types,
+                vec![("alloc_objects", "count"), ("alloc_space", "bytes")]
+            );
+        }
+
+        drop(guard);
+        allocate_blocks();
+        let guard = HeapProfilerGuard::new(1).unwrap();
+        assert!(guard.report().data.is_empty());
+    }
+
+    #[test]
+    fn scale_samples() {
+        assert_eq!(
+            AllocStat::scaled(3, 300, 1),
+            AllocStat {
+                objects: 3,
+                bytes: 300
+            }
+        );
+        // an allocation of the size of the rate is sampled 63% of the time
+        let stat = AllocStat::scaled(100, 100 * 1024, 1024);
+        assert_eq!(stat.objects, 158);
+    }
+}
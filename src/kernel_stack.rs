@@ -0,0 +1,365 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Sampling of the kernel stacks of blocked threads through `/proc/<tid>/stack`.
+//!
+//! The signal handler only sees the threads which consume CPU time, so a
+//! thread blocked in a syscall never shows up in the report. This backend
+//! runs next to the timer: a background thread periodically reads the kernel
+//! stack of every thread of the process and records it as a separate stack,
+//! whose frames are suffixed with `_[k]` like in `perf script`.
+//!
+//! Reading `/proc/<tid>/stack` requires `CAP_SYS_ADMIN` and a kernel built
+//! with `CONFIG_STACKTRACE`. Without them the backend logs a warning and stops.
+//!
+//! With `ProfilerGuardBuilder::merge_kernel_stacks`, the reader sends the
+//! profiling signal to every blocked thread after reading its kernel stack,
+//! and the signal handler unwinds the user stack of the thread into
+//! `USER_STACK_REQUEST` instead of sampling it, so that the kernel frames are
+//! recorded below the user frames which led to the syscall.
+
+use std::collections::HashMap;
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+use nix::sys::signal::Signal;
+
+use crate::backtrace::{Frame, Trace, TraceImpl};
+use crate::error::Result;
+use crate::frames::{symbolicate, symbolicate_instruction, Frames, Symbol};
+use crate::profiler::{ucontext_pc, PROFILER};
+use crate::thread_state::current_tid;
+use crate::MAX_DEPTH;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct KernelStack {
+    /// Kernel functions, from the innermost frame.
+    functions: Vec<String>,
+    /// The interrupted instruction of the thread in user space, followed by
+    /// the return addresses of its callers, or nothing if they aren't merged.
+    user_stack: Vec<usize>,
+    thread_name: String,
+    thread_id: u64,
+}
+
+/// The states of `USER_STACK_REQUEST`, besides the id of the thread whose
+/// stack is requested.
+const IDLE: i32 = 0;
+const UNWINDING: i32 = -1;
+const UNWOUND: i32 = -2;
+
+/// A user stack requested by the reader from the signal handler of a thread.
+/// Only the reader makes requests, one at a time.
+struct UserStackRequest {
+    state: AtomicI32,
+    len: AtomicUsize,
+    addresses: [AtomicUsize; MAX_DEPTH],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+
+static USER_STACK_REQUEST: UserStackRequest = UserStackRequest {
+    state: AtomicI32::new(IDLE),
+    len: AtomicUsize::new(0),
+    addresses: [NO_ADDRESS; MAX_DEPTH],
+};
+
+/// How long the reader waits for a thread to unwind its stack, e.g. a thread
+/// which blocks the signal never does.
+const UNWIND_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Unwind the user stack of the current thread if the reader requested it,
+/// in which case it returns `true` and the signal isn't a sample. It's
+/// async-signal-safe.
+pub(crate) fn serve_user_stack_request(ucontext: *mut libc::c_void) -> bool {
+    let request = &USER_STACK_REQUEST;
+    let tid = current_tid() as i32;
+    if request
+        .state
+        .compare_exchange(tid, UNWINDING, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        return false;
+    }
+
+    let mut len = 0;
+    if let Some(pc) = ucontext_pc(ucontext) {
+        request.addresses[0].store(pc, Ordering::Relaxed);
+        len = 1;
+        // `backtrace` unwinds the frames of the handler before the one of the
+        // interrupted instruction
+        let mut found = cfg!(feature = "frame-pointer");
+        TraceImpl::trace(ucontext, |frame| {
+            let ip = Frame::ip(frame);
+            if !found {
+                found = ip == pc;
+                return true;
+            }
+            if len == MAX_DEPTH {
+                return false;
+            }
+            request.addresses[len].store(ip, Ordering::Relaxed);
+            len += 1;
+            true
+        });
+    }
+    request.len.store(len, Ordering::Relaxed);
+    request.state.store(UNWOUND, Ordering::Release);
+
+    true
+}
+
+/// Interrupt the thread `tid` with `signal` to unwind its user stack. It's
+/// empty if the thread doesn't handle the signal in time.
+fn request_user_stack(tid: u32, signal: Signal) -> Vec<usize> {
+    let request = &USER_STACK_REQUEST;
+    request.state.store(tid as i32, Ordering::Release);
+    let sent = unsafe {
+        libc::syscall(
+            libc::SYS_tgkill,
+            libc::getpid(),
+            tid as libc::pid_t,
+            signal as c_int,
+        )
+    } == 0;
+
+    let deadline = Instant::now() + UNWIND_TIMEOUT;
+    loop {
+        match request.state.load(Ordering::Acquire) {
+            UNWOUND => break,
+            // the handler is unwinding, it's done soon
+            UNWINDING => {}
+            // give up, unless the handler takes it meanwhile
+            _ if (!sent || Instant::now() >= deadline)
+                && request
+                    .state
+                    .compare_exchange(tid as i32, IDLE, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok() =>
+            {
+                return Vec::new();
+            }
+            _ => {}
+        }
+        std::thread::yield_now();
+    }
+
+    let len = request.len.load(Ordering::Relaxed);
+    let stack = request.addresses[..len]
+        .iter()
+        .map(|address| address.load(Ordering::Relaxed))
+        .collect();
+    request.state.store(IDLE, Ordering::Release);
+    stack
+}
+
+/// The kernel stacks sampled so far, with their count and the time of their
+/// first and last sample.
+#[derive(Default)]
+pub(crate) struct KernelStacks {
+    stacks: HashMap<KernelStack, (isize, SystemTime, SystemTime)>,
+}
+
+impl KernelStacks {
+    fn record(&mut self, stack: KernelStack, timestamp: SystemTime) {
+        let entry = self
+            .stacks
+            .entry(stack)
+            .or_insert((0, timestamp, timestamp));
+        entry.0 += 1;
+        entry.2 = timestamp;
+    }
+
+    /// The sampled stacks as resolved frames.
+    pub(crate) fn frames(&self) -> impl Iterator<Item = (Frames, isize)> + '_ {
+        self.stacks.iter().map(|(stack, (count, first, last))| {
+            let mut frames: Vec<Vec<Symbol>> = stack
+                .functions
+                .iter()
+                .map(|function| {
+                    vec![Symbol {
+                        name: Some(format!("{}_[k]", function).into_bytes()),
+                        addr: None,
+                        lineno: None,
+                        filename: None,
+                        offset: None,
+                    }]
+                })
+                .collect();
+            // the interrupted instruction is resolved at its own address
+            if let Some((pc, callers)) = stack.user_stack.split_first() {
+                frames.push(symbolicate_instruction(*pc));
+                frames.extend(callers.iter().map(|ip| symbolicate(*ip)));
+            }
+
+            (
+                Frames {
+                    frames,
+                    thread_name: stack.thread_name.clone(),
+                    thread_id: stack.thread_id,
+                    sample_timestamp: *first,
+                    last_sample_timestamp: *last,
+                    truncated: false,
+                    labels: Vec::new(),
+                },
+                *count,
+            )
+        })
+    }
+}
+
+/// Extract the function name from a line of `/proc/<tid>/stack`, which looks
+/// like `[<0>] do_sys_poll+0x3f2/0x5c0`.
+fn parse_stack_line(line: &str) -> Option<&str> {
+    let (_, symbol) = line.split_once("] ")?;
+    let function = symbol.split('+').next()?.trim();
+    if function.is_empty() {
+        None
+    } else {
+        Some(function)
+    }
+}
+
+fn read_kernel_stack(tid: u32) -> std::io::Result<Vec<String>> {
+    let stack = std::fs::read_to_string(format!("/proc/self/task/{}/stack", tid))?;
+    Ok(stack
+        .lines()
+        .filter_map(parse_stack_line)
+        .map(str::to_owned)
+        .collect())
+}
+
+fn thread_name(tid: u32) -> String {
+    std::fs::read_to_string(format!("/proc/self/task/{}/comm", tid))
+        .map(|name| name.trim_end_matches('\n').to_owned())
+        .unwrap_or_default()
+}
+
+/// Read the kernel stacks of all threads except `skip_tid`, and their user
+/// stacks if `signal` is given, returns `false` if they can't be read at all.
+fn sample_threads(skip_tid: u32, signal: Option<Signal>) -> bool {
+    let tasks = match std::fs::read_dir("/proc/self/task") {
+        Ok(tasks) => tasks,
+        Err(err) => {
+            log::warn!("fail to list threads: {}", err);
+            return false;
+        }
+    };
+
+    let timestamp = SystemTime::now();
+    let mut stacks = Vec::new();
+    for task in tasks.flatten() {
+        let tid = match task.file_name().to_str().and_then(|tid| tid.parse().ok()) {
+            Some(tid) if tid != skip_tid => tid,
+            _ => continue,
+        };
+
+        match read_kernel_stack(tid) {
+            Ok(functions) if !functions.is_empty() => stacks.push(KernelStack {
+                functions,
+                user_stack: signal.map_or_else(Vec::new, |signal| request_user_stack(tid, signal)),
+                thread_name: thread_name(tid),
+                thread_id: tid as u64,
+            }),
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                log::warn!("fail to read the kernel stack of thread {}: {}", tid, err);
+                return false;
+            }
+        }
+    }
+
+    if let Ok(profiler) = PROFILER.write().as_mut() {
+        if !profiler.running || profiler.paused {
+            return true;
+        }
+        for stack in stacks {
+            profiler.kernel_stacks.record(stack, timestamp);
+        }
+    }
+
+    true
+}
+
+/// Samples the kernel stacks of every thread of the current process until
+/// it's dropped.
+pub(crate) struct KernelStackSampler {
+    stop: Arc<AtomicBool>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl KernelStackSampler {
+    /// Sample at `frequency`, and request the user stacks of the blocked
+    /// threads with `merge_signal`, whose handler must be registered.
+    pub(crate) fn new(frequency: c_int, merge_signal: Option<Signal>) -> Result<Self> {
+        let interval = Duration::from_secs(1) / frequency.max(1) as u32;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("pprof-kstack".to_owned())
+                .spawn(move || {
+                    let reader_tid = unsafe { libc::syscall(libc::SYS_gettid) } as u32;
+                    while !stop.load(Ordering::Acquire) {
+                        if !sample_threads(reader_tid, merge_signal) {
+                            return;
+                        }
+                        std::thread::sleep(interval);
+                    }
+                })?
+        };
+
+        Ok(Self {
+            stop,
+            reader: Some(reader),
+        })
+    }
+}
+
+impl Drop for KernelStackSampler {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(reader) = self.reader.take() {
+            if reader.join().is_err() {
+                log::error!("kernel stack reader thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_kernel_stack() {
+        assert_eq!(
+            parse_stack_line("[<0>] do_sys_poll+0x3f2/0x5c0"),
+            Some("do_sys_poll")
+        );
+        assert_eq!(
+            parse_stack_line("[<ffffffff8108c4e5>] futex_wait_queue_me+0xc5/0x120"),
+            Some("futex_wait_queue_me")
+        );
+        assert_eq!(parse_stack_line(""), None);
+
+        let mut stacks = KernelStacks::default();
+        let stack = KernelStack {
+            functions: vec!["do_sys_poll".to_owned(), "__x64_sys_poll".to_owned()],
+            user_stack: Vec::new(),
+            thread_name: "main".to_owned(),
+            thread_id: 1,
+        };
+        stacks.record(stack.clone(), SystemTime::now());
+        stacks.record(stack, SystemTime::now());
+
+        let frames: Vec<_> = stacks.frames().collect();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].1, 2);
+        assert_eq!(frames[0].0.frames[0][0].name(), "do_sys_poll_[k]");
+    }
+}
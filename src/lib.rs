@@ -39,31 +39,104 @@
 //! You can find more details in
 //! [README.md](https://github.com/tikv/pprof-rs/blob/master/README.md)
 
+// The `disabled` feature compiles the signal handler, the timers and the
+// global profiler out, which leaves the helpers they use unused.
+#![cfg_attr(feature = "disabled", allow(dead_code))]
+
 /// Define the MAX supported stack depth. TODO: make this variable mutable.
 pub const MAX_DEPTH: usize = 128;
 
 /// Define the MAX supported thread name length. TODO: make this variable mutable.
 pub const MAX_THREAD_NAME: usize = 16;
 
+#[cfg(unix)]
 mod addr_validate;
 
 mod backtrace;
+mod call_graph;
 mod collector;
+#[cfg(feature = "contention")]
+pub mod contention;
+mod cpu_time;
+#[cfg(feature = "debug-info")]
+mod debug_info;
+#[cfg(feature = "debuginfod")]
+mod debuginfod;
+mod diff;
 mod error;
 mod frames;
+mod heap;
+#[cfg(all(feature = "kernel-stack", target_os = "linux"))]
+mod kernel_stack;
+mod libraries;
+#[cfg(all(feature = "frame-pointer", target_os = "linux"))]
+mod memory_map;
+mod modules;
+#[cfg(all(feature = "otlp", feature = "_protobuf"))]
+#[allow(clippy::useless_conversion)]
+#[allow(clippy::needless_update)]
+mod otlp;
+#[cfg(all(feature = "perf-event", target_os = "linux"))]
+mod perf_event;
+#[cfg(windows)]
+mod platform;
 mod profiler;
+mod rate_limiter;
+mod raw;
+mod registers;
 mod report;
+mod shared;
+#[cfg(unix)]
+mod signal_stack;
+pub mod tag;
+mod thread_state;
 mod timer;
 
+#[cfg(unix)]
+pub use nix::sys::signal::Signal;
+
+#[cfg(unix)]
 pub use self::addr_validate::validate;
-pub use self::collector::{Collector, HashCounter};
+pub use self::call_graph::CallGraph;
+pub use self::collector::{CollectMode, Collector, HashCounter};
+pub use self::cpu_time::ThreadCpuTime;
+pub use self::diff::{Change, ReportDiff, StackDiff};
 pub use self::error::{Error, Result};
-pub use self::frames::{Frames, Symbol};
-pub use self::profiler::{ProfilerGuard, ProfilerGuardBuilder};
-pub use self::report::{Report, ReportBuilder, UnresolvedReport};
+pub use self::frames::{symbolicate, Frames, Symbol};
+pub use self::heap::{AllocProfiler, AllocStat, HeapProfilerGuard, HeapReport};
+pub use self::libraries::{LibraryChanges, LoadedLibrary};
+#[cfg(all(feature = "otlp", feature = "_protobuf"))]
+pub use self::otlp::OtlpExporter;
+#[cfg(all(feature = "perf-event", target_os = "linux"))]
+pub use self::perf_event::PerfEventKind;
+#[cfg(all(
+    unix,
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "loongarch64"
+    ),
+    feature = "frame-pointer"
+))]
+pub use self::profiler::ingest_stack;
+pub use self::profiler::{profile_for, ProfilerGuard, ProfilerGuardBuilder, ThreadNameResolver};
+pub use self::raw::{RawModule, RawReport, RawSample};
+pub use self::registers::{Registers, RegistersHook, CALLEE_SAVED_REGISTERS};
+pub use self::report::{
+    FunctionStat, Report, ReportBuilder, Sample, ThreadSummary, TopFunctions, UnresolvedReport,
+};
+pub use self::shared::SharedRegion;
+#[cfg(unix)]
+pub use self::signal_stack::{install_signal_stack, SignalStack};
+pub use self::thread_state::{ThreadState, ThreadStates};
+pub use self::timer::{timer_resolution, ProfilingMode};
 
 #[cfg(feature = "flamegraph")]
-pub use inferno::flamegraph;
+pub use inferno::{differential, flamegraph};
+
+#[cfg(feature = "regex")]
+pub use regex::Regex;
 
 #[allow(clippy::all)]
 #[cfg(all(feature = "prost-codec", not(feature = "protobuf-codec")))]
@@ -74,6 +147,53 @@ pub mod protos {
         env!("CARGO_MANIFEST_DIR"),
         "/proto/perftools.profiles.rs"
     ));
+
+    /// The messages of the OpenTelemetry profiles signal, in its
+    /// `v1experimental` version. **only available with `otlp` feature**
+    #[cfg(feature = "otlp")]
+    // the comments of the protos mention e.g. `rejected_<signal>` fields
+    #[allow(rustdoc::invalid_html_tags)]
+    pub mod opentelemetry {
+        pub mod proto {
+            pub mod common {
+                pub mod v1 {
+                    include!(concat!(
+                        env!("CARGO_MANIFEST_DIR"),
+                        "/proto/opentelemetry.proto.common.v1.rs"
+                    ));
+                }
+            }
+
+            pub mod resource {
+                pub mod v1 {
+                    include!(concat!(
+                        env!("CARGO_MANIFEST_DIR"),
+                        "/proto/opentelemetry.proto.resource.v1.rs"
+                    ));
+                }
+            }
+
+            pub mod profiles {
+                pub mod v1experimental {
+                    include!(concat!(
+                        env!("CARGO_MANIFEST_DIR"),
+                        "/proto/opentelemetry.proto.profiles.v1experimental.rs"
+                    ));
+                }
+            }
+
+            pub mod collector {
+                pub mod profiles {
+                    pub mod v1experimental {
+                        include!(concat!(
+                            env!("CARGO_MANIFEST_DIR"),
+                            "/proto/opentelemetry.proto.collector.profiles.v1experimental.rs"
+                        ));
+                    }
+                }
+            }
+        }
+    }
 }
 
 #[cfg(feature = "protobuf-codec")]
@@ -83,7 +203,45 @@ pub mod protos {
     include!(concat!(env!("OUT_DIR"), "/mod.rs"));
 
     pub use self::profile::*;
+
+    /// The messages of the OpenTelemetry profiles signal, in its
+    /// `v1experimental` version, under the paths of their packages like
+    /// with `prost-codec`. **only available with `otlp` feature**
+    #[cfg(feature = "otlp")]
+    pub mod opentelemetry {
+        pub mod proto {
+            pub mod common {
+                pub mod v1 {
+                    pub use crate::protos::otlp::common::*;
+                }
+            }
+
+            pub mod resource {
+                pub mod v1 {
+                    pub use crate::protos::otlp::resource::*;
+                }
+            }
+
+            pub mod profiles {
+                pub mod v1experimental {
+                    pub use crate::protos::otlp::pprofextended::*;
+                    pub use crate::protos::otlp::profiles::*;
+                }
+            }
+
+            pub mod collector {
+                pub mod profiles {
+                    pub mod v1experimental {
+                        pub use crate::protos::otlp::profiles_service::*;
+                    }
+                }
+            }
+        }
+    }
 }
 
+#[cfg(all(feature = "otlp", not(feature = "_protobuf")))]
+compile_error!("Either feature \"prost-codec\" or \"protobuf-codec\" must be enabled when \"otlp\" feature is enabled.");
+
 #[cfg(feature = "criterion")]
 pub mod criterion;
@@ -0,0 +1,253 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::HashSet;
+use std::fmt::{self, Debug, Formatter};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use findshlibs::{Segment, SharedLibrary, TargetSharedLibrary};
+
+/// A shared library (or the executable) loaded in the process.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LoadedLibrary {
+    /// Path of the library, empty for the executable on Linux.
+    pub name: PathBuf,
+    /// Actual address ranges `(start, end)` of the segments of the library.
+    pub segments: Vec<(usize, usize)>,
+}
+
+/// The libraries loaded and unloaded since the previous check.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LibraryChanges {
+    pub added: Vec<LoadedLibrary>,
+    pub removed: Vec<LoadedLibrary>,
+}
+
+pub(crate) fn loaded_libraries() -> Vec<LoadedLibrary> {
+    let mut libraries = Vec::new();
+    TargetSharedLibrary::each(|shlib| {
+        libraries.push(LoadedLibrary {
+            name: PathBuf::from(shlib.name()),
+            segments: shlib
+                .segments()
+                .map(|seg| {
+                    let start = seg.actual_virtual_memory_address(shlib).0;
+                    (start, start + seg.len())
+                })
+                .collect(),
+        });
+    });
+    libraries
+}
+
+/// A code segment of a loaded module, written as a `Mapping` of the pprof
+/// profiles so that they can be symbolicated offline.
+#[cfg(feature = "_protobuf")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CodeMapping {
+    /// Actual address range `[start, limit)` of the segment.
+    pub(crate) start: usize,
+    pub(crate) limit: usize,
+    /// Offset of `start` in the file of the module.
+    pub(crate) file_offset: u64,
+    pub(crate) path: PathBuf,
+    /// Build id of the module in hex, e.g. its GNU build id on Linux, or
+    /// empty if it has none.
+    pub(crate) build_id: String,
+}
+
+/// The code segments of the loaded modules, the executable first.
+#[cfg(feature = "_protobuf")]
+pub(crate) fn code_mappings() -> Vec<CodeMapping> {
+    // the segments don't state their offset in the file, but the mappings
+    // of the kernel do
+    #[cfg(target_os = "linux")]
+    let file_mappings = std::fs::read_to_string("/proc/self/maps")
+        .map(|maps| parse_file_mappings(&maps))
+        .unwrap_or_default();
+
+    let mut mappings = Vec::new();
+    TargetSharedLibrary::each(|shlib| {
+        let path = PathBuf::from(shlib.name());
+        // the executable itself is listed without a name on Linux
+        let path = if path.as_os_str().is_empty() {
+            std::env::current_exe().unwrap_or_default()
+        } else {
+            path
+        };
+        let build_id: String = shlib
+            .id()
+            .map(|id| id.as_bytes().iter().map(|b| format!("{:02x}", b)).collect())
+            .unwrap_or_default();
+        // otherwise, the first loaded segment is assumed to start the file
+        let base = shlib
+            .segments()
+            .filter(|seg| seg.is_load())
+            .map(|seg| seg.stated_virtual_memory_address().0)
+            .min()
+            .unwrap_or_default();
+
+        for seg in shlib.segments().filter(|seg| seg.is_code()) {
+            let start = seg.actual_virtual_memory_address(shlib).0;
+            let mapping = (
+                start,
+                start + seg.len(),
+                seg.stated_virtual_memory_address().0.wrapping_sub(base) as u64,
+            );
+            #[cfg(target_os = "linux")]
+            let mapping = file_mappings
+                .iter()
+                .find(|(map_start, map_limit, _)| *map_start <= start && start < *map_limit)
+                .copied()
+                .unwrap_or(mapping);
+
+            mappings.push(CodeMapping {
+                start: mapping.0,
+                limit: mapping.1,
+                file_offset: mapping.2,
+                path: path.clone(),
+                build_id: build_id.clone(),
+            });
+        }
+    });
+    mappings
+}
+
+/// Parse the executable ranges `(start, limit, file offset)` of
+/// `/proc/<pid>/maps`.
+#[cfg(all(feature = "_protobuf", target_os = "linux"))]
+fn parse_file_mappings(maps: &str) -> Vec<(usize, usize, u64)> {
+    maps.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_ascii_whitespace();
+            let (range, perms, offset) = (fields.next()?, fields.next()?, fields.next()?);
+            if perms.as_bytes().get(2) != Some(&b'x') {
+                return None;
+            }
+            let (start, limit) = range.split_once('-')?;
+            Some((
+                usize::from_str_radix(start, 16).ok()?,
+                usize::from_str_radix(limit, 16).ok()?,
+                u64::from_str_radix(offset, 16).ok()?,
+            ))
+        })
+        .collect()
+}
+
+/// A callback registered with `ProfilerGuardBuilder::on_library_change`.
+#[derive(Clone)]
+pub(crate) struct LibraryCallback(pub(crate) Arc<dyn Fn(&LibraryChanges) + Send + Sync>);
+
+impl Debug for LibraryCallback {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("LibraryCallback")
+    }
+}
+
+/// Tracks the loaded libraries and calls `callback` when they change.
+pub(crate) struct LibraryWatcher {
+    known: HashSet<LoadedLibrary>,
+    callback: LibraryCallback,
+}
+
+impl LibraryWatcher {
+    pub(crate) fn new(callback: LibraryCallback) -> Self {
+        Self {
+            known: loaded_libraries().into_iter().collect(),
+            callback,
+        }
+    }
+
+    fn diff(&mut self, current: Vec<LoadedLibrary>) -> Option<LibraryChanges> {
+        let current: HashSet<_> = current.into_iter().collect();
+        let mut changes = LibraryChanges {
+            added: current.difference(&self.known).cloned().collect(),
+            removed: self.known.difference(&current).cloned().collect(),
+        };
+        self.known = current;
+
+        if changes.added.is_empty() && changes.removed.is_empty() {
+            return None;
+        }
+        changes
+            .added
+            .sort_by_key(|library| library.segments.clone());
+        changes
+            .removed
+            .sort_by_key(|library| library.segments.clone());
+        Some(changes)
+    }
+
+    /// Compare the loaded libraries with the previous check, and call the
+    /// callback if they changed.
+    pub(crate) fn check(&mut self) {
+        if let Some(changes) = self.diff(loaded_libraries()) {
+            (self.callback.0)(&changes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library(name: &str, start: usize) -> LoadedLibrary {
+        LoadedLibrary {
+            name: PathBuf::from(name),
+            segments: vec![(start, start + 0x1000)],
+        }
+    }
+
+    #[test]
+    fn diff_libraries() {
+        let mut watcher = LibraryWatcher {
+            known: vec![library("libc.so", 0x1000), library("libfoo.so", 0x3000)]
+                .into_iter()
+                .collect(),
+            callback: LibraryCallback(Arc::new(|_| {})),
+        };
+
+        let changes = watcher
+            .diff(vec![
+                library("libc.so", 0x1000),
+                library("libbar.so", 0x5000),
+            ])
+            .unwrap();
+        assert_eq!(changes.added, vec![library("libbar.so", 0x5000)]);
+        assert_eq!(changes.removed, vec![library("libfoo.so", 0x3000)]);
+
+        assert!(watcher
+            .diff(vec![
+                library("libc.so", 0x1000),
+                library("libbar.so", 0x5000)
+            ])
+            .is_none());
+    }
+
+    #[test]
+    #[cfg(all(feature = "_protobuf", target_os = "linux"))]
+    fn parse_executable_mappings() {
+        let maps = "\
+55d0c0a00000-55d0c0a02000 r--p 00000000 fd:01 1234 /usr/bin/app
+55d0c0a02000-55d0c0a08000 r-xp 00002000 fd:01 1234 /usr/bin/app
+7f0000001000-7f0000801000 rw-p 00000000 00:00 0
+";
+        assert_eq!(
+            parse_file_mappings(maps),
+            vec![(0x55d0c0a02000, 0x55d0c0a08000, 0x2000)]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "_protobuf")]
+    fn executable_code_mapping() {
+        let addr = code_mappings as *const () as usize;
+        let mappings = code_mappings();
+        let mapping = mappings
+            .iter()
+            .find(|mapping| mapping.start <= addr && addr < mapping.limit)
+            .unwrap();
+        assert_eq!(mapping.path, std::env::current_exe().unwrap());
+        assert!(mapping.build_id.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+}
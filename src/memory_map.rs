@@ -0,0 +1,270 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! The readable mappings of the process, checked by the frame pointer
+//! unwinder before it dereferences a frame pointer.
+//!
+//! A runtime which unmaps the stacks of its threads (or of its coroutines)
+//! can leave a frame pointer pointing into memory which isn't mapped anymore
+//! when the signal arrives. While the profiler runs, a background thread
+//! reads `/proc/self/maps` into a lock-free table, and the unwinder stops as
+//! soon as a frame pointer falls outside of the readable ranges of the table,
+//! without the syscalls of `validate`. A range unmapped since the latest
+//! refresh is still in the table, so the unwinder keeps validating the frame
+//! pointers which are found there.
+
+use std::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::error::Result;
+
+/// The max number of readable ranges, after merging the adjacent ones.
+const MAX_RANGES: usize = 1 << 12;
+
+/// The length of the table when its content isn't known, e.g. before the
+/// first refresh or when the process has more than `MAX_RANGES` ranges.
+const UNKNOWN: usize = usize::MAX;
+
+/// The interval between two refreshes of the table.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The readable address ranges `[start, end)`, sorted by address. The table
+/// is a seqlock: the sequence is odd while it's being written, and a reader
+/// which saw it change discards what it read.
+struct MappedRanges {
+    sequence: AtomicUsize,
+    len: AtomicUsize,
+    starts: Box<[AtomicUsize]>,
+    ends: Box<[AtomicUsize]>,
+}
+
+impl Default for MappedRanges {
+    fn default() -> Self {
+        Self {
+            sequence: AtomicUsize::new(0),
+            len: AtomicUsize::new(UNKNOWN),
+            starts: (0..MAX_RANGES).map(|_| AtomicUsize::new(0)).collect(),
+            ends: (0..MAX_RANGES).map(|_| AtomicUsize::new(0)).collect(),
+        }
+    }
+}
+
+impl MappedRanges {
+    /// Replace the content of the table, or mark it unknown if `ranges` is
+    /// `None` or doesn't fit. The writers must be serialized.
+    fn store(&self, ranges: Option<&[(usize, usize)]>) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence
+            .store(sequence.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        match ranges {
+            Some(ranges) if ranges.len() <= MAX_RANGES => {
+                for (i, (start, end)) in ranges.iter().enumerate() {
+                    self.starts[i].store(*start, Ordering::Relaxed);
+                    self.ends[i].store(*end, Ordering::Relaxed);
+                }
+                self.len.store(ranges.len(), Ordering::Relaxed);
+            }
+            _ => self.len.store(UNKNOWN, Ordering::Relaxed),
+        }
+
+        self.sequence
+            .store(sequence.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Whether the `len` bytes at `addr` are in the table, or `None` if the
+    /// table isn't known or was being written. It's async-signal-safe.
+    fn contains(&self, addr: usize, len: usize) -> Option<bool> {
+        let sequence = self.sequence.load(Ordering::Acquire);
+        if sequence % 2 == 1 {
+            return None;
+        }
+        let count = self.len.load(Ordering::Relaxed);
+        if count == UNKNOWN {
+            return None;
+        }
+        let count = count.min(MAX_RANGES);
+
+        // the number of ranges starting at or before `addr`
+        let (mut low, mut high) = (0, count);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.starts[mid].load(Ordering::Relaxed) <= addr {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let mapped = low > 0
+            && addr.checked_add(len).map_or(false, |end| {
+                end <= self.ends[low - 1].load(Ordering::Relaxed)
+            });
+
+        fence(Ordering::Acquire);
+        if self.sequence.load(Ordering::Relaxed) != sequence {
+            return None;
+        }
+        Some(mapped)
+    }
+}
+
+static MAPPED_RANGES: Lazy<MappedRanges> = Lazy::new(MappedRanges::default);
+
+/// Serializes the writers of `MAPPED_RANGES`.
+static WRITER: Mutex<()> = parking_lot::const_mutex(());
+
+/// Parse the readable ranges of `/proc/<pid>/maps`, merging the adjacent ones.
+fn parse_maps(maps: &str) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for line in maps.lines() {
+        let mut fields = line.split_ascii_whitespace();
+        let (range, perms) = match (fields.next(), fields.next()) {
+            (Some(range), Some(perms)) => (range, perms),
+            _ => continue,
+        };
+        if !perms.starts_with('r') {
+            continue;
+        }
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start, end),
+            None => continue,
+        };
+        let (start, end) = match (
+            usize::from_str_radix(start, 16),
+            usize::from_str_radix(end, 16),
+        ) {
+            (Ok(start), Ok(end)) if start < end => (start, end),
+            _ => continue,
+        };
+
+        match ranges.last_mut() {
+            Some(last) if last.1 == start => last.1 = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+    ranges
+}
+
+/// Reload the table from `/proc/self/maps`.
+fn refresh() -> std::io::Result<()> {
+    let _writer = WRITER.lock();
+    match std::fs::read_to_string("/proc/self/maps") {
+        Ok(maps) => {
+            MAPPED_RANGES.store(Some(&parse_maps(&maps)));
+            Ok(())
+        }
+        Err(err) => {
+            MAPPED_RANGES.store(None);
+            Err(err)
+        }
+    }
+}
+
+/// Whether the `len` bytes at `addr` are in the readable ranges, or `None`
+/// if they aren't known. It's async-signal-safe once the table is allocated,
+/// which `MemoryMapReader::new` does before the profiler takes any sample.
+pub(crate) fn is_mapped(addr: usize, len: usize) -> Option<bool> {
+    MAPPED_RANGES.contains(addr, len)
+}
+
+/// Keeps the table up to date from a background thread, and marks it
+/// unknown when it's dropped.
+pub(crate) struct MemoryMapReader {
+    stop: Arc<AtomicBool>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl MemoryMapReader {
+    pub(crate) fn new() -> Result<Self> {
+        // fill the table before the first sample
+        if let Err(err) = refresh() {
+            log::warn!("fail to read /proc/self/maps: {}", err);
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("pprof-maps".to_owned())
+                .spawn(move || {
+                    while !stop.load(Ordering::Acquire) {
+                        std::thread::sleep(REFRESH_INTERVAL);
+                        if let Err(err) = refresh() {
+                            log::warn!("fail to read /proc/self/maps: {}", err);
+                            return;
+                        }
+                    }
+                })?
+        };
+
+        Ok(Self {
+            stop,
+            reader: Some(reader),
+        })
+    }
+}
+
+impl Drop for MemoryMapReader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(reader) = self.reader.take() {
+            if reader.join().is_err() {
+                log::error!("memory map reader thread panicked");
+            }
+        }
+        let _writer = WRITER.lock();
+        MAPPED_RANGES.store(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_readable_ranges() {
+        let maps = "\
+55d0c0a00000-55d0c0a02000 r--p 00000000 fd:01 1234 /usr/bin/app
+55d0c0a02000-55d0c0a08000 r-xp 00002000 fd:01 1234 /usr/bin/app
+7f0000000000-7f0000001000 ---p 00000000 00:00 0
+7f0000001000-7f0000801000 rw-p 00000000 00:00 0
+ffffffffff600000-ffffffffff601000 --xp 00000000 00:00 0 [vsyscall]
+";
+        assert_eq!(
+            parse_maps(maps),
+            vec![
+                (0x55d0c0a00000, 0x55d0c0a08000),
+                (0x7f0000001000, 0x7f0000801000)
+            ]
+        );
+    }
+
+    #[test]
+    fn check_mapped_addresses() {
+        let table = MappedRanges::default();
+        assert_eq!(table.contains(0x1000, 8), None);
+
+        table.store(Some(&[(0x1000, 0x3000), (0x5000, 0x6000)]));
+        assert_eq!(table.contains(0x1000, 8), Some(true));
+        assert_eq!(table.contains(0x2ff8, 8), Some(true));
+        assert_eq!(table.contains(0x2ffc, 8), Some(false));
+        assert_eq!(table.contains(0x4000, 8), Some(false));
+        assert_eq!(table.contains(0x5800, 16), Some(true));
+        assert_eq!(table.contains(0x10, 16), Some(false));
+        assert_eq!(table.contains(usize::MAX - 8, 16), Some(false));
+
+        let on_stack = 0usize;
+        let addr = &on_stack as *const usize as usize;
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        table.store(Some(&parse_maps(&maps)));
+        assert_eq!(table.contains(addr, 8), Some(true));
+
+        table.store(None);
+        assert_eq!(table.contains(0x1000, 8), None);
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! The samples of a report by module, to tell which dependency burns the CPU.
+//!
+//! The crates are statically linked in the executable, so the samples are
+//! aggregated by crate, the first segment of the path of the functions, to
+//! tell the dependencies apart, and by shared library, where the sampled
+//! code is loaded, to tell the Rust code apart from the system libraries.
+//! The libraries are only known from the addresses of an `UnresolvedReport`,
+//! as the symbols only have their address in the file of their library.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use crate::backtrace::Frame;
+use crate::libraries::loaded_libraries;
+use crate::report::{sort_stats, UnresolvedReport};
+use crate::{FunctionStat, Report};
+
+const UNKNOWN: &str = "[unknown]";
+
+impl Report {
+    /// The self and total samples of the crates, as `top_functions` for the
+    /// functions, the crates with the most self samples first. The self
+    /// samples of a crate are the ones whose leaf function belongs to it, and
+    /// its total samples the ones where any function belongs to it. The
+    /// functions which aren't Rust paths, e.g. of the C library, are grouped
+    /// as `[unknown]`, and `UnresolvedReport::library_stats` tells where they
+    /// come from.
+    pub fn crate_stats(&self) -> Vec<(String, FunctionStat)> {
+        sort_stats(
+            self.stats_by(|symbol| {
+                crate_name(&symbol.name()).unwrap_or_else(|| UNKNOWN.to_owned())
+            }),
+        )
+    }
+}
+
+impl UnresolvedReport {
+    /// The self and total samples of the file names of the shared libraries
+    /// and of the executable, like `Report::crate_stats` for the crates. The
+    /// libraries are the ones loaded when it's called, so the report should
+    /// come from this process, and the addresses outside of them are grouped
+    /// as `[unknown]`. No stack needs to be symbolicated.
+    pub fn library_stats(&self) -> Vec<(String, FunctionStat)> {
+        let libraries: Vec<(usize, usize, String)> = loaded_libraries()
+            .into_iter()
+            .flat_map(|library| {
+                // the executable itself is listed without a name on Linux
+                let path = if library.name.as_os_str().is_empty() {
+                    std::env::current_exe().unwrap_or_default()
+                } else {
+                    library.name
+                };
+                let name = file_name(&path);
+                library
+                    .segments
+                    .into_iter()
+                    .map(move |(start, end)| (start, end, name.clone()))
+            })
+            .collect();
+        let library = |ip: usize| {
+            // a return address may be right after the end of the code, but
+            // not its call instruction
+            let ip = ip.wrapping_sub(1);
+            libraries
+                .iter()
+                .find(|(start, end, _)| *start <= ip && ip < *end)
+                .map_or(UNKNOWN, |(_, _, name)| name.as_str())
+        };
+
+        let mut stats: HashMap<String, FunctionStat> = HashMap::new();
+        let mut seen = HashSet::new();
+        for (key, count) in self.data.iter() {
+            if let Some(leaf) = key.frames.first() {
+                let name = library(Frame::ip(leaf)).to_owned();
+                stats.entry(name).or_default().self_count += count;
+            }
+
+            seen.clear();
+            for frame in key.frames.iter() {
+                let name = library(Frame::ip(frame));
+                if seen.insert(name) {
+                    stats.entry(name.to_owned()).or_default().total_count += count;
+                }
+            }
+        }
+
+        sort_stats(stats)
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => UNKNOWN.to_owned(),
+    }
+}
+
+/// The crate of the demangled Rust function `name`, e.g. `tokio` for
+/// `tokio::runtime::park::block_on` or `<tokio::net::TcpStream as
+/// tokio::io::AsyncRead>::poll_read`, or `None` if it isn't a path.
+fn crate_name(name: &str) -> Option<String> {
+    let path = name.trim_start_matches('<');
+    let end = path.find("::")?;
+    let name = &path[..end];
+    // e.g. the `&T` of `<&T as Trait>::f`
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return None;
+    }
+
+    Some(name.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crate_names() {
+        assert_eq!(
+            crate_name("tokio::runtime::park::block_on").as_deref(),
+            Some("tokio")
+        );
+        assert_eq!(
+            crate_name("<tokio::net::TcpStream as tokio::io::AsyncRead>::poll_read").as_deref(),
+            Some("tokio")
+        );
+        assert_eq!(
+            crate_name("alloc::vec::Vec<T,A>::push").as_deref(),
+            Some("alloc")
+        );
+        assert_eq!(crate_name("__libc_start_main"), None);
+        assert_eq!(crate_name("<&T as core::fmt::Display>::fmt"), None);
+    }
+}
@@ -0,0 +1,290 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! An exporter of the reports to an OpenTelemetry collector, or to any
+//! vendor accepting the profiles signal, by OTLP/HTTP.
+//!
+//! The profiles of `Report::otlp` are posted, encoded in protobuf, to the
+//! `/v1experimental/profiles` path of the collector, which serves OTLP/HTTP
+//! on port 4318 by default. Like the exporters of the OpenTelemetry SDKs, it
+//! can be configured by the `OTEL_EXPORTER_OTLP_*` environment variables.
+//!
+//! ```no_run
+//! let guard = pprof::ProfilerGuard::new(100).unwrap();
+//! let report = guard.report().build().unwrap();
+//! let exporter = pprof::OtlpExporter::new("http://localhost:4318")
+//!     .resource_attribute("service.name", "my-service");
+//! exporter.export(&report).unwrap();
+//! ```
+
+use std::time::Duration;
+
+use crate::protos::opentelemetry::proto::collector::profiles::v1experimental::{
+    ExportProfilesServiceRequest, ExportProfilesServiceResponse,
+};
+use crate::protos::opentelemetry::proto::common::v1::KeyValue;
+use crate::protos::opentelemetry::proto::resource::v1::Resource;
+use crate::report::string_value;
+use crate::{Report, Result};
+
+/// The path of the profiles signal, appended to the endpoint of a
+/// collector.
+const PROFILES_PATH: &str = "/v1experimental/profiles";
+
+/// The endpoint of a collector on the local host, unless
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` sets one.
+const DEFAULT_ENDPOINT: &str = "http://localhost:4318";
+
+/// The timeout of an export, unless `OTEL_EXPORTER_OTLP_TIMEOUT` sets it.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Exports the reports to an OpenTelemetry collector by OTLP/HTTP. **only
+/// available with `otlp` feature**
+pub struct OtlpExporter {
+    url: String,
+    headers: Vec<(String, String)>,
+    resource: Vec<(String, String)>,
+    agent: ureq::Agent,
+}
+
+impl OtlpExporter {
+    /// An exporter to the collector at `endpoint`, e.g.
+    /// `http://localhost:4318`, whose profiles path is appended to it.
+    pub fn new(endpoint: &str) -> Self {
+        Self::with_url(format!(
+            "{}{}",
+            endpoint.trim_end_matches('/'),
+            PROFILES_PATH
+        ))
+    }
+
+    /// An exporter configured like the ones of the OpenTelemetry SDKs:
+    /// - `OTEL_EXPORTER_OTLP_PROFILES_ENDPOINT` is the URL to post the
+    ///   profiles to, or else `OTEL_EXPORTER_OTLP_ENDPOINT` is the endpoint
+    ///   of the collector, `http://localhost:4318` by default.
+    /// - `OTEL_EXPORTER_OTLP_HEADERS` lists `key=value` headers, separated by
+    ///   commas, e.g. for the authentication.
+    /// - `OTEL_EXPORTER_OTLP_TIMEOUT` is the timeout, in milliseconds.
+    /// - `OTEL_SERVICE_NAME` is the `service.name` of the resource.
+    pub fn from_env() -> Self {
+        let mut exporter = match std::env::var("OTEL_EXPORTER_OTLP_PROFILES_ENDPOINT") {
+            Ok(url) => Self::with_url(url),
+            Err(_) => Self::new(
+                &std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+                    .unwrap_or_else(|_| DEFAULT_ENDPOINT.to_owned()),
+            ),
+        };
+        if let Ok(headers) = std::env::var("OTEL_EXPORTER_OTLP_HEADERS") {
+            exporter.headers = parse_pairs(&headers);
+        }
+        if let Some(timeout) = std::env::var("OTEL_EXPORTER_OTLP_TIMEOUT")
+            .ok()
+            .and_then(|timeout| timeout.trim().parse().ok())
+        {
+            exporter = exporter.timeout(Duration::from_millis(timeout));
+        }
+        if let Ok(service_name) = std::env::var("OTEL_SERVICE_NAME") {
+            exporter = exporter.resource_attribute("service.name", &service_name);
+        }
+
+        exporter
+    }
+
+    fn with_url(url: String) -> Self {
+        Self {
+            url,
+            headers: Vec::new(),
+            resource: Vec::new(),
+            agent: ureq::AgentBuilder::new().timeout(DEFAULT_TIMEOUT).build(),
+        }
+    }
+
+    /// Send the header `name` with every export.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Set the attribute `key` of the resource which produced the profiles,
+    /// e.g. its `service.name`.
+    pub fn resource_attribute(mut self, key: &str, value: &str) -> Self {
+        self.resource.retain(|(name, _)| name != key);
+        self.resource.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// The timeout of an export, 10 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.agent = ureq::AgentBuilder::new().timeout(timeout).build();
+        self
+    }
+
+    /// Export the profile of `report` to the collector. The profiles which
+    /// the collector rejects in part are logged.
+    pub fn export(&self, report: &Report) -> Result<()> {
+        let mut request = report.otlp()?;
+        let resource = Resource {
+            attributes: self
+                .resource
+                .iter()
+                .map(|(key, value)| KeyValue {
+                    key: key.clone(),
+                    value: Some(string_value(value.clone())).into(),
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>()
+                .into(),
+            ..Default::default()
+        };
+        for resource_profiles in request.resource_profiles.iter_mut() {
+            resource_profiles.resource = Some(resource.clone()).into();
+        }
+
+        let mut post = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", "application/x-protobuf");
+        for (name, value) in self.headers.iter() {
+            post = post.set(name, value);
+        }
+        let response = post
+            .send_bytes(&encode(&request)?)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+
+        let mut body = Vec::new();
+        std::io::Read::read_to_end(&mut response.into_reader(), &mut body)?;
+        if let Some(response) = decode(&body) {
+            if let Some(partial_success) = response.partial_success.as_ref() {
+                if partial_success.rejected_profiles > 0 {
+                    log::warn!(
+                        "the collector rejected {} profiles: {}",
+                        partial_success.rejected_profiles,
+                        partial_success.error_message
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The `key=value` pairs of `pairs`, separated by commas.
+fn parse_pairs(pairs: &str) -> Vec<(String, String)> {
+    pairs
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key.trim().to_owned(), value.trim().to_owned()))
+        .collect()
+}
+
+fn encode(request: &ExportProfilesServiceRequest) -> Result<Vec<u8>> {
+    #[cfg(all(feature = "prost-codec", not(feature = "protobuf-codec")))]
+    let content = crate::protos::Message::encode_to_vec(request);
+    #[cfg(feature = "protobuf-codec")]
+    let content = crate::protos::Message::write_to_bytes(request)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+    Ok(content)
+}
+
+fn decode(body: &[u8]) -> Option<ExportProfilesServiceResponse> {
+    #[cfg(all(feature = "prost-codec", not(feature = "protobuf-codec")))]
+    let response = crate::protos::Message::decode(body).ok();
+    #[cfg(feature = "protobuf-codec")]
+    let response = crate::protos::Message::parse_from_bytes(body).ok();
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    use crate::protos::opentelemetry::proto::collector::profiles::v1experimental::ExportProfilesPartialSuccess;
+
+    #[test]
+    fn header_pairs() {
+        assert_eq!(
+            parse_pairs("api-key=secret, tenant = a,invalid"),
+            vec![
+                ("api-key".to_owned(), "secret".to_owned()),
+                ("tenant".to_owned(), "a".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn export() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = Vec::new();
+            let mut length = 0;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                head.push(line.trim_end().to_owned());
+                line.clear();
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            let response = encode_response(ExportProfilesServiceResponse {
+                partial_success: Some(ExportProfilesPartialSuccess {
+                    rejected_profiles: 1,
+                    error_message: "too old".to_owned(),
+                    ..Default::default()
+                })
+                .into(),
+                ..Default::default()
+            });
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 200 OK\r\nContent-Type: application/x-protobuf\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                response.len(),
+            )
+            .unwrap();
+            reader.get_mut().write_all(&response).unwrap();
+            (head, body)
+        });
+
+        let exporter = OtlpExporter::new(&endpoint)
+            .header("api-key", "secret")
+            .resource_attribute("service.name", "test");
+        exporter
+            .export(&Report::new(HashMap::new(), Default::default()))
+            .unwrap();
+
+        let (head, body) = handle.join().unwrap();
+        assert_eq!(head[0], "POST /v1experimental/profiles HTTP/1.1");
+        assert!(head.contains(&"Content-Type: application/x-protobuf".to_owned()));
+        assert!(head.contains(&"api-key: secret".to_owned()));
+
+        #[cfg(all(feature = "prost-codec", not(feature = "protobuf-codec")))]
+        let request: ExportProfilesServiceRequest =
+            crate::protos::Message::decode(body.as_slice()).unwrap();
+        #[cfg(feature = "protobuf-codec")]
+        let request: ExportProfilesServiceRequest =
+            crate::protos::Message::parse_from_bytes(&body).unwrap();
+        let resource = request.resource_profiles[0].resource.as_ref().unwrap();
+        assert_eq!(resource.attributes[0].key, "service.name");
+        assert_eq!(
+            resource.attributes[0].value.as_ref(),
+            Some(&string_value("test".to_owned()))
+        );
+    }
+
+    fn encode_response(response: ExportProfilesServiceResponse) -> Vec<u8> {
+        #[cfg(all(feature = "prost-codec", not(feature = "protobuf-codec")))]
+        let content = crate::protos::Message::encode_to_vec(&response);
+        #[cfg(feature = "protobuf-codec")]
+        let content = crate::protos::Message::write_to_bytes(&response).unwrap();
+        content
+    }
+}
@@ -0,0 +1,753 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! A sampling backend built on top of `perf_event_open(2)`.
+//!
+//! Instead of arming `setitimer` and unwinding inside a signal handler, the
+//! kernel records a sample (including the user space callchain) into a ring
+//! buffer shared with the process. A background thread drains those buffers
+//! and feeds the samples into the same `Collector` as the signal handler, so
+//! the report API is unchanged.
+//!
+//! The kernel builds user space callchains by walking frame pointers, which is
+//! why this backend relies on the `frame-pointer` feature.
+//!
+//! With `ProfilerGuardBuilder::perf_event_kernel_callchains`, the kernel part
+//! of the callchains is recorded too, and named after `/proc/kallsyms` when
+//! the report is built.
+//!
+//! Per-task events can't be inherited when their ring buffer is mapped, so the
+//! reader thread periodically rescans `/proc/self/task` to attach the threads
+//! spawned after the profiler was started.
+
+use std::collections::{HashMap, HashSet};
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Instant, SystemTime};
+
+use once_cell::sync::Lazy;
+use smallvec::SmallVec;
+
+use crate::backtrace::frame_pointer::Frame;
+use crate::error::Result;
+use crate::frames::Symbol;
+use crate::profiler::PROFILER;
+use crate::timer::ReportTiming;
+use crate::MAX_DEPTH;
+
+#[cfg(feature = "hardware-counters")]
+const PERF_TYPE_HARDWARE: u32 = 0;
+const PERF_TYPE_SOFTWARE: u32 = 1;
+
+#[cfg(feature = "hardware-counters")]
+const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+#[cfg(feature = "hardware-counters")]
+const PERF_COUNT_HW_CACHE_MISSES: u64 = 3;
+#[cfg(feature = "hardware-counters")]
+const PERF_COUNT_HW_BRANCH_MISSES: u64 = 5;
+
+const PERF_COUNT_SW_CPU_CLOCK: u64 = 0;
+const PERF_COUNT_SW_CONTEXT_SWITCHES: u64 = 3;
+
+const PERF_SAMPLE_IP: u64 = 1 << 0;
+const PERF_SAMPLE_TID: u64 = 1 << 1;
+const PERF_SAMPLE_TIME: u64 = 1 << 2;
+const PERF_SAMPLE_CALLCHAIN: u64 = 1 << 5;
+
+const ATTR_FLAG_DISABLED: u64 = 1 << 0;
+const ATTR_FLAG_EXCLUDE_KERNEL: u64 = 1 << 5;
+const ATTR_FLAG_EXCLUDE_HV: u64 = 1 << 6;
+const ATTR_FLAG_FREQ: u64 = 1 << 10;
+const ATTR_FLAG_EXCLUDE_CALLCHAIN_KERNEL: u64 = 1 << 21;
+const ATTR_FLAG_USE_CLOCKID: u64 = 1 << 25;
+
+const PERF_RECORD_SAMPLE: u32 = 9;
+
+// the callchain contains markers such as `PERF_CONTEXT_USER`, which are all
+// larger than `PERF_CONTEXT_MAX` (-4095 as u64)
+const PERF_CONTEXT_MAX: u64 = -4095i64 as u64;
+
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 8;
+
+const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+const PERF_EVENT_IOC_DISABLE: libc::c_ulong = 0x2401;
+
+// 2^n pages of data following the metadata page
+const DATA_PAGES: usize = 16;
+
+// offsets of `data_head` and `data_tail` in `struct perf_event_mmap_page`
+const DATA_HEAD_OFFSET: usize = 1024;
+const DATA_TAIL_OFFSET: usize = 1032;
+
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    ty: u32,
+    size: u32,
+    config: u64,
+    sample_period_or_freq: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+    branch_sample_type: u64,
+    sample_regs_user: u64,
+    sample_stack_user: u32,
+    clockid: i32,
+    sample_regs_intr: u64,
+    aux_watermark: u32,
+    sample_max_stack: u16,
+    reserved: u16,
+}
+
+/// The event which triggers a sample in the `perf_event_open` backend. Its
+/// variants depend on the enabled features, so it can't be matched
+/// exhaustively.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PerfEventKind {
+    /// Sample the on-CPU time of each thread at the configured frequency.
+    CpuClock,
+    /// Sample every time a thread is switched out, which shows where threads
+    /// block or get preempted. The switches happen in the kernel, so it
+    /// requires `kernel.perf_event_paranoid` to be 1 or lower, or
+    /// `CAP_PERFMON`, like `perf_event_kernel_callchains`.
+    ContextSwitches,
+    /// Sample every `period` CPU cycles of each thread, counted by the
+    /// hardware, so that the samples are proportional to the work done
+    /// regardless of the frequency scaling. If `period` is `None`, the
+    /// kernel adjusts it to sample at the configured frequency. The hardware
+    /// counters are often unavailable in virtual machines, in which case the
+    /// profiler fails to start. **only available with `hardware-counters`
+    /// feature**
+    #[cfg(feature = "hardware-counters")]
+    CpuCycles { period: Option<u64> },
+    /// Sample every `period` misses of the last level cache of each thread,
+    /// so that the stacks are weighted by the misses they cause. The period
+    /// is fixed, as with a frequency the kernel would adapt it to the rate of
+    /// the misses, and the samples would be weighted by the time instead.
+    /// The `cpu` values of the pprof profile don't apply, only its `samples`.
+    /// **only available with `hardware-counters` feature**
+    #[cfg(feature = "hardware-counters")]
+    CacheMisses { period: u64 },
+    /// Sample every `period` mispredicted branches of each thread, so that
+    /// the stacks are weighted by their mispredictions, see `CacheMisses`.
+    /// **only available with `hardware-counters` feature**
+    #[cfg(feature = "hardware-counters")]
+    BranchMisses { period: u64 },
+}
+
+impl PerfEventKind {
+    fn attr(&self, frequency: c_int, kernel_callchains: bool) -> PerfEventAttr {
+        let mut attr = PerfEventAttr {
+            ty: PERF_TYPE_SOFTWARE,
+            size: std::mem::size_of::<PerfEventAttr>() as u32,
+            sample_type: PERF_SAMPLE_IP
+                | PERF_SAMPLE_TID
+                | PERF_SAMPLE_TIME
+                | PERF_SAMPLE_CALLCHAIN,
+            flags: ATTR_FLAG_DISABLED
+                | ATTR_FLAG_EXCLUDE_KERNEL
+                | ATTR_FLAG_EXCLUDE_HV
+                | ATTR_FLAG_EXCLUDE_CALLCHAIN_KERNEL
+                | ATTR_FLAG_USE_CLOCKID,
+            clockid: libc::CLOCK_MONOTONIC,
+            wakeup_events: 1,
+            ..Default::default()
+        };
+        if kernel_callchains {
+            attr.flags &= !(ATTR_FLAG_EXCLUDE_KERNEL | ATTR_FLAG_EXCLUDE_CALLCHAIN_KERNEL);
+        }
+
+        match self {
+            PerfEventKind::CpuClock => {
+                attr.config = PERF_COUNT_SW_CPU_CLOCK;
+                attr.sample_period_or_freq = frequency as u64;
+                attr.flags |= ATTR_FLAG_FREQ;
+            }
+            PerfEventKind::ContextSwitches => {
+                attr.config = PERF_COUNT_SW_CONTEXT_SWITCHES;
+                attr.sample_period_or_freq = 1;
+                // a thread is always switched out in the kernel, only the
+                // kernel part of its callchain may be left out
+                attr.flags &= !ATTR_FLAG_EXCLUDE_KERNEL;
+            }
+            #[cfg(feature = "hardware-counters")]
+            PerfEventKind::CpuCycles { period } => {
+                attr.ty = PERF_TYPE_HARDWARE;
+                attr.config = PERF_COUNT_HW_CPU_CYCLES;
+                match period {
+                    Some(period) => attr.sample_period_or_freq = *period,
+                    None => {
+                        attr.sample_period_or_freq = frequency as u64;
+                        attr.flags |= ATTR_FLAG_FREQ;
+                    }
+                }
+            }
+            #[cfg(feature = "hardware-counters")]
+            PerfEventKind::CacheMisses { period } => {
+                attr.ty = PERF_TYPE_HARDWARE;
+                attr.config = PERF_COUNT_HW_CACHE_MISSES;
+                attr.sample_period_or_freq = (*period).max(1);
+            }
+            #[cfg(feature = "hardware-counters")]
+            PerfEventKind::BranchMisses { period } => {
+                attr.ty = PERF_TYPE_HARDWARE;
+                attr.config = PERF_COUNT_HW_BRANCH_MISSES;
+                attr.sample_period_or_freq = (*period).max(1);
+            }
+        }
+
+        attr
+    }
+}
+
+/// A perf event opened for one thread, together with its ring buffer.
+struct PerfEvent {
+    fd: c_int,
+    mmap: *mut u8,
+    mmap_len: usize,
+    data_len: usize,
+    page_size: usize,
+}
+
+// The ring buffer is only touched by the reader thread once it's started.
+unsafe impl Send for PerfEvent {}
+
+impl PerfEvent {
+    fn open(attr: &PerfEventAttr, tid: libc::pid_t) -> std::io::Result<Self> {
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                attr as *const PerfEventAttr,
+                tid,
+                -1 as c_int,
+                -1 as c_int,
+                PERF_FLAG_FD_CLOEXEC,
+            )
+        } as c_int;
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let data_len = DATA_PAGES * page_size;
+        let mmap_len = page_size + data_len;
+        let mmap = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mmap_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if mmap == libc::MAP_FAILED {
+            let err = std::io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+
+        Ok(Self {
+            fd,
+            mmap: mmap as *mut u8,
+            mmap_len,
+            data_len,
+            page_size,
+        })
+    }
+
+    fn ioctl(&self, request: libc::c_ulong) -> std::io::Result<()> {
+        if unsafe { libc::ioctl(self.fd, request as _, 0) } < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn data_head(&self) -> &AtomicU64 {
+        unsafe { &*(self.mmap.add(DATA_HEAD_OFFSET) as *const AtomicU64) }
+    }
+
+    fn data_tail(&self) -> &AtomicU64 {
+        unsafe { &*(self.mmap.add(DATA_TAIL_OFFSET) as *const AtomicU64) }
+    }
+
+    fn read_bytes(&self, offset: u64, buf: &mut [u8]) {
+        let data = unsafe { self.mmap.add(self.page_size) };
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let index = (offset as usize + i) % self.data_len;
+            *byte = unsafe { *data.add(index) };
+        }
+    }
+
+    /// Call `f` with every record which hasn't been consumed yet.
+    fn drain<F: FnMut(u32, &[u8])>(&self, mut f: F) {
+        let head = self.data_head().load(Ordering::Acquire);
+        let mut tail = self.data_tail().load(Ordering::Relaxed);
+
+        let mut record = Vec::new();
+        while tail < head {
+            let mut header = [0u8; 8];
+            self.read_bytes(tail, &mut header);
+            let ty = u32::from_ne_bytes([header[0], header[1], header[2], header[3]]);
+            let size = u16::from_ne_bytes([header[6], header[7]]) as u64;
+            if size < 8 {
+                // a corrupted record, drop everything
+                tail = head;
+                break;
+            }
+
+            record.resize(size as usize - 8, 0);
+            self.read_bytes(tail + 8, &mut record);
+            f(ty, &record);
+
+            tail += size;
+        }
+
+        self.data_tail().store(tail, Ordering::Release);
+    }
+}
+
+impl Drop for PerfEvent {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.mmap as *mut libc::c_void, self.mmap_len);
+            libc::close(self.fd);
+        }
+    }
+}
+
+struct Sample {
+    tid: u32,
+    timestamp: SystemTime,
+    frames: SmallVec<[Frame; MAX_DEPTH]>,
+    truncated: bool,
+}
+
+fn read_u32(record: &[u8], offset: &mut usize) -> Option<u32> {
+    let bytes = record.get(*offset..*offset + 4)?;
+    *offset += 4;
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(bytes);
+    Some(u32::from_ne_bytes(buf))
+}
+
+fn read_u64(record: &[u8], offset: &mut usize) -> Option<u64> {
+    let bytes = record.get(*offset..*offset + 8)?;
+    *offset += 8;
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(bytes);
+    Some(u64::from_ne_bytes(buf))
+}
+
+fn parse_sample(record: &[u8], clock_base: (SystemTime, u64)) -> Option<Sample> {
+    let mut offset = 0;
+
+    let ip = read_u64(record, &mut offset)?;
+    let _pid = read_u32(record, &mut offset)?;
+    let tid = read_u32(record, &mut offset)?;
+    let time = read_u64(record, &mut offset)?;
+    let nr = read_u64(record, &mut offset)?;
+
+    let mut frames = SmallVec::new();
+    let mut truncated = false;
+    for _ in 0..nr {
+        let addr = read_u64(record, &mut offset)?;
+        if addr >= PERF_CONTEXT_MAX {
+            continue;
+        }
+        if frames.len() >= MAX_DEPTH {
+            truncated = true;
+            break;
+        }
+        frames.push(Frame { ip: addr as usize });
+    }
+    if frames.is_empty() {
+        frames.push(Frame { ip: ip as usize });
+    }
+
+    // `PERF_SAMPLE_TIME` is read from `CLOCK_MONOTONIC`, so it's translated
+    // relatively to the time when the backend was started
+    let (base_time, base_perf_time) = clock_base;
+    let timestamp =
+        base_time + std::time::Duration::from_nanos(time.saturating_sub(base_perf_time));
+
+    Some(Sample {
+        tid,
+        timestamp,
+        frames,
+        truncated,
+    })
+}
+
+fn thread_name(tid: u32) -> Vec<u8> {
+    std::fs::read(format!("/proc/self/task/{}/comm", tid))
+        .map(|mut name| {
+            while name.last() == Some(&b'\n') {
+                name.pop();
+            }
+            name.truncate(crate::MAX_THREAD_NAME - 1);
+            name
+        })
+        .unwrap_or_default()
+}
+
+fn perf_clock_now() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+fn list_threads() -> std::io::Result<Vec<libc::pid_t>> {
+    let mut tids = Vec::new();
+    for entry in std::fs::read_dir("/proc/self/task")? {
+        if let Some(tid) = entry?.file_name().to_str().and_then(|tid| tid.parse().ok()) {
+            tids.push(tid);
+        }
+    }
+
+    Ok(tids)
+}
+
+/// The text symbols of the kernel, sorted by address. Their addresses read as
+/// zero, and thus are dropped, unless `kernel.kptr_restrict` shows them to
+/// the process.
+static KERNEL_SYMBOLS: Lazy<Vec<(usize, String)>> = Lazy::new(|| {
+    std::fs::read_to_string("/proc/kallsyms")
+        .map(|kallsyms| parse_kallsyms(&kallsyms))
+        .unwrap_or_default()
+});
+
+fn parse_kallsyms(kallsyms: &str) -> Vec<(usize, String)> {
+    let mut symbols: Vec<_> = kallsyms
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_ascii_whitespace();
+            let (addr, ty, name) = (fields.next()?, fields.next()?, fields.next()?);
+            if ty != "t" && ty != "T" {
+                return None;
+            }
+            let addr = usize::from_str_radix(addr, 16)
+                .ok()
+                .filter(|addr| *addr != 0)?;
+            Some((addr, name.to_owned()))
+        })
+        .collect();
+    symbols.sort_unstable();
+    symbols
+}
+
+/// Name the kernel function which contains the byte before `probe`, with the
+/// `_[k]` suffix of the kernel stacks, or `None` if `probe` isn't a kernel
+/// address.
+pub(crate) fn kernel_symbol(probe: usize) -> Option<Symbol> {
+    // the kernel lives in the upper half of the address space
+    if probe < 1 << (usize::BITS - 1) {
+        return None;
+    }
+
+    let pc = probe.wrapping_sub(1);
+    let index = KERNEL_SYMBOLS.partition_point(|(addr, _)| *addr <= pc);
+    let (_, name) = KERNEL_SYMBOLS.get(index.checked_sub(1)?)?;
+    Some(Symbol {
+        name: Some(format!("{}_[k]", name).into_bytes()),
+        addr: None,
+        lineno: None,
+        filename: None,
+        offset: None,
+    })
+}
+
+/// The perf events of all sampled threads.
+struct PerfEvents {
+    attr: PerfEventAttr,
+    events: HashMap<libc::pid_t, PerfEvent>,
+    /// The events of the threads which exited, read a last time before
+    /// they're closed.
+    exited: Vec<(libc::pid_t, PerfEvent)>,
+    names: HashMap<u32, Vec<u8>>,
+    clock_base: (SystemTime, u64),
+}
+
+impl PerfEvents {
+    fn new(attr: PerfEventAttr) -> Self {
+        Self {
+            attr,
+            events: HashMap::new(),
+            exited: Vec::new(),
+            names: HashMap::new(),
+            clock_base: (SystemTime::now(), perf_clock_now()),
+        }
+    }
+
+    /// Attach to the threads which haven't been sampled yet, except `skip_tid`,
+    /// and detach from the threads which exited.
+    fn attach(&mut self, skip_tid: libc::pid_t) -> std::io::Result<()> {
+        let tids: HashSet<_> = list_threads()?.into_iter().collect();
+        let exited: Vec<_> = self
+            .events
+            .keys()
+            .filter(|tid| !tids.contains(tid))
+            .copied()
+            .collect();
+        for tid in exited {
+            if let Some(event) = self.events.remove(&tid) {
+                self.exited.push((tid, event));
+            }
+        }
+
+        for tid in tids {
+            if tid == skip_tid || self.events.contains_key(&tid) {
+                continue;
+            }
+
+            match PerfEvent::open(&self.attr, tid) {
+                Ok(event) => {
+                    event.ioctl(PERF_EVENT_IOC_ENABLE)?;
+                    self.events.insert(tid, event);
+                }
+                // the thread exited after being listed
+                Err(err) if err.raw_os_error() == Some(libc::ESRCH) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn wait(&self, timeout: c_int) {
+        let mut pollfds: Vec<libc::pollfd> = self
+            .events
+            .values()
+            .map(|event| libc::pollfd {
+                fd: event.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as _, timeout) };
+    }
+
+    fn disable(&self) {
+        for event in self.events.values() {
+            let _ = event.ioctl(PERF_EVENT_IOC_DISABLE);
+        }
+    }
+
+    /// Close the events of the threads which exited, and forget their names.
+    fn close_exited(&mut self) {
+        for (tid, _) in self.exited.drain(..) {
+            self.names.remove(&(tid as u32));
+        }
+    }
+
+    fn read(&mut self) {
+        let mut samples = Vec::new();
+        let exited = self.exited.iter().map(|(_, event)| event);
+        for event in self.events.values().chain(exited) {
+            event.drain(|ty, record| {
+                if ty == PERF_RECORD_SAMPLE {
+                    if let Some(sample) = parse_sample(record, self.clock_base) {
+                        samples.push(sample);
+                    }
+                }
+            });
+        }
+        if !samples.is_empty() {
+            if let Ok(profiler) = PROFILER.write().as_mut() {
+                for sample in samples {
+                    if profiler.is_shallow(sample.frames.len()) {
+                        continue;
+                    }
+                    if let Some(states) = &profiler.observed_states {
+                        profiler
+                            .thread_states
+                            .record(sample.tid as u64, states.load(sample.tid));
+                    }
+                    let name = self
+                        .names
+                        .entry(sample.tid)
+                        .or_insert_with(|| thread_name(sample.tid));
+                    profiler.sample(
+                        sample.frames,
+                        name,
+                        sample.tid as u64,
+                        sample.timestamp,
+                        sample.truncated,
+                        true,
+                        crate::tag::NO_LABELS,
+                    );
+                }
+            }
+        }
+        self.close_exited();
+    }
+}
+
+/// Samples every thread of the current process through `perf_event_open`
+/// until it's dropped.
+pub(crate) struct PerfEventSampler {
+    frequency: c_int,
+    start_time: SystemTime,
+    start_instant: Instant,
+    stop: Arc<AtomicBool>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl PerfEventSampler {
+    pub(crate) fn new(
+        kind: PerfEventKind,
+        frequency: c_int,
+        kernel_callchains: bool,
+    ) -> Result<Self> {
+        let mut events = PerfEvents::new(kind.attr(frequency, kernel_callchains));
+        // attach the existing threads before returning, so that a failure of
+        // `perf_event_open` (e.g. forbidden by `perf_event_paranoid`) is reported
+        events.attach(0)?;
+        let start_time = events.clock_base.0;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("pprof-perf".to_owned())
+                .spawn(move || {
+                    let reader_tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+                    while !stop.load(Ordering::Acquire) {
+                        events.wait(100);
+                        events.read();
+                        if let Err(err) = events.attach(reader_tid) {
+                            log::warn!("fail to attach perf event to new threads: {}", err);
+                        }
+                    }
+
+                    events.disable();
+                    events.read();
+                })?
+        };
+
+        Ok(Self {
+            frequency,
+            start_time,
+            start_instant: Instant::now(),
+            stop,
+            reader: Some(reader),
+        })
+    }
+
+    pub(crate) fn timing(&self) -> ReportTiming {
+        ReportTiming {
+            frequency: self.frequency,
+            start_time: self.start_time,
+            duration: self.start_instant.elapsed(),
+            active_duration: None,
+        }
+    }
+}
+
+impl Drop for PerfEventSampler {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(reader) = self.reader.take() {
+            if reader.join().is_err() {
+                log::error!("perf event reader thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_kernel_symbols() {
+        let kallsyms = "\
+ffffffff81000000 T _stext
+ffffffff81001000 t do_syscall_64
+ffffffff82000000 D jiffies
+ffffffffc0000000 t ext4_read [ext4]
+";
+        assert_eq!(
+            parse_kallsyms(kallsyms),
+            vec![
+                (0xffffffff81000000, "_stext".to_owned()),
+                (0xffffffff81001000, "do_syscall_64".to_owned()),
+                (0xffffffffc0000000, "ext4_read".to_owned()),
+            ]
+        );
+
+        // hidden by `kptr_restrict`
+        assert!(parse_kallsyms("0000000000000000 T _stext\n").is_empty());
+    }
+
+    #[test]
+    fn user_address_is_not_kernel_symbol() {
+        assert!(kernel_symbol(parse_kallsyms as *const () as usize).is_none());
+    }
+
+    #[test]
+    fn context_switches_include_the_kernel() {
+        let attr = PerfEventKind::ContextSwitches.attr(99, false);
+        assert_eq!(attr.flags & ATTR_FLAG_EXCLUDE_KERNEL, 0);
+        assert_ne!(attr.flags & ATTR_FLAG_EXCLUDE_CALLCHAIN_KERNEL, 0);
+
+        let attr = PerfEventKind::CpuClock.attr(99, false);
+        assert_ne!(attr.flags & ATTR_FLAG_EXCLUDE_KERNEL, 0);
+    }
+
+    #[test]
+    fn detach_exited_threads() {
+        let (sender, receiver) = std::sync::mpsc::channel::<()>();
+        let (tid_sender, tid_receiver) = std::sync::mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            tid_sender
+                .send(unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t)
+                .unwrap();
+            let _ = receiver.recv();
+        });
+        let tid = tid_receiver.recv().unwrap();
+
+        let mut events = PerfEvents::new(PerfEventKind::CpuClock.attr(99, false));
+        if let Err(err) = events.attach(0) {
+            // e.g. in a container without perf events
+            eprintln!("skip, perf events are unavailable: {}", err);
+            return;
+        }
+        assert!(events.events.contains_key(&tid));
+        events.names.insert(tid as u32, b"exited".to_vec());
+
+        drop(sender);
+        thread.join().unwrap();
+        events.attach(0).unwrap();
+        assert!(!events.events.contains_key(&tid));
+        assert!(events.exited.iter().any(|(exited, _)| *exited == tid));
+
+        events.close_exited();
+        assert!(events.exited.is_empty());
+        assert!(!events.names.contains_key(&(tid as u32)));
+    }
+
+    #[test]
+    #[cfg(feature = "hardware-counters")]
+    fn miss_events_have_a_fixed_period() {
+        let attr = PerfEventKind::CacheMisses { period: 1000 }.attr(99, false);
+        assert_eq!(attr.ty, PERF_TYPE_HARDWARE);
+        assert_eq!(attr.config, PERF_COUNT_HW_CACHE_MISSES);
+        assert_eq!(attr.sample_period_or_freq, 1000);
+        assert_eq!(attr.flags & ATTR_FLAG_FREQ, 0);
+
+        let attr = PerfEventKind::BranchMisses { period: 0 }.attr(99, false);
+        assert_eq!(attr.config, PERF_COUNT_HW_BRANCH_MISSES);
+        assert_eq!(attr.sample_period_or_freq, 1);
+    }
+}
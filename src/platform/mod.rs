@@ -0,0 +1,7 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! The sampling backends of the platforms which have no profiling signal.
+
+mod platform_windows;
+
+pub(crate) use self::platform_windows::*;
@@ -0,0 +1,445 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Sampling on Windows, which has no profiling signal: a sampler thread wakes
+//! up `frequency` times per second, and samples every thread of the process
+//! which ran since its previous tick. The thread is suspended with
+//! `SuspendThread`, its registers are read with `GetThreadContext` and its
+//! stack is unwound with `RtlVirtualUnwind`, then it's resumed before the
+//! sample is recorded.
+
+#[cfg(not(feature = "disabled"))]
+use std::collections::HashSet;
+#[cfg(not(feature = "disabled"))]
+use std::os::raw::c_int;
+#[cfg(not(feature = "disabled"))]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(not(feature = "disabled"))]
+use std::sync::Arc;
+#[cfg(not(feature = "disabled"))]
+use std::thread::JoinHandle;
+use std::time::Duration;
+#[cfg(not(feature = "disabled"))]
+use std::time::{Instant, SystemTime};
+
+#[cfg(not(feature = "disabled"))]
+use smallvec::SmallVec;
+#[cfg(not(feature = "disabled"))]
+use windows_sys::Win32::Foundation::{CloseHandle, INVALID_HANDLE_VALUE};
+use windows_sys::Win32::Foundation::{LocalFree, FILETIME, HANDLE};
+#[cfg(not(feature = "disabled"))]
+use windows_sys::Win32::System::Diagnostics::Debug::GetThreadContext;
+#[cfg(not(feature = "disabled"))]
+use windows_sys::Win32::System::Diagnostics::ToolHelp::{
+    CreateToolhelp32Snapshot, Thread32First, Thread32Next, TH32CS_SNAPTHREAD, THREADENTRY32,
+};
+#[cfg(not(feature = "disabled"))]
+use windows_sys::Win32::System::Threading::{
+    GetCurrentProcessId, OpenThread, ResumeThread, SuspendThread, THREAD_GET_CONTEXT,
+    THREAD_QUERY_LIMITED_INFORMATION, THREAD_SUSPEND_RESUME,
+};
+use windows_sys::Win32::System::Threading::{
+    GetCurrentThread, GetCurrentThreadId, GetThreadDescription, GetThreadTimes,
+};
+#[cfg(not(feature = "disabled"))]
+use windows_sys::Win32::System::WindowsProgramming::QueryThreadCycleTime;
+
+#[cfg(not(feature = "disabled"))]
+use crate::backtrace::windows::AlignedContext;
+#[cfg(not(feature = "disabled"))]
+use crate::backtrace::{Trace, TraceImpl};
+#[cfg(not(feature = "disabled"))]
+use crate::error::Result;
+#[cfg(not(feature = "disabled"))]
+use crate::timer::{ProfilingMode, ReportTiming};
+#[cfg(not(feature = "disabled"))]
+use crate::MAX_DEPTH;
+use crate::MAX_THREAD_NAME;
+
+#[cfg(all(not(feature = "disabled"), target_arch = "x86_64"))]
+const CONTEXT_FULL: u32 = windows_sys::Win32::System::Diagnostics::Debug::CONTEXT_FULL_AMD64;
+#[cfg(all(not(feature = "disabled"), target_arch = "aarch64"))]
+const CONTEXT_FULL: u32 = windows_sys::Win32::System::Diagnostics::Debug::CONTEXT_FULL_ARM64;
+#[cfg(all(not(feature = "disabled"), target_arch = "x86"))]
+const CONTEXT_FULL: u32 = windows_sys::Win32::System::Diagnostics::Debug::CONTEXT_FULL_X86;
+
+/// The id of the calling thread.
+pub(crate) fn current_thread_id() -> u64 {
+    unsafe { GetCurrentThreadId() as u64 }
+}
+
+/// Write the name of the calling thread into `name`, see
+/// `write_thread_name`.
+pub(crate) fn write_current_thread_name(name: &mut [u8; MAX_THREAD_NAME]) -> usize {
+    write_thread_name(unsafe { GetCurrentThread() }, current_thread_id(), name)
+}
+
+/// Write the description of `thread`, e.g. set by `std::thread::Builder::name`,
+/// into `name`, truncated to a whole character, or its id if it has none.
+/// Returns the length of the name.
+fn write_thread_name(thread: HANDLE, id: u64, name: &mut [u8; MAX_THREAD_NAME]) -> usize {
+    let mut description = std::ptr::null_mut();
+    let description = if unsafe { GetThreadDescription(thread, &mut description) } >= 0 {
+        let mut length = 0;
+        while unsafe { *description.add(length) } != 0 {
+            length += 1;
+        }
+        let decoded =
+            String::from_utf16_lossy(unsafe { std::slice::from_raw_parts(description, length) });
+        unsafe { LocalFree(description as _) };
+        decoded
+    } else {
+        String::new()
+    };
+    let description = if description.is_empty() {
+        id.to_string()
+    } else {
+        description
+    };
+
+    let mut length = description.len().min(MAX_THREAD_NAME);
+    while !description.is_char_boundary(length) {
+        length -= 1;
+    }
+    name[..length].copy_from_slice(&description.as_bytes()[..length]);
+    length
+}
+
+/// The user and kernel CPU time consumed by `thread`.
+fn thread_times(thread: HANDLE) -> Option<(Duration, Duration)> {
+    let zero = FILETIME {
+        dwLowDateTime: 0,
+        dwHighDateTime: 0,
+    };
+    let (mut creation, mut exit, mut kernel, mut user) = (zero, zero, zero, zero);
+    if unsafe { GetThreadTimes(thread, &mut creation, &mut exit, &mut kernel, &mut user) } == 0 {
+        return None;
+    }
+
+    // in units of 100ns
+    let duration = |time: FILETIME| {
+        let ticks = (time.dwHighDateTime as u64) << 32 | time.dwLowDateTime as u64;
+        Duration::from_nanos(ticks.saturating_mul(100))
+    };
+    Some((duration(user), duration(kernel)))
+}
+
+/// The CPU time consumed by `thread`. It's only updated on every tick of the
+/// scheduler, about every 15ms.
+pub(crate) fn thread_cpu_time(thread: HANDLE) -> Option<Duration> {
+    thread_times(thread).map(|(user, kernel)| user + kernel)
+}
+
+/// A thread of the process, opened to be suspended and sampled.
+#[cfg(not(feature = "disabled"))]
+pub(crate) struct SampledThread {
+    id: u32,
+    handle: HANDLE,
+    name: [u8; MAX_THREAD_NAME],
+    name_length: usize,
+    /// What the thread consumed at its previous tick: its cycles, or its
+    /// user time in nanoseconds with `ProfilingMode::UserCpu`.
+    consumed: Option<u64>,
+}
+
+// the handle isn't tied to the thread which opened it
+#[cfg(not(feature = "disabled"))]
+unsafe impl Send for SampledThread {}
+
+#[cfg(not(feature = "disabled"))]
+impl SampledThread {
+    fn open(id: u32) -> Option<Self> {
+        let access = THREAD_SUSPEND_RESUME | THREAD_GET_CONTEXT | THREAD_QUERY_LIMITED_INFORMATION;
+        let handle = unsafe { OpenThread(access, 0, id) };
+        // e.g. the thread exited after being listed
+        if handle == 0 {
+            return None;
+        }
+
+        let mut thread = SampledThread {
+            id,
+            handle,
+            name: [0; MAX_THREAD_NAME],
+            name_length: 0,
+            consumed: None,
+        };
+        thread.update_name();
+        Some(thread)
+    }
+
+    fn update_name(&mut self) {
+        self.name = [0; MAX_THREAD_NAME];
+        self.name_length = write_thread_name(self.handle, self.id as u64, &mut self.name);
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id as u64
+    }
+
+    pub(crate) fn name(&self) -> &[u8] {
+        &self.name[..self.name_length]
+    }
+
+    pub(crate) fn cpu_time(&self) -> Option<Duration> {
+        thread_cpu_time(self.handle)
+    }
+
+    /// Whether the thread consumed the time counted by `mode` since its
+    /// previous tick. The cycles are counted precisely, unlike the CPU time,
+    /// so that the threads which ran for less than a scheduler tick are
+    /// sampled too. A thread isn't sampled on its first tick.
+    fn ran(&mut self, mode: ProfilingMode) -> bool {
+        let consumed = if mode == ProfilingMode::UserCpu {
+            thread_times(self.handle).map(|(user, _)| user.as_nanos() as u64)
+        } else {
+            let mut cycles = 0;
+            (unsafe { QueryThreadCycleTime(self.handle, &mut cycles) } != 0).then_some(cycles)
+        };
+        let previous = std::mem::replace(&mut self.consumed, consumed);
+        matches!((previous, consumed), (Some(previous), Some(consumed)) if consumed > previous)
+    }
+
+    /// Suspend the thread, unwind its stack into `bt` and resume it. Returns
+    /// whether the stack was truncated, or `None` if the thread couldn't be
+    /// sampled. The suspended thread may hold any lock, e.g. the one of the
+    /// heap, so nothing in between must allocate nor take a lock.
+    pub(crate) fn unwind(
+        &self,
+        leaf_only: bool,
+        bt: &mut SmallVec<[<TraceImpl as Trace>::Frame; MAX_DEPTH]>,
+    ) -> Option<bool> {
+        if unsafe { SuspendThread(self.handle) } == u32::MAX {
+            return None;
+        }
+
+        let mut context: AlignedContext = unsafe { std::mem::zeroed() };
+        context.0.ContextFlags = CONTEXT_FULL;
+        // it also waits for the thread to be actually suspended
+        let read = unsafe { GetThreadContext(self.handle, &mut context.0) } != 0;
+        let mut truncated = false;
+        if read {
+            TraceImpl::trace(&mut context.0 as *mut _ as *mut libc::c_void, |frame| {
+                if bt.len() < bt.inline_size() {
+                    bt.push(frame.clone());
+                    !leaf_only
+                } else {
+                    truncated = true;
+                    false
+                }
+            });
+        }
+
+        unsafe { ResumeThread(self.handle) };
+        read.then_some(truncated)
+    }
+}
+
+#[cfg(not(feature = "disabled"))]
+impl Drop for SampledThread {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.handle) };
+    }
+}
+
+/// The sampler thread, which replaces the interval timer and the signal
+/// handler of the other platforms.
+#[cfg(not(feature = "disabled"))]
+pub struct Timer {
+    pub frequency: c_int,
+    pub start_time: SystemTime,
+    pub start_instant: Instant,
+    stop: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    sampler: Option<JoinHandle<()>>,
+}
+
+#[cfg(not(feature = "disabled"))]
+impl Timer {
+    const RESCAN_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// Spawn the sampler thread, which samples the threads which ran since
+    /// its previous tick `frequency` times per second. The wall time modes
+    /// aren't available, and the jitter is ignored.
+    pub fn new(frequency: c_int, mode: ProfilingMode, jitter: f64) -> Result<Timer> {
+        if jitter > 0.0 {
+            log::warn!("the jitter is ignored on Windows");
+        }
+        let period = Duration::from_secs(1) / frequency.max(1) as u32;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let paused = Arc::new(AtomicBool::new(false));
+        let sampler = {
+            let stop = stop.clone();
+            let paused = paused.clone();
+            std::thread::Builder::new()
+                .name("pprof-sampler".to_owned())
+                .spawn(move || {
+                    let sampler_id = unsafe { GetCurrentThreadId() };
+                    let mut threads = Vec::new();
+                    let mut scanned: Option<Instant> = None;
+                    let mut next = Instant::now() + period;
+                    while !stop.load(Ordering::Acquire) {
+                        let now = Instant::now();
+                        if now < next {
+                            std::thread::park_timeout(next - now);
+                            continue;
+                        }
+                        // the ticks missed e.g. while the machine slept are
+                        // skipped rather than caught up
+                        next = (next + period).max(now);
+                        if paused.load(Ordering::Acquire) {
+                            continue;
+                        }
+
+                        if scanned
+                            .map_or(true, |scanned| scanned.elapsed() >= Self::RESCAN_INTERVAL)
+                        {
+                            if let Err(err) = Self::rescan(&mut threads, sampler_id) {
+                                log::warn!("fail to list the threads to sample: {}", err);
+                            }
+                            scanned = Some(Instant::now());
+                        }
+                        for thread in threads.iter_mut() {
+                            if thread.ran(mode) {
+                                crate::profiler::sample_thread(thread);
+                            }
+                        }
+                    }
+                })?
+        };
+
+        Ok(Timer {
+            frequency,
+            start_time: SystemTime::now(),
+            start_instant: Instant::now(),
+            stop,
+            paused,
+            sampler: Some(sampler),
+        })
+    }
+
+    /// Open the threads of the process which aren't sampled yet, except
+    /// `skip_id`, and close the ones which exited. The ids of the exited
+    /// threads can't be reused while they are open.
+    fn rescan(threads: &mut Vec<SampledThread>, skip_id: u32) -> std::io::Result<()> {
+        let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0) };
+        if snapshot == INVALID_HANDLE_VALUE {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let process_id = unsafe { GetCurrentProcessId() };
+        let mut ids = HashSet::new();
+        let mut entry: THREADENTRY32 = unsafe { std::mem::zeroed() };
+        entry.dwSize = std::mem::size_of::<THREADENTRY32>() as u32;
+        let mut listed = unsafe { Thread32First(snapshot, &mut entry) } != 0;
+        while listed {
+            // the snapshot lists the threads of all processes
+            if entry.th32OwnerProcessID == process_id && entry.th32ThreadID != skip_id {
+                ids.insert(entry.th32ThreadID);
+            }
+            listed = unsafe { Thread32Next(snapshot, &mut entry) } != 0;
+        }
+        unsafe { CloseHandle(snapshot) };
+
+        threads.retain(|thread| ids.remove(&thread.id));
+        // the names may have been set after the threads were opened
+        for thread in threads.iter_mut() {
+            thread.update_name();
+        }
+        threads.extend(ids.into_iter().filter_map(SampledThread::open));
+
+        Ok(())
+    }
+
+    /// Stop sampling until `resume`.
+    pub(crate) fn pause(&self) {
+        self.paused.store(true, Ordering::Release);
+    }
+
+    /// Sample again after `pause`.
+    pub(crate) fn resume(&self) {
+        self.paused.store(false, Ordering::Release);
+    }
+
+    /// Returns a `ReportTiming` struct having this timer's frequency and start
+    /// time; and the time elapsed since its creation as duration.
+    pub fn timing(&self) -> ReportTiming {
+        ReportTiming {
+            frequency: self.frequency,
+            start_time: self.start_time,
+            duration: self.start_instant.elapsed(),
+            active_duration: None,
+        }
+    }
+}
+
+#[cfg(not(feature = "disabled"))]
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(sampler) = self.sampler.take() {
+            sampler.thread().unpark();
+            if sampler.join().is_err() {
+                log::error!("sampler thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(feature = "disabled"))]
+mod tests {
+    use super::*;
+    use crate::ProfilerGuardBuilder;
+
+    #[inline(never)]
+    fn spin(duration: Duration) -> u64 {
+        let start = Instant::now();
+        let mut n = 0u64;
+        while start.elapsed() < duration {
+            n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+        }
+        n
+    }
+
+    #[test]
+    fn thread_name() {
+        std::thread::Builder::new()
+            .name("a-rather-long-thread-name".to_owned())
+            .spawn(|| {
+                let mut name = [0; MAX_THREAD_NAME];
+                let length = write_current_thread_name(&mut name);
+                assert_eq!(
+                    &name[..length],
+                    &b"a-rather-long-thread-name"[..MAX_THREAD_NAME]
+                );
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn sample_busy_thread() {
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .build()
+            .unwrap();
+
+        std::thread::Builder::new()
+            .name("pprof-busy".to_owned())
+            .spawn(|| spin(Duration::from_millis(500)))
+            .unwrap()
+            .join()
+            .unwrap();
+
+        let report = guard.report().build().unwrap();
+        assert!(report.data.keys().any(|frames| {
+            frames.thread_name == "pprof-busy"
+                && frames
+                    .frames
+                    .iter()
+                    .flatten()
+                    .any(|symbol| symbol.name().contains("spin"))
+        }));
+    }
+}
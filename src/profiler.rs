@@ -1,12 +1,21 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
+#[cfg(unix)]
 use std::convert::TryInto;
 use std::os::raw::c_int;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+#[cfg(not(feature = "disabled"))]
+use std::sync::Weak;
+use std::time::{Duration, Instant, SystemTime};
 
+#[cfg(unix)]
 use nix::sys::signal;
+#[cfg(not(feature = "disabled"))]
 use once_cell::sync::Lazy;
-use parking_lot::RwLock;
+#[cfg(not(feature = "disabled"))]
+use parking_lot::Condvar;
+use parking_lot::{Mutex, RwLock};
 use smallvec::SmallVec;
 
 #[cfg(any(
@@ -17,22 +26,118 @@ use smallvec::SmallVec;
 ))]
 use findshlibs::{Segment, SharedLibrary, TargetSharedLibrary};
 
-use crate::backtrace::{Trace, TraceImpl};
-use crate::collector::Collector;
+use crate::backtrace::{Frame, Trace, TraceImpl};
+use crate::collector::{CollectMode, Collector};
+#[cfg(all(unix, not(feature = "disabled")))]
+use crate::cpu_time::current_thread_cpu_time;
+use crate::cpu_time::ThreadCpuTimeTable;
 use crate::error::{Error, Result};
 use crate::frames::UnresolvedFrames;
-use crate::report::ReportBuilder;
-use crate::timer::Timer;
+#[cfg(all(
+    feature = "kernel-stack",
+    target_os = "linux",
+    not(feature = "disabled")
+))]
+use crate::kernel_stack::KernelStackSampler;
+#[cfg(all(feature = "kernel-stack", target_os = "linux"))]
+use crate::kernel_stack::KernelStacks;
+use crate::libraries::{LibraryCallback, LibraryChanges, LibraryWatcher};
+#[cfg(all(
+    feature = "frame-pointer",
+    target_os = "linux",
+    not(feature = "disabled")
+))]
+use crate::memory_map::MemoryMapReader;
+#[cfg(all(feature = "perf-event", target_os = "linux"))]
+use crate::perf_event::PerfEventKind;
+#[cfg(all(feature = "perf-event", target_os = "linux", not(feature = "disabled")))]
+use crate::perf_event::PerfEventSampler;
+#[cfg(not(feature = "disabled"))]
+use crate::rate_limiter::monotonic_nanos;
+use crate::rate_limiter::{OverheadGovernor, RateLimiter};
+#[cfg(all(unix, not(feature = "disabled")))]
+use crate::registers::ucontext_registers;
+use crate::registers::RegistersHook;
+use crate::report::{Report, ReportBuilder, Window};
+use crate::shared::{SharedCollector, SharedRegion};
+#[cfg(all(unix, not(feature = "disabled")))]
+use crate::signal_stack::signal_stack_is_usable;
+#[cfg(all(unix, not(feature = "disabled")))]
+use crate::tag;
+#[cfg(all(target_os = "linux", not(feature = "disabled")))]
+use crate::thread_state::{current_tid, ThreadStateReader};
+#[cfg(target_os = "linux")]
+use crate::thread_state::{ObservedStates, ThreadStatesTable};
+#[cfg(all(unix, not(feature = "disabled")))]
+use crate::timer::rearm_jittered_timer;
+#[cfg(not(feature = "disabled"))]
+use crate::timer::{frequency_is_supported, timer_resolution, Timer};
+use crate::timer::{ProfilingMode, ReportTiming};
 use crate::{MAX_DEPTH, MAX_THREAD_NAME};
 
+/// The default of `ProfilerGuardBuilder::max_trace_samples`.
+const DEFAULT_MAX_TRACE_SAMPLES: usize = 1 << 16;
+
+#[cfg(not(feature = "disabled"))]
 pub(crate) static PROFILER: Lazy<RwLock<Result<Profiler>>> =
     Lazy::new(|| RwLock::new(Profiler::new()));
 
+/// With the `disabled` feature the profiler is never created, so that
+/// nothing is allocated, nor is the temporary file of the collector created.
+#[cfg(feature = "disabled")]
+pub(crate) static PROFILER: RwLock<Result<Profiler>> = RwLock::new(Err(Error::CreatingError));
+
+/// The state shared by the signal handler and the threads which control the
+/// profiler.
+///
+/// The handler only accesses it through `RwLock::try_write`, and drops the
+/// sample if the lock is held, so it never blocks nor observes a profiler
+/// being modified. The counters which the handler updates are atomics on top
+/// of that, so that race detectors like ThreadSanitizer, which don't model
+/// the signal delivery, don't report them as data races. They only need
+/// `Relaxed` ordering: the lock orders them with the rest of the profiler.
 pub struct Profiler {
     pub(crate) data: Collector<UnresolvedFrames>,
-    sample_counter: i32,
 
-    running: bool,
+    pub(crate) running: bool,
+
+    // set by `ProfilerGuard::pause`, the samples are dropped meanwhile
+    pub(crate) paused: bool,
+
+    mode: ProfilingMode,
+    off_cpu_threshold: Duration,
+    // the signal delivering the samples, whose handler is registered
+    #[cfg(unix)]
+    signal: signal::Signal,
+
+    // set by the signal handler when the collector rejects a sample, the
+    // warning is logged later outside of the handler
+    collector_full: AtomicBool,
+    collector_full_warned: AtomicBool,
+
+    pub(crate) thread_cpu_times: ThreadCpuTimeTable,
+
+    #[cfg(target_os = "linux")]
+    pub(crate) observed_states: Option<Arc<ObservedStates>>,
+    #[cfg(target_os = "linux")]
+    pub(crate) thread_states: ThreadStatesTable,
+
+    rate_limiter: Option<RateLimiter>,
+    overhead_governor: Option<OverheadGovernor>,
+    pub(crate) rate_limited_samples: AtomicU64,
+
+    min_stack_depth: usize,
+    pub(crate) shallow_samples: AtomicU64,
+
+    symbol_offsets: bool,
+    unresolved_addresses: bool,
+    signal_stack: bool,
+    thread_name_resolver: Option<ThreadNameResolver>,
+    registers_hook: Option<RegistersHook>,
+    shared: Option<SharedCollector>,
+
+    #[cfg(all(feature = "kernel-stack", target_os = "linux"))]
+    pub(crate) kernel_stacks: KernelStacks,
 
     #[cfg(any(
         target_arch = "x86_64",
@@ -43,9 +148,43 @@ pub struct Profiler {
     blocklist_segments: Vec<(usize, usize)>,
 }
 
-#[derive(Clone)]
+/// A builder of `ProfilerGuard`. Its `Debug` output shows the resolved
+/// configuration, including the address ranges excluded by the blocklist.
+#[derive(Clone, Debug)]
 pub struct ProfilerGuardBuilder {
     frequency: c_int,
+    mode: ProfilingMode,
+    max_sample_rate: Option<u64>,
+    max_overhead: Option<f64>,
+    max_runtime: Option<Duration>,
+    duty_cycle: Option<(Duration, Duration)>,
+    jitter: f64,
+    min_stack_depth: usize,
+    symbol_offsets: bool,
+    unresolved_addresses: bool,
+    collect_mode: CollectMode,
+    max_trace_samples: usize,
+    signal_stack: bool,
+    thread_name_resolver: Option<ThreadNameResolver>,
+    registers_hook: Option<RegistersHook>,
+    shared_region: Option<SharedRegion>,
+    library_callback: Option<LibraryCallback>,
+    #[cfg(target_os = "linux")]
+    per_thread_timers: bool,
+    #[cfg(target_os = "linux")]
+    signal: Option<signal::Signal>,
+    #[cfg(target_os = "linux")]
+    activation_threshold: Option<f64>,
+    #[cfg(target_os = "linux")]
+    thread_states: bool,
+    #[cfg(all(feature = "perf-event", target_os = "linux"))]
+    perf_event: Option<PerfEventKind>,
+    #[cfg(all(feature = "perf-event", target_os = "linux"))]
+    perf_event_kernel_callchains: bool,
+    #[cfg(all(feature = "kernel-stack", target_os = "linux"))]
+    kernel_stacks: bool,
+    #[cfg(all(feature = "kernel-stack", target_os = "linux"))]
+    merge_kernel_stacks: bool,
     #[cfg(any(
         target_arch = "x86_64",
         target_arch = "aarch64",
@@ -59,6 +198,38 @@ impl Default for ProfilerGuardBuilder {
     fn default() -> ProfilerGuardBuilder {
         ProfilerGuardBuilder {
             frequency: 99,
+            mode: ProfilingMode::default(),
+            max_sample_rate: None,
+            max_overhead: None,
+            max_runtime: None,
+            duty_cycle: None,
+            jitter: 0.0,
+            min_stack_depth: 0,
+            symbol_offsets: false,
+            unresolved_addresses: false,
+            collect_mode: CollectMode::default(),
+            max_trace_samples: DEFAULT_MAX_TRACE_SAMPLES,
+            signal_stack: false,
+            thread_name_resolver: None,
+            registers_hook: None,
+            shared_region: None,
+            library_callback: None,
+            #[cfg(target_os = "linux")]
+            per_thread_timers: true,
+            #[cfg(target_os = "linux")]
+            signal: None,
+            #[cfg(target_os = "linux")]
+            activation_threshold: None,
+            #[cfg(target_os = "linux")]
+            thread_states: false,
+            #[cfg(all(feature = "perf-event", target_os = "linux"))]
+            perf_event: None,
+            #[cfg(all(feature = "perf-event", target_os = "linux"))]
+            perf_event_kernel_callchains: false,
+            #[cfg(all(feature = "kernel-stack", target_os = "linux"))]
+            kernel_stacks: false,
+            #[cfg(all(feature = "kernel-stack", target_os = "linux"))]
+            merge_kernel_stacks: false,
 
             #[cfg(any(
                 target_arch = "x86_64",
@@ -76,6 +247,361 @@ impl ProfilerGuardBuilder {
         Self { frequency, ..self }
     }
 
+    /// Choose what kind of time is sampled. By default it's the user and
+    /// system CPU time, `ProfilingMode::UserCpu` excludes the time spent in the
+    /// kernel.
+    pub fn mode(self, mode: ProfilingMode) -> Self {
+        #[cfg(not(target_os = "linux"))]
+        let mode = if mode.is_wall_time() {
+            log::warn!(
+                "the {:?} mode is only available on Linux, the cpu time is sampled",
+                mode
+            );
+            ProfilingMode::Cpu
+        } else {
+            mode
+        };
+        Self { mode, ..self }
+    }
+
+    /// Sample the wall time of every thread instead of the CPU time, so that
+    /// the idle, sleeping and blocked threads show up in the report, a
+    /// shorthand for `mode(ProfilingMode::WallClock)`. Each thread is sampled
+    /// at `frequency` by a timer of its own, so the samples per second grow
+    /// with the number of threads.
+    /// **only available on Linux**, the CPU time is sampled elsewhere.
+    pub fn wall_clock(self) -> Self {
+        self.mode(ProfilingMode::WallClock)
+    }
+
+    /// Sample where the threads are blocked instead of where they consume
+    /// CPU time, a shorthand for `mode(ProfilingMode::OffCpu)`. Every sample
+    /// stands for `1 / frequency` of wall time during which its thread was
+    /// off-CPU, e.g. waiting for a lock, a syscall or its turn to run.
+    /// **only available on Linux**, the CPU time is sampled elsewhere.
+    pub fn off_cpu(self) -> Self {
+        self.mode(ProfilingMode::OffCpu)
+    }
+
+    /// Cap the number of samples taken per second across all threads. The
+    /// interval timer counts the CPU time of the whole process, so with many
+    /// busy threads the effective rate can be much higher than `frequency`.
+    /// Samples exceeding the cap are dropped and counted in
+    /// `Report::rate_limited_samples`.
+    pub fn max_sample_rate(self, max_sample_rate: u64) -> Self {
+        Self {
+            max_sample_rate: Some(max_sample_rate),
+            ..self
+        }
+    }
+
+    /// Keep the time spent in the signal handler under `max_overhead` of one
+    /// CPU, e.g. 0.01 for 1%. The cost of every sample is measured, and the
+    /// samples beyond the rate which fits in the budget are dropped and
+    /// counted in `Report::rate_limited_samples`, so the effective frequency
+    /// adapts to the depth of the stacks and the speed of the machine. The
+    /// rate achieved is `Report::achieved_sample_rate`.
+    pub fn max_overhead(self, max_overhead: f64) -> Self {
+        Self {
+            max_overhead: Some(max_overhead),
+            ..self
+        }
+    }
+
+    /// Drop the samples whose stack has fewer than `min_stack_depth` frames,
+    /// e.g. the samples caught in thin runtime glue, which only have one or
+    /// two frames. They are counted in `Report::shallow_samples`. With
+    /// `ProfilingMode::LeafOnly`, every stack has a single frame.
+    pub fn min_stack_depth(self, min_stack_depth: usize) -> Self {
+        Self {
+            min_stack_depth,
+            ..self
+        }
+    }
+
+    /// Stop the profiler after `max_runtime`, even if its guard is still
+    /// alive, e.g. because it was leaked, so that the signal handler and the
+    /// timer don't stay installed forever. A watchdog thread waits for it,
+    /// and exits when the guard is dropped before. A warning is logged when
+    /// the profiler is stopped, and the reports of the guard are empty from
+    /// then on.
+    pub fn max_runtime(self, max_runtime: Duration) -> Self {
+        Self {
+            max_runtime: Some(max_runtime),
+            ..self
+        }
+    }
+
+    /// Sample for `on`, then pause for `off`, and so on, so that a guard can
+    /// stay installed in a long running service with a bounded overhead. A
+    /// background thread pauses and resumes the profiler like
+    /// `ProfilerGuard::pause` and `ProfilerGuard::resume`, the time during
+    /// which it's paused is excluded from `Report::active_duration`. A pause
+    /// of the guard lasts until it's resumed, whatever the schedule.
+    pub fn duty_cycle(self, on: Duration, off: Duration) -> Self {
+        Self {
+            duty_cycle: Some((on, off)),
+            ..self
+        }
+    }
+
+    /// Randomize every interval of the timer by up to `fraction` of the
+    /// period, e.g. 0.2 for intervals between 80% and 120% of it, so that
+    /// the samples don't alias with a periodic workload, such as an event
+    /// loop ticking at the sampling frequency. The intervals average to the
+    /// period, so the frequency of the report still holds. The fraction is
+    /// clamped to `[0, 1]`. It only applies to the process wide timer, which
+    /// it selects instead of the per thread timers on Linux, and is ignored
+    /// with the wall time modes and `perf_event`.
+    pub fn jitter(self, fraction: f64) -> Self {
+        Self {
+            jitter: if fraction > 0.0 {
+                fraction.min(1.0)
+            } else {
+                0.0
+            },
+            ..self
+        }
+    }
+
+    /// Key sampled frames on the offset of the instruction pointer within its
+    /// symbol, instead of on the symbol alone. Different call sites and basic
+    /// blocks of a hot function then show up as separate entries (rendered as
+    /// `name+0x1a`), at the cost of more unique frames and a larger report.
+    pub fn symbol_offsets(self, symbol_offsets: bool) -> Self {
+        Self {
+            symbol_offsets,
+            ..self
+        }
+    }
+
+    /// Name the frames which can't be symbolicated (e.g. in a stripped
+    /// binary) after their module and their offset in it, such as
+    /// `libfoo.so+0x1234`, instead of leaving them unnamed. The offsets can
+    /// be resolved offline with `addr2line` against the unstripped binary.
+    pub fn report_unresolved_addresses(self, unresolved_addresses: bool) -> Self {
+        Self {
+            unresolved_addresses,
+            ..self
+        }
+    }
+
+    /// Select how the samples are collected. `CollectMode::Trace` keeps
+    /// every sample with its own time instead of counting the samples of
+    /// every stack, so that the flamecharts, the timelines and
+    /// `Report::samples` are exact, at the cost of a larger temporary file
+    /// and a report with a stack per sample.
+    pub fn collect_mode(self, collect_mode: CollectMode) -> Self {
+        Self {
+            collect_mode,
+            ..self
+        }
+    }
+
+    /// The maximum number of samples kept by `CollectMode::Trace`, 65536 by
+    /// default. The later samples are dropped, and the report is marked as
+    /// incomplete, as when the collector is full. Every sample takes about
+    /// 1KiB of the temporary file.
+    pub fn max_trace_samples(self, max_trace_samples: usize) -> Self {
+        Self {
+            max_trace_samples,
+            ..self
+        }
+    }
+
+    /// Run the signal handler on the alternate signal stack of the sampled
+    /// thread (`SA_ONSTACK`), so that a thread close to its stack limit
+    /// doesn't overflow it while being sampled. The threads have to install
+    /// a large enough stack with `install_signal_stack`, the samples of the
+    /// threads whose alternate stack is too small are skipped.
+    pub fn signal_stack(self, signal_stack: bool) -> Self {
+        Self {
+            signal_stack,
+            ..self
+        }
+    }
+
+    /// Name the sampled threads with `resolver`, e.g. after a registry of
+    /// the application with richer names than the OS thread names. It's
+    /// called in the signal handler with the `pthread_t` of the sampled
+    /// thread, so it must be async-signal-safe: it must not allocate nor
+    /// take locks. The name is padded with zeros, and the thread falls back
+    /// to its OS name if it returns `None`.
+    pub fn thread_name_resolver(self, resolver: ThreadNameResolver) -> Self {
+        Self {
+            thread_name_resolver: Some(resolver),
+            ..self
+        }
+    }
+
+    /// Pass the registers of every sample to `hook`, with the `pthread_t` of
+    /// the sampled thread, e.g. to unwind code without frame pointers nor
+    /// unwind tables with a custom unwinder. It's called in the signal
+    /// handler, after the blocklist and `max_sample_rate`, so it must be
+    /// async-signal-safe: it may copy the top of the stack from `sp` into a
+    /// preallocated buffer, but must not allocate nor take locks. The
+    /// unwound stacks can be fed back with `ingest_stack`. The hook isn't
+    /// called on the platforms whose registers aren't supported.
+    pub fn registers_hook(self, hook: RegistersHook) -> Self {
+        Self {
+            registers_hook: Some(hook),
+            ..self
+        }
+    }
+
+    /// Also aggregate the samples into `region`, e.g. a `memfd` mapped by the
+    /// caller, whose fixed layout can be read by another process with
+    /// `RawReport::from_shared_region`, even after this process crashed. The
+    /// region is cleared when the profiler starts, and keeps the samples
+    /// after it stops. The samples of the stacks which don't fit in the
+    /// region are left out of it, with a warning when the profiler stops.
+    pub fn shared_region(self, region: SharedRegion) -> Self {
+        Self {
+            shared_region: Some(region),
+            ..self
+        }
+    }
+
+    /// Call `callback` when the set of loaded libraries changed, e.g. after a
+    /// `dlopen`, so that caches keyed on the libraries can be updated. The
+    /// libraries are compared with the previous check every time a report is
+    /// built and on `ProfilerGuard::check_libraries`, never in the signal
+    /// handler.
+    pub fn on_library_change<F>(self, callback: F) -> Self
+    where
+        F: Fn(&LibraryChanges) + Send + Sync + 'static,
+    {
+        Self {
+            library_callback: Some(LibraryCallback(Arc::new(callback))),
+            ..self
+        }
+    }
+
+    /// Arm a timer per thread with `timer_create(2)`, which is the default,
+    /// or the single process wide `setitimer` with `false`. Each timer counts
+    /// the CPU time of its own thread and delivers the signal to it, so the
+    /// threads are sampled in proportion to their own CPU time rather than to
+    /// whichever thread the kernel picks. The process wide timer keeps running
+    /// to arm the threads spawned later as soon as they run, and its signals
+    /// are ignored on the threads which have their own timer. With `signal`,
+    /// the new threads are only armed within 100ms, so the short-lived ones
+    /// may be missed. The process wide timer is used instead with `jitter` or
+    /// `activate_above_cpu_percent`, or if the timers can't be created, e.g.
+    /// if `timer_create` is denied by a seccomp filter.
+    /// **only available on Linux**
+    #[cfg(target_os = "linux")]
+    pub fn per_thread_timers(self, per_thread_timers: bool) -> Self {
+        Self {
+            per_thread_timers,
+            ..self
+        }
+    }
+
+    /// Deliver the samples with `signal` instead of `SIGPROF`, or `SIGVTALRM`
+    /// with `ProfilingMode::UserCpu`, for applications which already handle
+    /// them, or run another profiler. The signal handler of pprof replaces
+    /// the one of `signal` while profiling, so it must not be used for
+    /// anything else. The signal can only be chosen for the per thread
+    /// timers, which are always used with it.
+    /// **only available on Linux**
+    #[cfg(target_os = "linux")]
+    pub fn signal(self, signal: signal::Signal) -> Self {
+        Self {
+            signal: Some(signal),
+            ..self
+        }
+    }
+
+    /// Only sample while the process is busy: a background thread measures
+    /// the CPU usage of the process from `/proc/self/stat` every 200ms, and
+    /// arms the timer while it's above `percent` of one CPU (e.g. 150 for one
+    /// and a half CPUs) and disarms it otherwise. The guard keeps running
+    /// meanwhile, and `Report::active_duration` is the time during which the
+    /// timer was armed. It only applies to the process wide timer, which it
+    /// selects instead of the per thread timers, and is ignored with the wall
+    /// time modes and `perf_event`.
+    /// **only available on Linux**
+    #[cfg(target_os = "linux")]
+    pub fn activate_above_cpu_percent(self, percent: f64) -> Self {
+        Self {
+            activation_threshold: Some(percent),
+            ..self
+        }
+    }
+
+    /// Also count the samples of every thread by its scheduling state, e.g.
+    /// running or in an uninterruptible sleep, available through
+    /// `Report::thread_states`. The states are read from `/proc` by a
+    /// background thread at the sampling frequency, which costs a few reads
+    /// per thread and period. **only available on Linux**
+    #[cfg(target_os = "linux")]
+    pub fn thread_states(self, thread_states: bool) -> Self {
+        Self {
+            thread_states,
+            ..self
+        }
+    }
+
+    /// Collect samples through `perf_event_open(2)` instead of `setitimer` and
+    /// `SIGPROF`. The kernel records the callchains, so the samples don't
+    /// suffer from the signal delivery skew, and `PerfEventKind::ContextSwitches`
+    /// can sample every time a thread is switched out. The `thread_states` and
+    /// the separate `kernel_stacks` are recorded alongside its samples.
+    /// **only available with `perf-event` feature on Linux**
+    #[cfg(all(feature = "perf-event", target_os = "linux"))]
+    pub fn perf_event(self, kind: PerfEventKind) -> Self {
+        Self {
+            perf_event: Some(kind),
+            ..self
+        }
+    }
+
+    /// Also record the kernel part of the callchains with `perf_event`, so
+    /// that the time spent in syscalls is attributed to the kernel functions,
+    /// whose names end with `_[k]`. They are named after `/proc/kallsyms`,
+    /// which hides the addresses unless `kernel.kptr_restrict` allows it.
+    /// Sampling the kernel requires `kernel.perf_event_paranoid` to be 1 or
+    /// lower, or `CAP_PERFMON`. **only available with `perf-event` feature on
+    /// Linux**
+    #[cfg(all(feature = "perf-event", target_os = "linux"))]
+    pub fn perf_event_kernel_callchains(self, perf_event_kernel_callchains: bool) -> Self {
+        Self {
+            perf_event_kernel_callchains,
+            ..self
+        }
+    }
+
+    /// Also sample the kernel stacks of all threads from `/proc/<tid>/stack`
+    /// at the same frequency, in a background thread. They show where the
+    /// threads are blocked in the kernel, which the CPU time based sampling
+    /// misses, and appear in `Report` as separate stacks whose frames end with
+    /// `_[k]`. Reading them requires `CAP_SYS_ADMIN`. **only available with
+    /// `kernel-stack` feature on Linux**
+    #[cfg(all(feature = "kernel-stack", target_os = "linux"))]
+    pub fn kernel_stacks(self, kernel_stacks: bool) -> Self {
+        Self {
+            kernel_stacks,
+            ..self
+        }
+    }
+
+    /// Record the kernel stacks of `kernel_stacks` below the user stacks
+    /// which led to the syscalls, instead of separate stacks. Every thread
+    /// blocked in the kernel is interrupted by the profiling signal after its
+    /// kernel stack is read, to unwind its user stack. Like with the wall
+    /// clock mode, most of the interrupted syscalls are restarted, but a few
+    /// (e.g. `epoll_wait`) fail with `EINTR`. It can't be used with the
+    /// `perf_event` backend, `build` returns `Error::InvalidArgument`, see
+    /// `perf_event_kernel_callchains` instead. **only available with
+    /// `kernel-stack` feature on Linux**
+    #[cfg(all(feature = "kernel-stack", target_os = "linux"))]
+    pub fn merge_kernel_stacks(self, merge_kernel_stacks: bool) -> Self {
+        Self {
+            merge_kernel_stacks,
+            ..self
+        }
+    }
+
     #[cfg(any(
         target_arch = "x86_64",
         target_arch = "aarch64",
@@ -83,49 +609,111 @@ impl ProfilerGuardBuilder {
         target_arch = "loongarch64"
     ))]
     pub fn blocklist<T: AsRef<str>>(self, blocklist: &[T]) -> Self {
-        let blocklist_segments = {
-            let mut segments = Vec::new();
-            TargetSharedLibrary::each(|shlib| {
-                let in_blocklist = match shlib.name().to_str() {
-                    Some(name) => {
-                        let mut in_blocklist = false;
-                        for blocked_name in blocklist.iter() {
-                            if name.contains(blocked_name.as_ref()) {
-                                in_blocklist = true;
-                            }
-                        }
-
-                        in_blocklist
-                    }
-
-                    None => false,
-                };
-                if in_blocklist {
-                    for seg in shlib.segments() {
-                        let avam = seg.actual_virtual_memory_address(shlib);
-                        let start = avam.0;
-                        let end = start + seg.len();
-                        segments.push((start, end));
-                    }
-                }
-            });
-            segments
-        };
-
         Self {
-            blocklist_segments,
+            blocklist_segments: blocklist_segments(blocklist),
             ..self
         }
     }
+
+    /// Return a guard which doesn't sample anything and whose reports are
+    /// empty, as the `disabled` feature compiles the profiler out.
+    #[cfg(feature = "disabled")]
+    pub fn build(self) -> Result<ProfilerGuard<'static>> {
+        Ok(ProfilerGuard::<'static> {
+            profiler: &PROFILER,
+            libraries: None,
+            window: None,
+        })
+    }
+
+    /// Start profiling. With the `disabled` feature, it returns a guard
+    /// which doesn't sample anything and whose reports are empty, without
+    /// registering the signal handler or arming the timer.
+    #[cfg(not(feature = "disabled"))]
     pub fn build(self) -> Result<ProfilerGuard<'static>> {
+        #[cfg(all(feature = "perf-event", feature = "kernel-stack", target_os = "linux"))]
+        if self.perf_event.is_some() && self.kernel_stacks && self.merge_kernel_stacks {
+            return Err(Error::InvalidArgument(
+                "merge_kernel_stacks needs the signal of the timer, use perf_event_kernel_callchains with perf_event",
+            ));
+        }
+
+        // held until the new sampler is published, so that two guards built
+        // at the same time don't both try to start the profiler
+        let mut shared = SAMPLER.lock();
+        // the sampler of a leaked guard may have been stopped by its watchdog
+        if let Some(sampler) = shared.upgrade().filter(|sampler| sampler.is_running()) {
+            return self.join(sampler);
+        }
+
         trigger_lazy();
 
+        #[cfg(all(
+            unix,
+            any(
+                target_arch = "x86_64",
+                target_arch = "aarch64",
+                target_arch = "riscv64",
+                target_arch = "loongarch64"
+            ),
+            feature = "frame-pointer"
+        ))]
+        warn_missing_frame_pointers();
+
+        if let Some(resolution) = timer_resolution() {
+            if !frequency_is_supported(self.frequency, resolution) {
+                log::warn!(
+                    "sampling frequency {} exceeds the timer resolution {:?}, the effective rate will be lower",
+                    self.frequency,
+                    resolution
+                );
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        let observed_states = if self.thread_states {
+            Some(Arc::new(ObservedStates::default()))
+        } else {
+            None
+        };
+
         match PROFILER.write().as_mut() {
             Err(err) => {
                 log::error!("Error in creating profiler: {}", err);
-                Err(Error::CreatingError)
+                return Err(Error::CreatingError);
             }
             Ok(profiler) => {
+                profiler.symbol_offsets = self.symbol_offsets;
+                profiler.unresolved_addresses = self.unresolved_addresses;
+                if self.collect_mode == CollectMode::Trace {
+                    profiler.data = Collector::trace(self.max_trace_samples)?;
+                }
+                profiler.signal_stack = self.signal_stack;
+                profiler.thread_name_resolver = self.thread_name_resolver;
+                profiler.registers_hook = self.registers_hook;
+                profiler.shared = self
+                    .shared_region
+                    .map(|region| SharedCollector::new(region, self.frequency));
+                profiler.mode = self.mode;
+                #[cfg(target_os = "linux")]
+                {
+                    profiler.signal = self.signal.unwrap_or_else(|| self.mode.signal());
+                }
+                #[cfg(all(unix, not(target_os = "linux")))]
+                {
+                    profiler.signal = self.mode.signal();
+                }
+                profiler.rate_limiter = self.max_sample_rate.map(RateLimiter::new);
+                profiler.overhead_governor = self.max_overhead.map(OverheadGovernor::new);
+                profiler.min_stack_depth = self.min_stack_depth;
+                // a thread which ran for half of the period was rather on-CPU
+                profiler.off_cpu_threshold =
+                    Duration::from_secs(1) / self.frequency.max(1) as u32 / 2;
+                #[cfg(target_os = "linux")]
+                {
+                    profiler.observed_states = observed_states.clone();
+                }
+
                 #[cfg(any(
                     target_arch = "x86_64",
                     target_arch = "aarch64",
@@ -136,24 +724,669 @@ impl ProfilerGuardBuilder {
                     profiler.blocklist_segments = self.blocklist_segments;
                 }
 
-                match profiler.start() {
-                    Ok(()) => Ok(ProfilerGuard::<'static> {
+                profiler.start()?;
+            }
+        }
+
+        let libraries = self
+            .library_callback
+            .map(|callback| Mutex::new(LibraryWatcher::new(callback)));
+
+        #[cfg(all(feature = "frame-pointer", target_os = "linux"))]
+        let memory_map = match MemoryMapReader::new() {
+            Ok(reader) => Some(reader),
+            Err(err) => {
+                if let Ok(profiler) = PROFILER.write().as_mut() {
+                    let _ = profiler.stop();
+                }
+                return Err(err);
+            }
+        };
+
+        #[cfg(all(feature = "kernel-stack", target_os = "linux"))]
+        let kernel_stack = if self.kernel_stacks {
+            let merge_signal = if self.merge_kernel_stacks {
+                Some(self.signal.unwrap_or_else(|| self.mode.signal()))
+            } else {
+                None
+            };
+            match KernelStackSampler::new(self.frequency, merge_signal) {
+                Ok(sampler) => Some(sampler),
+                Err(err) => {
+                    if let Ok(profiler) = PROFILER.write().as_mut() {
+                        let _ = profiler.stop();
+                    }
+                    return Err(err);
+                }
+            }
+        } else {
+            None
+        };
+
+        #[cfg(target_os = "linux")]
+        let thread_state = match observed_states {
+            Some(states) => match ThreadStateReader::new(self.frequency, states) {
+                Ok(reader) => Some(reader),
+                Err(err) => {
+                    if let Ok(profiler) = PROFILER.write().as_mut() {
+                        let _ = profiler.stop();
+                    }
+                    return Err(err);
+                }
+            },
+            None => None,
+        };
+
+        #[cfg(all(feature = "perf-event", target_os = "linux"))]
+        if let Some(kind) = self.perf_event {
+            if self.activation_threshold.is_some() {
+                log::warn!("the activation threshold is ignored with perf events");
+            }
+            if self.jitter > 0.0 {
+                log::warn!("the jitter is ignored with perf events");
+            }
+            // the sampler takes the profiler lock in its own thread
+            return match PerfEventSampler::new(
+                kind,
+                self.frequency,
+                self.perf_event_kernel_callchains,
+            ) {
+                Ok(perf_event) => {
+                    let sampler = Sampler::new(
+                        Backends {
+                            timer: None,
+                            perf_event: Some(perf_event),
+                            #[cfg(feature = "kernel-stack")]
+                            kernel_stack,
+                            thread_state,
+                            memory_map,
+                            paused: PausedTime::default(),
+                            paused_by_guard: false,
+                            off_duty: false,
+                        },
+                        self.max_runtime,
+                        self.duty_cycle,
+                    );
+                    *shared = Arc::downgrade(&sampler);
+
+                    Ok(ProfilerGuard::<'static> {
                         profiler: &PROFILER,
-                        timer: Some(Timer::new(self.frequency)),
-                    }),
-                    Err(err) => Err(err),
+                        sampler: Some(sampler),
+                        libraries,
+                        window: None,
+                    })
+                }
+                Err(err) => {
+                    if let Ok(profiler) = PROFILER.write().as_mut() {
+                        let _ = profiler.stop();
+                    }
+                    Err(err)
+                }
+            };
+        }
+
+        // the activation threshold and the jitter need the process wide timer,
+        // which can't deliver another signal
+        #[cfg(target_os = "linux")]
+        let per_thread = self.mode.is_wall_time()
+            || self.signal.is_some()
+            || (self.per_thread_timers
+                && self.activation_threshold.is_none()
+                && self.jitter == 0.0);
+        #[cfg(target_os = "linux")]
+        let timer = if per_thread {
+            if self.activation_threshold.is_some() {
+                log::warn!("the activation threshold is ignored with per thread timers");
+            }
+            if self.jitter > 0.0 {
+                log::warn!("the jitter is ignored with per thread timers");
+            }
+            let signal = self.signal.unwrap_or_else(|| self.mode.signal());
+            match Timer::new_per_thread(self.frequency, self.mode, signal) {
+                Ok(timer) => timer,
+                // e.g. `timer_create` is denied by a seccomp filter
+                Err(err) if !self.mode.is_wall_time() && self.signal.is_none() => {
+                    log::warn!(
+                        "fail to create per thread timers, falling back to setitimer: {}",
+                        err
+                    );
+                    Timer::new(self.frequency, self.mode, self.jitter)
+                }
+                Err(err) => {
+                    if let Ok(profiler) = PROFILER.write().as_mut() {
+                        let _ = profiler.stop();
+                    }
+                    return Err(err);
+                }
+            }
+        } else if let Some(threshold) = self.activation_threshold {
+            match Timer::new_activated_above(self.frequency, self.mode, self.jitter, threshold) {
+                Ok(timer) => timer,
+                Err(err) => {
+                    if let Ok(profiler) = PROFILER.write().as_mut() {
+                        let _ = profiler.stop();
+                    }
+                    return Err(err);
                 }
             }
+        } else {
+            Timer::new(self.frequency, self.mode, self.jitter)
+        };
+        #[cfg(all(unix, not(target_os = "linux")))]
+        let timer = Timer::new(self.frequency, self.mode, self.jitter);
+        #[cfg(windows)]
+        let timer = match Timer::new(self.frequency, self.mode, self.jitter) {
+            Ok(timer) => timer,
+            Err(err) => {
+                if let Ok(profiler) = PROFILER.write().as_mut() {
+                    let _ = profiler.stop();
+                }
+                return Err(err);
+            }
+        };
+
+        let sampler = Sampler::new(
+            Backends {
+                timer: Some(timer),
+                #[cfg(all(feature = "perf-event", target_os = "linux"))]
+                perf_event: None,
+                #[cfg(all(feature = "kernel-stack", target_os = "linux"))]
+                kernel_stack,
+                #[cfg(target_os = "linux")]
+                thread_state,
+                #[cfg(all(feature = "frame-pointer", target_os = "linux"))]
+                memory_map,
+                paused: PausedTime::default(),
+                paused_by_guard: false,
+                off_duty: false,
+            },
+            self.max_runtime,
+            self.duty_cycle,
+        );
+        *shared = Arc::downgrade(&sampler);
+
+        Ok(ProfilerGuard::<'static> {
+            profiler: &PROFILER,
+            sampler: Some(sampler),
+            libraries,
+            window: None,
+        })
+    }
+
+    /// Build a guard which shares the sampler of the running profiler. Only
+    /// the samples collected from now on are in its reports.
+    #[cfg(not(feature = "disabled"))]
+    fn join(self, sampler: Arc<Sampler>) -> Result<ProfilerGuard<'static>> {
+        log::info!("joining the running cpu profiler, its configuration is kept");
+
+        let timing = sampler.timing();
+        let active_duration = timing.active_duration.unwrap_or(timing.duration);
+        let window = match PROFILER.read().as_ref() {
+            Err(err) => {
+                log::error!("Error in creating profiler: {}", err);
+                return Err(Error::CreatingError);
+            }
+            Ok(profiler) => Window::new(profiler, active_duration)?,
+        };
+
+        let libraries = self
+            .library_callback
+            .map(|callback| Mutex::new(LibraryWatcher::new(callback)));
+
+        Ok(ProfilerGuard::<'static> {
+            profiler: &PROFILER,
+            sampler: Some(sampler),
+            libraries,
+            window: Some(window),
+        })
+    }
+}
+
+/// Find the address ranges of the loaded libraries whose name contains any of
+/// the strings in `blocklist`.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64",
+    target_arch = "loongarch64"
+))]
+fn blocklist_segments<T: AsRef<str>>(blocklist: &[T]) -> Vec<(usize, usize)> {
+    let mut segments = Vec::new();
+    TargetSharedLibrary::each(|shlib| {
+        let in_blocklist = match shlib.name().to_str() {
+            Some(name) => {
+                let mut in_blocklist = false;
+                for blocked_name in blocklist.iter() {
+                    if name.contains(blocked_name.as_ref()) {
+                        in_blocklist = true;
+                    }
+                }
+
+                in_blocklist
+            }
+
+            None => false,
+        };
+        if in_blocklist {
+            for seg in shlib.segments() {
+                let avam = seg.actual_virtual_memory_address(shlib);
+                let start = avam.0;
+                let end = start + seg.len();
+                segments.push((start, end));
+            }
+        }
+    });
+    segments
+}
+
+/// The samplers which feed the global profiler. The profiler is stopped when
+/// they are dropped.
+#[cfg(not(feature = "disabled"))]
+struct Backends {
+    timer: Option<Timer>,
+    #[cfg(all(feature = "perf-event", target_os = "linux"))]
+    perf_event: Option<PerfEventSampler>,
+    #[cfg(all(feature = "kernel-stack", target_os = "linux"))]
+    kernel_stack: Option<KernelStackSampler>,
+    #[cfg(target_os = "linux")]
+    thread_state: Option<ThreadStateReader>,
+    #[cfg(all(feature = "frame-pointer", target_os = "linux"))]
+    memory_map: Option<MemoryMapReader>,
+    paused: PausedTime,
+    // whether the profiler is paused by its guards, or by the duty cycle
+    paused_by_guard: bool,
+    off_duty: bool,
+}
+
+/// The time during which the profiler was paused.
+#[cfg(not(feature = "disabled"))]
+#[derive(Default)]
+struct PausedTime {
+    elapsed: Duration,
+    since: Option<Instant>,
+}
+
+#[cfg(not(feature = "disabled"))]
+impl PausedTime {
+    fn elapsed(&self) -> Duration {
+        self.elapsed + self.since.map_or(Duration::ZERO, |since| since.elapsed())
+    }
+}
+
+#[cfg(not(feature = "disabled"))]
+impl Backends {
+    fn timing(&self) -> ReportTiming {
+        #[cfg(all(feature = "perf-event", target_os = "linux"))]
+        let mut timing = match self.perf_event.as_ref() {
+            Some(sampler) => sampler.timing(),
+            None => self.timer.as_ref().map(Timer::timing).unwrap_or_default(),
+        };
+        #[cfg(not(all(feature = "perf-event", target_os = "linux")))]
+        let mut timing = self.timer.as_ref().map(Timer::timing).unwrap_or_default();
+
+        // the time the timer was armed already excludes the pauses
+        let paused = self.paused.elapsed();
+        if timing.active_duration.is_none() && paused > Duration::ZERO {
+            timing.active_duration = Some(timing.duration.saturating_sub(paused));
+        }
+        timing
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        self.paused_by_guard = true;
+        self.update_paused()
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        self.paused_by_guard = false;
+        self.update_paused()
+    }
+
+    fn set_off_duty(&mut self, off_duty: bool) -> Result<()> {
+        self.off_duty = off_duty;
+        self.update_paused()
+    }
+
+    /// Pause the profiler while either its guards or the duty cycle pause it.
+    fn update_paused(&mut self) -> Result<()> {
+        let paused = self.paused_by_guard || self.off_duty;
+        match (paused, self.paused.since) {
+            (true, None) => {
+                set_paused(true)?;
+                if let Some(timer) = &self.timer {
+                    timer.pause();
+                }
+                self.paused.since = Some(Instant::now());
+            }
+            (false, Some(since)) => {
+                if let Some(timer) = &self.timer {
+                    timer.resume();
+                }
+                set_paused(false)?;
+                self.paused.elapsed += since.elapsed();
+                self.paused.since = None;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Make the profiler drop the samples of all the backends, or record them
+/// again.
+#[cfg(not(feature = "disabled"))]
+fn set_paused(paused: bool) -> Result<()> {
+    match PROFILER.write().as_mut() {
+        Err(err) => {
+            log::error!("Error in creating profiler: {}", err);
+            Err(Error::CreatingError)
+        }
+        Ok(profiler) => {
+            profiler.paused = paused;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(feature = "disabled"))]
+impl Drop for Backends {
+    fn drop(&mut self) {
+        // Stop the profiler before the timer, so that a signal which is
+        // already pending when the timer is disarmed finds the handler
+        // unregistered, instead of sampling into a collector being reset.
+        match PROFILER.write().as_mut() {
+            Err(_) => {}
+            Ok(profiler) => match profiler.stop() {
+                Ok(()) => {}
+                Err(err) => log::error!("error while stopping profiler {}", err),
+            },
+        }
+
+        drop(self.timer.take());
+        #[cfg(all(feature = "perf-event", target_os = "linux"))]
+        drop(self.perf_event.take());
+        #[cfg(all(feature = "kernel-stack", target_os = "linux"))]
+        drop(self.kernel_stack.take());
+        #[cfg(target_os = "linux")]
+        drop(self.thread_state.take());
+        #[cfg(all(feature = "frame-pointer", target_os = "linux"))]
+        drop(self.memory_map.take());
+    }
+}
+
+/// The backends of the running profiler. They are shared by all the
+/// `ProfilerGuard`s built while the profiler is running, and are dropped
+/// when the last of them is dropped, or by the watchdog after the max
+/// runtime.
+#[cfg(not(feature = "disabled"))]
+struct Sampler {
+    backends: Arc<Mutex<Option<Backends>>>,
+    // notifies the watchdog and the duty cycle threads that the sampler is
+    // dropped
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+}
+
+#[cfg(not(feature = "disabled"))]
+impl Sampler {
+    fn new(
+        backends: Backends,
+        max_runtime: Option<Duration>,
+        duty_cycle: Option<(Duration, Duration)>,
+    ) -> Arc<Sampler> {
+        let backends = Arc::new(Mutex::new(Some(backends)));
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        if let Some(max_runtime) = max_runtime {
+            let stopped = stopped.clone();
+            let backends = backends.clone();
+            let spawned = std::thread::Builder::new()
+                .name("pprof-watchdog".to_owned())
+                .spawn(move || watchdog(max_runtime, &stopped, &backends));
+            if let Err(err) = spawned {
+                log::warn!("fail to spawn the watchdog of the profiler: {}", err);
+            }
+        }
+        if let Some((on, off)) = duty_cycle {
+            let stopped = stopped.clone();
+            let backends = backends.clone();
+            let spawned = std::thread::Builder::new()
+                .name("pprof-duty-cycle".to_owned())
+                .spawn(move || run_duty_cycle(on, off, &stopped, &backends));
+            if let Err(err) = spawned {
+                log::warn!("fail to spawn the duty cycle of the profiler: {}", err);
+            }
+        }
+
+        Arc::new(Sampler { backends, stopped })
+    }
+
+    fn is_running(&self) -> bool {
+        self.backends.lock().is_some()
+    }
+
+    fn pause(&self) -> Result<()> {
+        match self.backends.lock().as_mut() {
+            Some(backends) => backends.pause(),
+            None => Ok(()),
+        }
+    }
+
+    fn resume(&self) -> Result<()> {
+        match self.backends.lock().as_mut() {
+            Some(backends) => backends.resume(),
+            None => Ok(()),
+        }
+    }
+
+    fn timing(&self) -> ReportTiming {
+        self.backends
+            .lock()
+            .as_ref()
+            .map(Backends::timing)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(not(feature = "disabled"))]
+impl Drop for Sampler {
+    fn drop(&mut self) {
+        // the watchdog isn't joined, as it may be waiting for `SAMPLER`,
+        // which is held meanwhile, it exits as soon as it's notified
+        let (stopped, condvar) = &*self.stopped;
+        *stopped.lock() = true;
+        condvar.notify_all();
+        drop(self.backends.lock().take());
+    }
+}
+
+/// Stop the profiler after `max_runtime`, unless its sampler is dropped
+/// before.
+#[cfg(not(feature = "disabled"))]
+fn watchdog(
+    max_runtime: Duration,
+    stopped: &(Mutex<bool>, Condvar),
+    backends: &Mutex<Option<Backends>>,
+) {
+    if wait_stopped(stopped, max_runtime) {
+        return;
+    }
+
+    // no guard can join the profiler while it's stopped
+    let _shared = SAMPLER.lock();
+    if *stopped.0.lock() {
+        return;
+    }
+    let backends = backends.lock().take();
+    if let Some(backends) = backends {
+        log::warn!(
+            "the profiler ran for its max runtime of {:?} and is stopped, its guard may have leaked",
+            max_runtime
+        );
+        drop(backends);
+    }
+}
+
+/// Pause the profiler for `off` after every `on`, until its sampler is
+/// dropped.
+#[cfg(not(feature = "disabled"))]
+fn run_duty_cycle(
+    on: Duration,
+    off: Duration,
+    stopped: &(Mutex<bool>, Condvar),
+    backends: &Mutex<Option<Backends>>,
+) {
+    let mut off_duty = false;
+    while !wait_stopped(stopped, if off_duty { off } else { on }) {
+        off_duty = !off_duty;
+        match backends.lock().as_mut() {
+            Some(backends) => {
+                if let Err(err) = backends.set_off_duty(off_duty) {
+                    log::warn!("fail to apply the duty cycle of the profiler: {}", err);
+                }
+            }
+            // stopped by the watchdog
+            None => return,
         }
     }
 }
 
+/// Wait for `timeout`, and return whether the sampler was dropped meanwhile.
+#[cfg(not(feature = "disabled"))]
+fn wait_stopped(stopped: &(Mutex<bool>, Condvar), timeout: Duration) -> bool {
+    let deadline = Instant::now().checked_add(timeout);
+    let (stopped, condvar) = stopped;
+    let mut guard = stopped.lock();
+    while !*guard {
+        match deadline {
+            Some(deadline) => {
+                if condvar.wait_until(&mut guard, deadline).timed_out() {
+                    break;
+                }
+            }
+            None => condvar.wait(&mut guard),
+        }
+    }
+    *guard
+}
+
+/// A resolver of the thread names, see
+/// `ProfilerGuardBuilder::thread_name_resolver`.
+pub type ThreadNameResolver = fn(u64) -> Option<[u8; MAX_THREAD_NAME]>;
+
+/// The sampler of the running profiler, which `ProfilerGuardBuilder::build`
+/// joins instead of failing with `Error::Running`.
+#[cfg(not(feature = "disabled"))]
+static SAMPLER: Lazy<Mutex<Weak<Sampler>>> = Lazy::new(|| Mutex::new(Weak::new()));
+
 /// RAII structure used to stop profiling when dropped. It is the only interface to access profiler.
+///
+/// The guards built while another one is alive share its profiler, whose
+/// configuration is kept, and the sampling only stops when the last of them
+/// is dropped. The report of every guard only contains the samples collected
+/// since it was built, so independent libraries can profile the same process
+/// without coordinating. The thread CPU times, the thread states and the
+/// kernel stacks aren't windowed, they cover the whole lifetime of the shared
+/// profiler.
 pub struct ProfilerGuard<'a> {
     profiler: &'a RwLock<Result<Profiler>>,
-    timer: Option<Timer>,
+    #[cfg(not(feature = "disabled"))]
+    sampler: Option<Arc<Sampler>>,
+    libraries: Option<Mutex<LibraryWatcher>>,
+    window: Option<Window>,
+}
+
+/// Profile the process for `duration` with the configuration of `builder`,
+/// blocking the calling thread, and return the report. The report is
+/// built before the profiler stops, so it covers the whole duration even if
+/// no sample was taken.
+pub fn profile_for(builder: ProfilerGuardBuilder, duration: Duration) -> Result<Report> {
+    let guard = builder.build()?;
+
+    // `sleep` may return early if the thread is woken up, e.g. by a signal
+    let deadline = Instant::now() + duration;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        std::thread::sleep(deadline - now);
+    }
+
+    guard.report().build()
+}
+
+/// Push a stack captured outside of pprof-rs, e.g. by an eBPF program through
+/// `bpf_get_stackid`, into the running profiler, as if the signal handler had
+/// sampled it. `ips` are the addresses of the stack, from the innermost frame:
+/// like the ones of `bpf_get_stackid`, the first one is the address of the
+/// interrupted instruction, and is attributed to its own line, and the others
+/// are return addresses, attributed to the line of their call instruction.
+/// Only the first `MAX_DEPTH` frames and `MAX_THREAD_NAME` bytes of
+/// the thread name are kept. It's ignored if no profiler is running, while
+/// it's paused, or with the `disabled` feature.
+///
+/// The frames are built from bare addresses, so it requires the
+/// `frame-pointer` feature.
+#[cfg(all(
+    unix,
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "loongarch64"
+    ),
+    feature = "frame-pointer"
+))]
+pub fn ingest_stack(ips: &[usize], thread_id: u64, thread_name: &str, ts: SystemTime) {
+    use crate::backtrace::frame_pointer::Frame;
+
+    if cfg!(feature = "disabled") {
+        return;
+    }
+
+    let frames = ips.iter().take(MAX_DEPTH).map(|&ip| Frame { ip }).collect();
+    let thread_name = thread_name.as_bytes();
+    let thread_name = &thread_name[..thread_name.len().min(MAX_THREAD_NAME)];
+
+    if let Ok(profiler) = PROFILER.write().as_mut() {
+        let truncated = ips.len() > MAX_DEPTH;
+        profiler.sample(
+            frames,
+            thread_name,
+            thread_id,
+            ts,
+            truncated,
+            true,
+            crate::tag::NO_LABELS,
+        );
+    }
+}
+
+/// Log a warning, once, if the binary seems to be compiled without frame
+/// pointers, in which case the stacks unwound through them are garbage.
+#[cfg(all(
+    unix,
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "loongarch64"
+    ),
+    feature = "frame-pointer"
+))]
+fn warn_missing_frame_pointers() {
+    static CHECK: std::sync::Once = std::sync::Once::new();
+    CHECK.call_once(|| {
+        if !crate::backtrace::frame_pointer::frame_pointers_available() {
+            log::warn!(
+                "the frame pointers seem to be missing, the stacks will be truncated or wrong; \
+                 build with `RUSTFLAGS=\"-C force-frame-pointers=yes\"` and `-Z build-std`"
+            );
+        }
+    });
 }
 
+#[cfg(not(feature = "disabled"))]
 fn trigger_lazy() {
     let _ = backtrace::Backtrace::new();
     let _profiler = PROFILER.read();
@@ -165,29 +1398,101 @@ impl ProfilerGuard<'_> {
         ProfilerGuardBuilder::default().frequency(frequency).build()
     }
 
+    /// Replace the blocklist of the running profiler. The address ranges are
+    /// recomputed from the currently loaded libraries, so it also covers the
+    /// libraries loaded (e.g. through `dlopen`) after the profiler was built.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "loongarch64"
+    ))]
+    pub fn set_blocklist<T: AsRef<str>>(&self, blocklist: &[T]) -> Result<()> {
+        if cfg!(feature = "disabled") {
+            return Ok(());
+        }
+
+        let segments = blocklist_segments(blocklist);
+
+        // the signal handler only reads the segments while holding the lock
+        match self.profiler.write().as_mut() {
+            Err(err) => {
+                log::error!("Error in creating profiler: {}", err);
+                Err(Error::CreatingError)
+            }
+            Ok(profiler) => {
+                profiler.blocklist_segments = segments;
+                Ok(())
+            }
+        }
+    }
+
+    /// Stop sampling until `resume` is called, e.g. during an operation which
+    /// should be left out of the profile, keeping the samples collected so
+    /// far. The interval timer is disarmed, while the per thread timers and
+    /// the perf events keep running and their samples are dropped. The
+    /// paused time is left out of `Report::active_duration`. As the profiler
+    /// is shared, it pauses the guards which joined it as well.
+    pub fn pause(&self) -> Result<()> {
+        #[cfg(not(feature = "disabled"))]
+        if let Some(sampler) = &self.sampler {
+            return sampler.pause();
+        }
+        Ok(())
+    }
+
+    /// Resume sampling after `pause`.
+    pub fn resume(&self) -> Result<()> {
+        #[cfg(not(feature = "disabled"))]
+        if let Some(sampler) = &self.sampler {
+            return sampler.resume();
+        }
+        Ok(())
+    }
+
+    /// Call the callback registered with
+    /// `ProfilerGuardBuilder::on_library_change` if the loaded libraries
+    /// changed since the previous check.
+    pub fn check_libraries(&self) {
+        if let Some(libraries) = &self.libraries {
+            libraries.lock().check();
+        }
+    }
+
     /// Generate a report
-    pub fn report(&self) -> ReportBuilder {
-        ReportBuilder::new(
-            self.profiler,
-            self.timer.as_ref().map(Timer::timing).unwrap_or_default(),
-        )
+    pub fn report(&self) -> ReportBuilder<'_> {
+        self.check_libraries();
+
+        #[cfg(not(feature = "disabled"))]
+        let timing = self
+            .sampler
+            .as_ref()
+            .map(|sampler| sampler.timing())
+            .unwrap_or_default();
+        #[cfg(feature = "disabled")]
+        let timing = ReportTiming::default();
+        match &self.window {
+            Some(window) => {
+                ReportBuilder::new(self.profiler, window.timing(&timing)).window(window)
+            }
+            None => ReportBuilder::new(self.profiler, timing),
+        }
     }
 }
 
+#[cfg(not(feature = "disabled"))]
 impl<'a> Drop for ProfilerGuard<'a> {
     fn drop(&mut self) {
-        drop(self.timer.take());
-
-        match self.profiler.write().as_mut() {
-            Err(_) => {}
-            Ok(profiler) => match profiler.stop() {
-                Ok(()) => {}
-                Err(err) => log::error!("error while stopping profiler {}", err),
-            },
+        if let Some(sampler) = self.sampler.take() {
+            // the last guard stops the profiler, which mustn't be joined
+            // meanwhile
+            let _shared = SAMPLER.lock();
+            drop(sampler);
         }
     }
 }
 
+#[cfg(unix)]
 fn write_thread_name_fallback(current_thread: libc::pthread_t, name: &mut [libc::c_char]) {
     let mut len = 0;
     let mut base = 1;
@@ -213,13 +1518,16 @@ fn write_thread_name_fallback(current_thread: libc::pthread_t, name: &mut [libc:
     }
 }
 
-#[cfg(not(all(any(target_os = "linux", target_os = "macos"), target_env = "gnu")))]
-fn write_thread_name(current_thread: libc::pthread_t, name: &mut [libc::c_char]) {
+#[cfg(all(
+    unix,
+    not(all(any(target_os = "linux", target_os = "macos"), target_env = "gnu"))
+))]
+pub(crate) fn write_thread_name(current_thread: libc::pthread_t, name: &mut [libc::c_char]) {
     write_thread_name_fallback(current_thread, name);
 }
 
 #[cfg(all(any(target_os = "linux", target_os = "macos"), target_env = "gnu"))]
-fn write_thread_name(current_thread: libc::pthread_t, name: &mut [libc::c_char]) {
+pub(crate) fn write_thread_name(current_thread: libc::pthread_t, name: &mut [libc::c_char]) {
     let name_ptr = name as *mut [libc::c_char] as *mut libc::c_char;
     let ret = unsafe { libc::pthread_getname_np(current_thread, name_ptr, MAX_THREAD_NAME) };
 
@@ -228,8 +1536,10 @@ fn write_thread_name(current_thread: libc::pthread_t, name: &mut [libc::c_char])
     }
 }
 
+#[cfg(unix)]
 struct ErrnoProtector(libc::c_int);
 
+#[cfg(unix)]
 impl ErrnoProtector {
     fn new() -> Self {
         unsafe {
@@ -252,6 +1562,7 @@ impl ErrnoProtector {
     }
 }
 
+#[cfg(unix)]
 impl Drop for ErrnoProtector {
     fn drop(&mut self) {
         unsafe {
@@ -271,7 +1582,8 @@ impl Drop for ErrnoProtector {
     }
 }
 
-#[no_mangle]
+/// Returns the program counter saved in the `ucontext` of the signal handler.
+#[cfg(unix)]
 #[cfg_attr(
     not(all(any(
         target_arch = "x86_64",
@@ -282,112 +1594,455 @@ impl Drop for ErrnoProtector {
     allow(unused_variables)
 )]
 #[allow(clippy::unnecessary_cast)]
+pub(crate) fn ucontext_pc(ucontext: *mut libc::c_void) -> Option<usize> {
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "loongarch64"
+    ))]
+    {
+        if ucontext.is_null() {
+            return None;
+        }
+        let ucontext: *mut libc::ucontext_t = ucontext as *mut libc::ucontext_t;
+
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        let addr = unsafe { (*ucontext).uc_mcontext.gregs[libc::REG_RIP as usize] as usize };
+
+        #[cfg(all(target_arch = "x86_64", target_os = "freebsd"))]
+        let addr = unsafe { (*ucontext).uc_mcontext.mc_rip as usize };
+
+        #[cfg(all(target_arch = "x86_64", target_os = "macos"))]
+        let addr = unsafe {
+            let mcontext = (*ucontext).uc_mcontext;
+            if mcontext.is_null() {
+                0
+            } else {
+                (*mcontext).__ss.__rip as usize
+            }
+        };
+
+        #[cfg(all(
+            target_arch = "aarch64",
+            any(target_os = "android", target_os = "linux")
+        ))]
+        let addr = unsafe { (*ucontext).uc_mcontext.pc as usize };
+
+        #[cfg(all(target_arch = "aarch64", target_os = "freebsd"))]
+        let addr = unsafe { (*ucontext).mc_gpregs.gp_elr as usize };
+
+        #[cfg(all(target_arch = "aarch64", target_os = "macos"))]
+        let addr = unsafe {
+            let mcontext = (*ucontext).uc_mcontext;
+            if mcontext.is_null() {
+                0
+            } else {
+                (*mcontext).__ss.__pc as usize
+            }
+        };
+
+        #[cfg(all(target_arch = "riscv64", target_os = "linux"))]
+        let addr = unsafe { (*ucontext).uc_mcontext.__gregs[libc::REG_PC] as usize };
+
+        #[cfg(all(target_arch = "loongarch64", target_os = "linux"))]
+        let addr = unsafe { (*ucontext).uc_mcontext.__pc as usize };
+
+        Some(addr)
+    }
+
+    #[cfg(not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "loongarch64"
+    )))]
+    None
+}
+
+/// Returns the frame of the interrupted function, whose address is `pc`.
+#[cfg(all(
+    unix,
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "loongarch64"
+    ),
+    feature = "frame-pointer"
+))]
+fn leaf_frame(_ucontext: *mut libc::c_void, pc: usize) -> Option<<TraceImpl as Trace>::Frame> {
+    Some(crate::backtrace::frame_pointer::Frame { ip: pc })
+}
+
+/// Returns the frame of the interrupted function, whose address is `pc`.
+///
+/// The frames of `backtrace` can't be built from an address, so it unwinds
+/// through the signal handler and the signal trampoline until it finds the
+/// frame of `pc`.
+#[cfg(all(
+    unix,
+    not(all(
+        any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64",
+            target_arch = "loongarch64"
+        ),
+        feature = "frame-pointer"
+    ))
+))]
+fn leaf_frame(ucontext: *mut libc::c_void, pc: usize) -> Option<<TraceImpl as Trace>::Frame> {
+    const MAX_SEARCH_DEPTH: usize = 12;
+
+    let mut leaf = None;
+    let mut depth = 0;
+    TraceImpl::trace(ucontext, |frame| {
+        if crate::backtrace::Frame::ip(frame) == pc {
+            leaf = Some(frame.clone());
+            return false;
+        }
+        depth += 1;
+        depth < MAX_SEARCH_DEPTH
+    });
+
+    leaf
+}
+
+/// Whether the handler is registered with `SA_ONSTACK`. It's read by the
+/// handler before it takes the lock.
+#[cfg(all(unix, not(feature = "disabled")))]
+static HANDLER_ON_SIGNAL_STACK: AtomicBool = AtomicBool::new(false);
+
+#[cfg(all(unix, not(feature = "disabled")))]
+#[no_mangle]
 extern "C" fn perf_signal_handler(
     _signal: c_int,
-    _siginfo: *mut libc::siginfo_t,
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))] siginfo: *mut libc::siginfo_t,
     ucontext: *mut libc::c_void,
 ) {
     let _errno = ErrnoProtector::new();
 
+    // before anything which may return early, so that the timer keeps firing
+    rearm_jittered_timer();
+
+    // The frame of `sample_in_handler` holds the whole stack and doesn't fit
+    // on the small alternate stacks installed by the Rust runtime, so this
+    // check is done in a function with a small frame.
+    if HANDLER_ON_SIGNAL_STACK.load(Ordering::Relaxed) && !signal_stack_is_usable() {
+        return;
+    }
+
+    #[cfg(all(feature = "kernel-stack", target_os = "linux"))]
+    if crate::kernel_stack::serve_user_stack_request(ucontext) {
+        return;
+    }
+
+    #[cfg(target_os = "linux")]
+    if !crate::timer::sample_timer_signal(siginfo) {
+        return;
+    }
+
+    sample_in_handler(ucontext);
+}
+
+#[cfg(all(unix, not(feature = "disabled")))]
+#[inline(never)]
+fn sample_in_handler(ucontext: *mut libc::c_void) {
     if let Some(mut guard) = PROFILER.try_write() {
         if let Ok(profiler) = guard.as_mut() {
+            if !profiler.running || profiler.paused {
+                return;
+            }
+            let started = profiler
+                .overhead_governor
+                .as_ref()
+                .map(|_| monotonic_nanos());
+
+            let current_thread = unsafe { libc::pthread_self() };
+            let cpu_time = current_thread_cpu_time();
+            if profiler.mode == ProfilingMode::OffCpu {
+                let consumed = cpu_time.and_then(|cpu_time| {
+                    profiler
+                        .thread_cpu_times
+                        .record(current_thread as u64, cpu_time)
+                });
+                // the first sample of a thread can't tell
+                if !matches!(consumed, Some(consumed) if consumed < profiler.off_cpu_threshold) {
+                    return;
+                }
+            }
+
+            let pc = ucontext_pc(ucontext);
+
             #[cfg(any(
                 target_arch = "x86_64",
                 target_arch = "aarch64",
                 target_arch = "riscv64",
                 target_arch = "loongarch64"
             ))]
-            if !ucontext.is_null() {
-                let ucontext: *mut libc::ucontext_t = ucontext as *mut libc::ucontext_t;
+            if let Some(addr) = pc {
+                if profiler.is_blocklisted(addr) {
+                    return;
+                }
+            }
+
+            if let Some(rate_limiter) = profiler.rate_limiter.as_mut() {
+                if !rate_limiter.acquire(monotonic_nanos()) {
+                    profiler
+                        .rate_limited_samples
+                        .fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            }
+            if let (Some(governor), Some(started)) = (profiler.overhead_governor.as_mut(), started)
+            {
+                if !governor.acquire(started) {
+                    profiler
+                        .rate_limited_samples
+                        .fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            }
 
-                #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
-                let addr =
-                    unsafe { (*ucontext).uc_mcontext.gregs[libc::REG_RIP as usize] as usize };
+            // the frames are stored inline, and the stack is truncated before
+            // spilling to the heap, so that the handler never allocates
+            let mut bt: SmallVec<[<TraceImpl as Trace>::Frame; MAX_DEPTH]> = SmallVec::new();
+            let mut truncated = false;
 
-                #[cfg(all(target_arch = "x86_64", target_os = "freebsd"))]
-                let addr = unsafe { (*ucontext).uc_mcontext.mc_rip as usize };
+            let sample_timestamp: SystemTime = SystemTime::now();
+            // the frame pointers only lead to the return addresses, while
+            // `backtrace` unwinds through the signal trampoline to the
+            // interrupted instruction
+            let exact_leaf =
+                profiler.mode == ProfilingMode::LeafOnly || !cfg!(feature = "frame-pointer");
+            if profiler.mode == ProfilingMode::LeafOnly {
+                if let Some(frame) = pc.and_then(|pc| leaf_frame(ucontext, pc)) {
+                    bt.push(frame);
+                }
+            } else {
+                TraceImpl::trace(ucontext, |frame| {
+                    #[cfg(feature = "frame-pointer")]
+                    {
+                        let ip = crate::backtrace::Frame::ip(frame);
+                        if profiler.is_blocklisted(ip) {
+                            return false;
+                        }
+                    }
 
-                #[cfg(all(target_arch = "x86_64", target_os = "macos"))]
-                let addr = unsafe {
-                    let mcontext = (*ucontext).uc_mcontext;
-                    if mcontext.is_null() {
-                        0
+                    if bt.len() < bt.inline_size() {
+                        bt.push(frame.clone());
+                        true
                     } else {
-                        (*mcontext).__ss.__rip as usize
+                        truncated = true;
+                        false
                     }
-                };
+                });
+            }
 
-                #[cfg(all(
-                    target_arch = "aarch64",
-                    any(target_os = "android", target_os = "linux")
-                ))]
-                let addr = unsafe { (*ucontext).uc_mcontext.pc as usize };
+            if profiler.is_shallow(stack_depth(&bt, pc)) {
+                return;
+            }
 
-                #[cfg(all(target_arch = "aarch64", target_os = "freebsd"))]
-                let addr = unsafe { (*ucontext).mc_gpregs.gp_elr as usize };
+            if let Some(hook) = profiler.registers_hook {
+                if let Some(registers) = ucontext_registers(ucontext) {
+                    hook(current_thread as u64, &registers);
+                }
+            }
 
-                #[cfg(all(target_arch = "aarch64", target_os = "macos"))]
-                let addr = unsafe {
-                    let mcontext = (*ucontext).uc_mcontext;
-                    if mcontext.is_null() {
-                        0
-                    } else {
-                        (*mcontext).__ss.__pc as usize
+            let mut name = [0; MAX_THREAD_NAME];
+            match profiler
+                .thread_name_resolver
+                .and_then(|resolve| resolve(current_thread as u64))
+            {
+                Some(resolved) => {
+                    for (dst, byte) in name.iter_mut().zip(resolved) {
+                        *dst = byte as libc::c_char;
                     }
-                };
+                }
+                None => write_thread_name(current_thread, &mut name),
+            }
 
-                #[cfg(all(target_arch = "riscv64", target_os = "linux"))]
-                let addr = unsafe { (*ucontext).uc_mcontext.__gregs[libc::REG_PC] as usize };
+            // already recorded to tell whether the thread was off-CPU
+            if profiler.mode != ProfilingMode::OffCpu {
+                if let Some(cpu_time) = cpu_time {
+                    profiler
+                        .thread_cpu_times
+                        .record(current_thread as u64, cpu_time);
+                }
+            }
 
-                #[cfg(all(target_arch = "loongarch64", target_os = "linux"))]
-                let addr = unsafe { (*ucontext).uc_mcontext.__pc as usize };
+            #[cfg(target_os = "linux")]
+            if let Some(states) = &profiler.observed_states {
+                profiler
+                    .thread_states
+                    .record(current_thread as u64, states.load(current_tid()));
+            }
 
-                if profiler.is_blocklisted(addr) {
+            // the name isn't terminated if it fills the buffer
+            let name_length = name.iter().position(|c| *c == 0).unwrap_or(MAX_THREAD_NAME);
+            let name =
+                unsafe { std::slice::from_raw_parts(name.as_ptr() as *const u8, name_length) };
+            profiler.sample(
+                bt,
+                name,
+                current_thread as u64,
+                sample_timestamp,
+                truncated,
+                exact_leaf,
+                tag::current_label_set(),
+            );
+            if let (Some(governor), Some(started)) = (profiler.overhead_governor.as_mut(), started)
+            {
+                governor.record(monotonic_nanos().saturating_sub(started));
+            }
+        }
+    }
+}
+
+/// Sample `thread` from the sampler thread of Windows, which suspends it to
+/// unwind its stack, as the signal handler does for the interrupted thread
+/// on the other platforms. The lock is taken before the thread is suspended,
+/// as the thread may hold it. The labels and the registers of the thread
+/// can't be read from another thread, so they aren't recorded.
+#[cfg(all(windows, not(feature = "disabled")))]
+pub(crate) fn sample_thread(thread: &crate::platform::SampledThread) {
+    if let Some(mut guard) = PROFILER.try_write() {
+        if let Ok(profiler) = guard.as_mut() {
+            if !profiler.running || profiler.paused {
+                return;
+            }
+            let started = profiler
+                .overhead_governor
+                .as_ref()
+                .map(|_| monotonic_nanos());
+
+            if let Some(rate_limiter) = profiler.rate_limiter.as_mut() {
+                if !rate_limiter.acquire(monotonic_nanos()) {
+                    profiler
+                        .rate_limited_samples
+                        .fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            }
+            if let (Some(governor), Some(started)) = (profiler.overhead_governor.as_mut(), started)
+            {
+                if !governor.acquire(started) {
+                    profiler
+                        .rate_limited_samples
+                        .fetch_add(1, Ordering::Relaxed);
                     return;
                 }
             }
 
-            let mut bt: SmallVec<[<TraceImpl as Trace>::Frame; MAX_DEPTH]> =
-                SmallVec::with_capacity(MAX_DEPTH);
-            let mut index = 0;
-
+            let mut bt: SmallVec<[<TraceImpl as Trace>::Frame; MAX_DEPTH]> = SmallVec::new();
             let sample_timestamp: SystemTime = SystemTime::now();
-            TraceImpl::trace(ucontext, |frame| {
-                #[cfg(feature = "frame-pointer")]
-                {
-                    let ip = crate::backtrace::Frame::ip(frame);
-                    if profiler.is_blocklisted(ip) {
-                        return false;
-                    }
+            let truncated = match thread.unwind(profiler.mode == ProfilingMode::LeafOnly, &mut bt) {
+                Some(truncated) => truncated,
+                None => return,
+            };
+
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            if let Some(frame) = bt.first() {
+                if profiler.is_blocklisted(Frame::ip(frame)) {
+                    return;
                 }
+            }
 
-                if index < MAX_DEPTH {
-                    bt.push(frame.clone());
-                    index += 1;
-                    true
-                } else {
-                    false
-                }
-            });
+            if profiler.is_shallow(bt.len()) {
+                return;
+            }
 
-            let current_thread = unsafe { libc::pthread_self() };
-            let mut name = [0; MAX_THREAD_NAME];
-            let name_ptr = &mut name as *mut [libc::c_char] as *mut libc::c_char;
+            let resolved = profiler
+                .thread_name_resolver
+                .and_then(|resolve| resolve(thread.id()));
+            let name = match &resolved {
+                Some(resolved) => {
+                    let length = resolved.iter().position(|c| *c == 0);
+                    &resolved[..length.unwrap_or(MAX_THREAD_NAME)]
+                }
+                None => thread.name(),
+            };
 
-            write_thread_name(current_thread, &mut name);
+            if let Some(cpu_time) = thread.cpu_time() {
+                profiler.thread_cpu_times.record(thread.id(), cpu_time);
+            }
 
-            let name = unsafe { std::ffi::CStr::from_ptr(name_ptr) };
-            profiler.sample(bt, name.to_bytes(), current_thread as u64, sample_timestamp);
+            profiler.sample(
+                bt,
+                name,
+                thread.id(),
+                sample_timestamp,
+                truncated,
+                true,
+                crate::tag::NO_LABELS,
+            );
+            if let (Some(governor), Some(started)) = (profiler.overhead_governor.as_mut(), started)
+            {
+                governor.record(monotonic_nanos().saturating_sub(started));
+            }
         }
     }
 }
 
+/// The address of the signal handler, whose frames start the sampled stacks
+/// when they aren't skipped in the handler.
+#[cfg(all(unix, not(feature = "disabled")))]
+pub(crate) fn signal_handler_address() -> Option<usize> {
+    Some(perf_signal_handler as *const () as usize)
+}
+
+/// There is no signal handler with the `disabled` feature, nor on Windows.
+#[cfg(any(windows, feature = "disabled"))]
+pub(crate) fn signal_handler_address() -> Option<usize> {
+    None
+}
+
+/// The number of frames of a sampled stack, from the interrupted function.
+/// `backtrace` also unwinds the frames of the signal handler, which are
+/// skipped up to the one of the interrupted instruction `pc`.
+#[cfg(unix)]
+fn stack_depth(bt: &[<TraceImpl as Trace>::Frame], pc: Option<usize>) -> usize {
+    if cfg!(feature = "frame-pointer") {
+        return bt.len();
+    }
+    match pc.and_then(|pc| bt.iter().position(|frame| Frame::ip(frame) == pc)) {
+        Some(index) => bt.len() - index,
+        None => bt.len(),
+    }
+}
+
 impl Profiler {
+    #[cfg(not(feature = "disabled"))]
     fn new() -> Result<Self> {
         Ok(Profiler {
             data: Collector::new()?,
-            sample_counter: 0,
             running: false,
+            paused: false,
+            mode: ProfilingMode::default(),
+            off_cpu_threshold: Duration::ZERO,
+            #[cfg(unix)]
+            signal: ProfilingMode::default().signal(),
+            collector_full: AtomicBool::new(false),
+            collector_full_warned: AtomicBool::new(false),
+            thread_cpu_times: ThreadCpuTimeTable::default(),
+            #[cfg(target_os = "linux")]
+            observed_states: None,
+            #[cfg(target_os = "linux")]
+            thread_states: ThreadStatesTable::default(),
+            rate_limiter: None,
+            overhead_governor: None,
+            rate_limited_samples: AtomicU64::new(0),
+            min_stack_depth: 0,
+            shallow_samples: AtomicU64::new(0),
+            symbol_offsets: false,
+            unresolved_addresses: false,
+            signal_stack: false,
+            thread_name_resolver: None,
+            registers_hook: None,
+            shared: None,
+            #[cfg(all(feature = "kernel-stack", target_os = "linux"))]
+            kernel_stacks: KernelStacks::default(),
 
             #[cfg(any(
                 target_arch = "x86_64",
@@ -416,11 +2071,13 @@ impl Profiler {
 }
 
 impl Profiler {
+    #[cfg(not(feature = "disabled"))]
     pub fn start(&mut self) -> Result<()> {
         log::info!("starting cpu profiler");
         if self.running {
             Err(Error::Running)
         } else {
+            #[cfg(unix)]
             self.register_signal_handler()?;
             self.running = true;
 
@@ -428,18 +2085,43 @@ impl Profiler {
         }
     }
 
+    #[cfg(not(feature = "disabled"))]
     fn init(&mut self) -> Result<()> {
-        self.sample_counter = 0;
+        self.collector_full.store(false, Ordering::Relaxed);
+        self.collector_full_warned.store(false, Ordering::Relaxed);
+        self.thread_cpu_times = ThreadCpuTimeTable::default();
+        #[cfg(target_os = "linux")]
+        {
+            self.observed_states = None;
+            self.thread_states = ThreadStatesTable::default();
+        }
+        self.rate_limited_samples.store(0, Ordering::Relaxed);
+        self.shallow_samples.store(0, Ordering::Relaxed);
+        self.shared = None;
+        #[cfg(all(feature = "kernel-stack", target_os = "linux"))]
+        {
+            self.kernel_stacks = KernelStacks::default();
+        }
         self.data = Collector::new()?;
         self.running = false;
+        self.paused = false;
 
         Ok(())
     }
 
+    #[cfg(not(feature = "disabled"))]
     pub fn stop(&mut self) -> Result<()> {
         log::info!("stopping cpu profiler");
         if self.running {
+            // no sample is recorded from now on, even by a handler which was
+            // invoked before the unregistration and waits for the lock
+            self.running = false;
+            #[cfg(unix)]
             self.unregister_signal_handler()?;
+            self.warn_collector_full();
+            if let Some(shared) = &self.shared {
+                shared.warn_dropped();
+            }
             self.init()?;
 
             Ok(())
@@ -448,38 +2130,1222 @@ impl Profiler {
         }
     }
 
+    #[cfg(all(unix, not(feature = "disabled")))]
     fn register_signal_handler(&self) -> Result<()> {
         let handler = signal::SigHandler::SigAction(perf_signal_handler);
-        let sigaction = signal::SigAction::new(
-            handler,
-            // SA_RESTART will only restart a syscall when it's safe to do so,
-            // e.g. when it's a blocking read(2) or write(2). See man 7 signal.
-            signal::SaFlags::SA_SIGINFO | signal::SaFlags::SA_RESTART,
-            signal::SigSet::empty(),
-        );
-        unsafe { signal::sigaction(signal::SIGPROF, &sigaction) }?;
+        // SA_RESTART will only restart a syscall when it's safe to do so,
+        // e.g. when it's a blocking read(2) or write(2). See man 7 signal.
+        let mut flags = signal::SaFlags::SA_SIGINFO | signal::SaFlags::SA_RESTART;
+        if self.signal_stack {
+            flags |= signal::SaFlags::SA_ONSTACK;
+        }
+        HANDLER_ON_SIGNAL_STACK.store(self.signal_stack, Ordering::Relaxed);
+        let sigaction = signal::SigAction::new(handler, flags, signal::SigSet::empty());
+        unsafe { signal::sigaction(self.signal, &sigaction) }?;
 
         Ok(())
     }
 
+    #[cfg(all(unix, not(feature = "disabled")))]
     fn unregister_signal_handler(&self) -> Result<()> {
         let handler = signal::SigHandler::SigIgn;
-        unsafe { signal::signal(signal::SIGPROF, handler) }?;
+        unsafe { signal::signal(self.signal, handler) }?;
 
         Ok(())
     }
 
+    /// Whether a stack of `depth` frames is shallower than
+    /// `min_stack_depth`, in which case it's counted and must be dropped.
+    /// It's async-signal-safe.
+    pub(crate) fn is_shallow(&self, depth: usize) -> bool {
+        // the samples taken while stopped or paused are dropped anyway
+        if depth >= self.min_stack_depth || !self.running || self.paused {
+            return false;
+        }
+        self.shallow_samples.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
     // This function has to be AS-safe
+    #[allow(clippy::too_many_arguments)]
     pub fn sample(
         &mut self,
         backtrace: SmallVec<[<TraceImpl as Trace>::Frame; MAX_DEPTH]>,
         thread_name: &[u8],
         thread_id: u64,
         sample_timestamp: SystemTime,
+        truncated: bool,
+        exact_leaf: bool,
+        label_set: u32,
     ) {
-        let frames = UnresolvedFrames::new(backtrace, thread_name, thread_id, sample_timestamp);
-        self.sample_counter += 1;
+        if !self.running || self.paused {
+            return;
+        }
+
+        let mut frames = UnresolvedFrames::new(
+            backtrace,
+            thread_name,
+            thread_id,
+            sample_timestamp,
+            truncated,
+        );
+        frames.symbol_offsets = self.symbol_offsets;
+        frames.unresolved_addresses = self.unresolved_addresses;
+        frames.exact_leaf = exact_leaf;
+        frames.label_set = label_set;
+        frames.traced = self.data.is_trace();
+
+        if let Some(shared) = self.shared.as_mut() {
+            let ips = frames.frames.iter().map(|frame| Frame::ip(frame) as u64);
+            shared.add(ips, thread_id, thread_name, sample_timestamp, truncated);
+        }
+
+        if self.data.add_at(frames, 1, Some(sample_timestamp)).is_err() {
+            self.collector_full.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether the collector has rejected any sample since the profiler was started.
+    pub(crate) fn collector_full(&self) -> bool {
+        self.collector_full.load(Ordering::Relaxed)
+    }
+
+    /// Log a warning the first time it's found that the collector rejected a
+    /// sample. It must not be called in the signal handler.
+    pub(crate) fn warn_collector_full(&self) {
+        if self.collector_full() && !self.collector_full_warned.swap(true, Ordering::Relaxed) {
+            log::warn!("the collector is full, some samples have been dropped and the profile is incomplete");
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+#[cfg(not(feature = "disabled"))]
+mod tests {
+    use super::*;
+
+    // the tests share the global profiler
+    static PROFILER_LOCK: parking_lot::Mutex<()> = parking_lot::const_mutex(());
+
+    #[cfg(feature = "frame-pointer")]
+    #[inline(never)]
+    fn deep_recursive(depth: usize) -> usize {
+        if depth == 0 {
+            0
+        } else {
+            // keep the recursion from being turned into a loop
+            std::hint::black_box(deep_recursive(depth - 1)) + 1
+        }
+    }
+
+    #[test]
+    fn debug_builder() {
+        let builder = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .mode(ProfilingMode::UserCpu);
+
+        let debug = format!("{:?}", builder);
+        assert!(debug.contains("frequency: 1000"));
+        assert!(debug.contains("mode: UserCpu"));
+    }
+
+    #[test]
+    fn trace_samples() {
+        let _lock = PROFILER_LOCK.lock();
+        trigger_lazy();
+
+        let mut profiler = PROFILER.write();
+        let profiler = profiler.as_mut().unwrap();
+        profiler.data = Collector::trace(2).unwrap();
+        profiler.running = true;
+        let at = |millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis);
+        for millis in [10, 20, 30] {
+            profiler.sample(
+                SmallVec::new(),
+                b"main",
+                1,
+                at(millis),
+                false,
+                true,
+                tag::NO_LABELS,
+            );
+        }
+        assert!(profiler.collector_full());
+
+        // the samples of the same stack are kept apart by their time
+        let samples: std::collections::HashSet<UnresolvedFrames> = profiler
+            .data
+            .try_iter()
+            .unwrap()
+            .map(|entry| {
+                let mut frames = entry.item.clone();
+                frames.sample_timestamp = entry.first_seen.unwrap();
+                frames
+            })
+            .collect();
+        let mut times: Vec<SystemTime> = samples
+            .iter()
+            .map(|frames| frames.sample_timestamp)
+            .collect();
+        times.sort();
+        assert_eq!(times, [at(10), at(20)]);
+
+        profiler.init().unwrap();
+    }
+
+    #[test]
+    fn report_collector_full() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1)
+            .collect_mode(CollectMode::Trace)
+            .max_trace_samples(1)
+            .build()
+            .unwrap();
+        assert!(!guard.report().build().unwrap().was_collector_full());
+
+        if let Ok(profiler) = PROFILER.write().as_mut() {
+            for _ in 0..2 {
+                profiler.sample(
+                    SmallVec::new(),
+                    b"main",
+                    1,
+                    SystemTime::now(),
+                    false,
+                    true,
+                    tag::NO_LABELS,
+                );
+            }
+        }
+        assert!(guard.report().build().unwrap().was_collector_full());
+        assert!(guard
+            .report()
+            .build_unresolved()
+            .unwrap()
+            .was_collector_full());
+    }
+
+    #[test]
+    fn scale_values_below_half() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1)
+            .build()
+            .unwrap();
+        if let Ok(profiler) = PROFILER.write().as_mut() {
+            for thread_id in [1, 2, 3] {
+                profiler.sample(
+                    SmallVec::new(),
+                    b"main",
+                    thread_id,
+                    SystemTime::now(),
+                    false,
+                    true,
+                    tag::NO_LABELS,
+                );
+            }
+        }
+
+        let mut builder = guard.report();
+        builder
+            .value_scale(|_| 0.3)
+            .frames_post_processor(|frames| {
+                if frames.thread_id == 2 {
+                    frames.thread_id = 1;
+                }
+                frames.sample_timestamp = SystemTime::UNIX_EPOCH;
+                frames.last_sample_timestamp = SystemTime::UNIX_EPOCH;
+            });
+        // the samples of threads 1 and 2 end up in the same stack, and add
+        // up to 0.6, the one of thread 3 rounds to zero and is dropped
+        let report = builder.build().unwrap();
+        let counts: Vec<_> = report
+            .data
+            .iter()
+            .filter(|(frames, _)| (1..=3).contains(&frames.thread_id))
+            .map(|(frames, count)| (frames.thread_id, *count))
+            .collect();
+        assert_eq!(counts, [(1, 1)]);
+
+        let report = builder.build_unresolved().unwrap();
+        assert!(report
+            .data
+            .keys()
+            .all(|frames| !(1..=3).contains(&frames.thread_id)));
+    }
+
+    #[test]
+    fn report_without_symbolization() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .build()
+            .unwrap();
+        let start = std::time::Instant::now();
+        let mut n = 0u64;
+        while start.elapsed() < Duration::from_millis(200) {
+            n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+        }
+
+        let report = guard.report().symbolize(false).build().unwrap();
+        assert!(!report.data.is_empty());
+        for (frames, _) in report.data.iter() {
+            for symbol in frames.frames.iter().flatten() {
+                assert!(symbol.name.is_none() && symbol.filename.is_none());
+                assert!(symbol.addr.is_some());
+            }
+        }
+
+        let report = guard.report().build().unwrap();
+        assert!(report
+            .data
+            .keys()
+            .flat_map(|frames| frames.frames.iter().flatten())
+            .any(|symbol| symbol.name.is_some()));
+    }
+
+    #[test]
+    fn rapid_start_stop() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let stop = std::sync::Arc::new(AtomicBool::new(false));
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let stop = stop.clone();
+                std::thread::spawn(move || {
+                    let mut n = 0u64;
+                    while !stop.load(Ordering::Relaxed) {
+                        n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+                    }
+                })
+            })
+            .collect();
+
+        for _ in 0..20 {
+            let guard = ProfilerGuardBuilder::default()
+                .frequency(1000)
+                .build()
+                .unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(2));
+            guard.report().build_unresolved().unwrap();
+            drop(guard);
+
+            // the signals which are still in flight must not record anything
+            let profiler = PROFILER.read();
+            let profiler = profiler.as_ref().unwrap();
+            assert!(!profiler.running);
+            assert_eq!(profiler.data.try_iter().unwrap().count(), 0);
+        }
+
+        stop.store(true, Ordering::Relaxed);
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn stop_after_max_runtime() {
+        let _lock = PROFILER_LOCK.lock();
+
+        // the watchdog exits with the guard
+        let guard = ProfilerGuardBuilder::default()
+            .max_runtime(Duration::from_secs(3600))
+            .build()
+            .unwrap();
+        drop(guard);
+        assert!(!PROFILER.read().as_ref().unwrap().running);
+
+        // a leaked guard is stopped by the watchdog
+        let guard = ProfilerGuardBuilder::default()
+            .max_runtime(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        assert!(PROFILER.read().as_ref().unwrap().running);
+        std::mem::forget(guard);
+        let start = Instant::now();
+        while PROFILER.read().as_ref().unwrap().running {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // and doesn't prevent a new profiler from starting
+        let guard = ProfilerGuardBuilder::default().build().unwrap();
+        assert!(PROFILER.read().as_ref().unwrap().running);
+        assert!(guard.window.is_none());
+        drop(guard);
+        assert!(!PROFILER.read().as_ref().unwrap().running);
+    }
+
+    #[test]
+    fn activate_above_cpu_percent() {
+        let _lock = PROFILER_LOCK.lock();
+
+        fn burn(duration: Duration) {
+            let start = Instant::now();
+            let mut n = 0u64;
+            while start.elapsed() < duration {
+                n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+            }
+        }
+
+        // never busy enough
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .activate_above_cpu_percent(f64::INFINITY)
+            .build()
+            .unwrap();
+        burn(Duration::from_millis(500));
+        let report = guard.report().build_unresolved().unwrap();
+        assert!(report.data.is_empty());
+        assert_eq!(report.active_duration(), Duration::ZERO);
+        drop(guard);
+
+        // always busy enough once the usage is measured
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .activate_above_cpu_percent(0.0)
+            .build()
+            .unwrap();
+        burn(Duration::from_millis(800));
+        let report = guard.report().build_unresolved().unwrap();
+        assert!(!report.data.is_empty());
+        assert!(report.active_duration() > Duration::ZERO);
+        assert!(report.active_duration() < report.timing.duration);
+    }
+
+    #[test]
+    fn jittered_timer() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .jitter(0.5)
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        let mut n = 0u64;
+        while start.elapsed() < Duration::from_millis(300) {
+            n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+        }
+        let report = guard.report().build_unresolved().unwrap();
+        assert!(!report.data.is_empty());
+        drop(guard);
+
+        // the handler doesn't arm the timer again once it's dropped
+        assert!(!crate::timer::is_armed(ProfilingMode::Cpu));
+    }
+
+    #[test]
+    fn concurrent_reports() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        let mut n = 0u64;
+        while start.elapsed() < Duration::from_millis(200) {
+            n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+        }
+
+        // the reporters only share the read lock to copy the samples
+        let guard = &guard;
+        std::thread::scope(|scope| {
+            let reporters: Vec<_> = (0..4)
+                .map(|_| scope.spawn(move || guard.report().build().unwrap()))
+                .collect();
+            for reporter in reporters {
+                assert!(!reporter.join().unwrap().data.is_empty());
+            }
+        });
+    }
+
+    #[test]
+    fn drop_shallow_stacks() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .min_stack_depth(MAX_DEPTH + 1)
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        let mut n = 0u64;
+        while start.elapsed() < Duration::from_millis(200) {
+            n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+        }
+
+        // even the truncated stacks are shallower
+        let report = guard.report().build_unresolved().unwrap();
+        assert!(report.data.is_empty());
+        assert!(report.shallow_samples() > 0);
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let spin = |duration: Duration| {
+            let start = Instant::now();
+            let mut n = 0u64;
+            while start.elapsed() < duration {
+                n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+            }
+        };
+        let total = |guard: &ProfilerGuard| -> isize {
+            guard
+                .report()
+                .build_unresolved()
+                .unwrap()
+                .data
+                .values()
+                .sum()
+        };
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .build()
+            .unwrap();
+        spin(Duration::from_millis(100));
+
+        guard.pause().unwrap();
+        assert!(!crate::timer::is_armed(ProfilingMode::Cpu));
+        let before = total(&guard);
+        assert!(before > 0);
+        spin(Duration::from_millis(200));
+        // the samples are kept, but none is added
+        assert_eq!(total(&guard), before);
+        // pausing twice is a no-op
+        guard.pause().unwrap();
+
+        guard.resume().unwrap();
+        assert!(crate::timer::is_armed(ProfilingMode::Cpu));
+        spin(Duration::from_millis(100));
+        assert!(total(&guard) > before);
+
+        let report = guard.report().build_unresolved().unwrap();
+        assert!(report.active_duration() + Duration::from_millis(200) <= report.timing.duration);
+    }
+
+    #[test]
+    fn build_report_async() {
+        use std::future::Future;
+        use std::sync::Arc;
+        use std::task::{Context, Poll, Wake};
+
+        struct Unpark(std::thread::Thread);
+
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        fn block_on<F: Future>(future: F) -> F::Output {
+            let waker = Arc::new(Unpark(std::thread::current())).into();
+            let mut cx = Context::from_waker(&waker);
+            let mut future = Box::pin(future);
+            loop {
+                match future.as_mut().poll(&mut cx) {
+                    Poll::Ready(output) => return output,
+                    Poll::Pending => std::thread::park(),
+                }
+            }
+        }
+
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        let mut n = 0u64;
+        while start.elapsed() < Duration::from_millis(200) {
+            n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+        }
+
+        let mut builder = guard.report();
+        builder.frames_post_processor(|frames| frames.thread_name = "renamed".to_owned());
+        let report = block_on(builder.build_async()).unwrap();
+        assert!(!report.data.is_empty());
+        assert!(report
+            .data
+            .keys()
+            .all(|frames| frames.thread_name == "renamed"));
+        assert!(report.data.values().sum::<isize>() > 0);
+    }
+
+    #[test]
+    fn shared_profiler() {
+        let _lock = PROFILER_LOCK.lock();
+
+        fn burn(duration: Duration) {
+            let start = Instant::now();
+            let mut n = 0u64;
+            while start.elapsed() < duration {
+                n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+            }
+        }
+        fn total(report: &crate::UnresolvedReport) -> isize {
+            report.data.values().sum()
+        }
+
+        let first = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .build()
+            .unwrap();
+        burn(Duration::from_millis(200));
+
+        // the second guard joins the running profiler instead of failing
+        let second = ProfilerGuardBuilder::default()
+            .frequency(100)
+            .build()
+            .unwrap();
+        burn(Duration::from_millis(200));
+
+        let second_report = second.report().build_unresolved().unwrap();
+        let first_report = first.report().build_unresolved().unwrap();
+        assert!(total(&second_report) > 0);
+        assert!(total(&second_report) < total(&first_report));
+        assert_eq!(second_report.timing.frequency, 1000);
+        assert!(second_report.start_time() > first_report.start_time());
+
+        // the sampling goes on until the last guard is dropped
+        drop(first);
+        assert!(PROFILER.read().as_ref().unwrap().running);
+        burn(Duration::from_millis(50));
+        assert!(total(&second.report().build_unresolved().unwrap()) >= total(&second_report));
+
+        drop(second);
+        assert!(!PROFILER.read().as_ref().unwrap().running);
+    }
+
+    #[test]
+    fn max_overhead() {
+        let _lock = PROFILER_LOCK.lock();
+
+        // a few microseconds per second is a handful of samples
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .max_overhead(0.00001)
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        let mut n = 0u64;
+        while start.elapsed() < Duration::from_millis(500) {
+            n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+        }
+
+        let report = guard.report().build_unresolved().unwrap();
+        assert!(report.rate_limited_samples() > 0);
+        assert!(report.achieved_sample_rate() < 500.0);
+    }
+
+    #[test]
+    fn duty_cycle() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .duty_cycle(Duration::from_millis(50), Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        let mut n = 0u64;
+        while start.elapsed() < Duration::from_millis(600) {
+            n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+        }
+
+        // sampled about a third of the time
+        let report = guard.report().build_unresolved().unwrap();
+        assert!(!report.data.is_empty());
+        assert!(report.active_duration() > Duration::ZERO);
+        assert!(report.active_duration() * 2 < report.timing.duration);
+
+        // a pause of the guard outlasts the schedule
+        guard.pause().unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(PROFILER.read().as_ref().unwrap().paused);
+    }
+
+    #[test]
+    fn per_thread_timers_by_default() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .build()
+            .unwrap();
+        let tid = crate::thread_state::current_tid() as libc::pid_t;
+        assert!(crate::timer::has_thread_timer(tid));
+        // the process wide timer arms the new threads
+        assert!(crate::timer::is_armed(ProfilingMode::Cpu));
+
+        // a short-lived thread arms its own timer as soon as it runs, long
+        // before the rescan of the threads
+        let worker = std::thread::spawn(|| {
+            let start = std::time::Instant::now();
+            let mut n = 0u64;
+            while start.elapsed() < std::time::Duration::from_millis(50) {
+                n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+            }
+            let tid = crate::thread_state::current_tid() as libc::pid_t;
+            (
+                unsafe { libc::pthread_self() as u64 },
+                crate::timer::has_thread_timer(tid),
+            )
+        });
+        let (worker_id, armed) = worker.join().unwrap();
+        assert!(armed);
+
+        let report = guard.report().build_unresolved().unwrap();
+        assert!(report
+            .data
+            .keys()
+            .any(|frames| frames.thread_id == worker_id));
+        drop(guard);
+        assert!(!crate::timer::is_armed(ProfilingMode::Cpu));
+        assert!(!crate::timer::has_thread_timer(tid));
+    }
+
+    #[test]
+    fn process_timer() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .per_thread_timers(false)
+            .build()
+            .unwrap();
+        assert!(crate::timer::is_armed(ProfilingMode::Cpu));
+        let tid = crate::thread_state::current_tid() as libc::pid_t;
+        assert!(!crate::timer::has_thread_timer(tid));
+
+        let worker = std::thread::spawn(|| {
+            let start = std::time::Instant::now();
+            let mut n = 0u64;
+            while start.elapsed() < std::time::Duration::from_millis(200) {
+                n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+            }
+            unsafe { libc::pthread_self() as u64 }
+        });
+        let worker_id = worker.join().unwrap();
+
+        let report = guard.report().build_unresolved().unwrap();
+        assert!(report
+            .data
+            .keys()
+            .any(|frames| frames.thread_id == worker_id));
+        drop(guard);
+        assert!(!crate::timer::is_armed(ProfilingMode::Cpu));
+    }
+
+    #[test]
+    fn wall_clock() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .wall_clock()
+            .build()
+            .unwrap();
+
+        // a thread which never runs is only sampled on the wall time
+        let (sender, receiver) = std::sync::mpsc::channel::<()>();
+        let sleeper = std::thread::spawn(move || {
+            let _ = receiver.recv_timeout(std::time::Duration::from_millis(500));
+            unsafe { libc::pthread_self() as u64 }
+        });
+        let sleeper_id = sleeper.join().unwrap();
+        drop(sender);
+
+        let report = guard.report().build_unresolved().unwrap();
+        assert!(report
+            .data
+            .keys()
+            .any(|frames| frames.thread_id == sleeper_id));
+    }
+
+    #[inline(never)]
+    #[cfg(feature = "kernel-stack")]
+    fn wait_in_kernel(receiver: std::sync::mpsc::Receiver<()>) {
+        let _ = receiver.recv_timeout(Duration::from_millis(300));
+    }
+
+    #[test]
+    #[cfg(feature = "kernel-stack")]
+    fn merge_kernel_stacks() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(100)
+            .kernel_stacks(true)
+            .merge_kernel_stacks(true)
+            .build()
+            .unwrap();
+
+        let (sender, receiver) = std::sync::mpsc::channel::<()>();
+        std::thread::spawn(move || wait_in_kernel(receiver))
+            .join()
+            .unwrap();
+        drop(sender);
+
+        // the kernel frames are below the user frames which blocked, which
+        // the frame pointers of libc may not lead to
+        let report = guard.report().build().unwrap();
+        assert!(report.data.keys().any(|frames| {
+            let names: Vec<_> = frames
+                .frames
+                .iter()
+                .flatten()
+                .map(crate::Symbol::name)
+                .collect();
+            names.first().map_or(false, |name| name.ends_with("_[k]"))
+                && names.last().map_or(false, |name| !name.ends_with("_[k]"))
+                && (cfg!(feature = "frame-pointer")
+                    || names.iter().any(|name| name.contains("wait_in_kernel")))
+        }));
+    }
+
+    #[test]
+    fn off_cpu() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .off_cpu()
+            .build()
+            .unwrap();
+
+        let (sender, receiver) = std::sync::mpsc::channel::<()>();
+        let sleeper = std::thread::spawn(move || {
+            let _ = receiver.recv_timeout(std::time::Duration::from_millis(500));
+            unsafe { libc::pthread_self() as u64 }
+        });
+        let spinner = std::thread::spawn(|| {
+            let start = std::time::Instant::now();
+            let mut n = 0u64;
+            while start.elapsed() < std::time::Duration::from_millis(500) {
+                n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+            }
+            unsafe { libc::pthread_self() as u64 }
+        });
+        let sleeper_id = sleeper.join().unwrap();
+        let spinner_id = spinner.join().unwrap();
+        drop(sender);
+
+        let report = guard.report().build_unresolved().unwrap();
+        let samples = |thread_id| -> isize {
+            report
+                .data
+                .iter()
+                .filter(|(frames, _)| frames.thread_id == thread_id)
+                .map(|(_, count)| *count)
+                .sum()
+        };
+        // the spinning thread may still wait for a CPU now and then
+        assert!(samples(sleeper_id) > 100);
+        assert!(samples(spinner_id) < samples(sleeper_id) / 2);
+    }
+
+    #[test]
+    #[cfg(feature = "perf-event")]
+    fn sample_context_switches() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = match ProfilerGuardBuilder::default()
+            .perf_event(PerfEventKind::ContextSwitches)
+            .build()
+        {
+            Ok(guard) => guard,
+            // sampling the kernel is forbidden by `perf_event_paranoid`
+            Err(Error::IoError(err))
+                if matches!(err.raw_os_error(), Some(libc::EACCES | libc::EPERM)) =>
+            {
+                eprintln!("skip, context switches can't be sampled: {}", err);
+                return;
+            }
+            Err(err) => panic!("{}", err),
+        };
+
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as u64;
+        for _ in 0..200 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let report = guard.report().build_unresolved().unwrap();
+        let samples: isize = report
+            .data
+            .iter()
+            .filter(|(frames, _)| frames.thread_id == tid)
+            .map(|(_, count)| *count)
+            .sum();
+        assert!(samples > 100, "{} samples", samples);
+    }
+
+    #[test]
+    #[cfg(feature = "perf-event")]
+    fn perf_event_thread_states() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = match ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .perf_event(PerfEventKind::CpuClock)
+            .thread_states(true)
+            .build()
+        {
+            Ok(guard) => guard,
+            Err(Error::IoError(err))
+                if matches!(err.raw_os_error(), Some(libc::EACCES | libc::EPERM)) =>
+            {
+                eprintln!("skip, perf events can't be opened: {}", err);
+                return;
+            }
+            Err(err) => panic!("{}", err),
+        };
+        let start = Instant::now();
+        let mut n = 0u64;
+        while start.elapsed() < Duration::from_millis(300) {
+            n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+        }
+
+        // the samples of the perf events are keyed by the kernel thread id
+        let report = guard.report().build_unresolved().unwrap();
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as u64;
+        assert!(report.thread_states()[&tid].running > 0);
+    }
+
+    #[test]
+    #[cfg(all(feature = "perf-event", feature = "kernel-stack"))]
+    fn perf_event_merge_kernel_stacks() {
+        let result = ProfilerGuardBuilder::default()
+            .perf_event(PerfEventKind::CpuClock)
+            .kernel_stacks(true)
+            .merge_kernel_stacks(true)
+            .build();
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn resolve_thread_names() {
+        let _lock = PROFILER_LOCK.lock();
+
+        fn resolver(_thread_id: u64) -> Option<[u8; MAX_THREAD_NAME]> {
+            Some(*b"http-worker-0042")
+        }
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .thread_name_resolver(resolver)
+            .build()
+            .unwrap();
+
+        let start = std::time::Instant::now();
+        let mut n = 0u64;
+        while start.elapsed() < std::time::Duration::from_millis(300) {
+            n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+        }
+
+        let report = guard.report().build_unresolved().unwrap();
+        assert!(!report.data.is_empty());
+        assert!(report
+            .data
+            .keys()
+            .all(|frames| &frames.thread_name[..frames.thread_name_length] == b"http-worker-0042"));
+    }
+
+    #[test]
+    fn pass_registers_to_hook() {
+        let _lock = PROFILER_LOCK.lock();
+
+        static CALLS: AtomicU64 = AtomicU64::new(0);
+        static MISSING: AtomicU64 = AtomicU64::new(0);
+        fn hook(_thread_id: u64, registers: &crate::Registers) {
+            CALLS.fetch_add(1, Ordering::Relaxed);
+            if registers.pc == 0 || registers.sp == 0 {
+                MISSING.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .registers_hook(hook)
+            .build()
+            .unwrap();
+
+        let start = std::time::Instant::now();
+        let mut n = 0u64;
+        while start.elapsed() < std::time::Duration::from_millis(300) {
+            n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+        }
+        drop(guard);
+
+        assert!(CALLS.load(Ordering::Relaxed) > 0);
+        assert_eq!(MISSING.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn sample_into_shared_region() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let len = SharedRegion::size_for(1024);
+        let mut buf = vec![0u64; len / 8];
+        let region = unsafe { SharedRegion::new(buf.as_mut_ptr() as *mut u8, len) }.unwrap();
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .shared_region(region)
+            .build()
+            .unwrap();
+
+        let start = std::time::Instant::now();
+        let mut n = 0u64;
+        while start.elapsed() < std::time::Duration::from_millis(300) {
+            n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+        }
+        let samples: isize = guard
+            .report()
+            .build_unresolved()
+            .unwrap()
+            .data
+            .values()
+            .sum();
+        drop(guard);
+
+        // the region keeps the samples after the profiler stopped
+        let bytes = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, len) };
+        let report = crate::RawReport::from_shared_region(bytes).unwrap();
+        assert_eq!(report.frequency, 1000);
+        let shared: u64 = report.samples.iter().map(|sample| sample.count).sum();
+        assert!(shared > 0);
+        assert!(shared >= samples as u64);
+    }
+
+    #[test]
+    fn panic_in_report_building() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .build()
+            .unwrap();
+        let start = std::time::Instant::now();
+        let mut n = 0u64;
+        while start.elapsed() < std::time::Duration::from_millis(100) {
+            n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+        }
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            guard
+                .report()
+                .frames_post_processor(|_| panic!("malformed symbol"))
+                .build()
+        }));
+        assert!(result.is_err());
+
+        // the profiler is still running and its samples are intact
+        assert!(PROFILER.read().as_ref().unwrap().running);
+        let report = guard.report().build().unwrap();
+        assert!(!report.data.is_empty());
+        drop(guard);
+        assert!(!PROFILER.read().as_ref().unwrap().running);
+    }
+
+    #[test]
+    fn profile_for_duration() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let duration = Duration::from_millis(50);
+        let report = profile_for(ProfilerGuardBuilder::default(), duration).unwrap();
+        assert!(report.timing.duration >= duration);
+        assert!(report.end_time() >= report.start_time() + duration);
+        assert!(!PROFILER.read().as_ref().unwrap().running);
+    }
+
+    #[test]
+    fn custom_signal() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .signal(signal::Signal::SIGUSR1)
+            .build()
+            .unwrap();
+
+        // the handler of SIGPROF is left alone
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        assert_eq!(
+            unsafe { libc::sigaction(libc::SIGPROF, std::ptr::null(), &mut action) },
+            0
+        );
+        assert_ne!(
+            action.sa_sigaction,
+            perf_signal_handler as *const () as usize
+        );
+
+        let start = Instant::now();
+        let mut n = 0u64;
+        while start.elapsed() < Duration::from_millis(200) {
+            n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+        }
+        let report = guard.report().build_unresolved().unwrap();
+        assert!(!report.data.is_empty());
+    }
+
+    #[test]
+    fn sample_on_signal_stack() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .signal_stack(true)
+            .build()
+            .unwrap();
+
+        let worker = std::thread::spawn(|| {
+            let _stack = crate::install_signal_stack().unwrap();
+            let start = std::time::Instant::now();
+            let mut n = 0u64;
+            while start.elapsed() < std::time::Duration::from_millis(300) {
+                n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+            }
+            unsafe { libc::pthread_self() as u64 }
+        });
+        let worker_id = worker.join().unwrap();
+
+        let report = guard.report().build_unresolved().unwrap();
+        assert!(report
+            .data
+            .keys()
+            .any(|frames| frames.thread_id == worker_id));
+    }
+
+    #[test]
+    fn sample_thread_states() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .thread_states(true)
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        let mut n = 0u64;
+        while start.elapsed() < Duration::from_millis(300) {
+            n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+        }
+
+        let report = guard.report().build_unresolved().unwrap();
+        let current_thread = unsafe { libc::pthread_self() } as u64;
+        let states = report.thread_states()[&current_thread];
+        assert!(states.running > 0);
+        assert_eq!(states.disk_sleep, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "frame-pointer")]
+    fn ingest_external_stack() {
+        let _lock = PROFILER_LOCK.lock();
+
+        let ips = [
+            deep_recursive as *const () as usize + 1,
+            profile_for as *const () as usize + 1,
+        ];
+        // nothing is recorded while no profiler is running
+        ingest_stack(&ips, 4242, "ebpf", SystemTime::now());
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1)
+            .build()
+            .unwrap();
+        ingest_stack(&ips, 4242, "ebpf-collector-thread", SystemTime::now());
+        ingest_stack(&ips, 4242, "ebpf-collector-thread", SystemTime::now());
+
+        let report = guard.report().build_unresolved().unwrap();
+        let (frames, count) = report
+            .data
+            .iter()
+            .find(|(frames, _)| frames.thread_id == 4242)
+            .unwrap();
+        assert_eq!(*count, 2);
+        assert_eq!(frames.frames.len(), 2);
+        assert_eq!(
+            &frames.thread_name[..frames.thread_name_length],
+            b"ebpf-collector-t"
+        );
+        assert!(!frames.truncated);
+    }
+
+    #[test]
+    #[cfg(feature = "frame-pointer")]
+    fn ingest_stack_exact_leaf() {
+        let _lock = PROFILER_LOCK.lock();
+
+        // the first instruction of a function, which a return address would
+        // attribute to the function laid out before it
+        let leaf = deep_recursive as *const () as usize;
+        let ips = [leaf, profile_for as *const () as usize + 1];
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1)
+            .build()
+            .unwrap();
+        ingest_stack(&ips, 4343, "ebpf", SystemTime::now());
+
+        let unresolved = guard.report().build_unresolved().unwrap();
+        let (frames, _) = unresolved
+            .data
+            .iter()
+            .find(|(frames, _)| frames.thread_id == 4343)
+            .unwrap();
+        assert!(frames.exact_leaf);
+
+        let report = guard.report().build().unwrap();
+        let (frames, _) = report
+            .data
+            .iter()
+            .find(|(frames, _)| frames.thread_id == 4343)
+            .unwrap();
+        let symbol = frames.frames[0].last().unwrap();
+        assert!(symbol.name().ends_with("deep_recursive"), "{}", symbol);
+        assert!(frames.frames[1]
+            .iter()
+            .any(|symbol| symbol.name().contains("profile_for")));
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "disabled")]
+mod disabled_tests {
+    use super::*;
+
+    #[test]
+    fn disabled_profiler() {
+        let guard = ProfilerGuardBuilder::default().build().unwrap();
+        let report = guard.report().build().unwrap();
+        assert!(report.data.is_empty());
+        drop(guard);
+
+        #[cfg(unix)]
+        {
+            let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+            assert_eq!(
+                unsafe { libc::sigaction(libc::SIGPROF, std::ptr::null(), &mut action) },
+                0
+            );
+            assert_eq!(action.sa_sigaction, libc::SIG_DFL);
+        }
+    }
 
-        if let Ok(()) = self.data.add(frames, 1) {}
+    #[test]
+    #[cfg(all(unix, target_arch = "x86_64", feature = "frame-pointer"))]
+    fn disabled_ingest_stack() {
+        let guard = ProfilerGuardBuilder::default().build().unwrap();
+        ingest_stack(&[0x1000, 0x2000], 1, "ebpf", SystemTime::now());
+        let report = guard.report().build().unwrap();
+        assert!(report.data.is_empty());
+        assert!(PROFILER.read().is_err());
     }
 }
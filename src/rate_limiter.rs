@@ -0,0 +1,153 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Reads `CLOCK_MONOTONIC` in nanoseconds. It's async-signal-safe.
+#[cfg(unix)]
+pub(crate) fn monotonic_nanos() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+
+    ts.tv_sec as u64 * NANOS_PER_SEC + ts.tv_nsec as u64
+}
+
+/// Reads the monotonic clock in nanoseconds, from an arbitrary origin. There
+/// is no signal handler on Windows.
+#[cfg(windows)]
+pub(crate) fn monotonic_nanos() -> u64 {
+    static ORIGIN: once_cell::sync::Lazy<std::time::Instant> =
+        once_cell::sync::Lazy::new(std::time::Instant::now);
+
+    ORIGIN.elapsed().as_nanos() as u64
+}
+
+/// A token bucket which allows at most `rate` samples per second, with bursts
+/// of up to one second worth of samples. It only uses integer arithmetic and
+/// the monotonic clock, so it can be used in the signal handler.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    rate: u64,
+    // a sample costs `NANOS_PER_SEC` tokens, and `rate` tokens are refilled
+    // every nanosecond
+    tokens: u64,
+    last_refill: u64,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: u64) -> Self {
+        let rate = rate.max(1);
+        Self {
+            rate,
+            tokens: rate * NANOS_PER_SEC,
+            last_refill: monotonic_nanos(),
+        }
+    }
+
+    /// Returns `true` if a sample taken at `now` fits in the rate.
+    pub(crate) fn acquire(&mut self, now: u64) -> bool {
+        let elapsed = now.saturating_sub(self.last_refill);
+        self.last_refill = now.max(self.last_refill);
+        self.tokens = self
+            .tokens
+            .saturating_add(elapsed.saturating_mul(self.rate))
+            .min(self.rate * NANOS_PER_SEC);
+
+        if self.tokens >= NANOS_PER_SEC {
+            self.tokens -= NANOS_PER_SEC;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Change the rate, the bucket keeps its tokens up to the new burst.
+    fn set_rate(&mut self, rate: u64) {
+        self.rate = rate.max(1);
+        self.tokens = self.tokens.min(self.rate * NANOS_PER_SEC);
+    }
+}
+
+/// Limits the time spent in the signal handler to a fraction of one CPU, by
+/// limiting the rate of samples to the budget divided by the average cost of
+/// a sample. Like `RateLimiter`, it can be used in the signal handler.
+#[derive(Debug)]
+pub(crate) struct OverheadGovernor {
+    // nanoseconds of handler per second
+    budget: u64,
+    // a moving average of the nanoseconds spent to record a sample, zero
+    // until the first one is recorded
+    average_cost: u64,
+    limiter: RateLimiter,
+}
+
+impl OverheadGovernor {
+    // keeps `rate * NANOS_PER_SEC` far from overflowing
+    const MAX_RATE: u64 = 1_000_000;
+
+    pub(crate) fn new(max_overhead: f64) -> Self {
+        Self {
+            budget: (max_overhead.clamp(0.0, 1.0) * NANOS_PER_SEC as f64) as u64,
+            average_cost: 0,
+            limiter: RateLimiter::new(Self::MAX_RATE),
+        }
+    }
+
+    /// Returns `true` if a sample taken at `now` fits in the budget.
+    pub(crate) fn acquire(&mut self, now: u64) -> bool {
+        self.limiter.acquire(now)
+    }
+
+    /// Account for a sample which took `cost` nanoseconds to record, and
+    /// adapt the rate to it.
+    pub(crate) fn record(&mut self, cost: u64) {
+        self.average_cost = if self.average_cost == 0 {
+            cost
+        } else {
+            (self.average_cost * 7 + cost) / 8
+        };
+        let rate = self.budget / self.average_cost.max(1);
+        self.limiter.set_rate(rate.min(Self::MAX_RATE));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_rate() {
+        let mut limiter = RateLimiter::new(10);
+        let start = limiter.last_refill;
+
+        // the bucket starts full
+        let allowed = (0..100).filter(|_| limiter.acquire(start)).count();
+        assert_eq!(allowed, 10);
+
+        // 10 samples per second means one token every 100ms
+        assert!(!limiter.acquire(start + 50_000_000));
+        assert!(limiter.acquire(start + 100_000_000));
+        assert!(!limiter.acquire(start + 100_000_000));
+    }
+
+    #[test]
+    fn govern_overhead() {
+        // 1% of a CPU with samples of 1ms is 10 samples per second
+        let mut governor = OverheadGovernor::new(0.01);
+        governor.record(1_000_000);
+        let start = governor.limiter.last_refill;
+
+        let allowed = (0..100).filter(|_| governor.acquire(start)).count();
+        assert_eq!(allowed, 10);
+        assert!(!governor.acquire(start + 50_000_000));
+        assert!(governor.acquire(start + 100_000_000));
+
+        // cheaper samples raise the rate
+        for _ in 0..64 {
+            governor.record(100_000);
+        }
+        assert!((95..=100).contains(&governor.limiter.rate));
+    }
+}
@@ -0,0 +1,389 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! A compact binary dump of an `UnresolvedReport`.
+//!
+//! The dump keeps the raw instruction pointers of every stack together with
+//! the address ranges of the loaded modules, so that it can be shipped
+//! elsewhere and symbolicated later. All integers are LEB128 varints, the
+//! addresses of a stack are delta encoded, and strings are interned.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use findshlibs::{Segment, SharedLibrary, TargetSharedLibrary};
+
+use crate::backtrace::Frame;
+use crate::report::UnresolvedReport;
+use crate::timer::ReportTiming;
+use crate::{Error, Result};
+
+const MAGIC: &[u8; 4] = b"PPRF";
+const VERSION: u8 = 1;
+
+/// A module (the executable or a shared library) loaded in the profiled process.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RawModule {
+    /// Path of the module.
+    pub name: String,
+    /// The difference between the actual and the stated virtual memory addresses.
+    pub bias: u64,
+    /// Actual address ranges `(start, end)` of the segments of the module.
+    pub segments: Vec<(u64, u64)>,
+}
+
+/// An aggregated stack.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RawSample {
+    /// Instruction pointers of the stack, from the innermost frame.
+    pub ips: Vec<u64>,
+    pub count: u64,
+    pub thread_id: u64,
+    pub thread_name: String,
+    /// Nanoseconds since the unix epoch of the first sample of this stack.
+    pub timestamp: u64,
+    pub truncated: bool,
+}
+
+/// The content of a raw dump, see `UnresolvedReport::to_raw_bytes`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RawReport {
+    pub frequency: i32,
+    /// Nanoseconds since the unix epoch when the profiling started.
+    pub start_time: u64,
+    /// Duration of the profiling in nanoseconds.
+    pub duration: u64,
+    pub modules: Vec<RawModule>,
+    pub samples: Vec<RawSample>,
+}
+
+pub(crate) fn nanos_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+pub(crate) fn loaded_modules() -> Vec<RawModule> {
+    let mut modules = Vec::new();
+    TargetSharedLibrary::each(|shlib| {
+        modules.push(RawModule {
+            name: shlib.name().to_string_lossy().into_owned(),
+            bias: shlib.virtual_memory_bias().0 as u64,
+            segments: shlib
+                .segments()
+                .map(|seg| {
+                    let start = seg.actual_virtual_memory_address(shlib).0 as u64;
+                    (start, start + seg.len() as u64)
+                })
+                .collect(),
+        });
+    });
+    modules
+}
+
+impl UnresolvedReport {
+    /// Convert the report into a `RawReport`, which records the modules
+    /// currently loaded in the process for later symbolication.
+    pub fn to_raw_report(&self) -> RawReport {
+        let samples = self
+            .data
+            .iter()
+            .map(|(frames, count)| RawSample {
+                ips: frames
+                    .frames
+                    .iter()
+                    .map(|frame| Frame::ip(frame) as u64)
+                    .collect(),
+                count: *count as u64,
+                thread_id: frames.thread_id,
+                thread_name: String::from_utf8_lossy(
+                    &frames.thread_name[0..frames.thread_name_length],
+                )
+                .into_owned(),
+                timestamp: nanos_since_epoch(frames.sample_timestamp),
+                truncated: frames.truncated,
+            })
+            .collect();
+
+        RawReport {
+            frequency: self.timing.frequency,
+            start_time: nanos_since_epoch(self.timing.start_time),
+            duration: self.timing.duration.as_nanos() as u64,
+            modules: loaded_modules(),
+            samples,
+        }
+    }
+
+    /// Encode the report in the compact binary format of `RawReport`.
+    pub fn to_raw_bytes(&self) -> Vec<u8> {
+        self.to_raw_report().to_raw_bytes()
+    }
+}
+
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn varint(&mut self, mut value: u64) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.buf.push(byte);
+                return;
+            }
+            self.buf.push(byte | 0x80);
+        }
+    }
+
+    fn signed_varint(&mut self, value: i64) {
+        self.varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.varint(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(Error::InvalidRawReport("unexpected end of data"));
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Error::InvalidRawReport("varint is too long"))
+    }
+
+    fn signed_varint(&mut self) -> Result<i64> {
+        let value = self.varint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        let len = self.varint()? as usize;
+        // every element takes at least one byte, reject the absurd lengths
+        // before allocating for them
+        if len > self.buf.len() {
+            return Err(Error::InvalidRawReport("length exceeds the data"));
+        }
+        Ok(len)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.len()?;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+}
+
+impl RawReport {
+    /// Encode the report. The data starts with a magic number and a version,
+    /// so that `from_raw_bytes` can reject the incompatible dumps.
+    pub fn to_raw_bytes(&self) -> Vec<u8> {
+        let mut strings = Vec::new();
+        let mut string_index = HashMap::new();
+        let mut intern = |s: &str| -> u64 {
+            *string_index.entry(s.to_owned()).or_insert_with(|| {
+                strings.push(s.to_owned());
+                strings.len() as u64 - 1
+            })
+        };
+
+        let mut body = Writer { buf: Vec::new() };
+        body.varint(self.modules.len() as u64);
+        for module in self.modules.iter() {
+            body.varint(intern(&module.name));
+            body.varint(module.bias);
+            body.varint(module.segments.len() as u64);
+            for (start, end) in module.segments.iter() {
+                body.varint(*start);
+                body.varint(end - start);
+            }
+        }
+        body.varint(self.samples.len() as u64);
+        for sample in self.samples.iter() {
+            body.varint(sample.count);
+            body.varint(sample.thread_id);
+            body.varint(intern(&sample.thread_name));
+            body.varint(sample.timestamp);
+            body.varint(sample.truncated as u64);
+            body.varint(sample.ips.len() as u64);
+            let mut last = 0u64;
+            for ip in sample.ips.iter() {
+                body.signed_varint(ip.wrapping_sub(last) as i64);
+                last = *ip;
+            }
+        }
+
+        let mut writer = Writer { buf: Vec::new() };
+        writer.buf.extend_from_slice(MAGIC);
+        writer.buf.push(VERSION);
+        writer.varint(self.frequency as u64);
+        writer.varint(self.start_time);
+        writer.varint(self.duration);
+        writer.varint(strings.len() as u64);
+        for s in strings.iter() {
+            writer.bytes(s.as_bytes());
+        }
+        writer.buf.extend_from_slice(&body.buf);
+
+        writer.buf
+    }
+
+    /// Decode a report encoded by `to_raw_bytes`.
+    pub fn from_raw_bytes(bytes: &[u8]) -> Result<RawReport> {
+        let mut reader = Reader { buf: bytes };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(Error::InvalidRawReport("bad magic number"));
+        }
+        if reader.take(1)?[0] != VERSION {
+            return Err(Error::InvalidRawReport("unsupported version"));
+        }
+
+        let frequency = reader.varint()? as i32;
+        let start_time = reader.varint()?;
+        let duration = reader.varint()?;
+
+        let strings_len = reader.len()?;
+        let mut strings = Vec::with_capacity(strings_len);
+        for _ in 0..strings_len {
+            strings.push(reader.string()?);
+        }
+        let string = |index: u64| -> Result<String> {
+            strings
+                .get(index as usize)
+                .cloned()
+                .ok_or(Error::InvalidRawReport("string index out of range"))
+        };
+
+        let modules_len = reader.len()?;
+        let mut modules = Vec::with_capacity(modules_len);
+        for _ in 0..modules_len {
+            let name = string(reader.varint()?)?;
+            let bias = reader.varint()?;
+            let segments_len = reader.len()?;
+            let mut segments = Vec::with_capacity(segments_len);
+            for _ in 0..segments_len {
+                let start = reader.varint()?;
+                let len = reader.varint()?;
+                segments.push((start, start.wrapping_add(len)));
+            }
+            modules.push(RawModule {
+                name,
+                bias,
+                segments,
+            });
+        }
+
+        let samples_len = reader.len()?;
+        let mut samples = Vec::with_capacity(samples_len);
+        for _ in 0..samples_len {
+            let count = reader.varint()?;
+            let thread_id = reader.varint()?;
+            let thread_name = string(reader.varint()?)?;
+            let timestamp = reader.varint()?;
+            let truncated = reader.varint()? != 0;
+            let ips_len = reader.len()?;
+            let mut ips = Vec::with_capacity(ips_len);
+            let mut last = 0u64;
+            for _ in 0..ips_len {
+                last = last.wrapping_add(reader.signed_varint()? as u64);
+                ips.push(last);
+            }
+            samples.push(RawSample {
+                ips,
+                count,
+                thread_id,
+                thread_name,
+                timestamp,
+                truncated,
+            });
+        }
+
+        Ok(RawReport {
+            frequency,
+            start_time,
+            duration,
+            modules,
+            samples,
+        })
+    }
+
+    /// Timing metadata of the report.
+    pub fn timing(&self) -> ReportTiming {
+        ReportTiming {
+            frequency: self.frequency,
+            start_time: SystemTime::UNIX_EPOCH + Duration::from_nanos(self.start_time),
+            duration: Duration::from_nanos(self.duration),
+            active_duration: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_report_round_trip() {
+        let report = RawReport {
+            frequency: 99,
+            start_time: 1_700_000_000_000_000_000,
+            duration: 5_000_000_000,
+            modules: vec![RawModule {
+                name: "/usr/lib/libfoo.so".to_owned(),
+                bias: 0x7f00_0000_0000,
+                segments: vec![(0x7f00_0000_1000, 0x7f00_0000_9000)],
+            }],
+            samples: vec![
+                RawSample {
+                    ips: vec![0x7f00_0000_1234, 0x5555_0000_0010, 0x5555_0000_0000],
+                    count: 3,
+                    thread_id: 42,
+                    thread_name: "main".to_owned(),
+                    timestamp: 1_700_000_000_100_000_000,
+                    truncated: false,
+                },
+                RawSample {
+                    ips: vec![0x5555_0000_0020],
+                    count: 1,
+                    thread_id: 43,
+                    thread_name: "main".to_owned(),
+                    timestamp: 1_700_000_000_200_000_000,
+                    truncated: true,
+                },
+            ],
+        };
+
+        let bytes = report.to_raw_bytes();
+        assert_eq!(RawReport::from_raw_bytes(&bytes).unwrap(), report);
+    }
+
+    #[test]
+    fn reject_invalid_raw_report() {
+        let mut bytes = RawReport::default().to_raw_bytes();
+        assert!(RawReport::from_raw_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        bytes[4] = VERSION + 1;
+        assert!(RawReport::from_raw_bytes(&bytes).is_err());
+
+        assert!(RawReport::from_raw_bytes(b"JUNK").is_err());
+    }
+}
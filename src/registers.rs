@@ -0,0 +1,203 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! The registers of the sampled threads, for custom unwinders.
+//!
+//! The unwinders of pprof-rs rely on the frame pointers or on the unwind
+//! tables, which generated code or non-standard calling conventions may lack.
+//! With `ProfilerGuardBuilder::registers_hook`, the signal handler passes the
+//! registers saved in its `ucontext` to a hook, which can record them, and
+//! possibly a copy of the top of the stack, to unwind the sample later and
+//! feed it back with `ingest_stack`.
+
+/// The number of callee-saved registers in `Registers`.
+pub const CALLEE_SAVED_REGISTERS: usize = 11;
+
+/// The registers of a thread when it was interrupted by the profiling signal.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Registers {
+    /// The program counter.
+    pub pc: u64,
+    /// The stack pointer.
+    pub sp: u64,
+    /// The frame pointer: `rbp`, `x29`, `s0` or `fp`.
+    pub fp: u64,
+    /// The return address register: `x30` or `ra`. It's zero on x86_64,
+    /// where the return address is on the stack.
+    pub lr: u64,
+    /// The callee-saved registers other than the frame pointer: `rbx` and
+    /// `r12` to `r15` on x86_64, `x19` to `x28` on aarch64, `s1` to `s11` on
+    /// riscv64 and `s0` to `s8` on loongarch64. The remaining ones are zero.
+    pub callee_saved: [u64; CALLEE_SAVED_REGISTERS],
+}
+
+/// A hook called with the thread id and the registers of every sample, see
+/// `ProfilerGuardBuilder::registers_hook`.
+pub type RegistersHook = fn(u64, &Registers);
+
+/// Returns the registers saved in the `ucontext` of the signal handler, or
+/// `None` on the platforms where they aren't supported.
+#[cfg(unix)]
+#[cfg_attr(
+    not(any(
+        all(
+            any(target_arch = "x86_64", target_arch = "aarch64"),
+            any(target_os = "linux", target_os = "macos")
+        ),
+        all(
+            any(target_arch = "riscv64", target_arch = "loongarch64"),
+            target_os = "linux"
+        )
+    )),
+    allow(unused_variables)
+)]
+#[allow(clippy::unnecessary_cast)]
+pub(crate) fn ucontext_registers(ucontext: *mut libc::c_void) -> Option<Registers> {
+    if ucontext.is_null() {
+        return None;
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    {
+        let ucontext = ucontext as *mut libc::ucontext_t;
+        let gregs = unsafe { &(*ucontext).uc_mcontext.gregs };
+        let reg = |index: libc::c_int| gregs[index as usize] as u64;
+        let mut registers = Registers {
+            pc: reg(libc::REG_RIP),
+            sp: reg(libc::REG_RSP),
+            fp: reg(libc::REG_RBP),
+            ..Default::default()
+        };
+        for (dst, index) in registers.callee_saved.iter_mut().zip([
+            libc::REG_RBX,
+            libc::REG_R12,
+            libc::REG_R13,
+            libc::REG_R14,
+            libc::REG_R15,
+        ]) {
+            *dst = reg(index);
+        }
+        Some(registers)
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "macos"))]
+    {
+        let ucontext = ucontext as *mut libc::ucontext_t;
+        let mcontext = unsafe { (*ucontext).uc_mcontext };
+        if mcontext.is_null() {
+            return None;
+        }
+        let ss = unsafe { &(*mcontext).__ss };
+        let mut registers = Registers {
+            pc: ss.__rip,
+            sp: ss.__rsp,
+            fp: ss.__rbp,
+            ..Default::default()
+        };
+        for (dst, value) in registers
+            .callee_saved
+            .iter_mut()
+            .zip([ss.__rbx, ss.__r12, ss.__r13, ss.__r14, ss.__r15])
+        {
+            *dst = value;
+        }
+        Some(registers)
+    }
+
+    #[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+    {
+        let ucontext = ucontext as *mut libc::ucontext_t;
+        let mcontext = unsafe { &(*ucontext).uc_mcontext };
+        let mut registers = Registers {
+            pc: mcontext.pc as u64,
+            sp: mcontext.sp as u64,
+            fp: mcontext.regs[29] as u64,
+            lr: mcontext.regs[30] as u64,
+            ..Default::default()
+        };
+        for (dst, value) in registers
+            .callee_saved
+            .iter_mut()
+            .zip(&mcontext.regs[19..29])
+        {
+            *dst = *value as u64;
+        }
+        Some(registers)
+    }
+
+    #[cfg(all(target_arch = "aarch64", target_os = "macos"))]
+    {
+        let ucontext = ucontext as *mut libc::ucontext_t;
+        let mcontext = unsafe { (*ucontext).uc_mcontext };
+        if mcontext.is_null() {
+            return None;
+        }
+        let ss = unsafe { &(*mcontext).__ss };
+        let mut registers = Registers {
+            pc: ss.__pc,
+            sp: ss.__sp,
+            fp: ss.__fp,
+            lr: ss.__lr,
+            ..Default::default()
+        };
+        for (dst, value) in registers.callee_saved.iter_mut().zip(&ss.__x[19..29]) {
+            *dst = *value;
+        }
+        Some(registers)
+    }
+
+    #[cfg(all(target_arch = "riscv64", target_os = "linux"))]
+    {
+        let ucontext = ucontext as *mut libc::ucontext_t;
+        let gregs = unsafe { &(*ucontext).uc_mcontext.__gregs };
+        let mut registers = Registers {
+            pc: gregs[0] as u64,
+            sp: gregs[2] as u64,
+            fp: gregs[8] as u64,
+            lr: gregs[1] as u64,
+            ..Default::default()
+        };
+        // s1 is x9, s2 to s11 are x18 to x27
+        for (dst, value) in registers
+            .callee_saved
+            .iter_mut()
+            .zip(gregs[9..10].iter().chain(&gregs[18..28]))
+        {
+            *dst = *value as u64;
+        }
+        Some(registers)
+    }
+
+    #[cfg(all(target_arch = "loongarch64", target_os = "linux"))]
+    {
+        let ucontext = ucontext as *mut libc::ucontext_t;
+        let mcontext = unsafe { &(*ucontext).uc_mcontext };
+        let mut registers = Registers {
+            pc: mcontext.__pc as u64,
+            sp: mcontext.__gregs[3] as u64,
+            fp: mcontext.__gregs[22] as u64,
+            lr: mcontext.__gregs[1] as u64,
+            ..Default::default()
+        };
+        // s0 to s8 are r23 to r31
+        for (dst, value) in registers
+            .callee_saved
+            .iter_mut()
+            .zip(&mcontext.__gregs[23..32])
+        {
+            *dst = *value as u64;
+        }
+        Some(registers)
+    }
+
+    #[cfg(not(any(
+        all(
+            any(target_arch = "x86_64", target_arch = "aarch64"),
+            any(target_os = "linux", target_os = "macos")
+        ),
+        all(
+            any(target_arch = "riscv64", target_arch = "loongarch64"),
+            target_os = "linux"
+        )
+    )))]
+    None
+}
@@ -1,344 +1,4711 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Formatter};
+use std::future::Future;
+#[cfg(feature = "debug-info")]
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant, SystemTime};
 
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 
-use crate::frames::{Frames, UnresolvedFrames};
+use crate::cpu_time::ThreadCpuTime;
+#[cfg(feature = "debug-info")]
+use crate::debug_info::DebugInfo;
+use crate::frames::{Frames, Symbol, UnresolvedFrames};
 use crate::profiler::Profiler;
+use crate::thread_state::ThreadStates;
 use crate::timer::ReportTiming;
 
 use crate::{Error, Result};
 
 /// The final presentation of a report which is actually an `HashMap` from `Frames` to isize (count).
+#[non_exhaustive]
 pub struct Report {
     /// Key is a backtrace captured by profiler and value is count of it.
     pub data: HashMap<Frames, isize>,
 
     /// Collection frequency, start time, duration.
     pub timing: ReportTiming,
+
+    collector_full: bool,
+
+    thread_cpu_times: HashMap<u64, ThreadCpuTime>,
+
+    thread_states: HashMap<u64, ThreadStates>,
+
+    rate_limited_samples: u64,
+
+    shallow_samples: u64,
+}
+
+/// The samples of a function in a report.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FunctionStat {
+    /// Samples where the function is the leaf, i.e. the self time.
+    pub self_count: isize,
+    /// Samples where the function appears anywhere in the stack, i.e. the
+    /// total time. A recursive function is only counted once per sample.
+    pub total_count: isize,
+}
+
+/// The samples of a thread in a report, see `Report::thread_summary`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThreadSummary {
+    pub thread_id: u64,
+    /// The name of the thread in most of its samples, as a thread may be
+    /// renamed while it's profiled. It's empty if the thread has no name.
+    pub thread_name: String,
+    pub sample_count: isize,
+}
+
+/// A single sample of a report, see `Report::samples`.
+#[derive(Clone, Copy, Debug)]
+pub struct Sample<'a> {
+    /// The stack and the thread of the sample.
+    pub frames: &'a Frames,
+    pub timestamp: SystemTime,
+    /// The time the sample stands for, the sampling period.
+    pub weight: Duration,
+}
+
+/// The functions with the most samples, see `Report::top`. It's printed as a
+/// table like `pprof -top`, with the self (`flat`) and total (`cum`) samples
+/// of every function, their share of all the samples, and the running total
+/// of the self samples (`sum%`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopFunctions {
+    pub functions: Vec<(String, FunctionStat)>,
+    /// The samples of the report.
+    pub total: isize,
+}
+
+impl std::fmt::Display for TopFunctions {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let percent = |count: isize| match self.total {
+            0 => 0.0,
+            total => count as f64 * 100.0 / total as f64,
+        };
+
+        writeln!(
+            f,
+            "{:>10} {:>7} {:>7} {:>10} {:>7}  function",
+            "flat", "flat%", "sum%", "cum", "cum%"
+        )?;
+        let mut sum = 0;
+        for (name, stat) in &self.functions {
+            sum += stat.self_count;
+            writeln!(
+                f,
+                "{:>10} {:>6.2}% {:>6.2}% {:>10} {:>6.2}%  {}",
+                stat.self_count,
+                percent(stat.self_count),
+                percent(sum),
+                stat.total_count,
+                percent(stat.total_count),
+                name
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The stats with the most self samples first, then the most total samples.
+pub(crate) fn sort_stats(stats: HashMap<String, FunctionStat>) -> Vec<(String, FunctionStat)> {
+    let mut stats: Vec<_> = stats.into_iter().collect();
+    stats.sort_by(|(a_name, a), (b_name, b)| {
+        (Reverse(a.self_count), Reverse(a.total_count), a_name).cmp(&(
+            Reverse(b.self_count),
+            Reverse(b.total_count),
+            b_name,
+        ))
+    });
+
+    stats
+}
+
+/// Summarize the `(thread id, thread name, count)` of the stacks of a report
+/// by thread, the threads with the most samples first.
+fn thread_summary<'a>(stacks: impl Iterator<Item = (u64, &'a [u8], isize)>) -> Vec<ThreadSummary> {
+    let mut threads: HashMap<u64, HashMap<&[u8], isize>> = HashMap::new();
+    for (thread_id, thread_name, count) in stacks {
+        *threads
+            .entry(thread_id)
+            .or_default()
+            .entry(thread_name)
+            .or_default() += count;
+    }
+
+    let mut summary: Vec<_> = threads
+        .into_iter()
+        .map(|(thread_id, names)| {
+            let sample_count = names.values().sum();
+            let thread_name = names
+                .into_iter()
+                .max_by_key(|(name, count)| (*count, Reverse(*name)))
+                .map(|(name, _)| String::from_utf8_lossy(name).into_owned())
+                .unwrap_or_default();
+            ThreadSummary {
+                thread_id,
+                thread_name,
+                sample_count,
+            }
+        })
+        .collect();
+    summary.sort_by_key(|thread| (Reverse(thread.sample_count), thread.thread_id));
+    summary
 }
 
 /// The presentation of an unsymbolicated report which is actually an `HashMap` from `UnresolvedFrames` to isize (count).
+#[non_exhaustive]
 pub struct UnresolvedReport {
     /// key is a backtrace captured by profiler and value is count of it.
     pub data: HashMap<UnresolvedFrames, isize>,
 
     /// Collection frequency, start time, duration.
     pub timing: ReportTiming,
-}
 
-type FramesPostProcessor = Box<dyn Fn(&mut Frames)>;
+    collector_full: bool,
 
-/// A builder of `Report` and `UnresolvedReport`. It builds report from a running `Profiler`.
-pub struct ReportBuilder<'a> {
-    frames_post_processor: Option<FramesPostProcessor>,
-    profiler: &'a RwLock<Result<Profiler>>,
-    timing: ReportTiming,
+    thread_cpu_times: HashMap<u64, ThreadCpuTime>,
+
+    thread_states: HashMap<u64, ThreadStates>,
+
+    rate_limited_samples: u64,
+
+    shallow_samples: u64,
 }
 
-impl<'a> ReportBuilder<'a> {
-    pub(crate) fn new(profiler: &'a RwLock<Result<Profiler>>, timing: ReportTiming) -> Self {
+impl Report {
+    /// A report of `data`, without the state recorded by the profiler, e.g.
+    /// whether the collector was full.
+    pub fn new(data: HashMap<Frames, isize>, timing: ReportTiming) -> Self {
         Self {
-            frames_post_processor: None,
-            profiler,
+            data,
             timing,
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
         }
     }
 
-    /// Set `frames_post_processor` of a `ReportBuilder`. Before finally building a report, `frames_post_processor`
-    /// will be applied to every Frames.
-    pub fn frames_post_processor<T>(&mut self, frames_post_processor: T) -> &mut Self
+    /// The stacks of the report in a stable order: by descending count, then
+    /// by thread and by the names of their frames from the root. All outputs
+    /// are written in this order, so that the same samples always produce the
+    /// same bytes.
+    pub fn sorted_data(&self) -> Vec<(&Frames, isize)> {
+        let mut data: Vec<_> = self.data.iter().map(|(key, count)| (key, *count)).collect();
+        data.sort_by_cached_key(|(key, count)| {
+            let names: Vec<String> = key
+                .frames
+                .iter()
+                .rev()
+                .flat_map(|frame| frame.iter().rev().map(|symbol| symbol.to_string()))
+                .collect();
+            (
+                Reverse(*count),
+                key.thread_name_or_id(),
+                key.thread_id,
+                names,
+                key.truncated,
+            )
+        });
+
+        data
+    }
+
+    /// `folded` will write the report in the collapsed stack format of
+    /// Brendan Gregg's `flamegraph.pl`: one line per stack, with the thread
+    /// and the frames from the root separated by `;`, followed by a space and
+    /// the count, e.g. `main;work;compute 42`. The truncated stacks start
+    /// with a `[truncated]` frame below the thread.
+    pub fn folded<W>(&self, mut writer: W) -> Result<()>
     where
-        T: Fn(&mut Frames) + 'static,
+        W: std::io::Write,
     {
-        self.frames_post_processor
-            .replace(Box::new(frames_post_processor));
+        for line in self.folded_lines() {
+            writeln!(writer, "{}", line)?;
+        }
 
-        self
+        Ok(())
     }
 
-    /// Build an `UnresolvedReport`
-    pub fn build_unresolved(&self) -> Result<UnresolvedReport> {
-        let mut hash_map = HashMap::new();
+    /// The samples of the report one by one, in the order they were taken,
+    /// e.g. to correlate them with the latency of the requests, or to draw a
+    /// timeline. As only the first and the last sample of a stack are
+    /// timestamped, its other samples are spread evenly between them, unless
+    /// the profiler collected them with `CollectMode::Trace`.
+    pub fn samples(&self) -> impl Iterator<Item = Sample<'_>> {
+        let weight = Duration::from_nanos(1_000_000_000 / self.timing.frequency.max(1) as u64);
+        let mut samples: Vec<Sample> = self
+            .sorted_data()
+            .into_iter()
+            .flat_map(|(key, count)| {
+                sample_times(key.sample_timestamp, key.last_sample_timestamp, count).map(
+                    move |timestamp| Sample {
+                        frames: key,
+                        timestamp,
+                        weight,
+                    },
+                )
+            })
+            .collect();
+        samples.sort_by_key(|sample| sample.timestamp);
 
-        match self.profiler.read().as_ref() {
-            Err(err) => {
-                log::error!("Error in creating profiler: {}", err);
-                Err(Error::CreatingError)
-            }
-            Ok(profiler) => {
-                profiler.data.try_iter()?.for_each(|entry| {
-                    let count = entry.count;
-                    if count > 0 {
-                        let key = &entry.item;
-                        match hash_map.get_mut(key) {
-                            Some(value) => {
-                                *value += count;
-                            }
-                            None => {
-                                match hash_map.insert(key.clone(), count) {
-                                    None => {}
-                                    Some(_) => {
-                                        unreachable!();
-                                    }
-                                };
-                            }
-                        }
-                    }
-                });
+        samples.into_iter()
+    }
 
-                Ok(UnresolvedReport {
-                    data: hash_map,
-                    timing: self.timing.clone(),
-                })
+    /// same as `folded`, but the frames are followed by their file and line
+    /// when the debug info has them, e.g. `main;work (src/main.rs:12) 42`.
+    /// The sampled frames are keyed on their function, so a caller is at the
+    /// line of its first sample, unless
+    /// [`ProfilerGuardBuilder::symbol_offsets`](crate::ProfilerGuardBuilder::symbol_offsets)
+    /// keys them on their address.
+    pub fn folded_with_lines<W>(&self, mut writer: W) -> Result<()>
+    where
+        W: std::io::Write,
+    {
+        for line in self.folded_lines_with(folded_stack_with_lines) {
+            writeln!(writer, "{}", line)?;
+        }
+
+        Ok(())
+    }
+
+    /// `flamescope` will write the report in the text format of `perf script`,
+    /// which [FlameScope](https://github.com/Netflix/flamescope) loads to show
+    /// how the CPU usage varies within each second: one block per sample, in
+    /// the order they were taken, with the thread, the time and the frames
+    /// from the leaf. As only the first and the last sample of a stack are
+    /// timestamped, its other samples are spread evenly between them.
+    pub fn flamescope<W>(&self, mut writer: W) -> Result<()>
+    where
+        W: std::io::Write,
+    {
+        for sample in self.samples() {
+            let (key, period) = (sample.frames, sample.weight.as_nanos());
+            let time = sample
+                .timestamp
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            writeln!(
+                writer,
+                "{} {} [000] {}.{:06}: {} cpu-clock:",
+                key.thread_name_or_id(),
+                key.thread_id,
+                time.as_secs(),
+                time.subsec_micros(),
+                period
+            )?;
+            for symbol in key.frames.iter().flatten() {
+                let addr = symbol.addr.map_or(0, |addr| addr as usize);
+                writeln!(writer, "\t{:x} {} ([unknown])", addr, symbol)?;
             }
+            if key.truncated {
+                writeln!(writer, "\t0 [truncated] ([unknown])")?;
+            }
+            writeln!(writer)?;
         }
+
+        Ok(())
     }
 
-    /// Build a `Report`.
-    pub fn build(&self) -> Result<Report> {
-        let mut hash_map = HashMap::new();
+    /// The lines of `folded`, in the order of `sorted_data`.
+    pub(crate) fn folded_lines(&self) -> Vec<String> {
+        self.folded_lines_with(folded_stack)
+    }
 
-        match self.profiler.write().as_mut() {
-            Err(err) => {
-                log::error!("Error in creating profiler: {}", err);
-                Err(Error::CreatingError)
+    /// The lines of `folded`, with the stacks folded by `fold`.
+    pub(crate) fn folded_lines_with<F>(&self, fold: F) -> Vec<String>
+    where
+        F: Fn(&Frames) -> String,
+    {
+        use std::fmt::Write;
+
+        self.sorted_data()
+            .into_iter()
+            .map(|(key, value)| {
+                let mut line = fold(key);
+                write!(&mut line, " {}", value).unwrap();
+
+                line
+            })
+            .collect()
+    }
+
+    /// The self and total samples of every function of the report, keyed by
+    /// their demangled name. Inlined functions are counted like the others,
+    /// the innermost one of the leaf frame being the leaf.
+    pub(crate) fn function_stats_map(&self) -> HashMap<String, FunctionStat> {
+        self.stats_by(Symbol::name)
+    }
+
+    /// The self and total samples of the groups of functions named by
+    /// `group`, like `function_stats_map` for the functions themselves.
+    pub(crate) fn stats_by<F>(&self, mut group: F) -> HashMap<String, FunctionStat>
+    where
+        F: FnMut(&Symbol) -> String,
+    {
+        let mut stats: HashMap<String, FunctionStat> = HashMap::new();
+        let mut seen = HashSet::new();
+        for (key, count) in self.data.iter() {
+            if let Some(leaf) = key.frames.first().and_then(|frame| frame.first()) {
+                stats.entry(group(leaf)).or_default().self_count += count;
             }
-            Ok(profiler) => {
-                profiler.data.try_iter()?.for_each(|entry| {
-                    let count = entry.count;
-                    if count > 0 {
-                        let mut key = Frames::from(entry.item.clone());
-                        if let Some(processor) = &self.frames_post_processor {
-                            processor(&mut key);
-                        }
 
-                        match hash_map.get_mut(&key) {
-                            Some(value) => {
-                                *value += count;
-                            }
-                            None => {
-                                match hash_map.insert(key, count) {
-                                    None => {}
-                                    Some(_) => {
-                                        unreachable!();
-                                    }
-                                };
-                            }
-                        }
-                    }
-                });
+            seen.clear();
+            for symbol in key.frames.iter().flatten() {
+                let name = group(symbol);
+                if seen.insert(name.clone()) {
+                    stats.entry(name).or_default().total_count += count;
+                }
+            }
+        }
 
-                Ok(Report {
-                    data: hash_map,
+        stats
+    }
+
+    /// The sampled threads with their number of samples, the threads with
+    /// the most samples first. A thread missing from it was never sampled,
+    /// e.g. because it blocks the profiling signal.
+    pub fn thread_summary(&self) -> Vec<ThreadSummary> {
+        thread_summary(
+            self.data
+                .iter()
+                .map(|(key, count)| (key.thread_id, key.thread_name.as_bytes(), *count)),
+        )
+    }
+
+    /// A report per sampled thread, the threads with the most samples first,
+    /// e.g. to render a flamegraph per thread. They have the timing of this
+    /// report, and the CPU times and the states of their thread, but not the
+    /// dropped samples, which aren't counted per thread.
+    pub fn split_threads(&self) -> Vec<Report> {
+        let mut threads: HashMap<u64, HashMap<Frames, isize>> = HashMap::new();
+        for (key, count) in self.data.iter() {
+            threads
+                .entry(key.thread_id)
+                .or_default()
+                .insert(key.clone(), *count);
+        }
+
+        self.thread_summary()
+            .into_iter()
+            .filter_map(|thread| {
+                let data = threads.remove(&thread.thread_id)?;
+                let only_thread = |thread_id: &u64| *thread_id == thread.thread_id;
+                Some(Report {
+                    data,
                     timing: self.timing.clone(),
+                    collector_full: self.collector_full,
+                    thread_cpu_times: self
+                        .thread_cpu_times
+                        .iter()
+                        .filter(|(thread_id, _)| only_thread(thread_id))
+                        .map(|(thread_id, times)| (*thread_id, *times))
+                        .collect(),
+                    thread_states: self
+                        .thread_states
+                        .iter()
+                        .filter(|(thread_id, _)| only_thread(thread_id))
+                        .map(|(thread_id, states)| (*thread_id, *states))
+                        .collect(),
+                    rate_limited_samples: 0,
+                    shallow_samples: 0,
                 })
+            })
+            .collect()
+    }
+
+    /// The self and total samples of the function named `name`, or `None` if
+    /// it wasn't sampled.
+    pub fn function_stats(&self, name: &str) -> Option<FunctionStat> {
+        self.function_stats_map().remove(name)
+    }
+
+    /// The `n` functions with the most self samples, then the most total
+    /// samples.
+    pub fn top_functions(&self, n: usize) -> Vec<(String, FunctionStat)> {
+        let mut stats = sort_stats(self.function_stats_map());
+        stats.truncate(n);
+
+        stats
+    }
+
+    /// The `n` functions with the most self samples, as `top_functions`, in
+    /// a table which prints like `pprof -top`, e.g. with
+    /// `println!("{}", report.top(10))`.
+    pub fn top(&self, n: usize) -> TopFunctions {
+        TopFunctions {
+            functions: self.top_functions(n),
+            total: self.data.values().sum(),
+        }
+    }
+
+    /// The fraction of the samples of the report made by `count` samples,
+    /// e.g. the `self_count` of a `FunctionStat`, and the margin of error of
+    /// that fraction at a 95% confidence level, or `None` if there is no
+    /// sample. A function with 10% of the samples and a margin of 6% may
+    /// well take 4% or 16% of the time, so the profile should run longer
+    /// before drawing conclusions.
+    ///
+    /// The samples are considered independent, and the margin is the normal
+    /// approximation of the binomial distribution, which underestimates it
+    /// when `count` is lower than about 10.
+    pub fn sample_confidence(&self, count: isize) -> Option<(f64, f64)> {
+        let total: isize = self.data.values().sum();
+        if total <= 0 {
+            return None;
+        }
+
+        let total = total as f64;
+        let fraction = count as f64 / total;
+        let margin = 1.96 * (fraction * (1.0 - fraction) / total).max(0.0).sqrt();
+
+        Some((fraction, margin))
+    }
+
+    /// The fraction of the samples taken while the thread was in a system
+    /// call, or `None` if there is no sample. The signal is registered with
+    /// `SA_RESTART`, but some system calls, like `epoll_wait` or `nanosleep`,
+    /// fail with `EINTR` instead of restarting when interrupted. A high ratio
+    /// means that code which doesn't retry on `EINTR` is likely to see it.
+    ///
+    /// A sample is counted if its leaf function is a known system call
+    /// wrapper of the C library, so the system calls made directly, e.g.
+    /// through inline assembly, are missed.
+    pub fn syscall_interrupt_ratio(&self) -> Option<f64> {
+        let mut total = 0;
+        let mut in_syscall = 0;
+        for (key, count) in self.data.iter() {
+            total += count;
+            if let Some(leaf) = key.frames.first().and_then(|frame| frame.first()) {
+                if is_syscall_wrapper(&leaf.name()) {
+                    in_syscall += count;
+                }
             }
         }
+
+        if total > 0 {
+            Some(in_syscall as f64 / total as f64)
+        } else {
+            None
+        }
+    }
+
+    /// Returns `true` if the collector rejected some samples, in which case
+    /// this report is incomplete.
+    pub fn was_collector_full(&self) -> bool {
+        self.collector_full
+    }
+
+    /// The CPU time consumed by every sampled thread, keyed by thread id.
+    pub fn thread_cpu_times(&self) -> &HashMap<u64, ThreadCpuTime> {
+        &self.thread_cpu_times
+    }
+
+    /// The samples of every sampled thread by scheduling state, keyed by
+    /// thread id. It's empty unless `ProfilerGuardBuilder::thread_states` is
+    /// enabled.
+    pub fn thread_states(&self) -> &HashMap<u64, ThreadStates> {
+        &self.thread_states
+    }
+
+    /// The number of samples dropped because they exceeded
+    /// `ProfilerGuardBuilder::max_sample_rate` or
+    /// `ProfilerGuardBuilder::max_overhead`.
+    pub fn rate_limited_samples(&self) -> u64 {
+        self.rate_limited_samples
+    }
+
+    /// The number of samples recorded per second of `active_duration`,
+    /// across all the threads. It's lower than the frequency times the
+    /// number of busy threads when samples are dropped, e.g. to fit in
+    /// `ProfilerGuardBuilder::max_overhead`.
+    pub fn achieved_sample_rate(&self) -> f64 {
+        let samples: isize = self.data.values().sum();
+        samples as f64 / self.active_duration().as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// The number of samples dropped because their stack was shallower than
+    /// `ProfilerGuardBuilder::min_stack_depth`.
+    pub fn shallow_samples(&self) -> u64 {
+        self.shallow_samples
+    }
+
+    /// Wall-clock time when the profiling started.
+    pub fn start_time(&self) -> SystemTime {
+        self.timing.start_time
+    }
+
+    /// Wall-clock time when the report was built, i.e. the end of the
+    /// profiled window. It's the start time plus the duration measured on the
+    /// monotonic clock, so it isn't affected by adjustments of the system
+    /// clock during the profiling.
+    pub fn end_time(&self) -> SystemTime {
+        self.timing.start_time + self.timing.duration
+    }
+
+    /// The part of the profiled window during which the process was sampled,
+    /// which is shorter than the window with
+    /// `ProfilerGuardBuilder::activate_above_cpu_percent` or after
+    /// `ProfilerGuard::pause`. The rates of the samples should be computed
+    /// over it.
+    pub fn active_duration(&self) -> Duration {
+        self.timing.active_duration.unwrap_or(self.timing.duration)
     }
 }
 
-/// This will generate Report in a human-readable format:
-///
-/// ```shell
-/// FRAME: pprof::profiler::perf_signal_handler::h7b995c4ab2e66493 -> FRAME: Unknown -> FRAME: {func1} ->
-/// FRAME: {func2} -> FRAME: {func3} ->  THREAD: {thread_name} {count}
-/// ```
-impl Debug for Report {
-    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        for (key, val) in self.data.iter() {
-            write!(f, "{:?} {}", key, val)?;
-            writeln!(f)?;
+impl UnresolvedReport {
+    /// A report of `data`, see `Report::new`.
+    pub fn new(data: HashMap<UnresolvedFrames, isize>, timing: ReportTiming) -> Self {
+        Self {
+            data,
+            timing,
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
         }
+    }
 
-        Ok(())
+    /// The sampled threads with their number of samples, see
+    /// `Report::thread_summary`.
+    pub fn thread_summary(&self) -> Vec<ThreadSummary> {
+        thread_summary(self.data.iter().map(|(key, count)| {
+            (
+                key.thread_id,
+                &key.thread_name[..key.thread_name_length],
+                *count,
+            )
+        }))
+    }
+
+    /// Returns `true` if the collector rejected some samples, in which case
+    /// this report is incomplete.
+    pub fn was_collector_full(&self) -> bool {
+        self.collector_full
+    }
+
+    /// The CPU time consumed by every sampled thread, keyed by thread id.
+    pub fn thread_cpu_times(&self) -> &HashMap<u64, ThreadCpuTime> {
+        &self.thread_cpu_times
+    }
+
+    /// The samples of every sampled thread by scheduling state, keyed by
+    /// thread id. It's empty unless `ProfilerGuardBuilder::thread_states` is
+    /// enabled.
+    pub fn thread_states(&self) -> &HashMap<u64, ThreadStates> {
+        &self.thread_states
+    }
+
+    /// The number of samples dropped because they exceeded
+    /// `ProfilerGuardBuilder::max_sample_rate` or
+    /// `ProfilerGuardBuilder::max_overhead`.
+    pub fn rate_limited_samples(&self) -> u64 {
+        self.rate_limited_samples
+    }
+
+    /// The number of samples recorded per second of `active_duration`,
+    /// across all the threads. It's lower than the frequency times the
+    /// number of busy threads when samples are dropped, e.g. to fit in
+    /// `ProfilerGuardBuilder::max_overhead`.
+    pub fn achieved_sample_rate(&self) -> f64 {
+        let samples: isize = self.data.values().sum();
+        samples as f64 / self.active_duration().as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// The number of samples dropped because their stack was shallower than
+    /// `ProfilerGuardBuilder::min_stack_depth`.
+    pub fn shallow_samples(&self) -> u64 {
+        self.shallow_samples
+    }
+
+    /// Wall-clock time when the profiling started.
+    pub fn start_time(&self) -> SystemTime {
+        self.timing.start_time
+    }
+
+    /// Wall-clock time when the report was built, i.e. the end of the
+    /// profiled window. It's the start time plus the duration measured on the
+    /// monotonic clock, so it isn't affected by adjustments of the system
+    /// clock during the profiling.
+    pub fn end_time(&self) -> SystemTime {
+        self.timing.start_time + self.timing.duration
+    }
+
+    /// The part of the profiled window during which the process was sampled,
+    /// which is shorter than the window with
+    /// `ProfilerGuardBuilder::activate_above_cpu_percent` or after
+    /// `ProfilerGuard::pause`. The rates of the samples should be computed
+    /// over it.
+    pub fn active_duration(&self) -> Duration {
+        self.timing.active_duration.unwrap_or(self.timing.duration)
     }
 }
 
-#[cfg(feature = "flamegraph")]
-mod flamegraph {
-    use super::*;
-    use inferno::flamegraph;
-    use std::fmt::Write;
+/// The samples which a shared profiler had already collected when a
+/// `ProfilerGuard` joined it, and which are left out of its reports.
+pub(crate) struct Window {
+    start_time: SystemTime,
+    start_instant: Instant,
+    start_active_duration: Duration,
+    counts: HashMap<UnresolvedFrames, isize>,
+    rate_limited_samples: u64,
+    shallow_samples: u64,
+}
 
-    impl Report {
-        /// `flamegraph` will write an svg flamegraph into `writer` **only available with `flamegraph` feature**
-        pub fn flamegraph<W>(&self, writer: W) -> Result<()>
-        where
-            W: std::io::Write,
-        {
-            self.flamegraph_with_options(writer, &mut flamegraph::Options::default())
+impl Window {
+    /// Start a window on `profiler`, whose sampler was armed for
+    /// `active_duration` so far.
+    pub(crate) fn new(profiler: &Profiler, active_duration: Duration) -> Result<Self> {
+        Ok(Self {
+            start_time: SystemTime::now(),
+            start_instant: Instant::now(),
+            start_active_duration: active_duration,
+            counts: aggregate_samples(copy_samples(profiler)?),
+            rate_limited_samples: profiler.rate_limited_samples.load(Ordering::Relaxed),
+            shallow_samples: profiler.shallow_samples.load(Ordering::Relaxed),
+        })
+    }
+
+    /// The timing of the samples collected since the window started, from
+    /// the `timing` of the whole profiling.
+    pub(crate) fn timing(&self, timing: &ReportTiming) -> ReportTiming {
+        ReportTiming {
+            frequency: timing.frequency,
+            start_time: self.start_time,
+            duration: self.start_instant.elapsed(),
+            active_duration: timing
+                .active_duration
+                .map(|active| active.saturating_sub(self.start_active_duration)),
         }
+    }
 
-        /// same as `flamegraph`, but accepts custom `options` for the flamegraph
-        pub fn flamegraph_with_options<W>(
-            &self,
-            writer: W,
-            options: &mut flamegraph::Options,
-        ) -> Result<()>
-        where
-            W: std::io::Write,
-        {
-            let lines: Vec<String> = self
-                .data
-                .iter()
-                .map(|(key, value)| {
-                    let mut line = key.thread_name_or_id();
-                    line.push(';');
+    /// Remove the samples collected before the window started.
+    fn apply(&self, report: &mut UnresolvedReport) {
+        report.data = std::mem::take(&mut report.data)
+            .into_iter()
+            .filter_map(|(mut frames, count)| {
+                let count = count - self.counts.get(&frames).copied().unwrap_or(0);
+                if count <= 0 {
+                    return None;
+                }
+                frames.sample_timestamp = frames.sample_timestamp.max(self.start_time);
 
-                    for frame in key.frames.iter().rev() {
-                        for symbol in frame.iter().rev() {
-                            write!(&mut line, "{};", symbol).unwrap();
-                        }
-                    }
+                Some((frames, count))
+            })
+            .collect();
+        report.rate_limited_samples = report
+            .rate_limited_samples
+            .saturating_sub(self.rate_limited_samples);
+        report.shallow_samples = report.shallow_samples.saturating_sub(self.shallow_samples);
+    }
+}
 
-                    line.pop().unwrap_or_default();
-                    write!(&mut line, " {}", value).unwrap();
+/// The threads to report, see `ReportBuilder::include_threads` and
+/// `ReportBuilder::exclude_threads`.
+#[derive(Default)]
+struct ThreadFilter {
+    included: Vec<String>,
+    excluded: Vec<String>,
+}
 
-                    line
-                })
-                .collect();
-            if !lines.is_empty() {
-                flamegraph::from_lines(options, lines.iter().map(|s| &**s), writer).unwrap();
-                // TODO: handle this error
-            }
+impl ThreadFilter {
+    fn keep(&self, thread_id: u64, thread_name: &[u8]) -> bool {
+        let matches = |pattern: &String| match pattern.parse::<u64>() {
+            Ok(id) => id == thread_id,
+            Err(_) => glob_match(pattern.as_bytes(), thread_name),
+        };
+        (self.included.is_empty() || self.included.iter().any(matches))
+            && !self.excluded.iter().any(matches)
+    }
 
-            Ok(())
+    /// Drop the stacks of the threads which aren't reported, and their CPU
+    /// times and states.
+    fn apply(
+        &self,
+        report: &mut UnresolvedReport,
+        resolved: Vec<(Frames, isize)>,
+    ) -> Vec<(Frames, isize)> {
+        if self.included.is_empty() && self.excluded.is_empty() {
+            return resolved;
         }
+
+        report
+            .data
+            .retain(|key, _| self.keep(key.thread_id, &key.thread_name[..key.thread_name_length]));
+        let resolved: Vec<_> = resolved
+            .into_iter()
+            .filter(|(key, _)| self.keep(key.thread_id, key.thread_name.as_bytes()))
+            .collect();
+
+        let threads: HashSet<u64> = report
+            .data
+            .keys()
+            .map(|key| key.thread_id)
+            .chain(resolved.iter().map(|(key, _)| key.thread_id))
+            .collect();
+        report
+            .thread_cpu_times
+            .retain(|thread_id, _| threads.contains(thread_id));
+        report
+            .thread_states
+            .retain(|thread_id, _| threads.contains(thread_id));
+
+        resolved
     }
 }
 
-#[cfg(feature = "_protobuf")]
-#[allow(clippy::useless_conversion)]
-#[allow(clippy::needless_update)]
-mod protobuf {
-    use super::*;
-    use crate::protos;
-    use std::collections::HashSet;
-    use std::time::SystemTime;
+/// The number of the `count` samples of a stack sampled from `first` to
+/// `last` which were taken from `start` until `end`, with the time of the
+/// first and the last of them, see `ReportBuilder::time_range`.
+fn clip_samples(
+    first: SystemTime,
+    last: SystemTime,
+    count: isize,
+    start: SystemTime,
+    end: SystemTime,
+) -> Option<(isize, SystemTime, SystemTime)> {
+    let mut times = sample_times(first, last, count).filter(|time| *time >= start && *time < end);
+    let first = times.next()?;
+    let (count, last) = times.fold((1, first), |(count, _), time| (count + 1, time));
 
-    const SAMPLES: &str = "samples";
-    const COUNT: &str = "count";
-    const CPU: &str = "cpu";
-    const NANOSECONDS: &str = "nanoseconds";
-    const THREAD: &str = "thread";
+    Some((count, first, last))
+}
 
-    impl Report {
-        /// `pprof` will generate google's pprof format report.
-        pub fn pprof(&self) -> crate::Result<protos::Profile> {
-            let mut dedup_str = HashSet::new();
-            for key in self.data.keys() {
-                dedup_str.insert(key.thread_name_or_id());
-                for frame in key.frames.iter() {
-                    for symbol in frame {
-                        dedup_str.insert(symbol.name());
-                        dedup_str.insert(symbol.sys_name().into_owned());
-                        dedup_str.insert(symbol.filename().into_owned());
-                    }
-                }
+/// Only keep the samples of `report` and of the kernel stacks in `resolved`
+/// taken from `start` until `end`, and narrow the timing of the report to
+/// them.
+fn clip_report(
+    report: &mut UnresolvedReport,
+    resolved: &mut Vec<(Frames, isize)>,
+    start: SystemTime,
+    end: SystemTime,
+) {
+    report.data = std::mem::take(&mut report.data)
+        .into_iter()
+        .filter_map(|(mut key, count)| {
+            let (count, first, last) = clip_samples(
+                key.sample_timestamp,
+                key.last_sample_timestamp,
+                count,
+                start,
+                end,
+            )?;
+            key.sample_timestamp = first;
+            key.last_sample_timestamp = last;
+            Some((key, count))
+        })
+        .collect();
+    resolved.retain_mut(|(key, count)| {
+        match clip_samples(
+            key.sample_timestamp,
+            key.last_sample_timestamp,
+            *count,
+            start,
+            end,
+        ) {
+            Some((clipped, first, last)) => {
+                *count = clipped;
+                key.sample_timestamp = first;
+                key.last_sample_timestamp = last;
+                true
             }
-            dedup_str.insert(SAMPLES.into());
-            dedup_str.insert(COUNT.into());
-            dedup_str.insert(CPU.into());
-            dedup_str.insert(NANOSECONDS.into());
-            dedup_str.insert(THREAD.into());
-            // string table's first element must be an empty string
-            let mut str_tbl = vec!["".to_owned()];
-            str_tbl.extend(dedup_str.into_iter());
+            None => false,
+        }
+    });
+
+    // the active part of the profile is assumed to be spread evenly too
+    let timing = &mut report.timing;
+    let profile_end = timing.start_time + timing.duration;
+    let clipped_start = start.clamp(timing.start_time, profile_end);
+    let clipped_end = end.clamp(clipped_start, profile_end);
+    let duration = clipped_end
+        .duration_since(clipped_start)
+        .unwrap_or_default();
+    if let Some(active_duration) = timing.active_duration {
+        timing.active_duration = Some(match timing.duration.as_secs_f64() {
+            total if total > 0.0 => active_duration.mul_f64(duration.as_secs_f64() / total),
+            _ => Duration::ZERO,
+        });
+    }
+    timing.start_time = clipped_start;
+    timing.duration = duration;
+}
+
+/// Cut the stacks at their first frame whose subtree has less than
+/// `min_weight` of the samples, like the frames of a flamegraph narrower than
+/// its minimum width, see `ReportBuilder::min_weight`.
+fn cut_light_frames(data: HashMap<Frames, isize>, min_weight: f64) -> HashMap<Frames, isize> {
+    let total: isize = data.values().sum();
+    let min_count = total as f64 * min_weight;
+
+    // the tree of the stacks from their thread, each node being the index of
+    // its weight, and its children keyed by the names of their functions
+    let mut nodes: HashMap<(usize, String), usize> = HashMap::new();
+    let mut weights: Vec<isize> = Vec::new();
+    let paths: Vec<(Frames, isize, Vec<usize>)> = data
+        .into_iter()
+        .map(|(key, count)| {
+            let mut parent = usize::MAX;
+            let names = std::iter::once(key.thread_name_or_id()).chain(
+                key.frames
+                    .iter()
+                    .rev()
+                    .map(|frame| frame.iter().map(|symbol| symbol.name() + ";").collect()),
+            );
+            let path: Vec<usize> = names
+                .map(|name| {
+                    let node = *nodes.entry((parent, name)).or_insert_with(|| {
+                        weights.push(0);
+                        weights.len() - 1
+                    });
+                    weights[node] += count;
+                    parent = node;
+                    node
+                })
+                .collect();
+            (key, count, path)
+        })
+        .collect();
+
+    let mut cut = HashMap::new();
+    for (mut key, count, path) in paths {
+        let depth = path
+            .iter()
+            .position(|node| (weights[*node] as f64) < min_count)
+            .unwrap_or(path.len());
+        // the thread itself is too light
+        if depth == 0 {
+            continue;
+        }
+        // the frames are stored from the leaf, below the thread
+        let frames = depth - 1;
+        key.frames.drain(..key.frames.len() - frames);
+        *cut.entry(key).or_insert(0) += count;
+    }
+
+    cut
+}
 
-            let mut strings = HashMap::new();
-            for (index, name) in str_tbl.iter().enumerate() {
-                strings.insert(name.as_str(), index);
+/// Whether `name` matches the glob `pattern`, where `*` matches any
+/// characters and `?` a single one.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // the position after the last `*`, and the position of the name it's
+    // matched up to, to backtrack to when the rest doesn't match
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(c) if *c == b'?' || *c == name[n] => {
+                p += 1;
+                n += 1;
             }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
 
-            let mut samples = vec![];
-            let mut loc_tbl = vec![];
-            let mut fn_tbl = vec![];
-            let mut functions = HashMap::new();
-            for (key, count) in self.data.iter() {
-                let mut locs = vec![];
-                for frame in key.frames.iter() {
-                    for symbol in frame {
-                        let name = symbol.name();
-                        if let Some(loc_idx) = functions.get(&name) {
-                            locs.push(*loc_idx);
-                            continue;
+/// Copy the samples out of the collector, with the earliest and the latest
+/// sample time of every entry. It's the only part of building a report which
+/// needs the profiler, so that the lock is released as early as possible.
+fn copy_samples(profiler: &Profiler) -> Result<Vec<(UnresolvedFrames, isize)>> {
+    let mut samples = Vec::new();
+    profiler.data.try_iter()?.for_each(|entry| {
+        let count = entry.count;
+        if count <= 0 {
+            return;
+        }
+
+        let mut frames = entry.item.clone();
+        if let Some(first_seen) = entry.first_seen {
+            frames.sample_timestamp = first_seen;
+        }
+        if let Some(last_seen) = entry.last_seen {
+            frames.last_sample_timestamp = last_seen;
+        }
+        samples.push((frames, count));
+    });
+
+    Ok(samples)
+}
+
+/// Aggregate the samples copied by `copy_samples` by stack.
+fn aggregate_samples(samples: Vec<(UnresolvedFrames, isize)>) -> HashMap<UnresolvedFrames, isize> {
+    let mut hash_map: HashMap<UnresolvedFrames, isize> = HashMap::with_capacity(samples.len());
+    for (mut frames, count) in samples {
+        // the same stack may be stored more than once, keep the earliest and
+        // the latest sample time of all of them
+        let count = match hash_map.remove_entry(&frames) {
+            Some((existing, existing_count)) => {
+                frames.sample_timestamp = frames.sample_timestamp.min(existing.sample_timestamp);
+                frames.last_sample_timestamp = frames
+                    .last_sample_timestamp
+                    .max(existing.last_sample_timestamp);
+                count + existing_count
+            }
+            None => count,
+        };
+        hash_map.insert(frames, count);
+    }
+
+    hash_map
+}
+
+/// Splice the functions whose demangled name contains any of `hidden` out
+/// of `frames`. A frame whose inlined functions are all hidden is removed, so
+/// that its callees are attached to its caller.
+fn hide_functions(frames: &mut Frames, hidden: &[String]) {
+    if hidden.is_empty() {
+        return;
+    }
+
+    for frame in frames.frames.iter_mut() {
+        frame.retain(|symbol| {
+            let name = symbol.name();
+            !hidden.iter().any(|hidden| name.contains(hidden.as_str()))
+        });
+    }
+    frames.frames.retain(|frame| !frame.is_empty());
+}
+
+/// A pattern of `ReportBuilder::focus` and `ReportBuilder::prune`, or of
+/// their regex variants, matched against the demangled function names.
+#[derive(Clone)]
+enum FunctionPattern {
+    Substring(String),
+    #[cfg(feature = "regex")]
+    Regex(regex::Regex),
+}
+
+impl FunctionPattern {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            FunctionPattern::Substring(pattern) => name.contains(pattern.as_str()),
+            #[cfg(feature = "regex")]
+            FunctionPattern::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+/// Whether any function of `frames` has a demangled name matching any of
+/// `patterns`.
+fn contains_functions(frames: &Frames, patterns: &[FunctionPattern]) -> bool {
+    frames.frames.iter().flatten().any(|symbol| {
+        let name = symbol.name();
+        patterns.iter().any(|pattern| pattern.matches(&name))
+    })
+}
+
+/// Whether `frames` passes through one of the `focused` functions, if any, and
+/// through none of the `pruned` functions.
+fn keep_stack(frames: &Frames, focused: &[FunctionPattern], pruned: &[FunctionPattern]) -> bool {
+    (focused.is_empty() || contains_functions(frames, focused))
+        && !contains_functions(frames, pruned)
+}
+
+/// Whether `name` is a system call wrapper of the C library, ignoring the
+/// internal prefixes of glibc, e.g. `__GI___libc_read` or `__read_nocancel`.
+fn is_syscall_wrapper(name: &str) -> bool {
+    const WRAPPERS: &[&str] = &[
+        "syscall",
+        "syscall_cancel_arch",
+        "internal_syscall_cancel",
+        "read",
+        "write",
+        "readv",
+        "writev",
+        "pread64",
+        "pwrite64",
+        "open",
+        "open64",
+        "openat",
+        "openat64",
+        "close",
+        "fsync",
+        "fdatasync",
+        "ioctl",
+        "recv",
+        "recvfrom",
+        "recvmsg",
+        "send",
+        "sendto",
+        "sendmsg",
+        "accept",
+        "accept4",
+        "connect",
+        "poll",
+        "ppoll",
+        "select",
+        "pselect6",
+        "epoll_wait",
+        "epoll_pwait",
+        "nanosleep",
+        "clock_nanosleep",
+        "futex",
+        "lll_lock_wait",
+        "wait4",
+        "waitpid",
+        "sigtimedwait",
+    ];
+
+    let mut name = name.trim_start_matches("__GI_").trim_start_matches('_');
+    name = name.strip_prefix("libc_").unwrap_or(name);
+    name = name.strip_suffix("_nocancel").unwrap_or(name);
+    WRAPPERS.contains(&name)
+}
+
+type FramesPostProcessor = Box<dyn Fn(&mut Frames)>;
+type ValueScale = Box<dyn Fn(u64) -> f64>;
+
+/// A builder of `Report` and `UnresolvedReport`. It builds report from a running `Profiler`.
+pub struct ReportBuilder<'a> {
+    frames_post_processor: Option<FramesPostProcessor>,
+    value_scale: Option<ValueScale>,
+    profiler: &'a RwLock<Result<Profiler>>,
+    timing: ReportTiming,
+    window: Option<&'a Window>,
+    threads: ThreadFilter,
+    time_range: Option<(SystemTime, SystemTime)>,
+    min_weight: f64,
+    hidden_functions: Vec<String>,
+    focused_functions: Vec<FunctionPattern>,
+    pruned_functions: Vec<FunctionPattern>,
+    symbolize: bool,
+    #[cfg(feature = "debug-info")]
+    debug_info_paths: Vec<PathBuf>,
+}
+
+impl<'a> ReportBuilder<'a> {
+    pub(crate) fn new(profiler: &'a RwLock<Result<Profiler>>, timing: ReportTiming) -> Self {
+        Self {
+            frames_post_processor: None,
+            value_scale: None,
+            profiler,
+            timing,
+            window: None,
+            threads: ThreadFilter::default(),
+            time_range: None,
+            min_weight: 0.0,
+            hidden_functions: Vec::new(),
+            focused_functions: Vec::new(),
+            pruned_functions: Vec::new(),
+            symbolize: true,
+            #[cfg(feature = "debug-info")]
+            debug_info_paths: Vec::new(),
+        }
+    }
+
+    /// Only report the samples collected since `window` started.
+    pub(crate) fn window(self, window: &'a Window) -> Self {
+        Self {
+            window: Some(window),
+            ..self
+        }
+    }
+
+    /// Set `frames_post_processor` of a `ReportBuilder`. Before finally building a report, `frames_post_processor`
+    /// will be applied to every Frames.
+    pub fn frames_post_processor<T>(&mut self, frames_post_processor: T) -> &mut Self
+    where
+        T: Fn(&mut Frames) + 'static,
+    {
+        self.frames_post_processor
+            .replace(Box::new(frames_post_processor));
+
+        self
+    }
+
+    /// Set `value_scale` of a `ReportBuilder`. The count of every sample is multiplied by the
+    /// factor returned for its thread id, e.g. to normalize profiles collected in differently
+    /// throttled containers. As the counts are integers, the scaled counts of the samples which
+    /// end up in the same stack are summed before being rounded, and the stacks whose count
+    /// rounds to zero are dropped.
+    pub fn value_scale<T>(&mut self, value_scale: T) -> &mut Self
+    where
+        T: Fn(u64) -> f64 + 'static,
+    {
+        self.value_scale.replace(Box::new(value_scale));
+
+        self
+    }
+
+    /// Only report the threads matching any of `patterns`, e.g. `grpc-server-*`.
+    /// A pattern made of digits matches the thread with this id, and any other
+    /// is a glob matched against the thread name, where `*` matches any
+    /// characters and `?` a single one. If it's called several times, the
+    /// threads matching any of the patterns are reported.
+    pub fn include_threads<T: AsRef<str>>(&mut self, patterns: &[T]) -> &mut Self {
+        self.threads
+            .included
+            .extend(patterns.iter().map(|pattern| pattern.as_ref().to_owned()));
+
+        self
+    }
+
+    /// Don't report the threads matching any of `patterns`, with the same
+    /// patterns as `include_threads`. It's applied after `include_threads`.
+    pub fn exclude_threads<T: AsRef<str>>(&mut self, patterns: &[T]) -> &mut Self {
+        self.threads
+            .excluded
+            .extend(patterns.iter().map(|pattern| pattern.as_ref().to_owned()));
+
+        self
+    }
+
+    /// Only report the samples taken from `start` until `end`, e.g. to isolate
+    /// a latency spike within a long profile. As only the first and the last
+    /// sample of a stack are timestamped, its samples are assumed to be spread
+    /// evenly between them, so the counts are an estimate unless the stack
+    /// was sampled steadily. The CPU times, the states of the threads and the
+    /// dropped samples still cover the whole profile.
+    pub fn time_range(&mut self, start: SystemTime, end: SystemTime) -> &mut Self {
+        self.time_range = Some((start, end));
+
+        self
+    }
+
+    /// Hide the functions whose demangled name contains any of `names`, e.g.
+    /// allocator internals or lock primitives, like `pprof -hide`. They are
+    /// spliced out of every stack instead of dropping the samples: the
+    /// samples of a hidden leaf are attributed to its caller, and the callees
+    /// of a hidden function are attached to its caller. It's applied before
+    /// `frames_post_processor`, and only to `build`, as the stacks of an
+    /// `UnresolvedReport` have no names.
+    pub fn hide_functions<T: AsRef<str>>(&mut self, names: &[T]) -> &mut Self {
+        self.hidden_functions
+            .extend(names.iter().map(|name| name.as_ref().to_owned()));
+
+        self
+    }
+
+    /// Only keep the stacks which pass through a function whose demangled name
+    /// contains `name`, like `pprof -focus`. If it's called several times, the
+    /// stacks passing through any of the names are kept. It's applied after
+    /// `hide_functions`, and only to `build`.
+    pub fn focus(&mut self, name: &str) -> &mut Self {
+        self.focused_functions
+            .push(FunctionPattern::Substring(name.to_owned()));
+
+        self
+    }
+
+    /// Drop the stacks which pass through a function whose demangled name
+    /// contains `name`. It's applied after `hide_functions` and `focus`, and
+    /// only to `build`.
+    pub fn prune(&mut self, name: &str) -> &mut Self {
+        self.pruned_functions
+            .push(FunctionPattern::Substring(name.to_owned()));
+
+        self
+    }
+
+    /// Only keep the stacks which pass through a function whose demangled name
+    /// matches `regex`, like the `-focus` of `pprof`, whose patterns are
+    /// regular expressions. It's combined with `focus`: the stacks matching
+    /// any of their patterns are kept. **only available with `regex`
+    /// feature**
+    #[cfg(feature = "regex")]
+    pub fn focus_regex(&mut self, regex: regex::Regex) -> &mut Self {
+        self.focused_functions.push(FunctionPattern::Regex(regex));
+
+        self
+    }
+
+    /// Drop the stacks which pass through a function whose demangled name
+    /// matches `regex`, like the `-ignore` of `pprof`. It's combined with
+    /// `prune`, and applied after `focus` and `focus_regex`. **only available
+    /// with `regex` feature**
+    #[cfg(feature = "regex")]
+    pub fn ignore_regex(&mut self, regex: regex::Regex) -> &mut Self {
+        self.pruned_functions.push(FunctionPattern::Regex(regex));
+
+        self
+    }
+
+    /// Cut the stacks at the first frame whose callees, itself included, have
+    /// less than `fraction` of the samples, e.g. 0.001 for 0.1%, so that the
+    /// flamegraphs of large services only show the frames wide enough to
+    /// read. The samples of a cut frame are attributed to its caller, and the
+    /// stacks of a thread with less than `fraction` of the samples are
+    /// dropped. It's applied last, and only to `build`.
+    pub fn min_weight(&mut self, fraction: f64) -> &mut Self {
+        self.min_weight = fraction;
+
+        self
+    }
+
+    /// Whether the frames are symbolicated, `true` by default. Without
+    /// symbolication, every frame is a nameless symbol at the address of its
+    /// instruction, i.e. the interrupted one for the leaf and the call for the
+    /// others, and the `pprof` profile has the addresses and the mappings of
+    /// the modules, with their build ids, for `pprof` to symbolicate them
+    /// later, e.g. on a machine with the debug info of a stripped binary. The
+    /// options which match the names of the functions then match no frame.
+    pub fn symbolize(&mut self, symbolize: bool) -> &mut Self {
+        self.symbolize = symbolize;
+
+        self
+    }
+
+    /// Add a directory to search for the debug info of the modules, used for
+    /// the frames which can't be resolved otherwise, e.g. in stripped
+    /// binaries, or only from the symbol table, without their lines and
+    /// inlined functions. A module is looked up by its build id (in
+    /// `.build-id/xx/yyyy.debug`), its `.gnu_debuglink`, and its file name
+    /// with or without a `.debug` extension. The modules which aren't found
+    /// there are looked up where gdb looks for them: by build id in
+    /// `/usr/lib/debug`, and by `.gnu_debuglink` next to the module, in its
+    /// `.debug` directory and in `/usr/lib/debug`. With the `debuginfod`
+    /// feature, the modules which aren't found are downloaded by their build
+    /// id from the servers in `DEBUGINFOD_URLS`, if it's set.
+    /// **only available with `debug-info` feature**
+    #[cfg(feature = "debug-info")]
+    pub fn debug_info_path<P: Into<PathBuf>>(&mut self, path: P) -> &mut Self {
+        self.debug_info_paths.push(path.into());
+
+        self
+    }
+
+    fn scale(&self, thread_id: u64, count: isize) -> f64 {
+        match &self.value_scale {
+            Some(value_scale) => count as f64 * value_scale(thread_id),
+            None => count as f64,
+        }
+    }
+
+    /// Copy the samples out of the profiler. The lock is only held for the
+    /// copy, so that the aggregation, the symbolication and the user
+    /// callbacks which follow neither make the signal handler drop samples
+    /// nor leave the profiler in an inconsistent state if they panic. As it's
+    /// a read lock, several reports can be built concurrently. The counts
+    /// aren't scaled yet. The kernel stacks are returned aside, as they are
+    /// already resolved.
+    fn snapshot(&self) -> Result<(UnresolvedReport, Vec<(Frames, isize)>)> {
+        // nothing was sampled, and the profiler must not be initialized
+        if cfg!(feature = "disabled") {
+            return Ok((
+                UnresolvedReport {
+                    data: HashMap::new(),
+                    timing: self.timing.clone(),
+                    collector_full: false,
+                    thread_cpu_times: HashMap::new(),
+                    thread_states: HashMap::new(),
+                    rate_limited_samples: 0,
+                    shallow_samples: 0,
+                },
+                Vec::new(),
+            ));
+        }
+
+        let (samples, mut report, resolved) = match self.profiler.read().as_ref() {
+            Err(err) => {
+                log::error!("Error in creating profiler: {}", err);
+                return Err(Error::CreatingError);
+            }
+            Ok(profiler) => {
+                profiler.warn_collector_full();
+                let samples = copy_samples(profiler)?;
+
+                #[cfg(all(feature = "kernel-stack", target_os = "linux"))]
+                let resolved = profiler.kernel_stacks.frames().collect();
+                #[cfg(not(all(feature = "kernel-stack", target_os = "linux")))]
+                let resolved = Vec::new();
+
+                let report = UnresolvedReport {
+                    data: HashMap::new(),
+                    timing: self.timing.clone(),
+                    collector_full: profiler.collector_full(),
+                    thread_cpu_times: profiler.thread_cpu_times.iter().collect(),
+                    #[cfg(target_os = "linux")]
+                    thread_states: profiler.thread_states.iter().collect(),
+                    #[cfg(not(target_os = "linux"))]
+                    thread_states: HashMap::new(),
+                    rate_limited_samples: profiler.rate_limited_samples.load(Ordering::Relaxed),
+                    shallow_samples: profiler.shallow_samples.load(Ordering::Relaxed),
+                };
+                (samples, report, resolved)
+            }
+        };
+
+        report.data = aggregate_samples(samples);
+        if let Some(window) = self.window {
+            window.apply(&mut report);
+        }
+        let mut resolved = self.threads.apply(&mut report, resolved);
+        if let Some((start, end)) = self.time_range {
+            clip_report(&mut report, &mut resolved, start, end);
+        }
+
+        Ok((report, resolved))
+    }
+
+    /// Build an `UnresolvedReport`
+    pub fn build_unresolved(&self) -> Result<UnresolvedReport> {
+        let (mut report, _) = self.snapshot()?;
+        report.data.retain(|key, count| {
+            *count = self.scale(key.thread_id, *count).round() as isize;
+            *count != 0
+        });
+
+        Ok(report)
+    }
+
+    /// Build a `Report`.
+    pub fn build(&self) -> Result<Report> {
+        let (mut unresolved, resolved) = self.snapshot()?;
+        let data = std::mem::take(&mut unresolved.data);
+        let frames = self.symbolication().resolve(data, resolved);
+
+        Ok(self.finish(unresolved, frames))
+    }
+
+    /// Build a `Report` per sampled thread, see `Report::split_threads`. The
+    /// stacks of all the reports start with their thread as root frame, so a
+    /// single `build` already renders a flamegraph per thread side by side.
+    pub fn build_per_thread(&self) -> Result<Vec<Report>> {
+        Ok(self.build()?.split_threads())
+    }
+
+    /// Build a `Report`, symbolicating the stacks on a background thread.
+    /// The samples are copied out of the profiler before it returns, so the
+    /// report only covers the samples taken until then, and the returned
+    /// future resolves once the symbolication, which is the expensive part of
+    /// `build`, is done. `frames_post_processor` and `value_scale` are applied
+    /// on the thread polling the future. It doesn't depend on any async
+    /// runtime.
+    pub fn build_async(&self) -> impl Future<Output = Result<Report>> + '_ {
+        let started = self.snapshot().and_then(|(mut unresolved, resolved)| {
+            let data = std::mem::take(&mut unresolved.data);
+            let symbolizing = Symbolizing::spawn(self.symbolication(), data, resolved)?;
+            Ok((unresolved, symbolizing))
+        });
+
+        async move {
+            let (unresolved, symbolizing) = started?;
+            let frames = symbolizing.await?;
+            Ok(self.finish(unresolved, frames))
+        }
+    }
+
+    fn symbolication(&self) -> Symbolication {
+        Symbolication {
+            hidden_functions: self.hidden_functions.clone(),
+            focused_functions: self.focused_functions.clone(),
+            pruned_functions: self.pruned_functions.clone(),
+            symbolize: self.symbolize,
+            #[cfg(feature = "debug-info")]
+            debug_info_paths: self.debug_info_paths.clone(),
+        }
+    }
+
+    /// Apply `frames_post_processor` and `value_scale` to the symbolicated
+    /// stacks, and merge the ones which became identical.
+    fn finish(&self, unresolved: UnresolvedReport, frames: Vec<(Frames, isize)>) -> Report {
+        let mut weights = HashMap::new();
+        for (mut key, count) in frames {
+            if let Some(processor) = &self.frames_post_processor {
+                processor(&mut key);
+            }
+            let weight = self.scale(key.thread_id, count);
+            *weights.entry(key).or_insert(0.0) += weight;
+        }
+        // rounded once per stack, so that the samples which are scaled down
+        // add up instead of all rounding to zero
+        let mut hash_map: HashMap<_, _> = weights
+            .into_iter()
+            .map(|(key, weight)| (key, weight.round() as isize))
+            .filter(|(_, count)| *count != 0)
+            .collect();
+        if self.min_weight > 0.0 {
+            hash_map = cut_light_frames(hash_map, self.min_weight);
+        }
+
+        Report {
+            data: hash_map,
+            timing: unresolved.timing,
+            collector_full: unresolved.collector_full,
+            thread_cpu_times: unresolved.thread_cpu_times,
+            thread_states: unresolved.thread_states,
+            rate_limited_samples: unresolved.rate_limited_samples,
+            shallow_samples: unresolved.shallow_samples,
+        }
+    }
+}
+
+/// The options of a `ReportBuilder` which apply to the symbolication, owned so
+/// that it can run on another thread.
+struct Symbolication {
+    hidden_functions: Vec<String>,
+    focused_functions: Vec<FunctionPattern>,
+    pruned_functions: Vec<FunctionPattern>,
+    symbolize: bool,
+    #[cfg(feature = "debug-info")]
+    debug_info_paths: Vec<PathBuf>,
+}
+
+impl Symbolication {
+    /// Resolve the stacks, hide the hidden functions, and drop the stacks
+    /// which are filtered out by `focus` and `prune`. The kernel stacks in
+    /// `resolved` are only filtered.
+    fn resolve(
+        &self,
+        data: HashMap<UnresolvedFrames, isize>,
+        resolved: Vec<(Frames, isize)>,
+    ) -> Vec<(Frames, isize)> {
+        #[cfg(feature = "debug-info")]
+        let mut debug_info = DebugInfo::new(&self.debug_info_paths);
+
+        data.into_iter()
+            .map(|(key, count)| {
+                if !self.symbolize {
+                    return (Frames::unsymbolized(key), count);
+                }
+
+                #[cfg(feature = "debug-info")]
+                let frames = Frames::resolve(key, |ip| debug_info.resolve(ip));
+                #[cfg(not(feature = "debug-info"))]
+                let frames = Frames::from(key);
+
+                (frames, count)
+            })
+            .chain(resolved)
+            .filter_map(|(mut key, count)| {
+                hide_functions(&mut key, &self.hidden_functions);
+                keep_stack(&key, &self.focused_functions, &self.pruned_functions)
+                    .then_some((key, count))
+            })
+            .collect()
+    }
+}
+
+/// The state shared between a `Symbolizing` future and its thread.
+#[derive(Default)]
+struct SymbolizingState {
+    frames: Option<Vec<(Frames, isize)>>,
+    done: bool,
+    waker: Option<Waker>,
+}
+
+/// Marks the symbolication done and wakes the future when the thread exits,
+/// even if it panicked.
+struct SymbolizingDone(Arc<Mutex<SymbolizingState>>);
+
+impl Drop for SymbolizingDone {
+    fn drop(&mut self) {
+        let waker = {
+            let mut state = self.0.lock();
+            state.done = true;
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// A future resolved with the stacks symbolicated by a background thread.
+struct Symbolizing {
+    state: Arc<Mutex<SymbolizingState>>,
+}
+
+impl Symbolizing {
+    fn spawn(
+        symbolication: Symbolication,
+        data: HashMap<UnresolvedFrames, isize>,
+        resolved: Vec<(Frames, isize)>,
+    ) -> Result<Self> {
+        let state = Arc::new(Mutex::new(SymbolizingState::default()));
+        let done = SymbolizingDone(state.clone());
+        std::thread::Builder::new()
+            .name("pprof-symbolize".to_owned())
+            .spawn(move || {
+                let done = done;
+                let frames = symbolication.resolve(data, resolved);
+                done.0.lock().frames = Some(frames);
+            })?;
+
+        Ok(Self { state })
+    }
+}
+
+impl Future for Symbolizing {
+    type Output = Result<Vec<(Frames, isize)>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        if let Some(frames) = state.frames.take() {
+            return Poll::Ready(Ok(frames));
+        }
+        if state.done {
+            return Poll::Ready(Err(Error::SymbolizingPanicked));
+        }
+        state.waker = Some(cx.waker().clone());
+
+        Poll::Pending
+    }
+}
+
+impl Report {
+    /// Aggregate the reports of several time windows or processes into one.
+    /// The counts of the same stack on the same thread are summed, however
+    /// far apart it was sampled, and so are the samples of the threads. The
+    /// merged report spans from the earliest start to the latest end of the
+    /// reports, and its active duration is the sum of theirs. Its frequency
+    /// is the one of the first report, as the counts of reports sampled at
+    /// different frequencies don't weigh the same.
+    pub fn merge<I: IntoIterator<Item = Report>>(reports: I) -> Report {
+        let mut merged = Report {
+            data: HashMap::new(),
+            timing: ReportTiming::default(),
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+        // the stacks without their timestamps, with their count and the
+        // first and last of them
+        let mut stacks: HashMap<Frames, (isize, SystemTime, SystemTime)> = HashMap::new();
+        let mut end_time = None;
+        let mut active_duration = Duration::ZERO;
+
+        for (i, report) in reports.into_iter().enumerate() {
+            if i == 0 {
+                merged.timing.frequency = report.timing.frequency;
+                merged.timing.start_time = report.timing.start_time;
+            }
+            merged.timing.start_time = merged.timing.start_time.min(report.timing.start_time);
+            end_time = end_time.max(Some(report.end_time()));
+            active_duration += report.active_duration();
+
+            merged.collector_full |= report.collector_full;
+            merged.rate_limited_samples += report.rate_limited_samples;
+            merged.shallow_samples += report.shallow_samples;
+            for (thread_id, times) in report.thread_cpu_times {
+                let merged = merged
+                    .thread_cpu_times
+                    .entry(thread_id)
+                    .or_insert(ThreadCpuTime {
+                        samples: 0,
+                        ..times
+                    });
+                merged.samples += times.samples;
+                merged.first = merged.first.min(times.first);
+                merged.last = merged.last.max(times.last);
+            }
+            for (thread_id, states) in report.thread_states {
+                let merged = merged.thread_states.entry(thread_id).or_default();
+                merged.running += states.running;
+                merged.sleeping += states.sleeping;
+                merged.disk_sleep += states.disk_sleep;
+                merged.stopped += states.stopped;
+                merged.other += states.other;
+                merged.unknown += states.unknown;
+            }
+
+            for (mut key, count) in report.data {
+                let (first, last) = (key.sample_timestamp, key.last_sample_timestamp);
+                key.sample_timestamp = SystemTime::UNIX_EPOCH;
+                key.last_sample_timestamp = SystemTime::UNIX_EPOCH;
+                let stack = stacks.entry(key).or_insert((0, first, last));
+                stack.0 += count;
+                stack.1 = stack.1.min(first);
+                stack.2 = stack.2.max(last);
+            }
+        }
+
+        if let Some(end_time) = end_time {
+            merged.timing.duration = end_time
+                .duration_since(merged.timing.start_time)
+                .unwrap_or_default();
+            merged.timing.active_duration = Some(active_duration);
+        }
+        merged.data = stacks
+            .into_iter()
+            .map(|(mut key, (count, first, last))| {
+                key.sample_timestamp = first;
+                key.last_sample_timestamp = last;
+                (key, count)
+            })
+            .collect();
+
+        merged
+    }
+}
+
+/// Add `(stack, count)` pairs to the report, e.g. to replay or merge recorded
+/// profiles. The counts of the stacks already in the report are summed.
+impl Extend<(Frames, isize)> for Report {
+    fn extend<I: IntoIterator<Item = (Frames, isize)>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.data.reserve(iter.size_hint().0);
+        for (key, count) in iter {
+            *self.data.entry(key).or_insert(0) += count;
+        }
+    }
+}
+
+/// Add `(stack, count)` pairs to the report, e.g. to replay or merge recorded
+/// profiles. The counts of the stacks already in the report are summed.
+impl Extend<(UnresolvedFrames, isize)> for UnresolvedReport {
+    fn extend<I: IntoIterator<Item = (UnresolvedFrames, isize)>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.data.reserve(iter.size_hint().0);
+        for (key, count) in iter {
+            *self.data.entry(key).or_insert(0) += count;
+        }
+    }
+}
+
+/// This will generate Report in a human-readable format:
+///
+/// ```shell
+/// FRAME: pprof::profiler::perf_signal_handler::h7b995c4ab2e66493 -> FRAME: Unknown -> FRAME: {func1} ->
+/// FRAME: {func2} -> FRAME: {func3} ->  THREAD: {thread_name} {count}
+/// ```
+impl Debug for Report {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        for (key, val) in self.sorted_data() {
+            write!(f, "{:?} {}", key, val)?;
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The stack of `key` in the collapsed stack format, without the count: the
+/// thread and the frames from the root separated by `;`.
+pub(crate) fn folded_stack(key: &Frames) -> String {
+    fold_stack(key, |symbol| symbol.to_string())
+}
+
+/// Same as `folded_stack`, but the frames are followed by their file and line
+/// when they are known, e.g. `main;work (src/main.rs:12)`, so that the calls
+/// from different lines of a function are separate frames.
+pub(crate) fn folded_stack_with_lines(key: &Frames) -> String {
+    fold_stack(key, |symbol| match (&symbol.filename, symbol.lineno) {
+        (Some(filename), Some(lineno)) => {
+            format!("{} ({}:{})", symbol, filename.display(), lineno)
+        }
+        _ => symbol.to_string(),
+    })
+}
+
+fn fold_stack<F>(key: &Frames, mut name: F) -> String
+where
+    F: FnMut(&Symbol) -> String,
+{
+    let mut line = key.thread_name_or_id();
+
+    // the outermost frames are the ones lost to truncation, so mark the spot
+    // right below the deepest frame we still have
+    if key.truncated {
+        line.push_str(";[truncated]");
+    }
+
+    for frame in key.frames.iter().rev() {
+        for symbol in frame.iter().rev() {
+            line.push(';');
+            line.push_str(&name(symbol));
+        }
+    }
+
+    line
+}
+
+/// The times of the `count` samples of a stack sampled from `first` to `last`.
+/// Only the first and the last sample of a stack are timestamped, so the
+/// others are spread evenly between them.
+fn sample_times(
+    first: SystemTime,
+    last: SystemTime,
+    count: isize,
+) -> impl Iterator<Item = SystemTime> {
+    let span = last.duration_since(first).unwrap_or_default();
+    let count = count.max(0) as u32;
+    (0..count).map(move |i| match count {
+        1 => first,
+        _ => first + span / (count - 1) * i,
+    })
+}
+
+/// Write a file through `write` into a temporary file next to `path`, and
+/// rename it to `path` once it's complete, so that `path` never holds a
+/// partially written file, even if the process dies in the middle.
+#[cfg(any(feature = "flamegraph", feature = "_protobuf"))]
+fn write_atomically<F>(path: &std::path::Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut std::fs::File) -> Result<()>,
+{
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    write(file.as_file_mut())?;
+    file.as_file().sync_all()?;
+    file.persist(path).map_err(|err| err.error)?;
+
+    Ok(())
+}
+
+#[cfg(feature = "flamegraph")]
+mod flamegraph {
+    use super::*;
+    use inferno::{differential, flamegraph};
+
+    impl Report {
+        /// `flamegraph` will write an svg flamegraph into `writer` **only available with `flamegraph` feature**
+        pub fn flamegraph<W>(&self, writer: W) -> Result<()>
+        where
+            W: std::io::Write,
+        {
+            self.flamegraph_with_options(writer, &mut flamegraph::Options::default())
+        }
+
+        /// same as `flamegraph`, but returns the svg as bytes instead of writing it into a `writer`
+        pub fn flamegraph_to_vec(&self) -> Result<Vec<u8>> {
+            let mut svg = Vec::new();
+            self.flamegraph(&mut svg)?;
+
+            Ok(svg)
+        }
+
+        /// same as `flamegraph`, but returns the svg as a `String` instead of writing it into a
+        /// `writer`
+        pub fn flamegraph_svg(&self) -> Result<String> {
+            String::from_utf8(self.flamegraph_to_vec()?).map_err(|err| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, err.utf8_error()).into()
+            })
+        }
+
+        /// same as `flamegraph`, but writes the svg into the file at `path`. The
+        /// file is replaced atomically, so it's either absent, the previous
+        /// one, or complete.
+        pub fn write_flamegraph_to_file<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+            write_atomically(path.as_ref(), |file| {
+                let mut writer = std::io::BufWriter::new(file);
+                self.flamegraph(&mut writer)?;
+                std::io::Write::flush(&mut writer)?;
+
+                Ok(())
+            })
+        }
+
+        /// same as `flamegraph`, but accepts custom `options` for the flamegraph
+        pub fn flamegraph_with_options<W>(
+            &self,
+            writer: W,
+            options: &mut flamegraph::Options,
+        ) -> Result<()>
+        where
+            W: std::io::Write,
+        {
+            let lines = self.folded_lines();
+            if !lines.is_empty() {
+                flamegraph::from_lines(options, lines.iter().map(|s| &**s), writer).unwrap();
+                // TODO: handle this error
+            }
+
+            Ok(())
+        }
+
+        /// same as `flamegraph_with_options`, but the frames are annotated
+        /// with their file and line when the debug info has them, e.g.
+        /// `work (src/main.rs:12)`, like the `-lines` views of `pprof`. The
+        /// calls from different lines of a function are then separate frames,
+        /// see `folded_with_lines`.
+        pub fn flamegraph_with_lines<W>(
+            &self,
+            writer: W,
+            options: &mut flamegraph::Options,
+        ) -> Result<()>
+        where
+            W: std::io::Write,
+        {
+            let lines = self.folded_lines_with(folded_stack_with_lines);
+            if !lines.is_empty() {
+                flamegraph::from_lines(options, lines.iter().map(|s| &**s), writer).map_err(
+                    |err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()),
+                )?;
+            }
+
+            Ok(())
+        }
+
+        /// `flamechart` will write an svg flamechart into `writer`: unlike the
+        /// flamegraph, the x-axis is the time, so the stacks aren't merged
+        /// across the run but laid out in the order they were sampled, with a
+        /// separate chart per thread. As only the first and the last sample of
+        /// a stack are timestamped, its other samples are spread evenly between
+        /// them. **only available with `flamegraph` feature**
+        pub fn flamechart<W>(&self, writer: W) -> Result<()>
+        where
+            W: std::io::Write,
+        {
+            self.flamechart_with_options(writer, &mut flamegraph::Options::default())
+        }
+
+        /// same as `flamechart`, but accepts custom `options` for the
+        /// flamechart, whose `flame_chart` is always set
+        pub fn flamechart_with_options<W>(
+            &self,
+            writer: W,
+            options: &mut flamegraph::Options,
+        ) -> Result<()>
+        where
+            W: std::io::Write,
+        {
+            // keep the threads in the order they appear for the first time
+            let mut threads: Vec<(u64, Vec<(SystemTime, String)>)> = Vec::new();
+            for (key, count) in self.sorted_data() {
+                let samples = match threads.iter().position(|(id, _)| *id == key.thread_id) {
+                    Some(position) => &mut threads[position].1,
+                    None => {
+                        threads.push((key.thread_id, Vec::new()));
+                        &mut threads.last_mut().unwrap().1
+                    }
+                };
+                let stack = folded_stack(key);
+                samples.extend(
+                    sample_times(key.sample_timestamp, key.last_sample_timestamp, count)
+                        .map(|time| (time, stack.clone())),
+                );
+            }
+
+            // the consecutive samples of a stack are a single line
+            let mut lines: Vec<(String, usize)> = Vec::new();
+            for (_, mut samples) in threads {
+                samples.sort_by_key(|(time, _)| *time);
+                for (_, stack) in samples {
+                    match lines.last_mut() {
+                        Some((last, count)) if *last == stack => *count += 1,
+                        _ => lines.push((stack, 1)),
+                    }
+                }
+            }
+            // inferno expects the latest sample first, as perf writes them
+            let lines: Vec<String> = lines
+                .into_iter()
+                .rev()
+                .map(|(stack, count)| format!("{} {}", stack, count))
+                .collect();
+
+            options.flame_chart = true;
+            if !lines.is_empty() {
+                flamegraph::from_lines(options, lines.iter().map(|s| &**s), writer).map_err(
+                    |err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()),
+                )?;
+            }
+
+            Ok(())
+        }
+
+        /// `flamegraph_diff` will write a differential svg flamegraph into
+        /// `writer`: the frames are the ones of this report, colored red where
+        /// they have more samples than in `baseline` and blue where they have
+        /// fewer. **only available with `flamegraph` feature**
+        pub fn flamegraph_diff<W>(&self, baseline: &Report, writer: W) -> Result<()>
+        where
+            W: std::io::Write,
+        {
+            self.flamegraph_diff_with_options(
+                baseline,
+                writer,
+                differential::Options::default(),
+                &mut flamegraph::Options::default(),
+            )
+        }
+
+        /// same as `flamegraph_diff`, but accepts custom `diff_options`, e.g.
+        /// to normalize the samples of `baseline` to the ones of this report
+        /// when they ran for different durations, and custom `options` for the
+        /// flamegraph
+        pub fn flamegraph_diff_with_options<W>(
+            &self,
+            baseline: &Report,
+            writer: W,
+            diff_options: differential::Options,
+            options: &mut flamegraph::Options,
+        ) -> Result<()>
+        where
+            W: std::io::Write,
+        {
+            let before = baseline.folded_lines().join("\n");
+            let after = self.folded_lines().join("\n");
+            let mut lines = Vec::new();
+            differential::from_readers(
+                diff_options,
+                before.as_bytes(),
+                after.as_bytes(),
+                &mut lines,
+            )?;
+            if !lines.is_empty() {
+                flamegraph::from_reader(options, &lines[..], writer).map_err(|err| {
+                    std::io::Error::new(std::io::ErrorKind::Other, err.to_string())
+                })?;
+            }
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "speedscope")]
+mod speedscope {
+    use super::*;
+    use serde_json::{json, Value};
+
+    const SCHEMA: &str = "https://www.speedscope.app/file-format-schema.json";
+
+    /// The stacks of a thread, as indexes into the shared frames from the
+    /// root, with their weight in nanoseconds and the time of their first
+    /// sample.
+    struct Thread {
+        id: u64,
+        name: String,
+        stacks: Vec<(Vec<usize>, i64, SystemTime)>,
+    }
+
+    impl Report {
+        /// `speedscope` will write the report in the "sampled" profile format of
+        /// [speedscope](https://www.speedscope.app), with one profile per thread.
+        /// **only available with `speedscope` feature**
+        pub fn speedscope<W>(&self, writer: W) -> Result<()>
+        where
+            W: std::io::Write,
+        {
+            let (frames, threads) = self.speedscope_threads();
+            let profiles = threads
+                .into_iter()
+                .map(|thread| {
+                    let (samples, weights): (Vec<_>, Vec<_>) = thread
+                        .stacks
+                        .into_iter()
+                        .map(|(stack, weight, _)| (stack, weight))
+                        .unzip();
+                    let total: i64 = weights.iter().sum();
+                    json!({
+                        "type": "sampled",
+                        "name": thread.name,
+                        "unit": "nanoseconds",
+                        "startValue": 0,
+                        "endValue": total,
+                        "samples": samples,
+                        "weights": weights,
+                    })
+                })
+                .collect();
+
+            write_speedscope(writer, frames, profiles)
+        }
+
+        /// `speedscope_evented` will write the report in the "evented" profile
+        /// format of [speedscope](https://www.speedscope.app), with one profile
+        /// per thread. The samples of a stack aren't timestamped one by one,
+        /// so the stacks are laid out one after the other in the order of
+        /// their first sample, each lasting for the time of its samples: the
+        /// timeline only approximates the actual order of the calls.
+        /// **only available with `speedscope` feature**
+        pub fn speedscope_evented<W>(&self, writer: W) -> Result<()>
+        where
+            W: std::io::Write,
+        {
+            let (frames, threads) = self.speedscope_threads();
+            let profiles = threads
+                .into_iter()
+                .map(|mut thread| {
+                    // stable, so that the stacks sampled at the same time stay
+                    // in the order of the report
+                    thread.stacks.sort_by_key(|(_, _, first)| *first);
+
+                    let mut events = Vec::new();
+                    let mut open: Vec<usize> = Vec::new();
+                    let mut at = 0;
+                    for (stack, weight, _) in thread.stacks {
+                        let common = open
+                            .iter()
+                            .zip(&stack)
+                            .take_while(|(open, frame)| open == frame)
+                            .count();
+                        while open.len() > common {
+                            let frame = open.pop().unwrap();
+                            events.push(json!({ "type": "C", "frame": frame, "at": at }));
+                        }
+                        for frame in &stack[common..] {
+                            events.push(json!({ "type": "O", "frame": frame, "at": at }));
+                        }
+                        open = stack;
+                        at += weight;
+                    }
+                    while let Some(frame) = open.pop() {
+                        events.push(json!({ "type": "C", "frame": frame, "at": at }));
+                    }
+
+                    json!({
+                        "type": "evented",
+                        "name": thread.name,
+                        "unit": "nanoseconds",
+                        "startValue": 0,
+                        "endValue": at,
+                        "events": events,
+                    })
+                })
+                .collect();
+
+            write_speedscope(writer, frames, profiles)
+        }
+
+        /// The shared frames, and the stacks of every thread in the order
+        /// they appear for the first time in the report. The functions are
+        /// told apart by their file and line too, and the threads by their
+        /// id, the threads which share a name being suffixed with their id.
+        fn speedscope_threads(&self) -> (Vec<Value>, Vec<Thread>) {
+            let mut frames = Vec::new();
+            let mut frame_index = HashMap::new();
+            let mut threads: Vec<Thread> = Vec::new();
+
+            let nanos_per_sample = 1_000_000_000 / self.timing.frequency.max(1) as i64;
+            for (key, count) in self.sorted_data() {
+                let mut stack = Vec::new();
+                // speedscope expects the stack to start from the root
+                for frame in key.frames.iter().rev() {
+                    for symbol in frame.iter().rev() {
+                        let frame_key = (
+                            symbol.to_string(),
+                            symbol.filename().into_owned(),
+                            symbol.lineno(),
+                        );
+                        let index = *frame_index.entry(frame_key).or_insert_with_key(|key| {
+                            frames.push(json!({
+                                "name": key.0,
+                                "file": key.1,
+                                "line": key.2,
+                            }));
+                            frames.len() - 1
+                        });
+                        stack.push(index);
+                    }
+                }
+
+                let thread = match threads.iter().position(|thread| thread.id == key.thread_id) {
+                    Some(position) => &mut threads[position],
+                    None => {
+                        threads.push(Thread {
+                            id: key.thread_id,
+                            name: key.thread_name_or_id(),
+                            stacks: Vec::new(),
+                        });
+                        threads.last_mut().unwrap()
+                    }
+                };
+                thread
+                    .stacks
+                    .push((stack, count as i64 * nanos_per_sample, key.sample_timestamp));
+            }
+
+            let mut names: HashMap<String, usize> = HashMap::new();
+            for thread in threads.iter() {
+                *names.entry(thread.name.clone()).or_default() += 1;
+            }
+            for thread in threads.iter_mut() {
+                if names[&thread.name] > 1 {
+                    thread.name = format!("{} ({})", thread.name, thread.id);
+                }
+            }
+
+            (frames, threads)
+        }
+    }
+
+    fn write_speedscope<W>(writer: W, frames: Vec<Value>, profiles: Vec<Value>) -> Result<()>
+    where
+        W: std::io::Write,
+    {
+        let file = json!({
+            "$schema": SCHEMA,
+            "shared": { "frames": frames },
+            "profiles": profiles,
+            "name": "pprof-rs",
+            "activeProfileIndex": 0,
+            "exporter": format!("pprof-rs {}", env!("CARGO_PKG_VERSION")),
+        });
+
+        serde_json::to_writer(writer, &file).map_err(std::io::Error::from)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "gecko")]
+mod gecko {
+    use super::*;
+    use serde_json::{json, Value};
+
+    // the version of the Gecko profile format, the Firefox Profiler upgrades
+    // the older versions when it loads them
+    const GECKO_PROFILE_VERSION: u32 = 24;
+
+    /// The tables of a thread of the Gecko profile, where every frame and
+    /// stack is stored once, a stack being its innermost frame and the index
+    /// of its caller's stack.
+    #[derive(Default)]
+    struct Thread {
+        name: String,
+        strings: Vec<String>,
+        string_index: HashMap<String, usize>,
+        frames: Vec<Value>,
+        frame_index: HashMap<(usize, Option<u32>), usize>,
+        stacks: Vec<Value>,
+        stack_index: HashMap<(Option<usize>, usize), usize>,
+        // the stack and the time in milliseconds of every sample
+        samples: Vec<(usize, f64)>,
+    }
+
+    impl Thread {
+        fn string(&mut self, string: String) -> usize {
+            if let Some(index) = self.string_index.get(&string) {
+                return *index;
+            }
+            self.strings.push(string.clone());
+            self.string_index.insert(string, self.strings.len() - 1);
+            self.strings.len() - 1
+        }
+
+        fn frame(&mut self, symbol: &crate::Symbol) -> usize {
+            let location = self.string(symbol.to_string());
+            let line = symbol.lineno;
+            let frames = &mut self.frames;
+            *self.frame_index.entry((location, line)).or_insert_with(|| {
+                // location, relevantForJS, innerWindowID, implementation,
+                // optimizations, line, column, category, subcategory
+                frames.push(json!([location, false, 0, null, null, line, null, 0, 0]));
+                frames.len() - 1
+            })
+        }
+
+        fn stack(&mut self, prefix: Option<usize>, frame: usize) -> usize {
+            let stacks = &mut self.stacks;
+            *self.stack_index.entry((prefix, frame)).or_insert_with(|| {
+                stacks.push(json!([prefix, frame]));
+                stacks.len() - 1
+            })
+        }
+
+        fn into_json(mut self, thread_id: u64) -> Value {
+            self.samples.sort_by(|a, b| a.1.total_cmp(&b.1));
+            let samples: Vec<Value> = self
+                .samples
+                .iter()
+                .map(|(stack, time)| json!([stack, time, 0.0]))
+                .collect();
+
+            json!({
+                "name": self.name,
+                "processType": "default",
+                "registerTime": 0.0,
+                "unregisterTime": null,
+                "pid": std::process::id(),
+                "tid": thread_id,
+                "samples": {
+                    "schema": { "stack": 0, "time": 1, "eventDelay": 2 },
+                    "data": samples,
+                },
+                "markers": {
+                    "schema": {
+                        "name": 0,
+                        "startTime": 1,
+                        "endTime": 2,
+                        "phase": 3,
+                        "category": 4,
+                        "data": 5,
+                    },
+                    "data": [],
+                },
+                "stackTable": {
+                    "schema": { "prefix": 0, "frame": 1 },
+                    "data": self.stacks,
+                },
+                "frameTable": {
+                    "schema": {
+                        "location": 0,
+                        "relevantForJS": 1,
+                        "innerWindowID": 2,
+                        "implementation": 3,
+                        "optimizations": 4,
+                        "line": 5,
+                        "column": 6,
+                        "category": 7,
+                        "subcategory": 8,
+                    },
+                    "data": self.frames,
+                },
+                "stringTable": self.strings,
+            })
+        }
+    }
+
+    impl Report {
+        /// `gecko_profile` will write the report in the Gecko profile format,
+        /// which the [Firefox Profiler](https://profiler.firefox.com) loads
+        /// with a track per thread. The samples of a stack aren't timestamped
+        /// one by one, so they are spread evenly between the first and the
+        /// last sample of the stack, and the tracks only approximate when the
+        /// stacks ran. **only available with `gecko` feature**
+        pub fn gecko_profile<W>(&self, writer: W) -> Result<()>
+        where
+            W: std::io::Write,
+        {
+            let start_time = self.timing.start_time;
+            let millis_since_start = |time: SystemTime| {
+                time.duration_since(start_time)
+                    .unwrap_or_default()
+                    .as_secs_f64()
+                    * 1000.0
+            };
+
+            // keep the threads in the order they appear for the first time
+            let mut threads: Vec<(u64, Thread)> = Vec::new();
+            for (key, count) in self.sorted_data() {
+                let thread = match threads.iter().position(|(id, _)| *id == key.thread_id) {
+                    Some(position) => &mut threads[position].1,
+                    None => {
+                        threads.push((
+                            key.thread_id,
+                            Thread {
+                                name: key.thread_name_or_id(),
+                                ..Default::default()
+                            },
+                        ));
+                        &mut threads.last_mut().unwrap().1
+                    }
+                };
+
+                let mut stack = None;
+                for frame in key.frames.iter().rev() {
+                    for symbol in frame.iter().rev() {
+                        let frame = thread.frame(symbol);
+                        stack = Some(thread.stack(stack, frame));
+                    }
+                }
+                let stack = match stack {
+                    Some(stack) => stack,
+                    None => continue,
+                };
+
+                for time in sample_times(key.sample_timestamp, key.last_sample_timestamp, count) {
+                    thread.samples.push((stack, millis_since_start(time)));
+                }
+            }
+
+            let start_millis = start_time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64()
+                * 1000.0;
+            let profile = json!({
+                "meta": {
+                    "version": GECKO_PROFILE_VERSION,
+                    "startTime": start_millis,
+                    "shutdownTime": null,
+                    "interval": 1000.0 / self.timing.frequency.max(1) as f64,
+                    "stackwalk": 1,
+                    "debug": 0,
+                    "gcpoison": 0,
+                    "asyncstack": 0,
+                    "processType": 0,
+                    "presymbolicated": true,
+                    "categories": [
+                        { "name": "Other", "color": "grey", "subcategories": ["Other"] },
+                    ],
+                    "markerSchema": [],
+                    "product": "pprof-rs",
+                },
+                "libs": [],
+                "pausedRanges": [],
+                "processes": [],
+                "threads": threads
+                    .into_iter()
+                    .map(|(thread_id, thread)| thread.into_json(thread_id))
+                    .collect::<Vec<_>>(),
+            });
+
+            serde_json::to_writer(writer, &profile).map_err(std::io::Error::from)?;
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "html")]
+mod html {
+    use super::*;
+    use serde_json::{json, Value};
+
+    const TEMPLATE: &str = include_str!("report.html");
+
+    /// A frame of the flamegraph, with the samples of the stacks going
+    /// through it.
+    struct Node {
+        name: String,
+        value: isize,
+        children: Vec<Node>,
+    }
+
+    impl Node {
+        fn new(name: String) -> Self {
+            Node {
+                name,
+                value: 0,
+                children: Vec::new(),
+            }
+        }
+
+        fn child(&mut self, name: String) -> &mut Node {
+            let position = match self.children.iter().position(|child| child.name == name) {
+                Some(position) => position,
+                None => {
+                    self.children.push(Node::new(name));
+                    self.children.len() - 1
+                }
+            };
+            &mut self.children[position]
+        }
+
+        fn add(&mut self, names: &[String], count: isize) {
+            self.value += count;
+            if let Some((name, names)) = names.split_first() {
+                self.child(name.clone()).add(names, count);
+            }
+        }
+
+        fn to_json(&self) -> Value {
+            json!({
+                "n": self.name,
+                "v": self.value,
+                "c": self.children.iter().map(Node::to_json).collect::<Vec<_>>(),
+            })
+        }
+    }
+
+    impl Report {
+        /// `html` will write a self-contained interactive flamegraph into
+        /// `writer`: a single html page with a tab for all the threads and one
+        /// per thread, where a frame is zoomed by clicking it and the frames
+        /// matching a regex are highlighted. **only available with `html`
+        /// feature**
+        pub fn html<W>(&self, mut writer: W) -> Result<()>
+        where
+            W: std::io::Write,
+        {
+            let mut all = Node::new("all".to_owned());
+            // keep the threads in the order they appear for the first time
+            let mut threads: Vec<(u64, Node)> = Vec::new();
+            for (key, count) in self.sorted_data() {
+                let name = key.thread_name_or_id();
+                let thread = match threads.iter().position(|(id, _)| *id == key.thread_id) {
+                    Some(position) => &mut threads[position].1,
+                    None => {
+                        threads.push((key.thread_id, Node::new(name.clone())));
+                        &mut threads.last_mut().unwrap().1
+                    }
+                };
+
+                let mut names = Vec::new();
+                if key.truncated {
+                    names.push("[truncated]".to_owned());
+                }
+                for frame in key.frames.iter().rev() {
+                    for symbol in frame.iter().rev() {
+                        names.push(symbol.to_string());
+                    }
+                }
+
+                thread.add(&names, count);
+                names.insert(0, name);
+                all.add(&names, count);
+            }
+
+            let mut tabs = vec![json!({ "name": "All threads", "root": all.to_json() })];
+            tabs.extend(
+                threads
+                    .iter()
+                    .map(|(_, thread)| json!({ "name": thread.name, "root": thread.to_json() })),
+            );
+            // the data is embedded in a script, which a `</script>` in a
+            // symbol would end
+            let data = json!({ "threads": tabs }).to_string().replace("</", "<\\/");
+            writer.write_all(TEMPLATE.replace("/*DATA*/", &data).as_bytes())?;
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(feature = "otlp", feature = "_protobuf"))]
+pub(crate) use self::protobuf::otlp::string_value;
+
+#[cfg(feature = "_protobuf")]
+pub(crate) use self::protobuf::{encode_profile, ProfileValues};
+
+#[cfg(feature = "_protobuf")]
+#[allow(clippy::useless_conversion)]
+#[allow(clippy::needless_update)]
+mod protobuf {
+    use super::*;
+    use crate::frames::Symbol;
+    use crate::libraries::code_mappings;
+    use crate::protos;
+    use libc::c_void;
+    use std::collections::BTreeSet;
+    use std::path::PathBuf;
+    use std::time::SystemTime;
+
+    const SAMPLES: &str = "samples";
+    const COUNT: &str = "count";
+    const CPU: &str = "cpu";
+    const NANOSECONDS: &str = "nanoseconds";
+    const THREAD: &str = "thread";
+    const THREAD_ID: &str = "thread_id";
+    const FIRST_SAMPLE_TIME: &str = "first_sample_time";
+    const LAST_SAMPLE_TIME: &str = "last_sample_time";
+    const TRUNCATED: &str = "truncated";
+    /// The name and the filename of the symbols which don't have one.
+    const UNKNOWN: &str = "Unknown";
+
+    fn nanos_since_epoch(time: SystemTime) -> i64 {
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as i64
+    }
+
+    fn time_from_nanos(nanos: i64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos.max(0) as u64)
+    }
+
+    /// The values of the samples of a profile, and its period and time.
+    pub(crate) struct ProfileValues<'a> {
+        /// The `(type, unit)` of every value of the samples.
+        pub(crate) sample_types: &'a [(&'a str, &'a str)],
+        pub(crate) period_type: (&'a str, &'a str),
+        pub(crate) period: i64,
+        pub(crate) start_time: SystemTime,
+        pub(crate) duration: Duration,
+    }
+
+    /// Encode the stacks, with the values of their sample, into a `pprof`
+    /// profile. See `Report::pprof` for the mappings.
+    pub(crate) fn encode_profile(
+        stacks: Vec<(&Frames, Vec<i64>)>,
+        values: ProfileValues,
+    ) -> protos::Profile {
+        let mappings = code_mappings();
+
+        let mut dedup_str = BTreeSet::new();
+        for mapping in mappings.iter() {
+            dedup_str.insert(mapping.path.to_string_lossy().into_owned());
+            dedup_str.insert(mapping.build_id.clone());
+        }
+        for (key, _) in stacks.iter() {
+            dedup_str.insert(key.thread_name_or_id());
+            for (name, value) in key.labels.iter() {
+                dedup_str.insert(name.clone());
+                dedup_str.insert(value.clone());
+            }
+            for frame in key.frames.iter() {
+                for symbol in frame {
+                    dedup_str.insert(symbol.name());
+                    dedup_str.insert(symbol.sys_name().into_owned());
+                    dedup_str.insert(symbol.filename().into_owned());
+                }
+            }
+        }
+        for (ty, unit) in values.sample_types.iter().chain([&values.period_type]) {
+            dedup_str.insert((*ty).into());
+            dedup_str.insert((*unit).into());
+        }
+        dedup_str.insert(NANOSECONDS.into());
+        dedup_str.insert(THREAD.into());
+        dedup_str.insert(THREAD_ID.into());
+        dedup_str.insert(FIRST_SAMPLE_TIME.into());
+        dedup_str.insert(LAST_SAMPLE_TIME.into());
+        dedup_str.insert(TRUNCATED.into());
+        // string table's first element must be an empty string
+        let mut str_tbl = vec!["".to_owned()];
+        str_tbl.extend(dedup_str.into_iter());
+
+        let mut strings = HashMap::new();
+        for (index, name) in str_tbl.iter().enumerate() {
+            strings.insert(name.as_str(), index);
+        }
+
+        let mut samples = vec![];
+        let mut loc_tbl = vec![];
+        let mut fn_tbl = vec![];
+        let mut functions = HashMap::new();
+        let mut locations = HashMap::new();
+        // whether all the locations in a mapping have a function name
+        let mut has_functions: Vec<Option<bool>> = vec![None; mappings.len()];
+        for (key, value) in stacks {
+            let mut locs = vec![];
+            for frame in key.frames.iter() {
+                if frame.is_empty() {
+                    continue;
+                }
+                // the symbols of a frame are the functions inlined at its
+                // address, the innermost first, so they are the lines of
+                // a single location. The symbols which weren't symbolicated
+                // are told apart by their address
+                let loc_key: Vec<_> = frame
+                    .iter()
+                    .map(|symbol| {
+                        (
+                            symbol.raw_name(),
+                            symbol.filename(),
+                            symbol.lineno(),
+                            symbol.offset,
+                            (symbol.name.is_none() && symbol.filename.is_none())
+                                .then_some(symbol.addr)
+                                .flatten(),
+                        )
+                    })
+                    .collect();
+                if let Some(loc_id) = locations.get(&loc_key) {
+                    locs.push(*loc_id);
+                    continue;
+                }
+
+                // the sampled address, if the symbol's is known. It's only
+                // written for the locations in a mapping, as `pprof` reads it
+                // as an instruction address of its binary
+                let address = frame[0]
+                    .addr
+                    .map(|addr| (addr as usize).wrapping_add(frame[0].offset.unwrap_or_default()));
+                let mapping = address.and_then(|address| {
+                    mappings
+                        .iter()
+                        .position(|mapping| mapping.start <= address && address < mapping.limit)
+                });
+                let mut lines = vec![];
+                // the frames which weren't symbolicated are only an address,
+                // which `pprof` symbolicates with the binary of the mapping
+                let unsymbolized = mapping.is_some()
+                    && frame
+                        .iter()
+                        .all(|symbol| symbol.name.is_none() && symbol.filename.is_none());
+                for symbol in frame.iter().filter(|_| !unsymbolized) {
+                    let sys_name = symbol.sys_name();
+                    let filename = symbol.filename();
+                    // symbols which only differ in line or offset share
+                    // the same function
+                    let function_key = (sys_name, filename);
+                    let function_id = match functions.get(&function_key) {
+                        Some(function_id) => *function_id,
+                        None => {
+                            let function_id = fn_tbl.len() as u64 + 1;
+                            let function = protos::Function {
+                                id: function_id,
+                                name: *strings.get(symbol.name().as_str()).unwrap() as i64,
+                                system_name: *strings.get(function_key.0.as_ref()).unwrap() as i64,
+                                filename: *strings.get(function_key.1.as_ref()).unwrap() as i64,
+                                ..protos::Function::default()
+                            };
+                            functions.insert(function_key, function_id);
+                            fn_tbl.push(function);
+                            function_id
+                        }
+                    };
+                    lines.push(protos::Line {
+                        function_id,
+                        line: symbol.lineno() as i64,
+                        ..protos::Line::default()
+                    });
+                }
+                let (address, mapping_id) = match (address, mapping) {
+                    (Some(address), Some(index)) => {
+                        let named = frame.iter().all(|symbol| symbol.name.is_some());
+                        let has = has_functions[index].get_or_insert(true);
+                        *has &= named;
+                        (address as u64, index as u64 + 1)
+                    }
+                    _ => (0, 0),
+                };
+                let loc_id = loc_tbl.len() as u64 + 1;
+                let loc = protos::Location {
+                    id: loc_id,
+                    mapping_id,
+                    address,
+                    line: lines.into(),
+                    ..protos::Location::default()
+                };
+                locations.insert(loc_key, loc_id);
+                loc_tbl.push(loc);
+                // current frame locations
+                locs.push(loc_id);
+            }
+
+            let nanoseconds = *strings.get(NANOSECONDS).unwrap() as i64;
+            let mut labels = vec![
+                protos::Label {
+                    key: *strings.get(THREAD).unwrap() as i64,
+                    str: *strings.get(&key.thread_name_or_id().as_str()).unwrap() as i64,
+                    ..protos::Label::default()
+                },
+                protos::Label {
+                    key: *strings.get(THREAD_ID).unwrap() as i64,
+                    num: key.thread_id as i64,
+                    ..protos::Label::default()
+                },
+                protos::Label {
+                    key: *strings.get(FIRST_SAMPLE_TIME).unwrap() as i64,
+                    num: nanos_since_epoch(key.sample_timestamp),
+                    num_unit: nanoseconds,
+                    ..protos::Label::default()
+                },
+                protos::Label {
+                    key: *strings.get(LAST_SAMPLE_TIME).unwrap() as i64,
+                    num: nanos_since_epoch(key.last_sample_timestamp),
+                    num_unit: nanoseconds,
+                    ..protos::Label::default()
+                },
+            ];
+            if key.truncated {
+                labels.push(protos::Label {
+                    key: *strings.get(TRUNCATED).unwrap() as i64,
+                    num: 1,
+                    ..protos::Label::default()
+                });
+            }
+            for (name, value) in key.labels.iter() {
+                labels.push(protos::Label {
+                    key: *strings.get(name.as_str()).unwrap() as i64,
+                    str: *strings.get(value.as_str()).unwrap() as i64,
+                    ..protos::Label::default()
+                });
+            }
+            let sample = protos::Sample {
+                location_id: locs,
+                value,
+                label: labels.into(),
+                ..Default::default()
+            };
+            samples.push(sample);
+        }
+        let value_type = |(ty, unit): (&str, &str)| protos::ValueType {
+            ty: *strings.get(ty).unwrap() as i64,
+            unit: *strings.get(unit).unwrap() as i64,
+            ..Default::default()
+        };
+        let sample_type: Vec<_> = values
+            .sample_types
+            .iter()
+            .map(|value| value_type(*value))
+            .collect();
+        let period_type = value_type(values.period_type);
+        let mapping_tbl: Vec<_> = mappings
+            .iter()
+            .zip(has_functions)
+            .enumerate()
+            .map(|(index, (mapping, has_functions))| protos::Mapping {
+                id: index as u64 + 1,
+                memory_start: mapping.start as u64,
+                memory_limit: mapping.limit as u64,
+                file_offset: mapping.file_offset,
+                filename: *strings
+                    .get(mapping.path.to_string_lossy().as_ref())
+                    .unwrap() as i64,
+                build_id: *strings.get(mapping.build_id.as_str()).unwrap() as i64,
+                has_functions: has_functions.unwrap_or_default(),
+                ..protos::Mapping::default()
+            })
+            .collect();
+        protos::Profile {
+            sample_type: sample_type.into(),
+            sample: samples.into(),
+            mapping: mapping_tbl.into(),
+            string_table: str_tbl.into(),
+            function: fn_tbl.into(),
+            location: loc_tbl.into(),
+            time_nanos: nanos_since_epoch(values.start_time),
+            duration_nanos: values.duration.as_nanos() as i64,
+            period_type: Some(period_type).into(),
+            period: values.period,
+            ..protos::Profile::default()
+        }
+    }
+
+    impl Report {
+        /// Write the `pprof` profile, encoded, into the file at `path`. The
+        /// file is replaced atomically, so it's either absent, the previous
+        /// one, or complete.
+        pub fn write_pprof_to_file<P: AsRef<std::path::Path>>(&self, path: P) -> crate::Result<()> {
+            let profile = self.pprof()?;
+            write_atomically(path.as_ref(), |file| {
+                #[cfg(all(feature = "prost-codec", not(feature = "protobuf-codec")))]
+                let content = {
+                    use crate::protos::Message;
+                    let mut content = Vec::new();
+                    profile
+                        .encode(&mut content)
+                        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+                    content
+                };
+                #[cfg(feature = "protobuf-codec")]
+                let content = {
+                    use crate::protos::Message;
+                    profile
+                        .write_to_bytes()
+                        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?
+                };
+                std::io::Write::write_all(file, &content)?;
+
+                Ok(())
+            })
+        }
+
+        /// `pprof` will generate google's pprof format report. The code
+        /// segments of the modules loaded in the process are written as its
+        /// mappings, with their path and build id, and the locations whose
+        /// address is known refer to the mapping which contains it, so that
+        /// the profile can be symbolicated offline.
+        pub fn pprof(&self) -> crate::Result<protos::Profile> {
+            // every sample carries its count and the CPU time it stands for,
+            // so that viewers can switch between both without recomputing
+            let period = 1_000_000_000 / self.timing.frequency as i64;
+            let stacks = self
+                .sorted_data()
+                .into_iter()
+                .map(|(key, count)| (key, vec![count as i64, count as i64 * period]))
+                .collect();
+
+            Ok(encode_profile(
+                stacks,
+                ProfileValues {
+                    sample_types: &[(SAMPLES, COUNT), (CPU, NANOSECONDS)],
+                    period_type: (CPU, NANOSECONDS),
+                    period,
+                    start_time: self.timing.start_time,
+                    duration: self.timing.duration,
+                },
+            ))
+        }
+
+        /// Decode an encoded `pprof` profile, e.g. written by
+        /// `write_pprof_to_file`, back into a report. The profiles of this
+        /// crate round-trip, except for the offsets of the symbols, which are
+        /// lost, and the symbols in a mapping, whose address is the sampled
+        /// one instead of the symbol's. The counts
+        /// of the profiles of other tools are read from their `samples`
+        /// value, or from their first value if they don't have one. The
+        /// locations without lines, e.g. of `ReportBuilder::symbolize`, are
+        /// nameless symbols at their address.
+        pub fn from_pprof(bytes: &[u8]) -> crate::Result<Report> {
+            #[cfg(all(feature = "prost-codec", not(feature = "protobuf-codec")))]
+            let profile = {
+                use crate::protos::Message;
+                protos::Profile::decode(bytes)
+                    .map_err(|_| Error::InvalidPprof("malformed protobuf"))?
+            };
+            #[cfg(feature = "protobuf-codec")]
+            let profile: protos::Profile = crate::protos::Message::parse_from_bytes(bytes)
+                .map_err(|_| Error::InvalidPprof("malformed protobuf"))?;
+
+            Report::from_profile(&profile)
+        }
+
+        fn from_profile(profile: &protos::Profile) -> crate::Result<Report> {
+            let string = |index: i64| -> crate::Result<&str> {
+                usize::try_from(index)
+                    .ok()
+                    .and_then(|index| profile.string_table.get(index))
+                    .map(|string| string.as_str())
+                    .ok_or(Error::InvalidPprof("string index out of range"))
+            };
+
+            let functions: HashMap<u64, &protos::Function> = profile
+                .function
+                .iter()
+                .map(|function| (function.id, function))
+                .collect();
+            let mut locations = HashMap::new();
+            for location in profile.location.iter() {
+                let mut symbols = Vec::new();
+                for line in location.line.iter() {
+                    let function = functions
+                        .get(&line.function_id)
+                        .ok_or(Error::InvalidPprof("unknown function"))?;
+                    let name = match string(function.system_name)? {
+                        "" => string(function.name)?,
+                        name => name,
+                    };
+                    let filename = string(function.filename)?;
+                    symbols.push(Symbol {
+                        name: (name != UNKNOWN).then(|| name.as_bytes().to_vec()),
+                        lineno: (line.line > 0).then_some(line.line as u32),
+                        filename: (!filename.is_empty() && filename != UNKNOWN)
+                            .then(|| PathBuf::from(filename)),
+                        addr: (location.mapping_id != 0)
+                            .then_some(location.address as usize as *mut c_void),
+                        offset: None,
+                    });
+                }
+                // the address of a frame which wasn't symbolicated
+                if symbols.is_empty() && location.mapping_id != 0 {
+                    symbols.push(Symbol {
+                        name: None,
+                        addr: Some(location.address as usize as *mut c_void),
+                        lineno: None,
+                        filename: None,
+                        offset: None,
+                    });
+                }
+                locations.insert(location.id, symbols);
+            }
+
+            let count_index = profile
+                .sample_type
+                .iter()
+                .position(|ty| string(ty.ty).ok() == Some(SAMPLES))
+                .unwrap_or(0);
+            let start_time = time_from_nanos(profile.time_nanos);
+
+            let mut data = HashMap::new();
+            for sample in profile.sample.iter() {
+                let count = sample.value.get(count_index).copied().unwrap_or_default();
+                if count <= 0 {
+                    continue;
+                }
+                let frames = sample
+                    .location_id
+                    .iter()
+                    .map(|id| {
+                        locations
+                            .get(id)
+                            .cloned()
+                            .ok_or(Error::InvalidPprof("unknown location"))
+                    })
+                    .collect::<crate::Result<Vec<_>>>()?;
+
+                let mut thread_name = String::new();
+                let mut thread_id = None;
+                let mut sample_timestamp = start_time;
+                let mut last_sample_timestamp = start_time;
+                let mut truncated = false;
+                let mut labels = Vec::new();
+                for label in sample.label.iter() {
+                    match string(label.key)? {
+                        THREAD => thread_name = string(label.str)?.to_owned(),
+                        THREAD_ID => thread_id = Some(label.num as u64),
+                        FIRST_SAMPLE_TIME => sample_timestamp = time_from_nanos(label.num),
+                        LAST_SAMPLE_TIME => last_sample_timestamp = time_from_nanos(label.num),
+                        TRUNCATED => truncated = label.num != 0,
+                        // the labels of `tag::with_labels`
+                        key if label.str != 0 => {
+                            labels.push((key.to_owned(), string(label.str)?.to_owned()))
                         }
-                        let sys_name = symbol.sys_name();
-                        let filename = symbol.filename();
-                        let lineno = symbol.lineno();
-                        let function_id = fn_tbl.len() as u64 + 1;
-                        let function = protos::Function {
-                            id: function_id,
-                            name: *strings.get(name.as_str()).unwrap() as i64,
-                            system_name: *strings.get(sys_name.as_ref()).unwrap() as i64,
-                            filename: *strings.get(filename.as_ref()).unwrap() as i64,
-                            ..protos::Function::default()
-                        };
-                        functions.insert(name, function_id);
-                        let line = protos::Line {
-                            function_id,
-                            line: lineno as i64,
-                            ..protos::Line::default()
-                        };
-                        let loc = protos::Location {
-                            id: function_id,
-                            line: vec![line].into(),
-                            ..protos::Location::default()
+                        _ => {}
+                    }
+                }
+                labels.sort();
+                // the threads without a name are labeled with their id
+                if thread_id.map_or(false, |id| thread_name == id.to_string()) {
+                    thread_name.clear();
+                }
+
+                let frames = Frames {
+                    frames,
+                    thread_name,
+                    thread_id: thread_id.unwrap_or_default(),
+                    sample_timestamp,
+                    last_sample_timestamp,
+                    truncated,
+                    labels,
+                };
+                *data.entry(frames).or_insert(0) += count as isize;
+            }
+
+            let frequency = match profile.period {
+                period if period > 0 => (1_000_000_000 / period).clamp(1, i32::MAX as i64) as i32,
+                _ => 1,
+            };
+            Ok(Report {
+                data,
+                timing: ReportTiming {
+                    frequency,
+                    start_time,
+                    duration: Duration::from_nanos(profile.duration_nanos.max(0) as u64),
+                    active_duration: None,
+                },
+                collector_full: false,
+                thread_cpu_times: HashMap::new(),
+                thread_states: HashMap::new(),
+                rate_limited_samples: 0,
+                shallow_samples: 0,
+            })
+        }
+    }
+
+    #[cfg(feature = "otlp")]
+    pub(crate) mod otlp {
+        use super::*;
+        use crate::protos::opentelemetry::proto::collector::profiles::v1experimental::ExportProfilesServiceRequest;
+        use crate::protos::opentelemetry::proto::common::v1 as common;
+        use crate::protos::opentelemetry::proto::profiles::v1experimental as profiles;
+        use crate::protos::opentelemetry::proto::resource::v1::Resource;
+        use std::collections::hash_map::RandomState;
+        use std::hash::{BuildHasher, Hasher};
+
+        /// The attributes of the `thread` and `thread_id` labels, from the
+        /// OpenTelemetry semantic conventions.
+        const THREAD_NAME_ATTRIBUTE: &str = "thread.name";
+        const THREAD_ID_ATTRIBUTE: &str = "thread.id";
+
+        pub(crate) fn string_value(value: String) -> common::AnyValue {
+            #[cfg(all(feature = "prost-codec", not(feature = "protobuf-codec")))]
+            let value = common::any_value::Value::StringValue(value);
+            #[cfg(feature = "protobuf-codec")]
+            let value = common::AnyValue_oneof_value::string_value(value);
+            common::AnyValue {
+                value: Some(value),
+                ..Default::default()
+            }
+        }
+
+        fn int_value(value: i64) -> common::AnyValue {
+            #[cfg(all(feature = "prost-codec", not(feature = "protobuf-codec")))]
+            let value = common::any_value::Value::IntValue(value);
+            #[cfg(feature = "protobuf-codec")]
+            let value = common::AnyValue_oneof_value::int_value(value);
+            common::AnyValue {
+                value: Some(value),
+                ..Default::default()
+            }
+        }
+
+        /// A random id of 16 bytes, which is very unlikely to be all zeroes,
+        /// the invalid id.
+        fn random_id() -> Vec<u8> {
+            let mut id = Vec::with_capacity(16);
+            for _ in 0..2 {
+                let random = RandomState::new().build_hasher().finish();
+                id.extend_from_slice(&random.to_le_bytes());
+            }
+            id
+        }
+
+        /// Convert a `pprof` profile into an OpenTelemetry one, which has the
+        /// same tables, but refers to them by index instead of id. The stacks
+        /// of the samples are slices of `location_indices`, and their labels
+        /// are attributes, the numeric ones with their unit.
+        fn convert_profile(profile: protos::Profile) -> profiles::Profile {
+            let string = |index: i64| profile.string_table[index as usize].as_str();
+            // the ids of this crate's profiles are their index plus one
+            let index = |id: u64| id.saturating_sub(1);
+
+            let value_type = |value_type: &protos::ValueType| profiles::ValueType {
+                ty: value_type.ty,
+                unit: value_type.unit,
+                #[cfg(all(feature = "prost-codec", not(feature = "protobuf-codec")))]
+                aggregation_temporality: profiles::AggregationTemporality::Delta as i32,
+                #[cfg(feature = "protobuf-codec")]
+                aggregation_temporality:
+                    profiles::AggregationTemporality::AGGREGATION_TEMPORALITY_DELTA,
+                ..Default::default()
+            };
+
+            let mut location_indices = vec![];
+            let mut attribute_table = vec![];
+            let mut attribute_units = vec![];
+            let mut attributes = HashMap::new();
+            let mut samples = vec![];
+            for sample in profile.sample.iter() {
+                let locations_start_index = location_indices.len() as u64;
+                location_indices.extend(sample.location_id.iter().map(|id| index(*id) as i64));
+
+                let mut sample_attributes = vec![];
+                for label in sample.label.iter() {
+                    let key = match string(label.key) {
+                        THREAD => THREAD_NAME_ATTRIBUTE,
+                        THREAD_ID => THREAD_ID_ATTRIBUTE,
+                        key => key,
+                    };
+                    let value = match label.str {
+                        0 => Err(label.num),
+                        str => Ok(string(str)),
+                    };
+                    let attribute_index = *attributes.entry((key, value)).or_insert_with(|| {
+                        let value = match value {
+                            Ok(str) => string_value(str.to_owned()),
+                            Err(num) => int_value(num),
                         };
-                        // the fn_tbl has the same length with loc_tbl
-                        fn_tbl.push(function);
-                        loc_tbl.push(loc);
-                        // current frame locations
-                        locs.push(function_id);
+                        attribute_table.push(common::KeyValue {
+                            key: key.to_owned(),
+                            value: Some(value).into(),
+                            ..Default::default()
+                        });
+                        attribute_table.len() as u64 - 1
+                    });
+                    sample_attributes.push(attribute_index);
+
+                    // the unit of a numeric label is the one of its key
+                    if label.num_unit != 0
+                        && !attribute_units
+                            .iter()
+                            .any(|unit: &profiles::AttributeUnit| unit.attribute_key == label.key)
+                    {
+                        attribute_units.push(profiles::AttributeUnit {
+                            attribute_key: label.key,
+                            unit: label.num_unit,
+                            ..Default::default()
+                        });
                     }
                 }
-                let thread_name = protos::Label {
-                    key: *strings.get(THREAD).unwrap() as i64,
-                    str: *strings.get(&key.thread_name_or_id().as_str()).unwrap() as i64,
-                    ..protos::Label::default()
+
+                samples.push(profiles::Sample {
+                    locations_start_index,
+                    locations_length: sample.location_id.len() as u64,
+                    value: sample.value.clone(),
+                    attributes: sample_attributes,
+                    ..Default::default()
+                });
+            }
+
+            let mappings: Vec<_> = profile
+                .mapping
+                .iter()
+                .map(|mapping| profiles::Mapping {
+                    id: mapping.id,
+                    memory_start: mapping.memory_start,
+                    memory_limit: mapping.memory_limit,
+                    file_offset: mapping.file_offset,
+                    filename: mapping.filename,
+                    build_id: mapping.build_id,
+                    has_functions: mapping.has_functions,
+                    ..Default::default()
+                })
+                .collect();
+            let locations: Vec<_> = profile
+                .location
+                .iter()
+                .map(|location| {
+                    let lines: Vec<_> = location
+                        .line
+                        .iter()
+                        .map(|line| profiles::Line {
+                            function_index: index(line.function_id),
+                            line: line.line,
+                            ..Default::default()
+                        })
+                        .collect();
+                    profiles::Location {
+                        id: location.id,
+                        // the locations without a mapping have no address
+                        // either, so that they can't be mistaken for one in
+                        // the first mapping
+                        mapping_index: index(location.mapping_id),
+                        address: location.address,
+                        line: lines.into(),
+                        ..Default::default()
+                    }
+                })
+                .collect();
+            let functions: Vec<_> = profile
+                .function
+                .iter()
+                .map(|function| profiles::Function {
+                    id: function.id,
+                    name: function.name,
+                    system_name: function.system_name,
+                    filename: function.filename,
+                    start_line: function.start_line,
+                    ..Default::default()
+                })
+                .collect();
+            let sample_types: Vec<_> = profile.sample_type.iter().map(value_type).collect();
+            let period_type = profile.period_type.as_ref().map(value_type);
+
+            profiles::Profile {
+                sample_type: sample_types.into(),
+                sample: samples.into(),
+                mapping: mappings.into(),
+                location: locations.into(),
+                location_indices,
+                function: functions.into(),
+                attribute_table: attribute_table.into(),
+                attribute_units: attribute_units.into(),
+                time_nanos: profile.time_nanos,
+                duration_nanos: profile.duration_nanos,
+                period_type: period_type.into(),
+                period: profile.period,
+                string_table: profile.string_table,
+                ..Default::default()
+            }
+        }
+
+        impl Report {
+            /// `otlp` will convert the `pprof` profile of the report into an
+            /// OpenTelemetry profile, in the `v1experimental` version of the
+            /// signal, ready to be exported to a collector by OTLP. The
+            /// stacks, values, mappings and labels of `Report::pprof` are
+            /// kept, with the thread labels as the `thread.name` and
+            /// `thread.id` attributes. The resource has no attribute, the
+            /// application should at least set its `service.name`. **only
+            /// available with `otlp` feature**
+            pub fn otlp(&self) -> crate::Result<ExportProfilesServiceRequest> {
+                let profile = convert_profile(self.pprof()?);
+                let start_time = profile.time_nanos as u64;
+                let container = profiles::ProfileContainer {
+                    profile_id: random_id(),
+                    start_time_unix_nano: start_time,
+                    end_time_unix_nano: start_time + profile.duration_nanos as u64,
+                    profile: Some(profile).into(),
+                    ..Default::default()
                 };
-                let sample = protos::Sample {
-                    location_id: locs,
-                    value: vec![
-                        *count as i64,
-                        *count as i64 * 1_000_000_000 / self.timing.frequency as i64,
-                    ],
-                    label: vec![thread_name].into(),
+                let scope = common::InstrumentationScope {
+                    name: env!("CARGO_PKG_NAME").to_owned(),
+                    version: env!("CARGO_PKG_VERSION").to_owned(),
+                    ..Default::default()
+                };
+                let scope_profiles = profiles::ScopeProfiles {
+                    scope: Some(scope).into(),
+                    profiles: vec![container].into(),
+                    ..Default::default()
+                };
+                let resource_profiles = profiles::ResourceProfiles {
+                    resource: Some(Resource::default()).into(),
+                    scope_profiles: vec![scope_profiles].into(),
                     ..Default::default()
                 };
-                samples.push(sample);
+
+                Ok(ExportProfilesServiceRequest {
+                    resource_profiles: vec![resource_profiles].into(),
+                    ..Default::default()
+                })
             }
-            let samples_value = protos::ValueType {
-                ty: *strings.get(SAMPLES).unwrap() as i64,
-                unit: *strings.get(COUNT).unwrap() as i64,
-                ..Default::default()
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use crate::frames::Symbol;
+
+            fn symbol(name: &str) -> Symbol {
+                Symbol {
+                    name: Some(name.as_bytes().to_vec()),
+                    addr: None,
+                    lineno: Some(1),
+                    filename: Some(PathBuf::from("src/lib.rs")),
+                    offset: None,
+                }
+            }
+
+            fn attribute(key: &str, value: common::AnyValue) -> common::KeyValue {
+                common::KeyValue {
+                    key: key.to_owned(),
+                    value: Some(value).into(),
+                    ..Default::default()
+                }
+            }
+
+            #[test]
+            fn otlp_profile() {
+                let start_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+                let frames = vec![
+                    Frames {
+                        frames: vec![vec![symbol("leaf")], vec![symbol("main")]],
+                        thread_name: "main".to_owned(),
+                        thread_id: 1,
+                        sample_timestamp: start_time,
+                        last_sample_timestamp: start_time,
+                        truncated: false,
+                        labels: vec![("rpc".to_owned(), "get".to_owned())],
+                    },
+                    Frames {
+                        frames: vec![vec![symbol("main")]],
+                        thread_name: "worker".to_owned(),
+                        thread_id: 2,
+                        sample_timestamp: start_time,
+                        last_sample_timestamp: start_time,
+                        truncated: false,
+                        labels: Vec::new(),
+                    },
+                ];
+                let report = Report {
+                    data: frames.into_iter().zip([3, 1]).collect(),
+                    timing: ReportTiming {
+                        frequency: 100,
+                        start_time,
+                        duration: Duration::from_secs(2),
+                        active_duration: None,
+                    },
+                    collector_full: false,
+                    thread_cpu_times: HashMap::new(),
+                    thread_states: HashMap::new(),
+                    rate_limited_samples: 0,
+                    shallow_samples: 0,
+                };
+
+                let request = report.otlp().unwrap();
+                #[cfg(all(feature = "prost-codec", not(feature = "protobuf-codec")))]
+                let decoded = {
+                    use crate::protos::Message;
+                    ExportProfilesServiceRequest::decode(request.encode_to_vec().as_slice())
+                        .unwrap()
+                };
+                #[cfg(feature = "protobuf-codec")]
+                let decoded: ExportProfilesServiceRequest = {
+                    use crate::protos::Message;
+                    Message::parse_from_bytes(&request.write_to_bytes().unwrap()).unwrap()
+                };
+                assert_eq!(decoded, request);
+
+                let scope_profiles = &request.resource_profiles[0].scope_profiles[0];
+                assert_eq!(scope_profiles.scope.as_ref().unwrap().name, "pprof");
+                let container = &scope_profiles.profiles[0];
+                assert_eq!(container.profile_id.len(), 16);
+                assert_eq!(container.start_time_unix_nano, 1_700_000_000_000_000_000);
+                assert_eq!(container.end_time_unix_nano, 1_700_000_002_000_000_000);
+
+                let profile = container.profile.as_ref().unwrap();
+                let string = |index: i64| profile.string_table[index as usize].as_str();
+                let sample_types: Vec<_> = profile
+                    .sample_type
+                    .iter()
+                    .map(|ty| (string(ty.ty), string(ty.unit)))
+                    .collect();
+                assert_eq!(sample_types, vec![(SAMPLES, COUNT), (CPU, NANOSECONDS)]);
+
+                // the stacks, leaf first, and the attributes of every sample
+                let samples: Vec<_> = profile
+                    .sample
+                    .iter()
+                    .map(|sample| {
+                        let start = sample.locations_start_index as usize;
+                        let end = start + sample.locations_length as usize;
+                        let stack: Vec<_> = profile.location_indices[start..end]
+                            .iter()
+                            .map(|index| {
+                                let location = &profile.location[*index as usize];
+                                let function =
+                                    &profile.function[location.line[0].function_index as usize];
+                                string(function.name)
+                            })
+                            .collect();
+                        let attributes: Vec<_> = sample
+                            .attributes
+                            .iter()
+                            .map(|index| &profile.attribute_table[*index as usize])
+                            .collect();
+                        (stack, sample.value.clone(), attributes)
+                    })
+                    .collect();
+                assert_eq!(samples.len(), 2);
+                let (stack, value, attributes) = &samples[0];
+                assert_eq!(stack, &["leaf", "main"]);
+                assert_eq!(value, &[3, 30_000_000]);
+                assert!(attributes.contains(&&attribute(
+                    THREAD_NAME_ATTRIBUTE,
+                    string_value("main".to_owned())
+                )));
+                assert!(attributes.contains(&&attribute(THREAD_ID_ATTRIBUTE, int_value(1))));
+                assert!(attributes.contains(&&attribute("rpc", string_value("get".to_owned()))));
+                let (stack, value, attributes) = &samples[1];
+                assert_eq!(stack, &["main"]);
+                assert_eq!(value, &[1, 10_000_000]);
+                assert!(attributes.contains(&&attribute(THREAD_ID_ATTRIBUTE, int_value(2))));
+
+                // the sample times are in nanoseconds, and the attributes
+                // shared by the samples are only in the table once
+                let units: Vec<_> = profile
+                    .attribute_units
+                    .iter()
+                    .map(|unit| (string(unit.attribute_key), string(unit.unit)))
+                    .collect();
+                assert_eq!(
+                    units,
+                    vec![
+                        (FIRST_SAMPLE_TIME, NANOSECONDS),
+                        (LAST_SAMPLE_TIME, NANOSECONDS)
+                    ]
+                );
+                let first_sample_time =
+                    attribute(FIRST_SAMPLE_TIME, int_value(1_700_000_000_000_000_000));
+                assert_eq!(
+                    profile
+                        .attribute_table
+                        .iter()
+                        .filter(|attribute| **attribute == first_sample_time)
+                        .count(),
+                    1
+                );
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::frames::Symbol;
+
+        #[test]
+        fn pprof_value_types() {
+            let frames = Frames {
+                frames: vec![vec![Symbol {
+                    name: Some(b"foo".to_vec()),
+                    addr: None,
+                    lineno: None,
+                    filename: None,
+                    offset: None,
+                }]],
+                thread_name: "main".to_owned(),
+                thread_id: 1,
+                sample_timestamp: SystemTime::now(),
+                last_sample_timestamp: SystemTime::now(),
+                truncated: false,
+                labels: Vec::new(),
             };
-            let time_value = protos::ValueType {
-                ty: *strings.get(CPU).unwrap() as i64,
-                unit: *strings.get(NANOSECONDS).unwrap() as i64,
-                ..Default::default()
+            let report = Report {
+                data: vec![(frames, 3)].into_iter().collect(),
+                timing: ReportTiming {
+                    frequency: 100,
+                    ..Default::default()
+                },
+                collector_full: false,
+                thread_cpu_times: HashMap::new(),
+                thread_states: HashMap::new(),
+                rate_limited_samples: 0,
+                shallow_samples: 0,
             };
-            let profile = protos::Profile {
-                sample_type: vec![samples_value, time_value.clone()].into(),
-                sample: samples.into(),
-                string_table: str_tbl.into(),
-                function: fn_tbl.into(),
-                location: loc_tbl.into(),
-                time_nanos: self
-                    .timing
-                    .start_time
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap_or_default()
-                    .as_nanos() as i64,
-                duration_nanos: self.timing.duration.as_nanos() as i64,
-                period_type: Some(time_value).into(),
-                period: 1_000_000_000 / self.timing.frequency as i64,
-                ..protos::Profile::default()
+
+            let profile = report.pprof().unwrap();
+            let string = |index: i64| profile.string_table[index as usize].as_str();
+            let sample_types: Vec<_> = profile
+                .sample_type
+                .iter()
+                .map(|ty| (string(ty.ty), string(ty.unit)))
+                .collect();
+            assert_eq!(sample_types, vec![(SAMPLES, COUNT), (CPU, NANOSECONDS)]);
+            assert_eq!(profile.period, 10_000_000);
+            assert_eq!(profile.sample[0].value, vec![3, 30_000_000]);
+
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("profile.pb");
+            std::fs::write(&path, b"previous").unwrap();
+            report.write_pprof_to_file(&path).unwrap();
+            let content = std::fs::read(&path).unwrap();
+            #[cfg(all(feature = "prost-codec", not(feature = "protobuf-codec")))]
+            assert_eq!(content, crate::protos::Message::encode_to_vec(&profile));
+            #[cfg(feature = "protobuf-codec")]
+            assert_eq!(
+                content,
+                crate::protos::Message::write_to_bytes(&profile).unwrap()
+            );
+            assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        }
+
+        #[test]
+        fn pprof_mappings() {
+            let addr = code_mappings as *const () as *mut c_void;
+            let frames = Frames {
+                frames: vec![
+                    vec![Symbol {
+                        name: Some(b"code_mappings".to_vec()),
+                        addr: Some(addr),
+                        lineno: None,
+                        filename: None,
+                        offset: Some(4),
+                    }],
+                    vec![symbol("unmapped", None, Some(8))],
+                ],
+                thread_name: "main".to_owned(),
+                thread_id: 1,
+                sample_timestamp: SystemTime::now(),
+                last_sample_timestamp: SystemTime::now(),
+                truncated: false,
+                labels: Vec::new(),
+            };
+            let report = Report {
+                data: vec![(frames, 1)].into_iter().collect(),
+                timing: ReportTiming::default(),
+                collector_full: false,
+                thread_cpu_times: HashMap::new(),
+                thread_states: HashMap::new(),
+                rate_limited_samples: 0,
+                shallow_samples: 0,
+            };
+
+            let profile = report.pprof().unwrap();
+            let string = |index: i64| profile.string_table[index as usize].as_str();
+            let executable = std::env::current_exe().unwrap();
+            assert_eq!(
+                string(profile.mapping[0].filename),
+                executable.to_string_lossy()
+            );
+
+            let mapped = &profile.location[0];
+            assert_eq!(mapped.address, addr as u64 + 4);
+            let mapping = &profile.mapping[mapped.mapping_id as usize - 1];
+            assert!(
+                mapping.memory_start <= mapped.address && mapped.address < mapping.memory_limit
+            );
+            assert_eq!(string(mapping.filename), executable.to_string_lossy());
+            assert!(mapping.has_functions);
+
+            // a symbol without address has no instruction address either
+            assert_eq!(profile.location[1].mapping_id, 0);
+            assert_eq!(profile.location[1].address, 0);
+
+            let decoded = Report::from_pprof(&encode(&profile)).unwrap();
+            let (frames, _) = decoded.data.iter().next().unwrap();
+            assert_eq!(frames.frames[0][0].addr, Some(addr.wrapping_add(4)));
+            assert_eq!(frames.frames[0][0].offset, None);
+            assert_eq!(frames.frames[1][0].offset, None);
+        }
+
+        #[test]
+        fn pprof_unsymbolized() {
+            let addr = code_mappings as *const () as usize;
+            let bare = |address: usize| {
+                vec![Symbol {
+                    name: None,
+                    addr: Some(address as *mut c_void),
+                    lineno: None,
+                    filename: None,
+                    offset: None,
+                }]
+            };
+            let frames = Frames {
+                frames: vec![bare(addr + 4), bare(addr + 8), bare(addr + 4)],
+                thread_name: "main".to_owned(),
+                thread_id: 1,
+                sample_timestamp: SystemTime::UNIX_EPOCH,
+                last_sample_timestamp: SystemTime::UNIX_EPOCH,
+                truncated: false,
+                labels: Vec::new(),
+            };
+            let report = Report {
+                data: vec![(frames, 2)].into_iter().collect(),
+                timing: ReportTiming::default(),
+                collector_full: false,
+                thread_cpu_times: HashMap::new(),
+                thread_states: HashMap::new(),
+                rate_limited_samples: 0,
+                shallow_samples: 0,
+            };
+
+            // a location per address, without lines for `pprof` to symbolicate
+            let profile = report.pprof().unwrap();
+            assert_eq!(profile.sample[0].location_id, [1, 2, 1]);
+            assert_eq!(profile.location.len(), 2);
+            assert!(profile
+                .location
+                .iter()
+                .all(|location| location.line.is_empty()));
+            assert!(profile.function.is_empty());
+            let location = &profile.location[1];
+            assert_eq!(location.address, addr as u64 + 8);
+            assert!(!profile.mapping[location.mapping_id as usize - 1].has_functions);
+
+            let decoded = Report::from_pprof(&encode(&profile)).unwrap();
+            assert_eq!(decoded.data, report.data);
+        }
+
+        fn encode(profile: &protos::Profile) -> Vec<u8> {
+            #[cfg(all(feature = "prost-codec", not(feature = "protobuf-codec")))]
+            let content = crate::protos::Message::encode_to_vec(profile);
+            #[cfg(feature = "protobuf-codec")]
+            let content = crate::protos::Message::write_to_bytes(profile).unwrap();
+            content
+        }
+
+        fn symbol(name: &str, lineno: Option<u32>, offset: Option<usize>) -> Symbol {
+            Symbol {
+                name: Some(name.as_bytes().to_vec()),
+                addr: None,
+                lineno,
+                filename: lineno.map(|_| PathBuf::from("src/lib.rs")),
+                offset,
+            }
+        }
+
+        #[test]
+        fn pprof_round_trip() {
+            let start_time =
+                SystemTime::UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789);
+            let frames = vec![
+                Frames {
+                    // `inlined` is inlined into `caller`, they share a frame
+                    frames: vec![
+                        vec![
+                            symbol("inlined", Some(12), None),
+                            symbol("caller", Some(40), None),
+                        ],
+                        vec![symbol("main", Some(3), None)],
+                    ],
+                    thread_name: "main".to_owned(),
+                    thread_id: 1,
+                    sample_timestamp: start_time + Duration::from_millis(10),
+                    last_sample_timestamp: start_time + Duration::from_millis(900),
+                    truncated: false,
+                    labels: Vec::new(),
+                },
+                Frames {
+                    // the same function at another line
+                    frames: vec![vec![symbol("main", Some(4), None)]],
+                    thread_name: "main".to_owned(),
+                    thread_id: 1,
+                    sample_timestamp: start_time,
+                    last_sample_timestamp: start_time,
+                    truncated: false,
+                    labels: vec![
+                        ("rpc".to_owned(), "get".to_owned()),
+                        ("tenant".to_owned(), "a".to_owned()),
+                    ],
+                },
+                Frames {
+                    frames: vec![vec![Symbol {
+                        name: None,
+                        addr: None,
+                        lineno: None,
+                        filename: None,
+                        offset: None,
+                    }]],
+                    thread_name: String::new(),
+                    thread_id: 42,
+                    sample_timestamp: start_time + Duration::from_millis(5),
+                    last_sample_timestamp: start_time + Duration::from_millis(6),
+                    truncated: true,
+                    labels: Vec::new(),
+                },
+            ];
+            let report = Report {
+                data: frames.into_iter().zip([7, 3, 1]).collect(),
+                timing: ReportTiming {
+                    frequency: 99,
+                    start_time,
+                    duration: Duration::from_secs(1),
+                    active_duration: None,
+                },
+                collector_full: false,
+                thread_cpu_times: HashMap::new(),
+                thread_states: HashMap::new(),
+                rate_limited_samples: 0,
+                shallow_samples: 0,
+            };
+
+            let profile = report.pprof().unwrap();
+            // a location per frame, and a function per symbol
+            assert_eq!(profile.location.len(), 4);
+            assert_eq!(profile.function.len(), 4);
+
+            let decoded = Report::from_pprof(&encode(&profile)).unwrap();
+            assert_eq!(decoded.data, report.data);
+            let fields = |report: &Report| -> Vec<_> {
+                report
+                    .sorted_data()
+                    .into_iter()
+                    .flat_map(|(key, _)| key.frames.iter().flatten())
+                    .map(|symbol| (symbol.name.clone(), symbol.lineno, symbol.filename.clone()))
+                    .collect()
             };
-            Ok(profile)
+            assert_eq!(fields(&decoded), fields(&report));
+            assert_eq!(decoded.timing.frequency, 99);
+            assert_eq!(decoded.timing.start_time, start_time);
+            assert_eq!(decoded.timing.duration, Duration::from_secs(1));
+
+            // re-encoding the decoded report gives the same profile
+            assert_eq!(encode(&decoded.pprof().unwrap()), encode(&profile));
+
+            assert!(matches!(
+                Report::from_pprof(b"not a profile"),
+                Err(Error::InvalidPprof(_))
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frames::Symbol;
+
+    fn frames(thread_name: &str, names: &[&str]) -> Frames {
+        Frames {
+            frames: names
+                .iter()
+                .map(|name| {
+                    vec![Symbol {
+                        name: Some(name.as_bytes().to_vec()),
+                        addr: None,
+                        lineno: None,
+                        filename: None,
+                        offset: None,
+                    }]
+                })
+                .collect(),
+            thread_name: thread_name.to_owned(),
+            thread_id: 1,
+            sample_timestamp: SystemTime::UNIX_EPOCH,
+            last_sample_timestamp: SystemTime::UNIX_EPOCH,
+            truncated: false,
+            labels: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn sorted_data() {
+        let report = Report {
+            data: vec![
+                (frames("main", &["b", "main"]), 2),
+                (frames("main", &["a", "main"]), 2),
+                (frames("worker", &["c"]), 5),
+            ]
+            .into_iter()
+            .collect(),
+            timing: ReportTiming::default(),
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+
+        let sorted: Vec<_> = report
+            .sorted_data()
+            .into_iter()
+            .map(|(key, count)| (key.frames[0][0].name(), count))
+            .collect();
+        assert_eq!(
+            sorted,
+            vec![
+                ("c".to_owned(), 5),
+                ("a".to_owned(), 2),
+                ("b".to_owned(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "speedscope")]
+    fn speedscope_frames_and_threads() {
+        let at_line = |thread_id: u64, lineno: u32| {
+            let mut frames = frames("worker", &["work", "main"]);
+            frames.thread_id = thread_id;
+            frames.frames[0][0].filename = Some("src/work.rs".into());
+            frames.frames[0][0].lineno = Some(lineno);
+            frames
+        };
+        let report = Report::new(
+            vec![
+                (at_line(1, 10), 2),
+                (at_line(1, 20), 1),
+                (at_line(2, 10), 1),
+            ]
+            .into_iter()
+            .collect(),
+            ReportTiming::default(),
+        );
+
+        let mut json = Vec::new();
+        report.speedscope(&mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+
+        // `work` at each of its lines, and `main`
+        let frames = json["shared"]["frames"].as_array().unwrap();
+        let mut lines: Vec<_> = frames
+            .iter()
+            .filter(|frame| frame["name"] == "work")
+            .map(|frame| frame["line"].as_u64().unwrap())
+            .collect();
+        lines.sort();
+        assert_eq!(lines, [10, 20]);
+        assert_eq!(frames.len(), 3);
+
+        // the threads named the same get a profile each
+        let mut names: Vec<_> = json["profiles"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|profile| profile["name"].as_str().unwrap().to_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["worker (1)", "worker (2)"]);
+    }
+
+    #[test]
+    #[cfg(feature = "speedscope")]
+    fn speedscope_evented() {
+        let mut later = frames("main", &["b", "main"]);
+        later.sample_timestamp += Duration::from_secs(1);
+        let report = Report {
+            data: vec![(later, 1), (frames("main", &["a", "main"]), 2)]
+                .into_iter()
+                .collect(),
+            timing: ReportTiming {
+                frequency: 1,
+                ..Default::default()
+            },
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+
+        let mut json = Vec::new();
+        report.speedscope_evented(&mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        let profile = &json["profiles"][0];
+        assert_eq!(profile["type"], "evented");
+        assert_eq!(profile["endValue"], 3_000_000_000i64);
+
+        // `main` stays open while `a` and then `b` run below it
+        let events: Vec<_> = profile["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| {
+                let frame = event["frame"].as_u64().unwrap() as usize;
+                (
+                    event["type"].as_str().unwrap().to_owned(),
+                    json["shared"]["frames"][frame]["name"]
+                        .as_str()
+                        .unwrap()
+                        .to_owned(),
+                    event["at"].as_i64().unwrap() / 1_000_000_000,
+                )
+            })
+            .collect();
+        let expected: Vec<_> = [
+            ("O", "main", 0),
+            ("O", "a", 0),
+            ("C", "a", 2),
+            ("O", "b", 2),
+            ("C", "b", 3),
+            ("C", "main", 3),
+        ]
+        .iter()
+        .map(|(ty, name, at)| (ty.to_string(), name.to_string(), *at))
+        .collect();
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn folded_stacks() {
+        let mut truncated = frames("worker", &["c"]);
+        truncated.truncated = true;
+        let report = Report {
+            data: vec![(frames("main", &["a", "main"]), 2), (truncated, 5)]
+                .into_iter()
+                .collect(),
+            timing: ReportTiming::default(),
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+
+        let mut folded = Vec::new();
+        report.folded(&mut folded).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "worker;[truncated];c 5\nmain;main;a 2\n"
+        );
+    }
+
+    #[test]
+    fn folded_stacks_with_lines() {
+        // two calls from different lines of `main`
+        let mut first = frames("main", &["work", "main"]);
+        let mut second = frames("main", &["work", "main"]);
+        for (key, lineno) in [(&mut first, 12), (&mut second, 14)] {
+            key.frames[1][0].filename = Some("src/main.rs".into());
+            key.frames[1][0].lineno = Some(lineno);
+        }
+        let report = Report {
+            data: vec![(first, 3), (second, 1)].into_iter().collect(),
+            timing: ReportTiming::default(),
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+
+        let mut folded = Vec::new();
+        report.folded_with_lines(&mut folded).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "main;main (src/main.rs:12);work 3\nmain;main (src/main.rs:14);work 1\n"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "flamegraph")]
+    fn flamechart() {
+        let at = |millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis);
+        let mut a = frames("main", &["a", "main"]);
+        a.sample_timestamp = at(0);
+        a.last_sample_timestamp = at(10);
+        let mut b = frames("main", &["b", "main"]);
+        b.sample_timestamp = at(5);
+        b.last_sample_timestamp = at(5);
+        let report = Report {
+            data: vec![(a, 2), (b, 1)].into_iter().collect(),
+            timing: ReportTiming::default(),
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+
+        let mut svg = Vec::new();
+        report.flamechart(&mut svg).unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+        // `b` ran between the two samples of `a`, which aren't merged
+        let titles: Vec<_> = svg
+            .match_indices("<title>")
+            .map(|(i, _)| &svg[i + 7..i + 7 + svg[i..].find("</title>").unwrap() - 7])
+            .filter(|title| title.starts_with("a ") || title.starts_with("b "))
+            .collect();
+        assert_eq!(titles.len(), 3, "{:?}", titles);
+        assert!(titles[0].starts_with("a "));
+        assert!(titles[1].starts_with("b "));
+        assert!(titles[2].starts_with("a "));
+    }
+
+    #[test]
+    #[cfg(feature = "flamegraph")]
+    fn flamegraph_diff() {
+        let report = |a, b| Report {
+            data: vec![
+                (frames("main", &["grown", "main"]), a),
+                (frames("main", &["shrunk", "main"]), b),
+            ]
+            .into_iter()
+            .collect(),
+            timing: ReportTiming::default(),
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+
+        let mut svg = Vec::new();
+        report(3, 1)
+            .flamegraph_diff(&report(1, 3), &mut svg)
+            .unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+        // the frames are red when they grew and blue when they shrunk
+        let fill = |name: &str| {
+            let frame = &svg[svg.find(&format!("<title>{} ", name)).unwrap()..];
+            let fill = &frame[frame.find("fill=\"").unwrap() + 6..];
+            fill[..fill.find('"').unwrap()].to_owned()
+        };
+        assert!(fill("grown").starts_with("rgb(255,"), "{}", fill("grown"));
+        assert!(fill("shrunk").ends_with(",255)"), "{}", fill("shrunk"));
+    }
+
+    #[test]
+    #[cfg(feature = "html")]
+    fn html() {
+        let report = Report {
+            data: vec![
+                (frames("main", &["a", "main"]), 3),
+                (frames("main", &["b</script>", "main"]), 1),
+            ]
+            .into_iter()
+            .collect(),
+            timing: ReportTiming::default(),
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+
+        let mut html = Vec::new();
+        report.html(&mut html).unwrap();
+        let html = String::from_utf8(html).unwrap();
+        assert!(!html.contains("/*DATA*/"));
+        // the symbols can't end the script holding the data
+        assert_eq!(html.matches("</script>").count(), 1);
+
+        let data = html.split("const DATA = ").nth(1).unwrap();
+        let data = &data[..data.find(";\n").unwrap()];
+        let data: serde_json::Value = serde_json::from_str(data).unwrap();
+        let tabs = data["threads"].as_array().unwrap();
+        assert_eq!(tabs.len(), 2);
+        assert_eq!(tabs[0]["name"], "All threads");
+        let all = &tabs[0]["root"];
+        assert_eq!(all["v"], 4);
+        assert_eq!(all["c"][0]["n"], "main");
+
+        let main = &tabs[1]["root"]["c"][0];
+        assert_eq!(main["n"], "main");
+        assert_eq!(main["v"], 4);
+        let children: Vec<_> = main["c"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|child| (child["n"].as_str().unwrap(), child["v"].as_i64().unwrap()))
+            .collect();
+        assert_eq!(children, vec![("a", 3), ("b</script>", 1)]);
+    }
+
+    #[test]
+    #[cfg(feature = "gecko")]
+    fn gecko_profile() {
+        let mut stack = frames("main", &["a", "main"]);
+        stack.sample_timestamp = SystemTime::UNIX_EPOCH + Duration::from_millis(10);
+        stack.last_sample_timestamp = SystemTime::UNIX_EPOCH + Duration::from_millis(30);
+        let report = Report {
+            data: vec![(stack, 3), (frames("main", &["b", "main"]), 1)]
+                .into_iter()
+                .collect(),
+            timing: ReportTiming {
+                frequency: 100,
+                ..Default::default()
+            },
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+
+        let mut json = Vec::new();
+        report.gecko_profile(&mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["meta"]["interval"], 10.0);
+        let thread = &json["threads"][0];
+        assert_eq!(thread["name"], "main");
+
+        // `main` is shared by the stacks of `a` and `b`
+        let strings = thread["stringTable"].as_array().unwrap();
+        let name = |stack: &serde_json::Value| {
+            let frame = &thread["frameTable"]["data"][stack[1].as_u64().unwrap() as usize];
+            strings[frame[0].as_u64().unwrap() as usize].clone()
+        };
+        let stacks = thread["stackTable"]["data"].as_array().unwrap();
+        assert_eq!(stacks.len(), 3);
+        assert_eq!(name(&stacks[0]), "main");
+        assert!(stacks[0][0].is_null());
+        assert_eq!(stacks[1][0], 0);
+        assert_eq!(stacks[2][0], 0);
+
+        // the samples of a stack are spread over its first and last sample
+        let times: Vec<_> = thread["samples"]["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|sample| sample[1].as_f64().unwrap())
+            .collect();
+        assert_eq!(times, vec![0.0, 10.0, 20.0, 30.0]);
+    }
+
+    #[test]
+    fn extend_report() {
+        let mut report = Report {
+            data: vec![(frames("main", &["a", "main"]), 2)]
+                .into_iter()
+                .collect(),
+            timing: ReportTiming::default(),
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+
+        report.extend(vec![
+            (frames("main", &["a", "main"]), 3),
+            (frames("main", &["b", "main"]), 1),
+            (frames("main", &["b", "main"]), 1),
+        ]);
+        assert_eq!(report.data.len(), 2);
+        assert_eq!(report.data[&frames("main", &["a", "main"])], 5);
+        assert_eq!(report.data[&frames("main", &["b", "main"])], 2);
+    }
+
+    #[test]
+    fn merge_reports() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let report = |start, stacks: Vec<(Frames, isize)>| Report {
+            data: stacks
+                .into_iter()
+                .map(|(mut key, count)| {
+                    key.sample_timestamp = at(start);
+                    key.last_sample_timestamp = at(start + 1);
+                    (key, count)
+                })
+                .collect(),
+            timing: ReportTiming {
+                frequency: 99,
+                start_time: at(start),
+                duration: Duration::from_secs(2),
+                active_duration: None,
+            },
+            collector_full: false,
+            thread_cpu_times: vec![(
+                1,
+                ThreadCpuTime {
+                    samples: 2,
+                    first: Duration::from_secs(start),
+                    last: Duration::from_secs(start + 1),
+                },
+            )]
+            .into_iter()
+            .collect(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 1,
+            shallow_samples: 0,
+        };
+
+        let merged = Report::merge(vec![
+            report(10, vec![(frames("main", &["a", "main"]), 2)]),
+            report(
+                20,
+                vec![
+                    (frames("main", &["a", "main"]), 3),
+                    (frames("main", &["b", "main"]), 1),
+                ],
+            ),
+        ]);
+        // the same stack is summed, even though it was sampled at other times
+        assert_eq!(merged.data.len(), 2);
+        let (key, count) = merged
+            .data
+            .iter()
+            .find(|(key, _)| key.frames[0][0].name() == "a")
+            .unwrap();
+        assert_eq!(*count, 5);
+        assert_eq!(key.sample_timestamp, at(10));
+        assert_eq!(key.last_sample_timestamp, at(21));
+
+        assert_eq!(merged.timing.frequency, 99);
+        assert_eq!(merged.start_time(), at(10));
+        assert_eq!(merged.end_time(), at(22));
+        assert_eq!(merged.active_duration(), Duration::from_secs(4));
+        assert_eq!(merged.rate_limited_samples(), 2);
+        assert_eq!(
+            merged.thread_cpu_times()[&1],
+            ThreadCpuTime {
+                samples: 4,
+                first: Duration::from_secs(10),
+                last: Duration::from_secs(21),
+            }
+        );
+
+        assert!(Report::merge(Vec::new()).data.is_empty());
+    }
+
+    #[test]
+    fn clip_samples() {
+        let at = |millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis);
+        // the 11 samples are taken every 10ms from 0 to 100ms
+        let clip = |start, end| super::clip_samples(at(0), at(100), 11, at(start), at(end));
+        assert_eq!(clip(0, 1000), Some((11, at(0), at(100))));
+        assert_eq!(clip(25, 55), Some((3, at(30), at(50))));
+        assert_eq!(clip(30, 50), Some((2, at(30), at(40))));
+        assert_eq!(clip(101, 200), None);
+        assert_eq!(
+            super::clip_samples(at(5), at(5), 3, at(0), at(10)),
+            Some((3, at(5), at(5)))
+        );
+    }
+
+    #[test]
+    fn clip_report() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let mut report = UnresolvedReport {
+            data: HashMap::new(),
+            timing: ReportTiming {
+                frequency: 99,
+                start_time: at(0),
+                duration: Duration::from_secs(100),
+                active_duration: Some(Duration::from_secs(50)),
+            },
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+        let mut stack = frames("main", &["a", "main"]);
+        stack.sample_timestamp = at(10);
+        stack.last_sample_timestamp = at(30);
+        let mut resolved = vec![(stack, 3)];
+
+        super::clip_report(&mut report, &mut resolved, at(15), at(200));
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].1, 2);
+        assert_eq!(resolved[0].0.sample_timestamp, at(20));
+        assert_eq!(report.timing.start_time, at(15));
+        assert_eq!(report.timing.duration, Duration::from_secs(85));
+        assert_eq!(
+            report.timing.active_duration,
+            Some(Duration::from_millis(42500))
+        );
+    }
+
+    #[test]
+    fn cut_light_frames() {
+        let mut worker = frames("worker", &["d", "main"]);
+        worker.thread_id = 2;
+        let data = vec![
+            (frames("main", &["a", "work", "main"]), 50),
+            (frames("main", &["b", "work", "main"]), 4),
+            (frames("main", &["c", "b", "work", "main"]), 4),
+            (frames("main", &["e", "main"]), 40),
+            (worker, 2),
+        ]
+        .into_iter()
+        .collect();
+
+        // with 100 samples, the frames with less than 10 are cut
+        let cut = super::cut_light_frames(data, 0.1);
+        let mut stacks: Vec<_> = cut
+            .iter()
+            .map(|(key, count)| (super::folded_stack(key), *count))
+            .collect();
+        stacks.sort();
+        assert_eq!(
+            stacks,
+            vec![
+                ("main;main;e".to_owned(), 40),
+                ("main;main;work".to_owned(), 8),
+                ("main;main;work;a".to_owned(), 50),
+            ]
+        );
+    }
+
+    #[test]
+    fn glob_match() {
+        assert!(super::glob_match(b"grpc-server-*", b"grpc-server-3"));
+        assert!(super::glob_match(b"grpc-server-*", b"grpc-server-"));
+        assert!(!super::glob_match(b"grpc-server-*", b"grpc-client-3"));
+        assert!(super::glob_match(b"*-worker-?", b"tokio-worker-1"));
+        assert!(!super::glob_match(b"*-worker-?", b"tokio-worker-12"));
+        assert!(super::glob_match(b"*a*b", b"xaxab"));
+        assert!(super::glob_match(b"main", b"main"));
+        assert!(!super::glob_match(b"main", b"main2"));
+    }
+
+    #[test]
+    fn thread_filter() {
+        let filter = ThreadFilter {
+            included: vec!["grpc-*".to_owned(), "42".to_owned()],
+            excluded: vec!["grpc-client".to_owned()],
+        };
+        assert!(filter.keep(1, b"grpc-server"));
+        assert!(!filter.keep(2, b"grpc-client"));
+        assert!(filter.keep(42, b"main"));
+        assert!(!filter.keep(3, b"main"));
+
+        // every thread is reported by default
+        assert!(ThreadFilter::default().keep(3, b"main"));
+    }
+
+    #[test]
+    fn hide_functions() {
+        let hidden = vec!["lock".to_owned(), "alloc".to_owned()];
+
+        // a hidden leaf is attributed to its caller
+        let mut stack = frames("main", &["malloc_usable", "alloc_inner", "work", "main"]);
+        super::hide_functions(&mut stack, &hidden);
+        assert_eq!(stack, frames("main", &["work", "main"]));
+
+        // the callees of a hidden frame are attached to its caller
+        let mut stack = frames("main", &["work", "mutex_lock", "main"]);
+        super::hide_functions(&mut stack, &hidden);
+        assert_eq!(stack, frames("main", &["work", "main"]));
+
+        // only the hidden inlined functions of a frame are removed
+        let mut stack = frames("main", &["work", "main"]);
+        let mut inlined = stack.frames[0][0].clone();
+        inlined.name = Some(b"inlined_lock".to_vec());
+        stack.frames[0].insert(0, inlined);
+        super::hide_functions(&mut stack, &hidden);
+        assert_eq!(stack, frames("main", &["work", "main"]));
+    }
+
+    #[test]
+    fn focus_and_prune() {
+        let patterns = |names: &[&str]| -> Vec<FunctionPattern> {
+            names
+                .iter()
+                .map(|name| FunctionPattern::Substring(name.to_string()))
+                .collect()
+        };
+        let stack = frames("main", &["alloc", "parse_request", "main"]);
+
+        assert!(keep_stack(&stack, &[], &[]));
+        assert!(keep_stack(&stack, &patterns(&["parse"]), &[]));
+        assert!(keep_stack(&stack, &patterns(&["flush", "parse"]), &[]));
+        assert!(!keep_stack(&stack, &patterns(&["flush"]), &[]));
+        assert!(!keep_stack(&stack, &[], &patterns(&["alloc"])));
+        assert!(!keep_stack(
+            &stack,
+            &patterns(&["parse"]),
+            &patterns(&["alloc"])
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "regex")]
+    fn focus_and_ignore_regex() {
+        let regex =
+            |pattern: &str| vec![FunctionPattern::Regex(regex::Regex::new(pattern).unwrap())];
+        let stack = frames("main", &["alloc", "parse_request", "main"]);
+
+        assert!(keep_stack(&stack, &regex("^parse_"), &[]));
+        assert!(!keep_stack(&stack, &regex("^request"), &[]));
+        assert!(!keep_stack(&stack, &[], &regex("^(alloc|free)$")));
+        assert!(keep_stack(&stack, &[], &regex("^alloc_")));
+
+        // the substrings and the regexes are combined
+        let mut focused = regex("^flush$");
+        focused.push(FunctionPattern::Substring("parse".to_owned()));
+        assert!(keep_stack(&stack, &focused, &[]));
+    }
+
+    #[test]
+    fn split_threads() {
+        let mut other = frames("worker", &["b", "main"]);
+        other.thread_id = 2;
+        let report = Report {
+            data: vec![
+                (frames("main", &["a", "main"]), 1),
+                (other.clone(), 2),
+                (frames("main", &["c", "main"]), 2),
+            ]
+            .into_iter()
+            .collect(),
+            timing: ReportTiming::default(),
+            collector_full: false,
+            thread_cpu_times: vec![(2, ThreadCpuTime::default())].into_iter().collect(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 3,
+            shallow_samples: 0,
+        };
+
+        let threads = report.split_threads();
+        assert_eq!(threads.len(), 2);
+        assert_eq!(threads[0].data.len(), 2);
+        assert!(threads[0].data.keys().all(|key| key.thread_id == 1));
+        assert!(threads[0].thread_cpu_times().is_empty());
+        assert_eq!(threads[1].data, vec![(other, 2)].into_iter().collect());
+        assert_eq!(threads[1].thread_cpu_times().len(), 1);
+        assert_eq!(threads[1].rate_limited_samples(), 0);
+    }
+
+    #[test]
+    fn flamescope() {
+        let at = |millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis);
+        let mut a = frames("main", &["a", "main"]);
+        a.sample_timestamp = at(1000);
+        a.last_sample_timestamp = at(1020);
+        let mut b = frames("main", &["b", "main"]);
+        b.sample_timestamp = at(1010);
+        b.last_sample_timestamp = at(1010);
+        let report = Report {
+            data: vec![(a, 2), (b, 1)].into_iter().collect(),
+            timing: ReportTiming {
+                frequency: 100,
+                ..Default::default()
+            },
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+
+        let mut script = Vec::new();
+        report.flamescope(&mut script).unwrap();
+        assert_eq!(
+            String::from_utf8(script).unwrap(),
+            "main 1 [000] 1.000000: 10000000 cpu-clock:\n\
+             \t0 a ([unknown])\n\
+             \t0 main ([unknown])\n\
+             \n\
+             main 1 [000] 1.010000: 10000000 cpu-clock:\n\
+             \t0 b ([unknown])\n\
+             \t0 main ([unknown])\n\
+             \n\
+             main 1 [000] 1.020000: 10000000 cpu-clock:\n\
+             \t0 a ([unknown])\n\
+             \t0 main ([unknown])\n\
+             \n"
+        );
+    }
+
+    #[test]
+    fn samples() {
+        let at = |millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis);
+        let mut a = frames("main", &["a", "main"]);
+        a.sample_timestamp = at(0);
+        a.last_sample_timestamp = at(40);
+        let mut b = frames("worker", &["b", "worker"]);
+        b.sample_timestamp = at(30);
+        b.last_sample_timestamp = at(30);
+        let report = Report {
+            data: vec![(a, 3), (b, 1)].into_iter().collect(),
+            timing: ReportTiming {
+                frequency: 50,
+                ..Default::default()
+            },
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+
+        let samples: Vec<(SystemTime, &str)> = report
+            .samples()
+            .map(|sample| (sample.timestamp, sample.frames.thread_name.as_str()))
+            .collect();
+        assert_eq!(
+            samples,
+            [
+                (at(0), "main"),
+                (at(20), "main"),
+                (at(30), "worker"),
+                (at(40), "main")
+            ]
+        );
+        assert!(report
+            .samples()
+            .all(|sample| sample.weight == Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn top() {
+        let report = Report {
+            data: vec![
+                (frames("main", &["a", "main"]), 3),
+                (frames("main", &["b", "a", "main"]), 1),
+            ]
+            .into_iter()
+            .collect(),
+            timing: ReportTiming::default(),
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+
+        let top = report.top(2);
+        assert_eq!(top.total, 4);
+        assert_eq!(
+            top.to_string(),
+            "      flat   flat%    sum%        cum    cum%  function\n\
+             \x20        3  75.00%  75.00%          4 100.00%  a\n\
+             \x20        1  25.00% 100.00%          1  25.00%  b\n"
+        );
+    }
+
+    #[test]
+    fn sample_confidence() {
+        let mut report = Report {
+            data: vec![
+                (frames("main", &["a", "main"]), 10),
+                (frames("main", &["b", "main"]), 90),
+            ]
+            .into_iter()
+            .collect(),
+            timing: ReportTiming::default(),
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+
+        let (fraction, margin) = report.sample_confidence(10).unwrap();
+        assert_eq!(fraction, 0.1);
+        assert!((margin - 0.0588).abs() < 1e-4);
+        assert_eq!(report.sample_confidence(100), Some((1.0, 0.0)));
+
+        report.data.clear();
+        assert_eq!(report.sample_confidence(0), None);
+    }
+
+    #[test]
+    fn syscall_interrupt_ratio() {
+        let mut report = Report {
+            data: vec![
+                (frames("main", &["__GI___libc_read", "main"]), 1),
+                (frames("main", &["epoll_wait", "poll_events", "main"]), 2),
+                (frames("main", &["work", "main"]), 5),
+            ]
+            .into_iter()
+            .collect(),
+            timing: ReportTiming::default(),
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+        assert_eq!(report.syscall_interrupt_ratio(), Some(3.0 / 8.0));
+
+        report.data.clear();
+        assert_eq!(report.syscall_interrupt_ratio(), None);
+    }
+
+    #[test]
+    fn diff() {
+        let report = |data: Vec<(Frames, isize)>| Report {
+            data: data.into_iter().collect(),
+            timing: ReportTiming::default(),
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+        let mut other_thread = frames("main", &["same", "main"]);
+        other_thread.thread_id = 2;
+        let baseline = report(vec![
+            (frames("main", &["removed", "main"]), 4),
+            (frames("main", &["changed", "main"]), 1),
+            (other_thread, 2),
+        ]);
+        let after = report(vec![
+            (frames("main", &["added", "main"]), 2),
+            (frames("main", &["changed", "main"]), 4),
+            (frames("main", &["same", "main"]), 2),
+        ]);
+
+        // the stacks of the threads with the same name are matched
+        let diff = after.diff(&baseline);
+        let changes: Vec<_> = diff
+            .stacks
+            .iter()
+            .map(|stack| {
+                (
+                    stack.frames.frames[0][0].name(),
+                    stack.before,
+                    stack.after,
+                    stack.change(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("removed".to_owned(), 4, 0, crate::Change::Removed),
+                ("changed".to_owned(), 1, 4, crate::Change::Changed),
+                ("added".to_owned(), 0, 2, crate::Change::Added),
+                ("same".to_owned(), 2, 2, crate::Change::Unchanged),
+            ]
+        );
+        assert_eq!(
+            diff.to_string(),
+            "-4 4 -> 0 main;main;removed\n+3 1 -> 4 main;main;changed\n+2 0 -> 2 main;main;added\n"
+        );
+
+        #[cfg(feature = "_protobuf")]
+        {
+            let profile = diff.pprof().unwrap();
+            let mut values: Vec<_> = profile.sample.iter().map(|s| s.value[0]).collect();
+            values.sort();
+            assert_eq!(values, vec![-4, 2, 3]);
         }
     }
+
+    #[test]
+    fn module_stats() {
+        let mut unresolved = UnresolvedReport {
+            data: HashMap::new(),
+            timing: ReportTiming::default(),
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+        unresolved.data.insert(UnresolvedFrames::capture(), 1);
+
+        // the test itself is in the test executable
+        let executable = std::env::current_exe().unwrap();
+        let executable = executable.file_name().unwrap().to_string_lossy();
+        let libraries = unresolved.library_stats();
+        assert!(libraries
+            .iter()
+            .any(|(name, stat)| *name == executable && stat.total_count == 1));
+
+        let mut report = Report::merge(Vec::new());
+        report.extend(
+            unresolved
+                .data
+                .into_iter()
+                .map(|(key, count)| (Frames::from(key), count)),
+        );
+        let crates = report.crate_stats();
+        assert!(crates
+            .iter()
+            .any(|(name, stat)| name == "pprof" && stat.total_count == 1));
+        let self_count: isize = crates.iter().map(|(_, stat)| stat.self_count).sum();
+        assert_eq!(self_count, 1);
+    }
+
+    #[test]
+    fn call_graph() {
+        let report = Report {
+            data: vec![
+                // `even` and `odd` call each other
+                (frames("main", &["odd", "even", "odd", "main"]), 3),
+                (frames("main", &["even", "odd", "main"]), 2),
+                (frames("main", &["work", "main"]), 5),
+            ]
+            .into_iter()
+            .collect(),
+            timing: ReportTiming::default(),
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+
+        let graph = report.call_graph();
+        assert_eq!(graph.total, 10);
+        assert_eq!(
+            graph.nodes["odd"],
+            FunctionStat {
+                self_count: 3,
+                total_count: 5
+            }
+        );
+        assert_eq!(graph.nodes["main"].total_count, 10);
+
+        let edge = |caller: &str, callee: &str| {
+            graph
+                .edges
+                .get(&(caller.to_owned(), callee.to_owned()))
+                .copied()
+        };
+        // counted once per sample, though it's twice in the first stack
+        assert_eq!(edge("main", "odd"), Some(5));
+        assert_eq!(edge("odd", "even"), Some(5));
+        assert_eq!(edge("even", "odd"), Some(3));
+        assert_eq!(edge("main", "work"), Some(5));
+        assert_eq!(edge("work", "main"), None);
+        assert_eq!(graph.edges.len(), 4);
+
+        let mut dot = Vec::new();
+        graph.write_dot(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.starts_with("digraph"));
+        assert!(dot.contains("N1 [label=\"main\\n0 (0.00%)\\nof 10 (100.00%)\"];"));
+        assert_eq!(dot.matches(" -> ").count(), 4);
+
+        // the output is stable
+        let mut again = Vec::new();
+        graph.write_dot(&mut again).unwrap();
+        assert_eq!(String::from_utf8(again).unwrap(), dot);
+    }
+
+    #[test]
+    fn function_stats() {
+        let report = Report {
+            data: vec![
+                (frames("main", &["a", "a", "main"]), 3),
+                (frames("main", &["b", "a", "main"]), 2),
+                (frames("main", &["main"]), 1),
+            ]
+            .into_iter()
+            .collect(),
+            timing: ReportTiming::default(),
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+
+        let stat = |self_count, total_count| FunctionStat {
+            self_count,
+            total_count,
+        };
+        assert_eq!(report.function_stats("a"), Some(stat(3, 5)));
+        assert_eq!(report.function_stats("main"), Some(stat(1, 6)));
+        assert_eq!(report.function_stats("c"), None);
+        assert_eq!(
+            report.top_functions(2),
+            vec![("a".to_owned(), stat(3, 5)), ("b".to_owned(), stat(2, 2))]
+        );
+    }
+
+    #[test]
+    fn thread_summary() {
+        let thread = |thread_name: &str, thread_id, names: &[&str]| Frames {
+            thread_id,
+            ..frames(thread_name, names)
+        };
+        let report = Report {
+            data: vec![
+                (thread("main", 1, &["a", "main"]), 2),
+                (thread("main", 1, &["b", "main"]), 3),
+                // renamed while it was profiled
+                (thread("worker", 2, &["c"]), 1),
+                (thread("worker-0", 2, &["d"]), 4),
+                (thread("", 3, &["e"]), 5),
+            ]
+            .into_iter()
+            .collect(),
+            timing: ReportTiming::default(),
+            collector_full: false,
+            thread_cpu_times: HashMap::new(),
+            thread_states: HashMap::new(),
+            rate_limited_samples: 0,
+            shallow_samples: 0,
+        };
+
+        let summary = |thread_id, thread_name: &str, sample_count| ThreadSummary {
+            thread_id,
+            thread_name: thread_name.to_owned(),
+            sample_count,
+        };
+        assert_eq!(
+            report.thread_summary(),
+            vec![
+                summary(1, "main", 5),
+                summary(2, "worker-0", 5),
+                summary(3, "", 5)
+            ]
+        );
+    }
 }
@@ -0,0 +1,366 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! A copy of the aggregated stacks in a caller-provided memory region.
+//!
+//! With `ProfilerGuardBuilder::shared_region`, the signal handler also
+//! aggregates every sample into a region mapped by the caller, e.g. a
+//! `memfd` or a `MAP_SHARED` file mapping. The region has a fixed layout
+//! without pointers, so that another process can read the profile with
+//! `RawReport::from_shared_region`, even after the profiled process crashed.
+//!
+//! The region starts with a header, followed by the loaded modules encoded
+//! like `RawReport::to_raw_bytes`, and by an open addressing table of the
+//! stacks. The integers are in the native byte order, so it must be read on
+//! the same machine.
+
+use std::os::raw::c_int;
+use std::sync::atomic::{fence, Ordering};
+use std::time::SystemTime;
+
+use crate::raw::{loaded_modules, nanos_since_epoch, RawReport, RawSample};
+use crate::{Error, Result, MAX_DEPTH, MAX_THREAD_NAME};
+
+const MAGIC: &[u8; 8] = b"PPRFSHM\0";
+const VERSION: u32 = 1;
+
+/// Space reserved for the encoded modules.
+const MODULES_SIZE: usize = 64 * 1024;
+const MODULES_OFFSET: usize = std::mem::size_of::<Header>();
+const SLOTS_OFFSET: usize = MODULES_OFFSET + MODULES_SIZE;
+
+/// How many slots are probed for a stack before giving up, so that a full
+/// table doesn't make the signal handler scan all of it.
+const MAX_PROBES: usize = 16;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Header {
+    magic: [u8; 8],
+    version: u32,
+    max_depth: u32,
+    slots: u64,
+    frequency: i64,
+    /// Nanoseconds since the unix epoch when the profiling started.
+    start_time: u64,
+    modules_length: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Slot {
+    /// Number of samples of the stack, an empty slot has no sample. It's
+    /// written last when a slot is taken.
+    count: u64,
+    hash: u64,
+    thread_id: u64,
+    /// Nanoseconds since the unix epoch of the first and the last sample.
+    first_seen: u64,
+    last_seen: u64,
+    depth: u32,
+    truncated: u32,
+    /// The thread name, padded with zeros.
+    thread_name: [u8; MAX_THREAD_NAME],
+    ips: [u64; MAX_DEPTH],
+}
+
+/// A memory region provided to the profiler to hold a copy of the aggregated
+/// stacks, see `ProfilerGuardBuilder::shared_region`.
+#[derive(Clone, Debug)]
+pub struct SharedRegion {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// the region is only written by the profiler, under its lock
+unsafe impl Send for SharedRegion {}
+unsafe impl Sync for SharedRegion {}
+
+impl SharedRegion {
+    /// The size of a region which can hold `stacks` different stacks.
+    pub fn size_for(stacks: usize) -> usize {
+        SLOTS_OFFSET + stacks * std::mem::size_of::<Slot>()
+    }
+
+    /// Use the `len` bytes at `ptr`, e.g. an `mmap` of a `memfd`. The
+    /// previous content of the region is overwritten when the profiler
+    /// starts.
+    ///
+    /// # Safety
+    ///
+    /// The region must be valid for writes while the profiler is running,
+    /// and must not be written by anything else meanwhile.
+    pub unsafe fn new(ptr: *mut u8, len: usize) -> Result<SharedRegion> {
+        if ptr.is_null() || ptr as usize % std::mem::align_of::<Slot>() != 0 {
+            return Err(Error::InvalidSharedRegion("the region is not aligned"));
+        }
+        if len < Self::size_for(1) {
+            return Err(Error::InvalidSharedRegion("the region is too small"));
+        }
+
+        Ok(SharedRegion { ptr, len })
+    }
+
+    fn slots(&self) -> usize {
+        (self.len - SLOTS_OFFSET) / std::mem::size_of::<Slot>()
+    }
+}
+
+/// The writer of a `SharedRegion`, owned by the profiler.
+pub(crate) struct SharedCollector {
+    region: SharedRegion,
+    slots: usize,
+    dropped: u64,
+}
+
+impl SharedCollector {
+    /// Clear the region and write its header and the loaded modules.
+    pub(crate) fn new(region: SharedRegion, frequency: c_int) -> SharedCollector {
+        let slots = region.slots();
+        let start_time = nanos_since_epoch(SystemTime::now());
+
+        let modules = RawReport {
+            frequency,
+            start_time,
+            modules: loaded_modules(),
+            ..Default::default()
+        }
+        .to_raw_bytes();
+        let modules = if modules.len() <= MODULES_SIZE {
+            modules
+        } else {
+            log::warn!("the loaded modules don't fit in the shared region, they are left out");
+            Vec::new()
+        };
+
+        let header = Header {
+            magic: *MAGIC,
+            version: VERSION,
+            max_depth: MAX_DEPTH as u32,
+            slots: slots as u64,
+            frequency: frequency as i64,
+            start_time,
+            modules_length: modules.len() as u64,
+        };
+        unsafe {
+            std::ptr::write_bytes(region.ptr, 0, region.len);
+            std::ptr::write(region.ptr as *mut Header, header);
+            std::ptr::copy_nonoverlapping(
+                modules.as_ptr(),
+                region.ptr.add(MODULES_OFFSET),
+                modules.len(),
+            );
+        }
+
+        SharedCollector {
+            region,
+            slots,
+            dropped: 0,
+        }
+    }
+
+    fn slot(&mut self, index: usize) -> &mut Slot {
+        // it's in bounds as `index < slots`, and aligned as the region and
+        // `SLOTS_OFFSET` are
+        unsafe { &mut *(self.region.ptr.add(SLOTS_OFFSET) as *mut Slot).add(index) }
+    }
+
+    /// Add a sample of the stack made of `ips`, from the innermost frame.
+    /// It returns `false` if no slot was found for the stack, and the sample
+    /// is dropped. This function has to be AS-safe.
+    pub(crate) fn add<I>(
+        &mut self,
+        ips: I,
+        thread_id: u64,
+        thread_name: &[u8],
+        timestamp: SystemTime,
+        truncated: bool,
+    ) -> bool
+    where
+        I: Iterator<Item = u64> + Clone,
+    {
+        // FNV-1a, as it's cheap and doesn't allocate
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        let mut depth = 0;
+        for value in ips
+            .clone()
+            .take(MAX_DEPTH)
+            .chain([thread_id, truncated as u64])
+        {
+            hash = (hash ^ value).wrapping_mul(0x0100_0000_01b3);
+            depth += 1;
+        }
+        let depth = depth - 2;
+        let timestamp = nanos_since_epoch(timestamp);
+
+        let slots = self.slots;
+        for probe in 0..MAX_PROBES.min(slots) {
+            let slot = self.slot((hash as usize).wrapping_add(probe) % slots);
+            if slot.count == 0 {
+                slot.hash = hash;
+                slot.thread_id = thread_id;
+                slot.first_seen = timestamp;
+                slot.last_seen = timestamp;
+                slot.depth = depth as u32;
+                slot.truncated = truncated as u32;
+                let len = thread_name.len().min(MAX_THREAD_NAME);
+                slot.thread_name[..len].copy_from_slice(&thread_name[..len]);
+                for (dst, ip) in slot.ips.iter_mut().zip(ips.take(MAX_DEPTH)) {
+                    *dst = ip;
+                }
+                // the stack is written before the slot is seen as taken
+                fence(Ordering::Release);
+                unsafe { std::ptr::write_volatile(&mut slot.count, 1) };
+                return true;
+            }
+
+            if slot.hash == hash
+                && slot.thread_id == thread_id
+                && slot.truncated == truncated as u32
+                && slot.depth as usize == depth
+                && slot.ips[..depth]
+                    .iter()
+                    .copied()
+                    .eq(ips.clone().take(depth))
+            {
+                slot.count += 1;
+                slot.first_seen = slot.first_seen.min(timestamp);
+                slot.last_seen = slot.last_seen.max(timestamp);
+                return true;
+            }
+        }
+
+        self.dropped += 1;
+        false
+    }
+
+    /// Log a warning if some samples didn't fit in the region. It must not
+    /// be called in the signal handler.
+    pub(crate) fn warn_dropped(&self) {
+        if self.dropped > 0 {
+            log::warn!(
+                "{} samples didn't fit in the shared region, use a larger one",
+                self.dropped
+            );
+        }
+    }
+}
+
+impl RawReport {
+    /// Read the stacks aggregated in a `SharedRegion`, e.g. from a copy of a
+    /// `memfd` taken after the profiled process exited. The modules are
+    /// those loaded when the profiler started. If the process is still
+    /// running, the stack being recorded while the copy is taken may be
+    /// inconsistent.
+    pub fn from_shared_region(bytes: &[u8]) -> Result<RawReport> {
+        if bytes.len() < SLOTS_OFFSET {
+            return Err(Error::InvalidSharedRegion("the region is too small"));
+        }
+        // the structs are plain integers, any bit pattern is valid
+        let header = unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const Header) };
+        if &header.magic != MAGIC {
+            return Err(Error::InvalidSharedRegion("bad magic number"));
+        }
+        if header.version != VERSION || header.max_depth as usize != MAX_DEPTH {
+            return Err(Error::InvalidSharedRegion("unsupported version"));
+        }
+        let slots = header.slots as usize;
+        if header.modules_length as usize > MODULES_SIZE
+            || (bytes.len() - SLOTS_OFFSET) / std::mem::size_of::<Slot>() < slots
+        {
+            return Err(Error::InvalidSharedRegion("the region is truncated"));
+        }
+
+        let modules = match header.modules_length as usize {
+            0 => Vec::new(),
+            len => RawReport::from_raw_bytes(&bytes[MODULES_OFFSET..MODULES_OFFSET + len])?.modules,
+        };
+
+        let mut samples = Vec::new();
+        let mut end_time = header.start_time;
+        for index in 0..slots {
+            let offset = SLOTS_OFFSET + index * std::mem::size_of::<Slot>();
+            let slot =
+                unsafe { std::ptr::read_unaligned(bytes.as_ptr().add(offset) as *const Slot) };
+            if slot.count == 0 {
+                continue;
+            }
+
+            let name_length = slot
+                .thread_name
+                .iter()
+                .position(|byte| *byte == 0)
+                .unwrap_or(MAX_THREAD_NAME);
+            end_time = end_time.max(slot.last_seen);
+            samples.push(RawSample {
+                ips: slot.ips[..(slot.depth as usize).min(MAX_DEPTH)].to_vec(),
+                count: slot.count,
+                thread_id: slot.thread_id,
+                thread_name: String::from_utf8_lossy(&slot.thread_name[..name_length]).into_owned(),
+                timestamp: slot.first_seen,
+                truncated: slot.truncated != 0,
+            });
+        }
+
+        Ok(RawReport {
+            frequency: header.frequency as i32,
+            start_time: header.start_time,
+            duration: end_time - header.start_time,
+            modules,
+            samples,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_region_round_trip() {
+        let len = SharedRegion::size_for(8);
+        let mut buf = vec![0u64; len / 8];
+        let region = unsafe { SharedRegion::new(buf.as_mut_ptr() as *mut u8, len) }.unwrap();
+        let mut collector = SharedCollector::new(region, 99);
+
+        let ts = SystemTime::now();
+        let stack = [0x5555_0000_0010u64, 0x5555_0000_0000];
+        assert!(collector.add(stack.iter().copied(), 42, b"main", ts, false));
+        assert!(collector.add(stack.iter().copied(), 42, b"main", ts, false));
+        assert!(collector.add(stack[1..].iter().copied(), 43, b"worker", ts, true));
+
+        let bytes = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, len) };
+        let report = RawReport::from_shared_region(bytes).unwrap();
+        assert_eq!(report.frequency, 99);
+        assert!(!report.modules.is_empty());
+
+        let mut samples = report.samples;
+        samples.sort_by_key(|sample| sample.thread_id);
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].ips, stack);
+        assert_eq!(samples[0].count, 2);
+        assert_eq!(samples[0].thread_name, "main");
+        assert_eq!(samples[0].timestamp, nanos_since_epoch(ts));
+        assert_eq!(samples[1].ips, &stack[1..]);
+        assert_eq!(samples[1].thread_name, "worker");
+        assert!(samples[1].truncated);
+    }
+
+    #[test]
+    fn shared_region_full() {
+        let len = SharedRegion::size_for(1);
+        let mut buf = vec![0u64; len / 8];
+        let region = unsafe { SharedRegion::new(buf.as_mut_ptr() as *mut u8, len) }.unwrap();
+        let mut collector = SharedCollector::new(region, 99);
+
+        let ts = SystemTime::now();
+        assert!(collector.add([1u64].into_iter(), 1, b"main", ts, false));
+        assert!(!collector.add([2u64].into_iter(), 1, b"main", ts, false));
+    }
+
+    #[test]
+    fn reject_invalid_shared_region() {
+        let mut buf = vec![0u64; 2];
+        assert!(unsafe { SharedRegion::new(buf.as_mut_ptr() as *mut u8, 16) }.is_err());
+        assert!(RawReport::from_shared_region(&[0; SLOTS_OFFSET]).is_err());
+    }
+}
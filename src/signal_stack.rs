@@ -0,0 +1,133 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Alternate signal stacks for the sampling handler.
+//!
+//! The handler unwinds up to `MAX_DEPTH` frames on the stack of the
+//! interrupted thread, which overflows when the thread is close to its stack
+//! limit. With `ProfilerGuardBuilder::signal_stack`, the handler is
+//! registered with `SA_ONSTACK` and runs on the alternate stack of the thread
+//! instead. An alternate stack is per thread, so every thread which should be
+//! protected has to call `install_signal_stack`.
+//!
+//! The Rust runtime installs a small alternate stack on its threads to report
+//! stack overflows, which is too small for the handler. The handler skips the
+//! samples which would run on such a stack.
+
+use std::marker::PhantomData;
+use std::ptr::null_mut;
+
+use crate::error::Result;
+
+/// Size of the alternate stacks installed by `install_signal_stack`.
+pub(crate) const SIGNAL_STACK_SIZE: usize = 256 * 1024;
+
+/// An alternate signal stack installed on the current thread. The previous
+/// alternate stack of the thread is restored when it's dropped, so it must be
+/// dropped on the thread which installed it.
+pub struct SignalStack {
+    stack: *mut libc::c_void,
+    mmap_len: usize,
+    previous: libc::stack_t,
+    // the stack is attached to the thread which installed it
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Install an alternate signal stack, large enough for the sampling handler,
+/// on the current thread. It's only used if the profiler is built with
+/// `ProfilerGuardBuilder::signal_stack(true)`.
+pub fn install_signal_stack() -> Result<SignalStack> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    // a guard page below the stack turns an overflow into a crash rather than
+    // a memory corruption
+    let mmap_len = SIGNAL_STACK_SIZE + page_size;
+    let mmap = unsafe {
+        libc::mmap(
+            null_mut(),
+            mmap_len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if mmap == libc::MAP_FAILED {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    if unsafe { libc::mprotect(mmap, page_size, libc::PROT_NONE) } != 0 {
+        let err = std::io::Error::last_os_error();
+        unsafe { libc::munmap(mmap, mmap_len) };
+        return Err(err.into());
+    }
+
+    let stack = libc::stack_t {
+        ss_sp: unsafe { (mmap as *mut u8).add(page_size) } as *mut libc::c_void,
+        ss_flags: 0,
+        ss_size: SIGNAL_STACK_SIZE,
+    };
+    let mut previous: libc::stack_t = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sigaltstack(&stack, &mut previous) } != 0 {
+        let err = std::io::Error::last_os_error();
+        unsafe { libc::munmap(mmap, mmap_len) };
+        return Err(err.into());
+    }
+
+    Ok(SignalStack {
+        stack: mmap,
+        mmap_len,
+        previous,
+        _not_send: PhantomData,
+    })
+}
+
+impl Drop for SignalStack {
+    fn drop(&mut self) {
+        unsafe {
+            if libc::sigaltstack(&self.previous, null_mut()) != 0 {
+                // the stack may still be in use, leak it rather than unmap it
+                log::error!(
+                    "fail to restore the signal stack: {}",
+                    std::io::Error::last_os_error()
+                );
+                return;
+            }
+            libc::munmap(self.stack, self.mmap_len);
+        }
+    }
+}
+
+/// Returns `false` if the handler is running on an alternate stack which is
+/// too small for it. It's async-signal-safe.
+pub(crate) fn signal_stack_is_usable() -> bool {
+    let mut current: libc::stack_t = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sigaltstack(null_mut(), &mut current) } != 0 {
+        return false;
+    }
+
+    current.ss_flags & libc::SS_ONSTACK == 0 || current.ss_size >= SIGNAL_STACK_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn current_signal_stack() -> libc::stack_t {
+        let mut current: libc::stack_t = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { libc::sigaltstack(null_mut(), &mut current) }, 0);
+        current
+    }
+
+    #[test]
+    fn install_and_restore() {
+        let previous = current_signal_stack();
+
+        let stack = install_signal_stack().unwrap();
+        let installed = current_signal_stack();
+        assert_eq!(installed.ss_size, SIGNAL_STACK_SIZE);
+        assert!(signal_stack_is_usable());
+
+        drop(stack);
+        let restored = current_signal_stack();
+        assert_eq!(restored.ss_sp, previous.ss_sp);
+        assert_eq!(restored.ss_size, previous.ss_size);
+    }
+}
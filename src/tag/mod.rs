@@ -0,0 +1,179 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Labels of the samples, like the profiler labels of Go.
+//!
+//! The samples taken while `with_labels` runs its closure carry its key/value
+//! labels, which are written as the `Label`s of the samples of the `pprof`
+//! profile, to tell the requests or the tenants which burn the CPU apart.
+//!
+//! ```rust
+//! let sum: u64 = pprof::tag::with_labels(&[("rpc", "get")], || (0..1000).sum());
+//! ```
+//!
+//! The label sets are interned outside of the signal handler, which only
+//! reads the id of the set of the interrupted thread.
+//!
+//! With the `tracing` feature, `TracingLayer` labels the samples with the
+//! spans which are entered, instead of wrapping the code in `with_labels`.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+#[cfg(feature = "tracing")]
+mod tracing_layer;
+
+#[cfg(feature = "tracing")]
+pub use self::tracing_layer::TracingLayer;
+
+/// The labels of a sample, sorted by key.
+pub type Labels = Arc<Vec<(String, String)>>;
+
+/// The id of the empty label set.
+pub(crate) const NO_LABELS: u32 = 0;
+
+#[derive(Default)]
+struct Registry {
+    sets: Vec<Labels>,
+    ids: HashMap<Labels, u32>,
+}
+
+impl Registry {
+    fn intern(&mut self, labels: Vec<(String, String)>) -> u32 {
+        if labels.is_empty() {
+            return NO_LABELS;
+        }
+        let labels = Arc::new(labels);
+        if let Some(id) = self.ids.get(&labels) {
+            return *id;
+        }
+
+        self.sets.push(labels.clone());
+        let id = self.sets.len() as u32;
+        self.ids.insert(labels, id);
+        id
+    }
+
+    fn get(&self, id: u32) -> Labels {
+        match id.checked_sub(1) {
+            Some(index) => self.sets[index as usize].clone(),
+            None => Labels::default(),
+        }
+    }
+}
+
+static REGISTRY: Lazy<Mutex<Registry>> = Lazy::new(Default::default);
+
+thread_local! {
+    static CURRENT: Cell<u32> = const { Cell::new(NO_LABELS) };
+}
+
+/// Restores the labels of the thread when the closure returns or unwinds.
+struct Restore(u32);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        set_label_set(self.0);
+    }
+}
+
+/// Run `f` with `labels` added to the ones of the current thread, which are
+/// attached to the samples of the thread meanwhile. A key which is already
+/// set is overridden until `f` returns. The label sets are kept until the
+/// process exits, so the values shouldn't be unbounded, e.g. request ids.
+pub fn with_labels<R>(labels: &[(&str, &str)], f: impl FnOnce() -> R) -> R {
+    let _restore = Restore(push_labels(labels));
+    f()
+}
+
+/// Add `labels` to the ones of the current thread, and return the id of its
+/// previous label set, which `set_label_set` restores.
+pub(crate) fn push_labels<K, V>(labels: &[(K, V)]) -> u32
+where
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    let previous = current_label_set();
+    let mut merged: Vec<(String, String)> = get_labels(previous).as_ref().clone();
+    for (key, value) in labels {
+        let (key, value) = (key.as_ref(), value.as_ref());
+        match merged.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_owned(),
+            None => merged.push((key.to_owned(), value.to_owned())),
+        }
+    }
+    merged.sort();
+
+    let id = REGISTRY.lock().intern(merged);
+    set_label_set(id);
+    previous
+}
+
+/// The labels of the current thread, set by the enclosing `with_labels`.
+pub fn labels() -> Labels {
+    get_labels(current_label_set())
+}
+
+/// The id of the label set of the current thread. It's async-signal-safe.
+pub(crate) fn current_label_set() -> u32 {
+    CURRENT.try_with(Cell::get).unwrap_or(NO_LABELS)
+}
+
+/// Restore the label set `id` of the current thread, returned by
+/// `push_labels`.
+pub(crate) fn set_label_set(id: u32) {
+    let _ = CURRENT.try_with(|current| current.set(id));
+}
+
+/// The labels of the set `id`, returned by `current_label_set`.
+pub(crate) fn get_labels(id: u32) -> Labels {
+    if id == NO_LABELS {
+        return Labels::default();
+    }
+    REGISTRY.lock().get(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(labels: &Labels) -> Vec<(&str, &str)> {
+        labels
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn nested_labels() {
+        assert!(labels().is_empty());
+        with_labels(&[("rpc", "get"), ("tenant", "a")], || {
+            assert_eq!(pairs(&labels()), [("rpc", "get"), ("tenant", "a")]);
+            with_labels(&[("rpc", "put")], || {
+                assert_eq!(pairs(&labels()), [("rpc", "put"), ("tenant", "a")]);
+            });
+            assert_eq!(pairs(&labels()), [("rpc", "get"), ("tenant", "a")]);
+        });
+        assert!(labels().is_empty());
+        assert_eq!(current_label_set(), NO_LABELS);
+    }
+
+    #[test]
+    fn interned_labels() {
+        let first = with_labels(&[("rpc", "scan")], current_label_set);
+        let second = with_labels(&[("rpc", "scan")], current_label_set);
+        assert_ne!(first, NO_LABELS);
+        assert_eq!(first, second);
+        assert_eq!(pairs(&get_labels(first)), [("rpc", "scan")]);
+    }
+
+    #[test]
+    fn restored_on_panic() {
+        let result = std::panic::catch_unwind(|| with_labels(&[("rpc", "get")], || panic!()));
+        assert!(result.is_err());
+        assert!(labels().is_empty());
+    }
+}
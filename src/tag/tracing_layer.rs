@@ -0,0 +1,176 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::cell::RefCell;
+use std::fmt::Debug;
+
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::Subscriber;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use super::{current_label_set, push_labels, set_label_set};
+
+/// The label of the name of the innermost entered span.
+const SPAN: &str = "span";
+
+thread_local! {
+    /// The label sets of the thread before the entered spans were entered.
+    static ENTERED: RefCell<Vec<u32>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A `tracing_subscriber` layer which labels the samples of a thread with
+/// the span it's in, as `with_labels` would. The name of the innermost
+/// entered span is the `span` label, and the `fields` of the entered spans
+/// are labels too, the innermost value winning. **only available with
+/// `tracing` feature**
+///
+/// ```rust
+/// use tracing_subscriber::layer::SubscriberExt;
+///
+/// let subscriber = tracing_subscriber::registry()
+///     .with(pprof::tag::TracingLayer::new().fields(&["rpc"]));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct TracingLayer {
+    fields: Vec<&'static str>,
+}
+
+impl TracingLayer {
+    /// A layer which only labels the samples with the name of the span.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also label the samples with the values of `fields` in the entered
+    /// spans. Their values should be bounded, e.g. the kind of a request
+    /// rather than its id, as every label set is kept.
+    pub fn fields(mut self, fields: &[&'static str]) -> Self {
+        self.fields.extend_from_slice(fields);
+        self
+    }
+}
+
+/// The labels of a span, kept in its extensions.
+struct SpanLabels(Vec<(&'static str, String)>);
+
+/// Records the values of the selected fields of a span.
+struct FieldVisitor<'a> {
+    fields: &'a [&'static str],
+    labels: &'a mut Vec<(&'static str, String)>,
+}
+
+impl FieldVisitor<'_> {
+    fn set(&mut self, field: &Field, value: String) {
+        if !self.fields.contains(&field.name()) {
+            return;
+        }
+        match self.labels.iter_mut().find(|(key, _)| *key == field.name()) {
+            Some((_, v)) => *v = value,
+            None => self.labels.push((field.name(), value)),
+        }
+    }
+}
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, value.to_owned());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.set(field, format!("{:?}", value));
+    }
+}
+
+impl<S> Layer<S> for TracingLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut labels = vec![(SPAN, attrs.metadata().name().to_owned())];
+        attrs.record(&mut FieldVisitor {
+            fields: &self.fields,
+            labels: &mut labels,
+        });
+        span.extensions_mut().insert(SpanLabels(labels));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(labels) = span.extensions_mut().get_mut::<SpanLabels>() {
+                values.record(&mut FieldVisitor {
+                    fields: &self.fields,
+                    labels: &mut labels.0,
+                });
+            }
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        let previous = match ctx.span(id) {
+            Some(span) => match span.extensions().get::<SpanLabels>() {
+                Some(labels) => push_labels(&labels.0),
+                None => current_label_set(),
+            },
+            None => current_label_set(),
+        };
+        let _ = ENTERED.try_with(|entered| entered.borrow_mut().push(previous));
+    }
+
+    fn on_exit(&self, _id: &Id, _ctx: Context<'_, S>) {
+        let previous = ENTERED
+            .try_with(|entered| entered.borrow_mut().pop())
+            .ok()
+            .flatten();
+        if let Some(previous) = previous {
+            set_label_set(previous);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tag::labels;
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn pairs() -> Vec<(String, String)> {
+        labels().to_vec()
+    }
+
+    fn pair(key: &str, value: &str) -> (String, String) {
+        (key.to_owned(), value.to_owned())
+    }
+
+    #[test]
+    fn span_labels() {
+        let subscriber =
+            tracing_subscriber::registry().with(TracingLayer::new().fields(&["rpc", "region"]));
+        tracing::subscriber::with_default(subscriber, || {
+            let request = tracing::info_span!("request", rpc = "get", id = 7);
+            let _request = request.enter();
+            assert_eq!(pairs(), [pair("rpc", "get"), pair("span", "request")]);
+
+            {
+                let read = tracing::info_span!("read", region = 3u64);
+                let _read = read.enter();
+                assert_eq!(
+                    pairs(),
+                    [
+                        pair("region", "3"),
+                        pair("rpc", "get"),
+                        pair("span", "read")
+                    ]
+                );
+            }
+
+            assert_eq!(pairs(), [pair("rpc", "get"), pair("span", "request")]);
+        });
+        assert!(labels().is_empty());
+    }
+}
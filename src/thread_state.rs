@@ -0,0 +1,318 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! The scheduling state of the sampled threads.
+//!
+//! The state of a thread is only exposed through `/proc/self/task/<tid>/stat`,
+//! which can't be read in the signal handler. With
+//! `ProfilerGuardBuilder::thread_states`, a background thread reads the state
+//! of every thread of the process at the sampling frequency into a lock-free
+//! table, and the signal handler looks up the state of its own thread there
+//! when it takes a sample. The state is the one observed by the latest read,
+//! so it lags behind the sample by up to one sampling period.
+
+#[cfg(target_os = "linux")]
+use std::os::raw::c_int;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
+#[cfg(target_os = "linux")]
+use std::thread::JoinHandle;
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use crate::error::Result;
+
+/// The max number of threads whose state can be tracked at the same time.
+#[cfg(target_os = "linux")]
+const MAX_TRACKED_THREADS: usize = 1 << 10;
+
+/// The scheduling state of a thread, from the third field of
+/// `/proc/<pid>/task/<tid>/stat`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ThreadState {
+    /// `R`, running or runnable.
+    Running,
+    /// `S`, in an interruptible sleep, e.g. waiting for an event.
+    Sleeping,
+    /// `D`, in an uninterruptible sleep, usually waiting for IO.
+    DiskSleep,
+    /// `T` or `t`, stopped by a signal or a tracer.
+    Stopped,
+    /// Any other state, e.g. `I` for idle kernel threads.
+    Other,
+}
+
+#[cfg(target_os = "linux")]
+impl ThreadState {
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            b'R' => Some(ThreadState::Running),
+            b'S' => Some(ThreadState::Sleeping),
+            b'D' => Some(ThreadState::DiskSleep),
+            b'T' | b't' => Some(ThreadState::Stopped),
+            0 => None,
+            _ => Some(ThreadState::Other),
+        }
+    }
+}
+
+/// The number of samples of a thread taken in each scheduling state.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ThreadStates {
+    pub running: u64,
+    pub sleeping: u64,
+    pub disk_sleep: u64,
+    pub stopped: u64,
+    pub other: u64,
+    /// Samples taken before the state of the thread was first read.
+    pub unknown: u64,
+}
+
+#[cfg(target_os = "linux")]
+impl ThreadStates {
+    fn record(&mut self, state: Option<ThreadState>) {
+        let count = match state {
+            Some(ThreadState::Running) => &mut self.running,
+            Some(ThreadState::Sleeping) => &mut self.sleeping,
+            Some(ThreadState::DiskSleep) => &mut self.disk_sleep,
+            Some(ThreadState::Stopped) => &mut self.stopped,
+            Some(ThreadState::Other) => &mut self.other,
+            None => &mut self.unknown,
+        };
+        *count += 1;
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn slot(key: u64) -> usize {
+    (key.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 54) as usize % MAX_TRACKED_THREADS
+}
+
+/// The latest observed state of every thread, keyed by kernel thread id. It's
+/// written by the reader thread and read by the signal handler, without locks.
+#[cfg(target_os = "linux")]
+pub(crate) struct ObservedStates {
+    entries: Box<[(AtomicU32, AtomicU8)]>,
+}
+
+#[cfg(target_os = "linux")]
+impl Default for ObservedStates {
+    fn default() -> Self {
+        Self {
+            entries: (0..MAX_TRACKED_THREADS)
+                .map(|_| (AtomicU32::new(0), AtomicU8::new(0)))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl ObservedStates {
+    /// Store the state of `tid`. Threads which don't fit in the table are
+    /// ignored. It must only be called by a single thread.
+    fn store(&self, tid: u32, code: u8) {
+        let start = slot(tid as u64);
+        for probe in 0..MAX_TRACKED_THREADS {
+            let (id, state) = &self.entries[(start + probe) % MAX_TRACKED_THREADS];
+            let current = id.load(Ordering::Acquire);
+            if current == tid {
+                state.store(code, Ordering::Relaxed);
+                return;
+            }
+            if current == 0 {
+                // publish the state before the thread id
+                state.store(code, Ordering::Relaxed);
+                id.store(tid, Ordering::Release);
+                return;
+            }
+        }
+    }
+
+    /// The latest observed state of `tid`. It's async-signal-safe.
+    pub(crate) fn load(&self, tid: u32) -> Option<ThreadState> {
+        let start = slot(tid as u64);
+        for probe in 0..MAX_TRACKED_THREADS {
+            let (id, state) = &self.entries[(start + probe) % MAX_TRACKED_THREADS];
+            match id.load(Ordering::Acquire) {
+                0 => return None,
+                current if current == tid => {
+                    return ThreadState::from_code(state.load(Ordering::Relaxed))
+                }
+                _ => {}
+            }
+        }
+
+        None
+    }
+}
+
+/// Returns the kernel id of the calling thread. It's async-signal-safe.
+#[cfg(target_os = "linux")]
+pub(crate) fn current_tid() -> u32 {
+    unsafe { libc::syscall(libc::SYS_gettid) as u32 }
+}
+
+/// Extract the state code from the content of `/proc/<pid>/task/<tid>/stat`,
+/// which looks like `1234 (name) S 1 ...`. The name may contain spaces and
+/// parentheses, so the state is looked up after the last `)`.
+#[cfg(target_os = "linux")]
+fn parse_stat(stat: &str) -> Option<u8> {
+    let (_, rest) = stat.rsplit_once(')')?;
+    rest.trim_start().bytes().next()
+}
+
+#[cfg(target_os = "linux")]
+fn read_states(states: &ObservedStates) -> bool {
+    let tasks = match std::fs::read_dir("/proc/self/task") {
+        Ok(tasks) => tasks,
+        Err(err) => {
+            log::warn!("fail to list threads: {}", err);
+            return false;
+        }
+    };
+
+    for task in tasks.flatten() {
+        let tid = match task.file_name().to_str().and_then(|tid| tid.parse().ok()) {
+            Some(tid) => tid,
+            None => continue,
+        };
+        // the thread may have exited since it was listed
+        if let Ok(stat) = std::fs::read_to_string(task.path().join("stat")) {
+            if let Some(code) = parse_stat(&stat) {
+                states.store(tid, code);
+            }
+        }
+    }
+
+    true
+}
+
+/// A fixed size open addressing table from thread id to `ThreadStates`. It's
+/// allocated up front so that it can be updated in the signal handler.
+#[cfg(target_os = "linux")]
+pub(crate) struct ThreadStatesTable {
+    entries: Box<[(u64, ThreadStates)]>,
+}
+
+#[cfg(target_os = "linux")]
+impl Default for ThreadStatesTable {
+    fn default() -> Self {
+        Self {
+            entries: vec![(0, ThreadStates::default()); MAX_TRACKED_THREADS].into_boxed_slice(),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl ThreadStatesTable {
+    /// Records that a sample was taken on `thread_id` while it was in
+    /// `state`. Threads which don't fit in the table are ignored.
+    pub(crate) fn record(&mut self, thread_id: u64, state: Option<ThreadState>) {
+        // 0 marks an empty slot
+        let key = thread_id.max(1);
+        let start = slot(key);
+        for probe in 0..MAX_TRACKED_THREADS {
+            let (id, entry) = &mut self.entries[(start + probe) % MAX_TRACKED_THREADS];
+            if *id == 0 {
+                *id = key;
+            }
+            if *id == key {
+                entry.record(state);
+                return;
+            }
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u64, ThreadStates)> + '_ {
+        self.entries
+            .iter()
+            .filter(|(id, _)| *id != 0)
+            .map(|(id, entry)| (*id, *entry))
+    }
+}
+
+/// Reads the state of every thread of the current process into
+/// `ObservedStates` until it's dropped.
+#[cfg(target_os = "linux")]
+pub(crate) struct ThreadStateReader {
+    stop: Arc<AtomicBool>,
+    reader: Option<JoinHandle<()>>,
+}
+
+#[cfg(target_os = "linux")]
+impl ThreadStateReader {
+    pub(crate) fn new(frequency: c_int, states: Arc<ObservedStates>) -> Result<Self> {
+        let interval = Duration::from_secs(1) / frequency.max(1) as u32;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("pprof-tstate".to_owned())
+                .spawn(move || {
+                    while !stop.load(Ordering::Acquire) {
+                        if !read_states(&states) {
+                            return;
+                        }
+                        std::thread::sleep(interval);
+                    }
+                })?
+        };
+
+        Ok(Self {
+            stop,
+            reader: Some(reader),
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for ThreadStateReader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(reader) = self.reader.take() {
+            if reader.join().is_err() {
+                log::error!("thread state reader thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn observe_thread_states() {
+        assert_eq!(parse_stat("1234 (a) b) c) S 1 1234"), Some(b'S'));
+        assert_eq!(parse_stat("1234 (worker) R 1"), Some(b'R'));
+        assert_eq!(parse_stat(""), None);
+
+        let states = ObservedStates::default();
+        assert!(read_states(&states));
+        // the current thread is running while it reads its own state
+        assert_eq!(states.load(current_tid()), Some(ThreadState::Running));
+        assert_eq!(states.load(0), None);
+
+        let mut table = ThreadStatesTable::default();
+        table.record(42, Some(ThreadState::Running));
+        table.record(42, Some(ThreadState::DiskSleep));
+        table.record(42, None);
+        let entries: Vec<_> = table.iter().collect();
+        assert_eq!(
+            entries,
+            vec![(
+                42,
+                ThreadStates {
+                    running: 1,
+                    disk_sleep: 1,
+                    unknown: 1,
+                    ..Default::default()
+                }
+            )]
+        );
+    }
+}
@@ -1,9 +1,30 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
+#[cfg(all(target_os = "linux", not(feature = "disabled")))]
+use std::collections::{HashMap, HashSet};
+#[cfg(not(feature = "disabled"))]
 use std::os::raw::c_int;
+#[cfg(all(unix, not(feature = "disabled")))]
 use std::ptr::null_mut;
-use std::time::{Duration, Instant, SystemTime};
+#[cfg(all(target_os = "linux", not(feature = "disabled")))]
+use std::sync::atomic::AtomicBool;
+#[cfg(all(unix, not(feature = "disabled")))]
+use std::sync::atomic::{AtomicI32, AtomicU64, AtomicUsize, Ordering};
+#[cfg(all(target_os = "linux", not(feature = "disabled")))]
+use std::sync::Arc;
+#[cfg(all(target_os = "linux", not(feature = "disabled")))]
+use std::thread::JoinHandle;
+#[cfg(all(unix, not(feature = "disabled")))]
+use std::time::Instant;
+use std::time::{Duration, SystemTime};
 
+#[cfg(all(unix, not(feature = "disabled")))]
+use nix::sys::signal::Signal;
+
+#[cfg(all(target_os = "linux", not(feature = "disabled")))]
+use crate::error::Result;
+
+#[cfg(all(unix, not(feature = "disabled")))]
 #[repr(C)]
 #[derive(Clone)]
 struct Timeval {
@@ -11,6 +32,7 @@ struct Timeval {
     pub tv_usec: i64,
 }
 
+#[cfg(all(unix, not(feature = "disabled")))]
 #[repr(C)]
 #[derive(Clone)]
 struct Itimerval {
@@ -18,43 +40,485 @@ struct Itimerval {
     pub it_value: Timeval,
 }
 
+#[cfg(all(unix, not(feature = "disabled")))]
 extern "C" {
     fn setitimer(which: c_int, new_value: *mut Itimerval, old_value: *mut Itimerval) -> c_int;
+    #[cfg(all(test, unix, not(feature = "disabled")))]
+    fn getitimer(which: c_int, curr_value: *mut Itimerval) -> c_int;
+}
+
+/// Whether the process wide timer of `mode` is armed.
+#[cfg(all(test, unix, not(feature = "disabled")))]
+pub(crate) fn is_armed(mode: ProfilingMode) -> bool {
+    let mut value = Itimerval {
+        it_interval: timeval(0),
+        it_value: timeval(0),
+    };
+    unsafe { getitimer(mode.which(), &mut value) };
+    value.it_value.tv_sec != 0 || value.it_value.tv_usec != 0
 }
 
+#[cfg(all(unix, not(feature = "disabled")))]
+const ITIMER_VIRTUAL: c_int = 1;
+#[cfg(all(unix, not(feature = "disabled")))]
 const ITIMER_PROF: c_int = 2;
 
+/// Decides which kind of time the sampling timer counts, and thus what the
+/// samples of a profile represent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ProfilingMode {
+    /// Both user and system CPU time, through `ITIMER_PROF` and `SIGPROF`.
+    #[default]
+    Cpu,
+    /// User space CPU time only, through `ITIMER_VIRTUAL` and `SIGVTALRM`.
+    /// Time spent in the kernel (e.g. in syscalls) is not sampled.
+    UserCpu,
+    /// Same timer as `Cpu`, but only the interrupted function is recorded and
+    /// the stack isn't unwound. The report is a flat histogram of the hot
+    /// functions without call trees, at a fraction of the cost per sample.
+    LeafOnly,
+    /// Wall time: every thread is sampled at the frequency whether it's
+    /// running, sleeping or blocked, through a `CLOCK_MONOTONIC` timer per
+    /// thread which delivers `SIGPROF` to it. The samples show where the
+    /// threads wait, e.g. on a lock or on IO, and not only where they burn CPU.
+    /// The blocking syscalls are interrupted by the samples, most of them are
+    /// restarted as the handler is installed with `SA_RESTART`, but a few
+    /// (e.g. `epoll_wait`) fail with `EINTR` and must be retried by the caller.
+    /// **only available on Linux**, the CPU time is sampled elsewhere.
+    WallClock,
+    /// Off-CPU time: the threads are sampled on the wall time like
+    /// `WallClock`, but only the samples of the threads which barely
+    /// consumed CPU time since their previous sample are kept. Every sample
+    /// stands for a sampling period during which its thread was blocked or
+    /// waiting to run, at the stack where it was, so the report attributes
+    /// the blocked time to the call stacks.
+    /// **only available on Linux**, the CPU time is sampled elsewhere.
+    OffCpu,
+}
+
+impl ProfilingMode {
+    #[cfg(all(unix, not(feature = "disabled")))]
+    fn which(&self) -> c_int {
+        match self {
+            ProfilingMode::Cpu
+            | ProfilingMode::LeafOnly
+            | ProfilingMode::WallClock
+            | ProfilingMode::OffCpu => ITIMER_PROF,
+            ProfilingMode::UserCpu => ITIMER_VIRTUAL,
+        }
+    }
+
+    /// The kind of the per-thread CPU clock counting the time of this mode,
+    /// see `CPUCLOCK_SCHED` and `CPUCLOCK_VIRT` in the kernel, or `None` for
+    /// the wall time.
+    #[cfg(all(target_os = "linux", not(feature = "disabled")))]
+    fn cpu_clock(&self) -> Option<libc::clockid_t> {
+        const CPUCLOCK_VIRT: libc::clockid_t = 1;
+        const CPUCLOCK_SCHED: libc::clockid_t = 2;
+
+        match self {
+            ProfilingMode::Cpu | ProfilingMode::LeafOnly => Some(CPUCLOCK_SCHED),
+            ProfilingMode::UserCpu => Some(CPUCLOCK_VIRT),
+            ProfilingMode::WallClock | ProfilingMode::OffCpu => None,
+        }
+    }
+
+    /// The per-thread CPU clock counting the time of this mode, or the
+    /// monotonic clock for the wall time.
+    #[cfg(all(target_os = "linux", not(feature = "disabled")))]
+    fn thread_clock(&self, tid: libc::pid_t) -> libc::clockid_t {
+        match self.cpu_clock() {
+            Some(clock) => thread_cpu_clock(tid, clock),
+            None => libc::CLOCK_MONOTONIC,
+        }
+    }
+
+    /// Whether this mode samples the wall time, which needs a timer per
+    /// thread.
+    pub(crate) fn is_wall_time(&self) -> bool {
+        matches!(self, ProfilingMode::WallClock | ProfilingMode::OffCpu)
+    }
+
+    /// The signal delivered when the timer of this mode expires.
+    #[cfg(all(unix, not(feature = "disabled")))]
+    pub(crate) fn signal(&self) -> Signal {
+        match self {
+            ProfilingMode::Cpu
+            | ProfilingMode::LeafOnly
+            | ProfilingMode::WallClock
+            | ProfilingMode::OffCpu => Signal::SIGPROF,
+            ProfilingMode::UserCpu => Signal::SIGVTALRM,
+        }
+    }
+}
+
+/// The id of the CPU clock of kind `clock` of the thread `tid`, see
+/// `MAKE_THREAD_CPUCLOCK` in the kernel.
+#[cfg(all(target_os = "linux", not(feature = "disabled")))]
+fn thread_cpu_clock(tid: libc::pid_t, clock: libc::clockid_t) -> libc::clockid_t {
+    const CPUCLOCK_PERTHREAD_MASK: libc::clockid_t = 4;
+
+    (!tid << 3) | CPUCLOCK_PERTHREAD_MASK | clock
+}
+
+/// Returns the resolution of the process CPU-time clock, which bounds how often
+/// the profiling timer can fire. A frequency higher than its inverse can't be
+/// delivered, and the effective sampling rate will be lower than requested.
+#[cfg(unix)]
+pub fn timer_resolution() -> Option<Duration> {
+    let mut res = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_getres(libc::CLOCK_PROCESS_CPUTIME_ID, &mut res) } != 0 {
+        return None;
+    }
+
+    Some(Duration::new(res.tv_sec as u64, res.tv_nsec as u32))
+}
+
+/// The resolution of the sleeps of the sampler thread isn't known on
+/// Windows.
+#[cfg(windows)]
+pub fn timer_resolution() -> Option<Duration> {
+    None
+}
+
+/// Returns `false` if `frequency` is higher than what a timer with the given
+/// `resolution` can deliver.
+#[cfg(not(feature = "disabled"))]
+pub(crate) fn frequency_is_supported(frequency: c_int, resolution: Duration) -> bool {
+    resolution.as_nanos() * frequency.max(0) as u128 <= 1_000_000_000
+}
+
+/// Extract the mask of the blocked signals from the content of
+/// `/proc/<pid>/task/<tid>/status`.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+fn parse_blocked_signals(status: &str) -> Option<u64> {
+    let mask = status
+        .lines()
+        .find_map(|line| line.strip_prefix("SigBlk:"))?;
+    u64::from_str_radix(mask.trim(), 16).ok()
+}
+
+/// Returns `true` if the thread `tid` of the current process blocks `signal`,
+/// in which case it's never sampled.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+fn thread_blocks_signal(tid: libc::pid_t, signal: Signal) -> bool {
+    std::fs::read_to_string(format!("/proc/self/task/{}/status", tid))
+        .ok()
+        .and_then(|status| parse_blocked_signals(&status))
+        .map_or(false, |mask| mask & (1 << (signal as u64 - 1)) != 0)
+}
+
+/// Log a warning if the thread `tid` blocks `signal`.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+fn warn_if_signal_blocked(tid: libc::pid_t, signal: Signal) {
+    if thread_blocks_signal(tid, signal) {
+        let name =
+            std::fs::read_to_string(format!("/proc/self/task/{}/comm", tid)).unwrap_or_default();
+        log::warn!(
+            "thread {} ({}) blocks {}, it won't be sampled",
+            tid,
+            name.trim_end(),
+            signal
+        );
+    }
+}
+
+/// Log a warning for every thread of the process which blocks `signal`.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+fn warn_blocked_threads(signal: Signal) {
+    if let Ok(tasks) = std::fs::read_dir("/proc/self/task") {
+        for task in tasks.flatten() {
+            if let Some(tid) = task.file_name().to_str().and_then(|tid| tid.parse().ok()) {
+                warn_if_signal_blocked(tid, signal);
+            }
+        }
+    }
+}
+
+#[cfg(all(unix, not(feature = "disabled")))]
+fn timeval(micros: i64) -> Timeval {
+    Timeval {
+        tv_sec: micros / 1e6 as i64,
+        tv_usec: micros % 1e6 as i64,
+    }
+}
+
+/// The randomization of the intervals of the process wide timer, see
+/// `ProfilerGuardBuilder::jitter`. The timer is then armed for a single
+/// expiry at a time, and the signal handler arms it again with the next
+/// interval.
+#[cfg(all(unix, not(feature = "disabled")))]
+struct Jitter {
+    /// The max deviation from the period as a fraction of it, as the bits of
+    /// an `f64`. Zero when the intervals aren't randomized.
+    fraction: AtomicU64,
+    period_micros: AtomicU64,
+    which: AtomicI32,
+    /// The state of the xorshift generator of the intervals.
+    state: AtomicU64,
+    /// The number of signal handlers arming the timer again.
+    rearming: AtomicUsize,
+}
+
+#[cfg(all(unix, not(feature = "disabled")))]
+static JITTER: Jitter = Jitter::new();
+
+#[cfg(all(unix, not(feature = "disabled")))]
+impl Jitter {
+    const fn new() -> Self {
+        Self {
+            fraction: AtomicU64::new(0),
+            period_micros: AtomicU64::new(0),
+            which: AtomicI32::new(0),
+            state: AtomicU64::new(1),
+            rearming: AtomicUsize::new(0),
+        }
+    }
+
+    fn enable(&self, which: c_int, period_micros: i64, fraction: f64) {
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        self.state.store(seed | 1, Ordering::Relaxed);
+        self.period_micros
+            .store(period_micros as u64, Ordering::Relaxed);
+        self.which.store(which, Ordering::Relaxed);
+        self.fraction.store(fraction.to_bits(), Ordering::SeqCst);
+    }
+
+    /// Stop arming the timer again, and wait for the signal handlers which
+    /// are doing it, so that the timer can be disarmed for good.
+    fn disable(&self) {
+        self.fraction.store(0, Ordering::SeqCst);
+        while self.rearming.load(Ordering::SeqCst) != 0 {
+            std::hint::spin_loop();
+        }
+    }
+
+    /// The next interval, uniformly distributed within the fraction around
+    /// the period, so that the average interval is still the period.
+    fn next_interval(&self, fraction: f64) -> i64 {
+        // the handlers racing on the state may draw the same interval, which
+        // doesn't matter
+        let mut x = self.state.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state.store(x, Ordering::Relaxed);
+
+        // uniform in [-1, 1)
+        let unit = (x >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0;
+        let period = self.period_micros.load(Ordering::Relaxed) as f64;
+        // a zero interval would disarm the timer
+        ((period * (1.0 + fraction * unit)) as i64).max(1)
+    }
+
+    /// Arm the timer for its next expiry, if the intervals are randomized.
+    /// It's async-signal-safe.
+    fn rearm(&self) {
+        self.rearming.fetch_add(1, Ordering::SeqCst);
+        let fraction = f64::from_bits(self.fraction.load(Ordering::SeqCst));
+        if fraction > 0.0 {
+            let it_value = timeval(self.next_interval(fraction));
+            unsafe {
+                setitimer(
+                    self.which.load(Ordering::Relaxed),
+                    &mut Itimerval {
+                        it_interval: timeval(0),
+                        it_value,
+                    },
+                    null_mut(),
+                )
+            };
+        }
+        self.rearming.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Arm the process wide timer again with a randomized interval, if it was
+/// built with a jitter. It's called at the start of the signal handler.
+#[cfg(all(unix, not(feature = "disabled")))]
+pub(crate) fn rearm_jittered_timer() {
+    JITTER.rearm();
+}
+
+/// Arm the interval timer `which` to expire `frequency` times per second of
+/// the time it counts, with every interval randomized by up to `jitter` of
+/// the period.
+#[cfg(all(unix, not(feature = "disabled")))]
+fn arm(which: c_int, frequency: c_int, jitter: f64) {
+    let interval = 1e6 as i64 / i64::from(frequency);
+    let mut it_interval = timeval(interval);
+    let mut it_value = it_interval.clone();
+    if jitter > 0.0 {
+        JITTER.enable(which, interval, jitter);
+        it_interval = timeval(0);
+        it_value = timeval(JITTER.next_interval(jitter));
+    }
+
+    unsafe {
+        setitimer(
+            which,
+            &mut Itimerval {
+                it_interval,
+                it_value,
+            },
+            null_mut(),
+        )
+    };
+}
+
+/// Disarm the interval timer `which`.
+#[cfg(all(unix, not(feature = "disabled")))]
+fn disarm(which: c_int) {
+    JITTER.disable();
+    unsafe {
+        setitimer(
+            which,
+            &mut Itimerval {
+                it_interval: timeval(0),
+                it_value: timeval(0),
+            },
+            null_mut(),
+        )
+    };
+}
+
+#[cfg(all(windows, not(feature = "disabled")))]
+pub(crate) use crate::platform::Timer;
+
+#[cfg(all(unix, not(feature = "disabled")))]
 pub struct Timer {
     pub frequency: c_int,
     pub start_time: SystemTime,
     pub start_instant: Instant,
+    which: c_int,
+    jitter: f64,
+    #[cfg(target_os = "linux")]
+    thread_timers: Option<ThreadTimers>,
+    #[cfg(target_os = "linux")]
+    activation: Option<Activation>,
 }
 
+#[cfg(all(unix, not(feature = "disabled")))]
 impl Timer {
-    pub fn new(frequency: c_int) -> Timer {
-        let interval = 1e6 as i64 / i64::from(frequency);
-        let it_interval = Timeval {
-            tv_sec: interval / 1e6 as i64,
-            tv_usec: interval % 1e6 as i64,
-        };
-        let it_value = it_interval.clone();
-
-        unsafe {
-            setitimer(
-                ITIMER_PROF,
-                &mut Itimerval {
-                    it_interval,
-                    it_value,
-                },
-                null_mut(),
-            )
-        };
+    /// Create a timer with `setitimer(2)`, which counts the CPU time of the
+    /// whole process, and whose intervals are randomized by up to `jitter` of
+    /// the period. On Linux, a warning is logged for the existing threads
+    /// which block its signal, the threads spawned later aren't checked.
+    pub fn new(frequency: c_int, mode: ProfilingMode, jitter: f64) -> Timer {
+        #[cfg(target_os = "linux")]
+        warn_blocked_threads(mode.signal());
+
+        let which = mode.which();
+        arm(which, frequency, jitter);
 
         Timer {
             frequency,
             start_time: SystemTime::now(),
             start_instant: Instant::now(),
+            which,
+            jitter,
+            #[cfg(target_os = "linux")]
+            thread_timers: None,
+            #[cfg(target_os = "linux")]
+            activation: None,
+        }
+    }
+
+    /// Create a timer with `setitimer(2)` like `Timer::new`, but which is
+    /// only armed while the CPU usage of the process is above `threshold`
+    /// percent of one CPU. A background thread measures the usage from
+    /// `/proc/self/stat` and arms or disarms the timer.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn new_activated_above(
+        frequency: c_int,
+        mode: ProfilingMode,
+        jitter: f64,
+        threshold: f64,
+    ) -> Result<Timer> {
+        warn_blocked_threads(mode.signal());
+
+        let which = mode.which();
+        Ok(Timer {
+            frequency,
+            start_time: SystemTime::now(),
+            start_instant: Instant::now(),
+            which,
+            jitter,
+            thread_timers: None,
+            activation: Some(Activation::new(which, frequency, jitter, threshold)?),
+        })
+    }
+
+    /// Create a timer per thread with `timer_create(2)`, which counts the CPU
+    /// time of its own thread and delivers `signal` to that thread only.
+    /// The threads spawned later arm their own timer on the first signal of
+    /// the process wide timer, see `SelfArming`, or are picked up by a
+    /// background thread with another signal or the wall time. A warning is
+    /// logged for every thread which blocks the signal.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn new_per_thread(
+        frequency: c_int,
+        mode: ProfilingMode,
+        signal: Signal,
+    ) -> Result<Timer> {
+        Ok(Timer {
+            frequency,
+            start_time: SystemTime::now(),
+            start_instant: Instant::now(),
+            which: mode.which(),
+            jitter: 0.0,
+            thread_timers: Some(ThreadTimers::new(frequency, mode, signal)?),
+            activation: None,
+        })
+    }
+
+    /// Disarm the timer until `resume`. The per thread timers keep running,
+    /// but not the process wide timer which arms the new threads.
+    pub(crate) fn pause(&self) {
+        #[cfg(target_os = "linux")]
+        if let Some(thread_timers) = &self.thread_timers {
+            if let Some(which) = thread_timers.self_arming {
+                disarm(which);
+            }
+            return;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(activation) = &self.activation {
+            activation.pause(self.which);
+            return;
         }
+
+        disarm(self.which);
+    }
+
+    /// Arm the timer again after `pause`.
+    pub(crate) fn resume(&self) {
+        #[cfg(target_os = "linux")]
+        if let Some(thread_timers) = &self.thread_timers {
+            if let Some(which) = thread_timers.self_arming {
+                arm(which, self.frequency, 0.0);
+            }
+            return;
+        }
+        // the supervisor arms it again if the process is busy
+        #[cfg(target_os = "linux")]
+        if let Some(activation) = &self.activation {
+            activation.paused.store(false, Ordering::Release);
+            return;
+        }
+
+        arm(self.which, self.frequency, self.jitter);
     }
 
     /// Returns a `ReportTiming` struct having this timer's frequency and start
@@ -64,30 +528,692 @@ impl Timer {
             frequency: self.frequency,
             start_time: self.start_time,
             duration: self.start_instant.elapsed(),
+            #[cfg(target_os = "linux")]
+            active_duration: self
+                .activation
+                .as_ref()
+                .map(|activation| activation.active.lock().elapsed()),
+            #[cfg(not(target_os = "linux"))]
+            active_duration: None,
         }
     }
 }
 
+#[cfg(all(unix, not(feature = "disabled")))]
 impl Drop for Timer {
     fn drop(&mut self) {
-        let it_interval = Timeval {
-            tv_sec: 0,
-            tv_usec: 0,
+        #[cfg(target_os = "linux")]
+        if self.thread_timers.take().is_some() {
+            return;
+        }
+
+        // stop the supervisor first, so that it can't arm the timer again
+        #[cfg(target_os = "linux")]
+        drop(self.activation.take());
+
+        disarm(self.which);
+    }
+}
+
+/// Extract the CPU time of the process, in clock ticks, from the content of
+/// `/proc/self/stat`: the sum of `utime` and `stime`, the 14th and 15th
+/// fields, which are counted after the last `)` as the name may contain
+/// spaces and parentheses.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+fn parse_cpu_ticks(stat: &str) -> Option<u64> {
+    let (_, rest) = stat.rsplit_once(')')?;
+    let mut fields = rest.split_ascii_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(utime + stime)
+}
+
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+fn process_cpu_ticks() -> Option<u64> {
+    std::fs::read_to_string("/proc/self/stat")
+        .ok()
+        .and_then(|stat| parse_cpu_ticks(&stat))
+}
+
+/// The time during which the timer was armed.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+#[derive(Default)]
+struct ActiveTime {
+    elapsed: Duration,
+    since: Option<Instant>,
+}
+
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+impl ActiveTime {
+    fn elapsed(&self) -> Duration {
+        self.elapsed + self.since.map_or(Duration::ZERO, |since| since.elapsed())
+    }
+}
+
+/// A background thread which arms the interval timer while the CPU usage of
+/// the process is above a threshold, and disarms it otherwise.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+struct Activation {
+    stop: Arc<AtomicBool>,
+    supervisor: Option<JoinHandle<()>>,
+    active: Arc<parking_lot::Mutex<ActiveTime>>,
+    /// Keeps the timer disarmed whatever the usage, see `Timer::pause`.
+    paused: Arc<AtomicBool>,
+}
+
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+impl Activation {
+    /// The window over which the CPU usage is measured.
+    const USAGE_INTERVAL: Duration = Duration::from_millis(200);
+
+    fn new(which: c_int, frequency: c_int, jitter: f64, threshold: f64) -> Result<Self> {
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as f64;
+        let active = Arc::new(parking_lot::Mutex::new(ActiveTime::default()));
+        let paused = Arc::new(AtomicBool::new(false));
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let supervisor = {
+            let stop = stop.clone();
+            let active = active.clone();
+            let paused = paused.clone();
+            std::thread::Builder::new()
+                .name("pprof-activate".to_owned())
+                .spawn(move || {
+                    let mut previous = (Instant::now(), process_cpu_ticks());
+                    while !stop.load(Ordering::Acquire) {
+                        std::thread::sleep(Self::USAGE_INTERVAL);
+                        let current = (Instant::now(), process_cpu_ticks());
+                        let usage = match (previous.1, current.1) {
+                            (Some(before), Some(after)) => {
+                                let cpu = after.saturating_sub(before) as f64 / ticks_per_second;
+                                let wall = (current.0 - previous.0).as_secs_f64();
+                                Some(cpu / wall.max(f64::EPSILON) * 100.0)
+                            }
+                            _ => None,
+                        };
+                        previous = current;
+
+                        // keep the timer as it is if the usage is unknown
+                        let busy = match usage {
+                            Some(usage) => usage > threshold,
+                            None => continue,
+                        };
+                        let mut active = active.lock();
+                        // checked under the lock, so that a pause can't be
+                        // followed by arming the timer
+                        let busy = busy && !paused.load(Ordering::Acquire);
+                        match (busy, active.since) {
+                            (true, None) => {
+                                arm(which, frequency, jitter);
+                                active.since = Some(Instant::now());
+                            }
+                            (false, Some(since)) => {
+                                disarm(which);
+                                active.elapsed += since.elapsed();
+                                active.since = None;
+                            }
+                            _ => {}
+                        }
+                    }
+                })?
         };
-        let it_value = it_interval.clone();
-        unsafe {
-            setitimer(
-                ITIMER_PROF,
-                &mut Itimerval {
-                    it_interval,
-                    it_value,
-                },
-                null_mut(),
-            )
+
+        Ok(Self {
+            stop,
+            supervisor: Some(supervisor),
+            active,
+            paused,
+        })
+    }
+
+    /// Disarm the timer until the pause is cleared.
+    fn pause(&self, which: c_int) {
+        self.paused.store(true, Ordering::Release);
+        let mut active = self.active.lock();
+        if let Some(since) = active.since.take() {
+            disarm(which);
+            active.elapsed += since.elapsed();
+        }
+    }
+}
+
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+impl Drop for Activation {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(supervisor) = self.supervisor.take() {
+            if supervisor.join().is_err() {
+                log::error!("timer supervisor thread panicked");
+            }
+        }
+    }
+}
+
+/// A POSIX timer armed on the CPU clock of one thread.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+struct ThreadTimer(libc::timer_t);
+
+// `timer_t` is an opaque id, it isn't tied to the thread which created it.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+unsafe impl Send for ThreadTimer {}
+
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+impl ThreadTimer {
+    fn new(
+        tid: libc::pid_t,
+        mode: ProfilingMode,
+        signal: Signal,
+        interval: Duration,
+    ) -> std::io::Result<Self> {
+        let mut event: libc::sigevent = unsafe { std::mem::zeroed() };
+        event.sigev_notify = libc::SIGEV_THREAD_ID;
+        event.sigev_signo = signal as c_int;
+        event.sigev_notify_thread_id = tid;
+
+        let mut timer: libc::timer_t = null_mut();
+        if unsafe { libc::timer_create(mode.thread_clock(tid), &mut event, &mut timer) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let timer = ThreadTimer(timer);
+
+        let interval = libc::timespec {
+            tv_sec: interval.as_secs() as _,
+            tv_nsec: interval.subsec_nanos() as _,
         };
+        let spec = libc::itimerspec {
+            it_interval: interval,
+            it_value: interval,
+        };
+        if unsafe { libc::timer_settime(timer.0, 0, &spec, null_mut()) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(timer)
+    }
+}
+
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+impl Drop for ThreadTimer {
+    fn drop(&mut self) {
+        unsafe { libc::timer_delete(self.0) };
     }
 }
 
+/// The timers of all threads of the process, kept in sync with
+/// `/proc/self/task` by a background thread.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+struct ThreadTimers {
+    stop: Arc<AtomicBool>,
+    scanner: Option<JoinHandle<()>>,
+    /// The process wide timer, if the threads arm their own timers, see
+    /// `SelfArming`.
+    self_arming: Option<c_int>,
+}
+
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+impl ThreadTimers {
+    const RESCAN_INTERVAL: Duration = Duration::from_millis(100);
+
+    fn new(frequency: c_int, mode: ProfilingMode, signal: Signal) -> Result<Self> {
+        let interval = Duration::from_secs(1) / frequency.max(1) as u32;
+        // the process wide timer can only deliver the signal of its mode, and
+        // doesn't count the wall time
+        if !mode.is_wall_time() && signal == mode.signal() {
+            return Self::new_self_arming(frequency, mode, interval);
+        }
+
+        let mut timers = HashMap::new();
+        // arm the existing threads before returning, so that a failure of
+        // `timer_create` is reported
+        Self::rescan(&mut timers, 0, mode, signal, interval)?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let scanner = {
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("pprof-timer".to_owned())
+                .spawn(move || {
+                    let scanner_tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+                    timers.remove(&scanner_tid);
+                    while !stop.load(Ordering::Acquire) {
+                        std::thread::sleep(Self::RESCAN_INTERVAL);
+                        if let Err(err) =
+                            Self::rescan(&mut timers, scanner_tid, mode, signal, interval)
+                        {
+                            log::warn!("fail to create timers for new threads: {}", err);
+                        }
+                    }
+                })?
+        };
+
+        Ok(Self {
+            stop,
+            scanner: Some(scanner),
+            self_arming: None,
+        })
+    }
+
+    /// Arm the existing threads, and the process wide timer whose signal
+    /// arms the threads spawned later as soon as they run. The background
+    /// thread only deletes the timers of the threads which exited.
+    fn new_self_arming(frequency: c_int, mode: ProfilingMode, interval: Duration) -> Result<Self> {
+        let signal = mode.signal();
+        SELF_ARMING.configure(mode, signal, interval);
+        let mut threads = match SELF_ARMING.arm_existing() {
+            Ok(threads) => threads,
+            Err(err) => {
+                SELF_ARMING.clear();
+                return Err(err.into());
+            }
+        };
+        for tid in threads.iter() {
+            warn_if_signal_blocked(*tid, signal);
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let scanner = {
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("pprof-timer".to_owned())
+                .spawn(move || {
+                    while !stop.load(Ordering::Acquire) {
+                        std::thread::sleep(Self::RESCAN_INTERVAL);
+                        SELF_ARMING.reap();
+                        // the new threads are only checked for the warning
+                        if let Ok(current) = list_threads() {
+                            for tid in current.difference(&threads) {
+                                warn_if_signal_blocked(*tid, signal);
+                            }
+                            threads = current;
+                        }
+                    }
+                })
+        };
+        let scanner = match scanner {
+            Ok(scanner) => scanner,
+            Err(err) => {
+                SELF_ARMING.clear();
+                return Err(err.into());
+            }
+        };
+
+        SELF_ARMING.enabled.store(true, Ordering::SeqCst);
+        arm(mode.which(), frequency, 0.0);
+
+        Ok(Self {
+            stop,
+            scanner: Some(scanner),
+            self_arming: Some(mode.which()),
+        })
+    }
+
+    /// Arm the threads which don't have a timer yet, except `skip_tid`, and
+    /// delete the timers of the threads which exited.
+    fn rescan(
+        timers: &mut HashMap<libc::pid_t, ThreadTimer>,
+        skip_tid: libc::pid_t,
+        mode: ProfilingMode,
+        signal: Signal,
+        interval: Duration,
+    ) -> std::io::Result<()> {
+        let tids = list_threads()?;
+
+        timers.retain(|tid, _| tids.contains(tid));
+        for tid in tids {
+            if tid == skip_tid || timers.contains_key(&tid) {
+                continue;
+            }
+
+            match ThreadTimer::new(tid, mode, signal, interval) {
+                Ok(timer) => {
+                    warn_if_signal_blocked(tid, signal);
+                    timers.insert(tid, timer);
+                }
+                // the thread exited after being listed
+                Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+impl Drop for ThreadTimers {
+    fn drop(&mut self) {
+        // the signals which are still pending are handled like the others
+        // until the handlers are done with the table
+        if let Some(which) = self.self_arming {
+            disarm(which);
+            SELF_ARMING.disable();
+        }
+        self.stop.store(true, Ordering::Release);
+        if let Some(scanner) = self.scanner.take() {
+            if scanner.join().is_err() {
+                log::error!("timer scanner thread panicked");
+            }
+        }
+        if self.self_arming.is_some() {
+            SELF_ARMING.clear();
+        }
+    }
+}
+
+/// The ids of the threads of the current process.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+fn list_threads() -> std::io::Result<HashSet<libc::pid_t>> {
+    let mut tids = HashSet::new();
+    for entry in std::fs::read_dir("/proc/self/task")? {
+        if let Some(tid) = entry?.file_name().to_str().and_then(|tid| tid.parse().ok()) {
+            tids.insert(tid);
+        }
+    }
+
+    Ok(tids)
+}
+
+/// Create a timer on the CPU clock `clock` of the thread `tid`, which
+/// delivers `signal` to it every `interval`, and return its kernel id. It
+/// calls the syscalls directly, so it's async-signal-safe.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+fn create_thread_timer(
+    tid: libc::pid_t,
+    clock: libc::clockid_t,
+    signal: c_int,
+    interval: Duration,
+) -> std::io::Result<c_int> {
+    let mut event: libc::sigevent = unsafe { std::mem::zeroed() };
+    event.sigev_notify = libc::SIGEV_THREAD_ID;
+    event.sigev_signo = signal;
+    event.sigev_notify_thread_id = tid;
+
+    let mut timer: c_int = 0;
+    if unsafe { libc::syscall(libc::SYS_timer_create, clock, &mut event, &mut timer) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let interval = libc::timespec {
+        tv_sec: interval.as_secs() as _,
+        tv_nsec: interval.subsec_nanos() as _,
+    };
+    let spec = libc::itimerspec {
+        it_interval: interval,
+        it_value: interval,
+    };
+    let no_old_spec: *mut libc::itimerspec = null_mut();
+    if unsafe { libc::syscall(libc::SYS_timer_settime, timer, 0, &spec, no_old_spec) } != 0 {
+        let err = std::io::Error::last_os_error();
+        unsafe { libc::syscall(libc::SYS_timer_delete, timer) };
+        return Err(err);
+    }
+
+    Ok(timer)
+}
+
+/// The max number of threads which can have a timer armed by `SelfArming`
+/// at the same time, the others are sampled by the process wide timer.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+const MAX_SELF_ARMED_THREADS: usize = 1 << 10;
+
+/// The thread id of an entry of `SelfArming` which was never used.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+const EMPTY_THREAD: libc::pid_t = 0;
+/// The thread id of an entry of `SelfArming` whose thread exited, which can
+/// be reused but doesn't end the probing.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+const EXITED_THREAD: libc::pid_t = -1;
+
+/// The timer of an entry of `SelfArming` which isn't created yet.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+const PENDING_TIMER: c_int = -1;
+/// The timer of an entry of `SelfArming` which couldn't be created, the
+/// thread is then sampled by the process wide timer.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+const FAILED_TIMER: c_int = -2;
+
+/// The per thread timers which are armed by the threads themselves. The
+/// process wide timer keeps running beside them, and when its signal is
+/// delivered to a thread without a timer, e.g. which was just spawned, the
+/// handler samples it and arms one for it, so that even the short-lived
+/// threads are sampled from their start. Its signals are ignored on the
+/// threads which have their own timer.
+///
+/// The timers are kept in a lock-free open addressing table from thread id
+/// to kernel timer id. An entry is only added by its own thread in the signal
+/// handler, or before the process wide timer is armed, and removed by the
+/// scanner thread once its thread exited.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+struct SelfArming {
+    enabled: AtomicBool,
+    /// The number of signal handlers using the table.
+    handling: AtomicUsize,
+    clock: AtomicI32,
+    signal: AtomicI32,
+    interval_nanos: AtomicU64,
+    threads: [(AtomicI32, AtomicI32); MAX_SELF_ARMED_THREADS],
+}
+
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+static SELF_ARMING: SelfArming = SelfArming::new();
+
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+impl SelfArming {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: (AtomicI32, AtomicI32) =
+        (AtomicI32::new(EMPTY_THREAD), AtomicI32::new(PENDING_TIMER));
+
+    const fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            handling: AtomicUsize::new(0),
+            clock: AtomicI32::new(0),
+            signal: AtomicI32::new(0),
+            interval_nanos: AtomicU64::new(0),
+            threads: [Self::EMPTY; MAX_SELF_ARMED_THREADS],
+        }
+    }
+
+    fn configure(&self, mode: ProfilingMode, signal: Signal, interval: Duration) {
+        self.clock
+            .store(mode.cpu_clock().unwrap_or_default(), Ordering::Relaxed);
+        self.signal.store(signal as c_int, Ordering::Relaxed);
+        self.interval_nanos
+            .store(interval.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Stop arming the threads, and wait for the signal handlers which are
+    /// using the table, so that it can be cleared.
+    fn disable(&self) {
+        self.enabled.store(false, Ordering::SeqCst);
+        while self.handling.load(Ordering::SeqCst) != 0 {
+            std::hint::spin_loop();
+        }
+    }
+
+    fn slot(tid: libc::pid_t) -> usize {
+        ((tid as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 54) as usize % MAX_SELF_ARMED_THREADS
+    }
+
+    /// The index of the entry of `tid`, and whether it was just claimed for
+    /// it, or `None` if the table is full. It's async-signal-safe.
+    fn entry(&self, tid: libc::pid_t) -> Option<(usize, bool)> {
+        loop {
+            let start = Self::slot(tid);
+            let mut free = None;
+            for probe in 0..MAX_SELF_ARMED_THREADS {
+                let index = (start + probe) % MAX_SELF_ARMED_THREADS;
+                match self.threads[index].0.load(Ordering::Acquire) {
+                    current if current == tid => return Some((index, false)),
+                    EMPTY_THREAD => {
+                        free.get_or_insert((index, EMPTY_THREAD));
+                        break;
+                    }
+                    EXITED_THREAD => {
+                        free.get_or_insert((index, EXITED_THREAD));
+                    }
+                    _ => {}
+                }
+            }
+
+            let (index, current) = free?;
+            if self.threads[index]
+                .0
+                .compare_exchange(current, tid, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                return Some((index, true));
+            }
+            // another thread claimed it first
+        }
+    }
+
+    /// Create the timer of the entry `index` claimed for `tid`. It's
+    /// async-signal-safe.
+    fn arm(&self, index: usize, tid: libc::pid_t) -> std::io::Result<()> {
+        let timer = create_thread_timer(
+            tid,
+            thread_cpu_clock(tid, self.clock.load(Ordering::Relaxed)),
+            self.signal.load(Ordering::Relaxed),
+            Duration::from_nanos(self.interval_nanos.load(Ordering::Relaxed)),
+        );
+        let id = match &timer {
+            Ok(id) => *id,
+            Err(_) => FAILED_TIMER,
+        };
+        self.threads[index].1.store(id, Ordering::Release);
+        timer.map(|_| ())
+    }
+
+    /// Arm the threads of the process, and return them. It must be called
+    /// before the process wide timer is armed.
+    fn arm_existing(&self) -> std::io::Result<HashSet<libc::pid_t>> {
+        let tids = list_threads()?;
+        for tid in tids.iter() {
+            let index = match self.entry(*tid) {
+                Some((index, true)) => index,
+                _ => continue,
+            };
+            match self.arm(index, *tid) {
+                Ok(()) => {}
+                // the thread exited after being listed
+                Err(err) if err.raw_os_error() == Some(libc::EINVAL) => self.release(index),
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(tids)
+    }
+
+    /// Whether the signal described by `siginfo` must be sampled. The
+    /// signals of the process wide timer are only sampled on the threads
+    /// which don't have a timer, which are armed by the way. It's
+    /// async-signal-safe.
+    fn sample(&self, siginfo: *const libc::siginfo_t) -> bool {
+        if !self.enabled.load(Ordering::SeqCst)
+            || siginfo.is_null()
+            || unsafe { (*siginfo).si_code } == libc::SI_TIMER
+        {
+            return true;
+        }
+
+        let tid = crate::thread_state::current_tid() as libc::pid_t;
+        match self.entry(tid) {
+            Some((index, true)) => {
+                let _ = self.arm(index, tid);
+                true
+            }
+            Some((index, false)) => self.threads[index].1.load(Ordering::Acquire) == FAILED_TIMER,
+            None => true,
+        }
+    }
+
+    /// Delete the timer of the entry `index`, and free it for another thread.
+    fn release(&self, index: usize) {
+        let (tid, timer) = &self.threads[index];
+        let id = timer.swap(PENDING_TIMER, Ordering::AcqRel);
+        if id >= 0 {
+            unsafe { libc::syscall(libc::SYS_timer_delete, id) };
+        }
+        tid.store(EXITED_THREAD, Ordering::Release);
+    }
+
+    /// Delete the timers of the threads which exited.
+    fn reap(&self) {
+        for (index, (tid, timer)) in self.threads.iter().enumerate() {
+            let tid = tid.load(Ordering::Acquire);
+            // a pending timer is being created by its running thread
+            if tid <= 0 || timer.load(Ordering::Acquire) == PENDING_TIMER {
+                continue;
+            }
+            if std::fs::metadata(format!("/proc/self/task/{}", tid)).is_err() {
+                self.release(index);
+            }
+        }
+    }
+
+    /// Delete all the timers. It must be called once the table isn't used
+    /// anymore.
+    fn clear(&self) {
+        for (index, (tid, _)) in self.threads.iter().enumerate() {
+            if tid.load(Ordering::Acquire) != EMPTY_THREAD {
+                self.release(index);
+                tid.store(EMPTY_THREAD, Ordering::Release);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn is_armed(&self, tid: libc::pid_t) -> bool {
+        self.threads.iter().any(|(id, timer)| {
+            id.load(Ordering::Acquire) == tid && timer.load(Ordering::Acquire) >= 0
+        })
+    }
+}
+
+/// Whether the signal described by `siginfo` must be sampled, which is only
+/// not the case for the signals of the process wide timer on the threads
+/// which have their own timer. It's called by the signal handler.
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+pub(crate) fn sample_timer_signal(siginfo: *const libc::siginfo_t) -> bool {
+    SELF_ARMING.handling.fetch_add(1, Ordering::SeqCst);
+    let sample = SELF_ARMING.sample(siginfo);
+    SELF_ARMING.handling.fetch_sub(1, Ordering::SeqCst);
+    sample
+}
+
+/// Whether the thread `tid` armed its own timer.
+#[cfg(all(test, unix, not(feature = "disabled")))]
+#[cfg(target_os = "linux")]
+pub(crate) fn has_thread_timer(tid: libc::pid_t) -> bool {
+    SELF_ARMING.is_armed(tid)
+}
+
 /// Timing metadata for a collected report.
 #[derive(Clone)]
 pub struct ReportTiming {
@@ -97,6 +1223,11 @@ pub struct ReportTiming {
     pub start_time: SystemTime,
     /// Collection duration.
     pub duration: Duration,
+    /// The part of the duration during which the timer was armed, with
+    /// `ProfilerGuardBuilder::activate_above_cpu_percent`, and the profiler
+    /// wasn't paused. `None` means that it was armed during the whole
+    /// duration.
+    pub active_duration: Option<Duration>,
 }
 
 impl Default for ReportTiming {
@@ -105,6 +1236,75 @@ impl Default for ReportTiming {
             frequency: 1,
             start_time: SystemTime::UNIX_EPOCH,
             duration: Default::default(),
+            active_duration: None,
         }
     }
 }
+
+#[cfg(all(unix, not(feature = "disabled")))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supported_frequency() {
+        // a kernel with `CONFIG_HZ=250`
+        let resolution = Duration::from_millis(4);
+        assert!(frequency_is_supported(99, resolution));
+        assert!(frequency_is_supported(250, resolution));
+        assert!(!frequency_is_supported(1000, resolution));
+
+        assert!(timer_resolution().is_some());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn blocked_signals() {
+        let status = "Name:\tworker\nSigPnd:\t0000000000000000\nSigBlk:\t0000000004000000\n";
+        assert_eq!(parse_blocked_signals(status), Some(1 << 26));
+        assert_eq!(parse_blocked_signals("Name:\tworker\n"), None);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+        let worker = std::thread::spawn(move || {
+            let mut set = nix::sys::signal::SigSet::empty();
+            set.add(Signal::SIGPROF);
+            set.thread_block().unwrap();
+            tx.send(unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t)
+                .unwrap();
+            done_rx.recv().unwrap();
+        });
+        let tid = rx.recv().unwrap();
+        assert!(thread_blocks_signal(tid, Signal::SIGPROF));
+        assert!(!thread_blocks_signal(tid, Signal::SIGVTALRM));
+        done_tx.send(()).unwrap();
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn jittered_intervals() {
+        let jitter = Jitter::new();
+        jitter.enable(ITIMER_PROF, 10_000, 0.5);
+        let intervals: Vec<_> = (0..10_000).map(|_| jitter.next_interval(0.5)).collect();
+        assert!(intervals
+            .iter()
+            .all(|interval| (5_000..15_000).contains(interval)));
+        assert!(intervals.iter().any(|interval| *interval < 6_000));
+        assert!(intervals.iter().any(|interval| *interval > 14_000));
+        let mean = intervals.iter().sum::<i64>() / intervals.len() as i64;
+        assert!((9_800..10_200).contains(&mean));
+
+        // the interval never drops to zero, which would disarm the timer
+        jitter.enable(ITIMER_PROF, 1, 1.0);
+        assert!((0..100).all(|_| jitter.next_interval(1.0) >= 1));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn cpu_ticks() {
+        let stat = "1234 (a) b) c) R 1 1234 1234 0 -1 4194304 100 0 0 0 250 31 0 0 20 0 4";
+        assert_eq!(parse_cpu_ticks(stat), Some(281));
+        assert_eq!(parse_cpu_ticks("1234 (a) R 1"), None);
+        assert!(process_cpu_ticks().is_some());
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! The signal handler must not allocate, even for stacks deeper than
+//! `MAX_DEPTH`. The allocations are counted by a global allocator, so the test
+//! lives in its own binary.
+
+#![cfg(all(target_os = "linux", not(feature = "disabled")))]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use pprof::{ProfilerGuardBuilder, MAX_DEPTH};
+
+struct CountingAllocator;
+
+thread_local! {
+    static TRACKING: Cell<bool> = const { Cell::new(false) };
+}
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if TRACKING.with(Cell::get) {
+            ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Spin without allocating at the bottom of a recursion of `depth` frames.
+/// Every allocation counted while it's spinning is made by the signal
+/// handler.
+#[inline(never)]
+fn deep_recursive(depth: usize, duration: Duration, track: bool) -> usize {
+    if depth == 0 {
+        TRACKING.with(|tracking| tracking.set(track));
+        let start = Instant::now();
+        let mut spins = 0;
+        while start.elapsed() < duration {
+            spins = std::hint::black_box(spins + 1);
+        }
+        TRACKING.with(|tracking| tracking.set(false));
+        spins
+    } else {
+        // keep the recursion from being turned into a loop
+        std::hint::black_box(deep_recursive(depth - 1, duration, track)) + 1
+    }
+}
+
+#[test]
+fn malloc_free() {
+    let guard = ProfilerGuardBuilder::default()
+        .frequency(1000)
+        .build()
+        .unwrap();
+
+    let spinner = std::thread::Builder::new()
+        .name("spinner".to_owned())
+        .spawn(|| {
+            // warm up the unwinder, which may allocate on its first use
+            deep_recursive(1, Duration::from_millis(200), false);
+
+            ALLOCATIONS.store(0, Ordering::SeqCst);
+            deep_recursive(1, Duration::from_millis(200), true);
+            deep_recursive(MAX_DEPTH * 2, Duration::from_millis(500), true);
+            ALLOCATIONS.load(Ordering::SeqCst)
+        })
+        .unwrap();
+    let allocations = spinner.join().unwrap();
+
+    let report = guard.report().build().unwrap();
+    // the frame pointers are only followed as far as the frames keep them
+    let samples: isize = report
+        .data
+        .iter()
+        .filter(|(frames, _)| {
+            frames.thread_name == "spinner" && (frames.truncated || cfg!(feature = "frame-pointer"))
+        })
+        .map(|(_, count)| count)
+        .sum();
+    assert!(samples > 0, "no deep stack is sampled");
+    assert_eq!(allocations, 0);
+}